@@ -7,11 +7,16 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also records the git hash and the build timestamp into environment
+//! variables so that the firmware can report which build it is running.
 
 use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -28,4 +33,70 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+
+    // The on-target tests (tests/*.rs, embedded-test) need their own linker
+    // script. Only add it when they are built, so host tests still link.
+    if env::var_os("CARGO_FEATURE_ON_TARGET_TEST").is_some()
+        && env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("none")
+    {
+        println!("cargo:rustc-link-arg-tests=-Tembedded-test.x");
+    }
+
+    // Build information read by `env!` in `src/version.rs`.
+    // Both fall back to "unknown" so that building from a source tarball
+    // or on a machine without git still works.
+    println!("cargo:rustc-env=PICO_TIMER_GIT_HASH={}", git_hash());
+    println!(
+        "cargo:rustc-env=PICO_TIMER_BUILD_TIMESTAMP={}",
+        build_timestamp()
+    );
+    // Pick up a new hash when HEAD moves to another commit or branch.
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
+
+fn git_hash() -> String {
+    Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// Formats the current time as `YYYY-MM-DDTHH:MM:SSZ` (UTC).
+fn build_timestamp() -> String {
+    let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) else {
+        return "unknown".to_string();
+    };
+    let secs = now.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let time = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+// Converts days since 1970-01-01 into a (year, month, day) civil date.
+// Algorithm from Howard Hinnant's "chrono-Compatible Low-Level Date Algorithms".
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
 }
@@ -1,6 +1,8 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    /* 最後の4 KBは保存した設定のセクタ（src/config.rsのCONFIG_FLASH_OFFSET）、
+       その前の4 KBはリセットの記録のセクタ（src/reset_log.rsのRESET_LOG_FLASH_OFFSET） */
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 8K
     RAM   : ORIGIN = 0x20000000, LENGTH = 256K
 }
 
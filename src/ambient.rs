@@ -0,0 +1,181 @@
+// 周囲の明るさでLEDのモードを自動で切り替えるモジュール
+//
+// GPIO26（ADC0）にLDR（光依存抵抗）またはフォトダイオードをつなぐ。
+// 想定している回路は以下の分圧回路。
+//
+//   3V3 ── LDR ──┬── 固定抵抗 ── GND
+//                └── GPIO26(ADC0)
+//
+// この回路では明るいほどLDRの抵抗値が下がるので、ADCの読み値は「明るいほど大きく」なる。
+// LDRと固定抵抗の位置を入れ替えた回路では向きが逆になるので、
+// その場合は下の閾値の大小関係とnext_mode()の比較を反転させること。
+//
+// モードの自動の切り替え（automode）
+//   読み値がAMBIENT_DARK_THRESHOLDを下回ったらSolidからBlinkに、AMBIENT_BRIGHT_THRESHOLDを上回ったらBlinkからSolidにする。
+//   起動時はAMBIENT_AUTO_MODE（true）で有効。UARTのautomode on|offで切り替えられる。
+//   GPIO26はプルをつけていないので、LDRをつながない基板ではピンが浮いて読み値が定まらず、モードがでたらめに切り替わる。
+//   LDRを載せない基板向けにビルドするときは、AMBIENT_AUTO_MODEをfalseにすること。
+//   ほかの方法（UARTのmode、セレクタ、知らせ、エッグタイマーなど。mode::set_mode()）でモードを変えると、
+//   自動の切り替えは止まる（suspend_auto_mode()）。その後で読み値が閾値をまたいでも、選んだモードを上書きしない。
+//   もう一度automode onにすれば再開する。起動時の設定（config.rs）のモードと、起動して最初のセレクタの読み取りでは止めない。
+//
+// 周囲の明るさによる調光（autodim）
+//   常時点いている表示灯が夜に眩しくないよう、読み値からLEDの明るさの倍率を決める
+//   （led::set_ambient_scale()）。起動時は無効で、UARTのautodim onで有効にする。
+//   ・読み値がAMBIENT_DIM_DARK以下ならAMBIENT_DIM_MIN、AMBIENT_DIM_BRIGHT以上ならAMBIENT_DIM_MAX。
+//     その間はAMBIENT_DIM_CURVE（autodim linear|quadratic|log|steps Nで変えられる）の曲線でつなぐ。
+//     曲線ごとの明るさの変わり方はresponse_curve.rsを参照
+//   ・読み値はそのまま使わず、指数移動平均（1サンプルごとに差の1/2^AMBIENT_DIM_SMOOTHING_SHIFT
+//     だけ近づける）でならしてから倍率にする。ADCのノイズや手をかざした程度の一瞬の影で
+//     明るさがちらつかず、部屋の明かりを消したときもサンプルごとに少しずつ暗くなる
+
+use crate::cs_trace::free;
+use crate::led;
+use crate::mode::LedMode;
+use crate::pull::{self, Pull};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::response_curve::ResponseCurve;
+use rp_pico::hal::{
+    adc::{Adc, AdcPin},
+    gpio,
+};
+
+// ADCのread()はembedded-hal 0.2のOneShotトレイトで宣言されている。
+use embedded_hal_0_2::adc::OneShot;
+
+// ADCは12bitなので読み値は0〜4095。
+// 読み値がこれを下回ったら暗い部屋とみなす。
+pub const AMBIENT_DARK_THRESHOLD: u16 = 1200;
+// 読み値がこれを上回ったら明るい部屋とみなす。
+// DARKとBRIGHTの間はヒステリシス帯で、境界付近でモードがバタつかないようにしている。
+pub const AMBIENT_BRIGHT_THRESHOLD: u16 = 1600;
+
+// 明るさをサンプリングする周期
+pub const AMBIENT_SAMPLE_INTERVAL_MS: u32 = 500;
+assert_alarm_interval_ms!(AMBIENT_SAMPLE_INTERVAL_MS);
+
+// ヒステリシス帯の幅が0以下だと意味がないのでコンパイル時に検査する
+const _: () = assert!(AMBIENT_DARK_THRESHOLD < AMBIENT_BRIGHT_THRESHOLD);
+
+// 調光が最も暗くなる/明るくなる読み値
+pub const AMBIENT_DIM_DARK: u16 = 200;
+pub const AMBIENT_DIM_BRIGHT: u16 = 3000;
+// 調光の倍率の範囲（u16::MAXで全体の明るさのまま）
+pub const AMBIENT_DIM_MIN: u16 = u16::MAX / 16;
+pub const AMBIENT_DIM_MAX: u16 = u16::MAX;
+pub const AMBIENT_DIM_CURVE: ResponseCurve = ResponseCurve::Quadratic;
+// 指数移動平均の重み。3なら1サンプル（AMBIENT_SAMPLE_INTERVAL_MS）ごとに差の1/8だけ近づく
+pub const AMBIENT_DIM_SMOOTHING_SHIFT: u32 = 3;
+
+const _: () = assert!(AMBIENT_DIM_DARK < AMBIENT_DIM_BRIGHT);
+
+// 起動時にモードを自動で切り替えるか
+pub const AMBIENT_AUTO_MODE: bool = true;
+
+static DIMMING: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+static AUTO_MODE: Mutex<Cell<bool>> = Mutex::new(Cell::new(AMBIENT_AUTO_MODE));
+static DIM_CURVE: Mutex<Cell<ResponseCurve>> = Mutex::new(Cell::new(AMBIENT_DIM_CURVE));
+
+// 調光を有効/無効にする。無効にすると倍率はすぐにu16::MAX（調光なし）に戻る。
+pub fn set_dimming(enabled: bool) {
+    free(|cs| {
+        DIMMING.borrow(cs).set(enabled);
+        if !enabled {
+            led::set_ambient_scale(cs, u16::MAX);
+        }
+    });
+}
+
+pub fn is_dimming(cs: &CriticalSection) -> bool {
+    DIMMING.borrow(cs).get()
+}
+
+// モードの自動の切り替えを有効/無効にする。次のサンプルから反映される。
+pub fn set_auto_mode(enabled: bool) {
+    free(|cs| AUTO_MODE.borrow(cs).set(enabled));
+}
+
+pub fn is_auto_mode(cs: &CriticalSection) -> bool {
+    AUTO_MODE.borrow(cs).get()
+}
+
+// ほかの方法でモードを変えたときにmode::set_mode()から呼ぶ。自動の切り替えを止める。
+pub fn suspend_auto_mode(cs: &CriticalSection) {
+    if AUTO_MODE.borrow(cs).replace(false) {
+        defmt::info!("ambient: mode set explicitly, auto mode suspended");
+    }
+}
+
+// 調光の曲線を変える。次のサンプルから反映される。
+pub fn set_dim_curve(curve: ResponseCurve) {
+    free(|cs| DIM_CURVE.borrow(cs).set(curve));
+}
+
+pub fn dim_curve(cs: &CriticalSection) -> ResponseCurve {
+    DIM_CURVE.borrow(cs).get()
+}
+
+// 読み値から調光の倍率を求める
+fn dim_scale(curve: ResponseCurve, reading: u16) -> u16 {
+    let span = u32::from(AMBIENT_DIM_BRIGHT - AMBIENT_DIM_DARK);
+    let position =
+        u32::from(reading.clamp(AMBIENT_DIM_DARK, AMBIENT_DIM_BRIGHT) - AMBIENT_DIM_DARK);
+    // DARK〜BRIGHTのどこにいるかを0〜u16::MAXの割合にして曲線をかける
+    let curved = u32::from(curve.apply((position * u32::from(u16::MAX) / span) as u16));
+    let range = u32::from(AMBIENT_DIM_MAX - AMBIENT_DIM_MIN);
+    AMBIENT_DIM_MIN + (range * curved / u32::from(u16::MAX)) as u16
+}
+
+// 分圧回路の電圧をそのまま測るので、プル抵抗はつけない
+pub const AMBIENT_PULL: Pull = Pull::None;
+
+pub type AmbientPin = pull::InputPin<gpio::bank0::Gpio26>;
+
+// ADC本体は温度センサー（thermal）と共有するので、読むときに借りる
+pub struct AmbientLight {
+    pin: AdcPin<AmbientPin>,
+    // 調光に使う、ならした読み値（2^AMBIENT_DIM_SMOOTHING_SHIFT倍して小数部を残している）
+    filtered: Option<u32>,
+}
+
+impl AmbientLight {
+    pub fn new(pin: AdcPin<AmbientPin>) -> Self {
+        Self {
+            pin,
+            filtered: None,
+        }
+    }
+
+    // 読み値をならして、調光が有効ならLEDの倍率に反映する。最初の読み値はそのまま使う。
+    pub fn update_dimming(&mut self, reading: u16) {
+        let sample = u32::from(reading) << AMBIENT_DIM_SMOOTHING_SHIFT;
+        let filtered = match self.filtered {
+            None => sample,
+            // filtered += (sample - filtered) / 2^shift を整数で行う
+            Some(f) => f - (f >> AMBIENT_DIM_SMOOTHING_SHIFT) + u32::from(reading),
+        };
+        self.filtered = Some(filtered);
+        let smoothed = (filtered >> AMBIENT_DIM_SMOOTHING_SHIFT) as u16;
+        free(|cs| {
+            if is_dimming(cs) {
+                led::set_ambient_scale(cs, dim_scale(dim_curve(cs), smoothed));
+            }
+        });
+    }
+
+    pub fn read(&mut self, adc: &mut Adc) -> u16 {
+        // RP2040のADCは変換完了を待つので常にOkが返る
+        adc.read(&mut self.pin).unwrap()
+    }
+}
+
+// 現在のモードと読み値から次のモードを決める。
+// 閾値をまたいだときだけ切り替え、ヒステリシス帯の中では今のモードを維持する。
+pub fn next_mode(current: LedMode, reading: u16) -> LedMode {
+    match current {
+        LedMode::Solid if reading < AMBIENT_DARK_THRESHOLD => LedMode::Blink,
+        LedMode::Blink if reading > AMBIENT_BRIGHT_THRESHOLD => LedMode::Solid,
+        current => current,
+    }
+}
@@ -0,0 +1,138 @@
+// 起動時のバナー
+//
+// ターミナルをつないだときに、何のデバイスがどのピン配置で動いているかすぐにわかるように、
+// 起動時に1回だけUARTとdefmtの両方へ出す。
+// ピン番号はmain()で実際に使ったピンから取るので、配線を変えてもバナーとずれることはない。
+// PinMapに入れる番号は、pin_table::registered()で一覧（pin_table.rs）と同じかを確かめたもの。
+//
+// 文字列の分だけフラッシュを使うので、banner featureを外すと何も出さない空の関数になる。
+//   cargo build --no-default-features
+
+use crate::status_tx::StatusTx;
+
+// ピン番号（GPIOの番号）
+#[derive(Default)]
+pub struct PinMap {
+    pub led: u8,
+    pub tone: u8,
+    pub ambient: u8,
+    pub button: u8,
+    pub onewire: u8,
+    pub toggle_button: u8,
+    pub heartbeat: u8,
+    pub pulse: u8,
+    pub manchester: u8,
+    pub estop: u8,
+    pub zero_cross: u8,
+    pub edge_counter: u8,
+    pub pulse_train: u8,
+    pub ir: u8,
+    // 出力の許可の入力（output_enable.rs）。cs-traceフィーチャーでは同じピンをcs_traceの出力に使うのでNone
+    pub output_enable: Option<u8>,
+    // セレクタの(bit0, bit1)
+    pub selector: (u8, u8),
+    // 2色LEDの(赤, 緑)
+    pub bicolor: (u8, u8),
+    // ロータリーエンコーダーの(A, B)
+    pub encoder: (u8, u8),
+    pub uart_tx: u8,
+    pub uart_rx: u8,
+    pub i2c_sda: u8,
+    pub i2c_scl: u8,
+}
+
+#[cfg(feature = "banner")]
+pub fn print_banner(pins: &PinMap, tx: &mut StatusTx) {
+    use crate::cs_trace::free;
+    use crate::{interval, mode, prescaler, tone, version};
+    use defmt::Display2Format;
+
+    // 同じ行をUARTとdefmtの両方に出す
+    let mut emit = |args: core::fmt::Arguments| {
+        defmt::info!("{}", Display2Format(&args));
+        tx.write_line_blocking(args);
+    };
+
+    emit(format_args!("        _                   _   _"));
+    emit(format_args!(
+        "  _ __ (_) ___ ___       | |_(_)_ __ ___   ___ _ __"
+    ));
+    emit(format_args!(
+        " | '_ \\| |/ __/ _ \\ _____| __| | '_ ` _ \\ / _ \\ '__|"
+    ));
+    emit(format_args!(
+        " | |_) | | (_| (_) |_____| |_| | | | | | |  __/ |"
+    ));
+    emit(format_args!(
+        " | .__/|_|\\___\\___/       \\__|_|_| |_| |_|\\___|_|"
+    ));
+    emit(format_args!(" |_|"));
+    emit(format_args!(
+        "pico-timer {} (git {}) built {}",
+        version::VERSION,
+        version::GIT_HASH,
+        version::BUILD_TIMESTAMP
+    ));
+    emit(format_args!(
+        "pins: LED=GPIO{} TONE=GPIO{} AMBIENT=GPIO{}(ADC{}) BUTTON=GPIO{} 1-WIRE=GPIO{} HEARTBEAT=GPIO{}",
+        pins.led,
+        pins.tone,
+        pins.ambient,
+        // ADCの入力はGPIO26から順にADC0, ADC1, ...
+        pins.ambient - 26,
+        pins.button,
+        pins.onewire,
+        pins.heartbeat
+    ));
+    emit(format_args!(
+        "pins: UART0 TX=GPIO{} RX=GPIO{} I2C0 SDA=GPIO{} SCL=GPIO{} TOGGLE=GPIO{} PULSE=GPIO{} SEL=GPIO{},{} MANCH=GPIO{} ESTOP=GPIO{} ZC=GPIO{} EDGE=GPIO{} PULSES=GPIO{} IR=GPIO{} BICOLOR=GPIO{},{} ENC=GPIO{},{}",
+        pins.uart_tx,
+        pins.uart_rx,
+        pins.i2c_sda,
+        pins.i2c_scl,
+        pins.toggle_button,
+        pins.pulse,
+        pins.selector.0,
+        pins.selector.1,
+        pins.manchester,
+        pins.estop,
+        pins.zero_cross,
+        pins.edge_counter,
+        pins.pulse_train,
+        pins.ir,
+        pins.bicolor.0,
+        pins.bicolor.1,
+        pins.encoder.0,
+        pins.encoder.1
+    ));
+    match pins.output_enable {
+        Some(gpio) => emit(format_args!(
+            "pins: EN=GPIO{} (active {})",
+            gpio,
+            if crate::output_enable::OUTPUT_ENABLE_ACTIVE_HIGH {
+                "high"
+            } else {
+                "low"
+            }
+        )),
+        None => emit(format_args!("pins: EN=none")),
+    }
+    let (interval_ms, prescale, mode, tone_hz) = free(|cs| {
+        (
+            interval::interval_ms(cs),
+            prescaler::prescale(cs),
+            mode::mode(cs),
+            tone::tone_freq(cs),
+        )
+    });
+    emit(format_args!(
+        "config: interval={}ms prescale={} mode={} tone={}Hz",
+        interval_ms,
+        prescale,
+        mode.name(),
+        tone_hz
+    ));
+}
+
+#[cfg(not(feature = "banner"))]
+pub fn print_banner(_pins: &PinMap, _tx: &mut StatusTx) {}
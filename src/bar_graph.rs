@@ -0,0 +1,75 @@
+// LedMode::Barの「時間の棒グラフ」
+//
+// 0〜100%の値を、BAR_WINDOW_MSの窓のうち点灯している時間の割合で表す。
+//   点灯 BAR_WINDOW_MS × 値 / 100 → 消灯 残り
+// をくり返す。電池の残量のような量を、LED1つで目で読めるように表示するためのもの。
+// 明るさ（PWMのデューティ）で表すと見た目の明るさとの関係がわかりにくいが、点灯している長さなら読み取りやすい。
+//
+// 値を変えるのはset_bar_value()で、いつ呼んでもよい。使うのは窓の始まりで、窓の途中では変えないので、
+// 1つの窓の点灯と消灯の長さは必ず同じ値から決まり、値が変わっても窓の長さは変わらない。
+// 0%なら窓の間ずっと消灯、100%なら窓の間ずっと点灯していて、その間は点灯/消灯を切り替えない。
+// 窓の途中の点灯/消灯（STATE）はモードがBarに切り替わったときにreset()で窓の始まりに戻す。
+
+use crate::cs_trace::free;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::decimal_blink::Step;
+
+pub const BAR_WINDOW_MS: u32 = 2000;
+assert_alarm_interval_ms!(BAR_WINDOW_MS);
+// 起動時の値
+pub const BAR_DEFAULT_PERCENT: u8 = 50;
+
+static BAR_PERCENT: Mutex<Cell<u8>> = Mutex::new(Cell::new(BAR_DEFAULT_PERCENT));
+// 窓の後半（消灯）が残っていればその長さ
+static REMAINING_OFF_MS: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+
+// 表示する値を変える。100より大きければ100に丸め、実際に設定した値を返す。次の窓から表示する。
+pub fn set_bar_value(percent: u8) -> u8 {
+    let percent = percent.min(100);
+    free(|cs| BAR_PERCENT.borrow(cs).set(percent));
+    percent
+}
+
+pub fn bar_value(cs: &CriticalSection) -> u8 {
+    BAR_PERCENT.borrow(cs).get()
+}
+
+// 1つの窓のうち点灯している時間
+pub fn on_ms(percent: u8) -> u32 {
+    BAR_WINDOW_MS * u32::from(percent.min(100)) / 100
+}
+
+// モードがBarに切り替わったときにmode::set_mode()から呼ぶ（位相をそろえるときはphase_sync.rsからも）
+pub fn reset(cs: &CriticalSection) {
+    REMAINING_OFF_MS.borrow(cs).set(None);
+}
+
+// TIMER_IRQ_0から呼ぶ。次に出す点灯/消灯とその長さを返す。
+pub fn next_step(cs: &CriticalSection) -> Step {
+    if let Some(off_ms) = REMAINING_OFF_MS.borrow(cs).take() {
+        return Step {
+            on: false,
+            duration_ms: off_ms,
+        };
+    }
+    // 窓の始まり。ここで読んだ値で窓の最後まで表示する。
+    let on_ms = on_ms(bar_value(cs));
+    match on_ms {
+        0 => Step {
+            on: false,
+            duration_ms: BAR_WINDOW_MS,
+        },
+        BAR_WINDOW_MS => Step {
+            on: true,
+            duration_ms: BAR_WINDOW_MS,
+        },
+        _ => {
+            REMAINING_OFF_MS.borrow(cs).set(Some(BAR_WINDOW_MS - on_ms));
+            Step {
+                on: true,
+                duration_ms: on_ms,
+            }
+        }
+    }
+}
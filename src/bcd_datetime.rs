@@ -0,0 +1,275 @@
+// DS3231の時刻のレジスタ（BCD）と日時の変換
+//
+// ds3231.rsのI2Cの転送から、ペリフェラルに触らない変換の部分だけをここに分けて、ホストでテストする。
+// BCDの変換、12時間表示、世紀のビット、うるう年はどれも間違えやすいので、境界の値をテストで確かめている。
+//
+// 時刻のレジスタ（0x00〜0x06）はすべてBCD（10進の各桁を4bitずつ）で格納されている。
+//   0x00 秒   bit6-4: 10の位, bit3-0: 1の位
+//   0x01 分   同上
+//   0x02 時   bit6: 12/24時間表示（0なら24時間）, bit5-4: 10の位, bit3-0: 1の位
+//             12時間表示のときはbit5がPM、bit4が10の位
+//   0x03 曜日 1〜7
+//   0x04 日   bit5-4: 10の位, bit3-0: 1の位
+//   0x05 月   bit7: 世紀, bit4: 10の位, bit3-0: 1の位
+//   0x06 年   下2桁
+
+pub const HOURS_12H: u8 = 1 << 6;
+pub const HOURS_PM: u8 = 1 << 5;
+pub const MONTH_CENTURY: u8 = 1 << 7;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+// "YYYY-MM-DD HH:MM:SS"の形で表示する
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+impl DateTime {
+    // "YYYY-MM-DD HH:MM:SS"の形の文字列から読み取る
+    pub fn parse(s: &str) -> Option<Self> {
+        let (date, time) = s.split_once(' ')?;
+        let mut date = date.splitn(3, '-');
+        let mut time = time.splitn(3, ':');
+        let dt = DateTime {
+            year: date.next()?.parse().ok()?,
+            month: date.next()?.parse().ok()?,
+            day: date.next()?.parse().ok()?,
+            hour: time.next()?.parse().ok()?,
+            minute: time.next()?.parse().ok()?,
+            second: time.next()?.parse().ok()?,
+        };
+        dt.is_valid().then_some(dt)
+    }
+
+    // DS3231が表現できるのは2000〜2199年
+    pub fn is_valid(&self) -> bool {
+        (2000..=2199).contains(&self.year)
+            && (1..=12).contains(&self.month)
+            && (1..=days_in_month(self.year, self.month)).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    // 曜日（1 = 日曜日 〜 7 = 土曜日）。Sakamotoの方法で求める。
+    pub fn weekday(&self) -> u8 {
+        const T: [u16; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+        let y = if self.month < 3 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let d =
+            y + y / 4 - y / 100 + y / 400 + T[usize::from(self.month - 1)] + u16::from(self.day);
+        (d % 7) as u8 + 1
+    }
+}
+
+pub fn is_leap_year(year: u16) -> bool {
+    (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400)
+}
+
+pub fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// BCDを2進数に変換する。各桁が0〜9に収まっていなければNone。
+// 呼び出し側で制御用のビット（12/24時間表示や世紀など）を落としてから渡すこと。
+pub fn bcd_to_bin(bcd: u8) -> Option<u8> {
+    let (tens, ones) = (bcd >> 4, bcd & 0x0F);
+    (tens <= 9 && ones <= 9).then_some(tens * 10 + ones)
+}
+
+// 0〜99の2進数をBCDに変換する
+pub fn bin_to_bcd(bin: u8) -> u8 {
+    ((bin / 10) << 4) | (bin % 10)
+}
+
+// 時のレジスタを24時間表示の時に変換する
+pub fn decode_hours(reg: u8) -> Option<u8> {
+    if reg & HOURS_12H == 0 {
+        return bcd_to_bin(reg & 0x3F);
+    }
+    // 12時間表示では12時が0時（AM）または12時（PM）を表す
+    let hour12 = bcd_to_bin(reg & 0x1F)?;
+    let pm = reg & HOURS_PM != 0;
+    match (hour12, pm) {
+        (12, false) => Some(0),
+        (12, true) => Some(12),
+        (1..=11, false) => Some(hour12),
+        (1..=11, true) => Some(hour12 + 12),
+        _ => None,
+    }
+}
+
+pub fn decode(regs: &[u8; 7]) -> Option<DateTime> {
+    let century = if regs[5] & MONTH_CENTURY != 0 { 100 } else { 0 };
+    let dt = DateTime {
+        second: bcd_to_bin(regs[0] & 0x7F)?,
+        minute: bcd_to_bin(regs[1] & 0x7F)?,
+        hour: decode_hours(regs[2])?,
+        // regs[3]は曜日なので日時の計算には使わない
+        day: bcd_to_bin(regs[4] & 0x3F)?,
+        month: bcd_to_bin(regs[5] & 0x1F)?,
+        year: 2000 + century + u16::from(bcd_to_bin(regs[6])?),
+    };
+    dt.is_valid().then_some(dt)
+}
+
+// 24時間表示で書き込む
+pub fn encode(dt: &DateTime) -> [u8; 7] {
+    let years = dt.year - 2000;
+    let century = if years >= 100 { MONTH_CENTURY } else { 0 };
+    [
+        bin_to_bcd(dt.second),
+        bin_to_bcd(dt.minute),
+        bin_to_bcd(dt.hour),
+        dt.weekday(),
+        bin_to_bcd(dt.day),
+        bin_to_bcd(dt.month) | century,
+        bin_to_bcd((years % 100) as u8),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dt(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    #[test]
+    fn bcd_round_trips_0_to_99() {
+        for bin in 0..=99 {
+            assert_eq!(bcd_to_bin(bin_to_bcd(bin)), Some(bin));
+        }
+        assert_eq!(bin_to_bcd(59), 0x59);
+        assert_eq!(bcd_to_bin(0x42), Some(42));
+    }
+
+    #[test]
+    fn bcd_rejects_nibbles_above_9() {
+        assert_eq!(bcd_to_bin(0x0A), None);
+        assert_eq!(bcd_to_bin(0xA0), None);
+        assert_eq!(bcd_to_bin(0x1F), None);
+        assert_eq!(bcd_to_bin(0xFF), None);
+    }
+
+    #[test]
+    fn decode_hours_24h() {
+        assert_eq!(decode_hours(0x00), Some(0));
+        assert_eq!(decode_hours(0x23), Some(23));
+        assert_eq!(decode_hours(0x1A), None);
+    }
+
+    #[test]
+    fn decode_hours_12h_am_pm() {
+        // 12 AMは0時、12 PMは12時
+        assert_eq!(decode_hours(HOURS_12H | 0x12), Some(0));
+        assert_eq!(decode_hours(HOURS_12H | HOURS_PM | 0x12), Some(12));
+        assert_eq!(decode_hours(HOURS_12H | 0x01), Some(1));
+        assert_eq!(decode_hours(HOURS_12H | 0x11), Some(11));
+        assert_eq!(decode_hours(HOURS_12H | HOURS_PM | 0x01), Some(13));
+        assert_eq!(decode_hours(HOURS_12H | HOURS_PM | 0x11), Some(23));
+        // 12時間表示に0時や13時はない
+        assert_eq!(decode_hours(HOURS_12H | 0x00), None);
+        assert_eq!(decode_hours(HOURS_12H | 0x13), None);
+    }
+
+    #[test]
+    fn encode_decode_round_trips() {
+        for d in [
+            dt(2000, 1, 1, 0, 0, 0),
+            dt(2024, 2, 29, 12, 34, 56),
+            dt(2099, 12, 31, 23, 59, 59),
+            dt(2100, 1, 1, 0, 0, 0),
+            dt(2199, 12, 31, 23, 59, 59),
+        ] {
+            assert_eq!(decode(&encode(&d)), Some(d));
+        }
+    }
+
+    #[test]
+    fn century_bit() {
+        let regs = encode(&dt(2100, 3, 1, 0, 0, 0));
+        assert_ne!(regs[5] & MONTH_CENTURY, 0);
+        assert_eq!(regs[6], 0x00);
+        let regs = encode(&dt(2099, 3, 1, 0, 0, 0));
+        assert_eq!(regs[5] & MONTH_CENTURY, 0);
+        assert_eq!(regs[6], 0x99);
+        // 世紀のビットが立っていれば2100年代として読む
+        let mut regs = encode(&dt(2005, 6, 7, 8, 9, 10));
+        regs[5] |= MONTH_CENTURY;
+        assert_eq!(decode(&regs), Some(dt(2105, 6, 7, 8, 9, 10)));
+    }
+
+    #[test]
+    fn leap_days() {
+        assert!(is_leap_year(2000));
+        assert!(is_leap_year(2024));
+        assert!(!is_leap_year(2100));
+        assert!(!is_leap_year(2023));
+        assert_eq!(days_in_month(2024, 2), 29);
+        assert_eq!(days_in_month(2100, 2), 28);
+        assert!(dt(2024, 2, 29, 0, 0, 0).is_valid());
+        assert!(!dt(2023, 2, 29, 0, 0, 0).is_valid());
+        assert!(!dt(2100, 2, 29, 0, 0, 0).is_valid());
+        // うるう日のない年の2月29日はレジスタから読んでも受け付けない
+        let mut regs = encode(&dt(2023, 2, 28, 0, 0, 0));
+        regs[4] = 0x29;
+        assert_eq!(decode(&regs), None);
+    }
+
+    #[test]
+    fn decode_rejects_invalid_bcd() {
+        let mut regs = encode(&dt(2024, 5, 6, 7, 8, 9));
+        regs[0] = 0x5A;
+        assert_eq!(decode(&regs), None);
+    }
+
+    #[test]
+    fn weekday_is_sunday_first() {
+        // 2000-01-01は土曜日、2024-02-29は木曜日
+        assert_eq!(dt(2000, 1, 1, 0, 0, 0).weekday(), 7);
+        assert_eq!(dt(2024, 2, 29, 0, 0, 0).weekday(), 5);
+        assert_eq!(dt(2024, 3, 3, 0, 0, 0).weekday(), 1);
+    }
+
+    #[test]
+    fn parse_checks_range() {
+        assert_eq!(
+            DateTime::parse("2024-02-29 23:59:59"),
+            Some(dt(2024, 2, 29, 23, 59, 59))
+        );
+        assert_eq!(DateTime::parse("2023-02-29 00:00:00"), None);
+        assert_eq!(DateTime::parse("1999-12-31 00:00:00"), None);
+        assert_eq!(DateTime::parse("2024-01-01 24:00:00"), None);
+    }
+}
@@ -0,0 +1,138 @@
+// クリティカルセクションとMutex<RefCell>の借用にかかる時間を測るベンチマーク（benchフィーチャー）
+//
+// このクレートではグローバル変数をすべてcortex_m::interrupt::Mutexに入れ、
+// free(|cs| ...)かISRの中のCriticalSection::new()で借りている。その手間が何サイクルかを測って
+// defmtに出す。アトミック変数と比べるかどうかを決めるときの目安にする。
+//
+// 測り方
+//   RP2040のコア（Cortex-M0+）にはDWTのサイクルカウンタ（CYCCNT）がないので、
+//   かわりにSysTickをclk_sysで動かし、24bitのカウンタの減った量をサイクル数とする。
+//   測りたい処理の前後でカウンタを読み、何もしないとき（読み出し2回だけ）のサイクル数を引く。
+//   BENCH_RUNS回くり返して最小・平均・最大を出す。
+//   割り込みを有効にする前の起動中に1回だけ実行するので、途中で割り込みが入ることはない。
+//   フラッシュから実行しているので、XIPキャッシュに乗るまでの最初の数回は遅くなる（最大に出る）。
+//
+// 測る処理（実際のコードと同じ書き方にしている）
+//   free                : 何もしないfree(|_| {})。割り込みの禁止と復帰だけのコスト
+//   free + Cell         : free()の中でカウンタを1つ増やす（INTERRUPT_COUNTERと同じ）
+//   RefCell borrow      : ISRと同じく、CriticalSection::new()で借りてborrow_mut().as_mut()する
+//   free + RefCell      : メインループと同じく、free()の中でborrow_mut().as_mut()する
+//   atomic              : AtomicU32をloadして1足してstoreする（atomic-counterのカウンタと同じ）
+//
+// SysTickはtick-sourceフィーチャーでも使うので、測り終わったらSysTickを止めて返す。
+
+use crate::cs_trace::free;
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::Cell;
+use core::hint::black_box;
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use cortex_m::peripheral::{syst::SystClkSource, SYST};
+
+pub const BENCH_RUNS: u32 = 1000;
+
+// SysTickは24bitのダウンカウンタ
+const SYST_MASK: u32 = 0x00FF_FFFF;
+
+static BENCH_COUNTER: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+static BENCH_ATOMIC: AtomicU32 = AtomicU32::new(0);
+static BENCH_PERIPHERAL: GlobalPeripheral<u32> = initial_global_peripheral();
+
+struct Stats {
+    min: u32,
+    max: u32,
+    total: u32,
+}
+
+// fを1回実行するのにかかったサイクル数をBENCH_RUNS回測る。baselineはそこから引く分。
+fn measure(baseline: u32, mut f: impl FnMut()) -> Stats {
+    let mut stats = Stats {
+        min: u32::MAX,
+        max: 0,
+        total: 0,
+    };
+    for _ in 0..BENCH_RUNS {
+        let start = SYST::get_current();
+        f();
+        let end = SYST::get_current();
+        let cycles = (start.wrapping_sub(end) & SYST_MASK).saturating_sub(baseline);
+        stats.min = stats.min.min(cycles);
+        stats.max = stats.max.max(cycles);
+        stats.total += cycles;
+    }
+    stats
+}
+
+fn report(name: &str, stats: &Stats) {
+    defmt::info!(
+        "bench {=str}: min {} / mean {} / max {} cycles",
+        name,
+        stats.min,
+        stats.total / BENCH_RUNS,
+        stats.max
+    );
+}
+
+// 割り込みを有効にする前に呼ぶ
+pub fn run(syst: &mut SYST) {
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(SYST_MASK);
+    syst.clear_current();
+    syst.enable_counter();
+
+    free(|cs| BENCH_PERIPHERAL.borrow(cs).replace(Some(0)));
+
+    // カウンタを読むだけのサイクル数。最小値を引く分にする。
+    let baseline = measure(0, || {}).min;
+    defmt::info!(
+        "bench: {} runs each, timed with SysTick on clk_sys (overhead {} cycles subtracted)",
+        BENCH_RUNS,
+        baseline
+    );
+
+    report(
+        "free",
+        &measure(baseline, || {
+            free(|cs| {
+                black_box(cs);
+            })
+        }),
+    );
+    report(
+        "free + Cell",
+        &measure(baseline, || {
+            free(|cs| {
+                let counter = BENCH_COUNTER.borrow(cs);
+                counter.set(counter.get().wrapping_add(1));
+            })
+        }),
+    );
+    report(
+        "RefCell borrow",
+        &measure(baseline, || {
+            let cs = unsafe { CriticalSection::new() };
+            if let Some(value) = BENCH_PERIPHERAL.borrow(&cs).borrow_mut().as_mut() {
+                *value = black_box(value.wrapping_add(1));
+            };
+        }),
+    );
+    report(
+        "free + RefCell",
+        &measure(baseline, || {
+            free(|cs| {
+                if let Some(value) = BENCH_PERIPHERAL.borrow(cs).borrow_mut().as_mut() {
+                    *value = black_box(value.wrapping_add(1));
+                }
+            })
+        }),
+    );
+    report(
+        "atomic",
+        &measure(baseline, || {
+            let next = BENCH_ATOMIC.load(Ordering::Relaxed).wrapping_add(1);
+            BENCH_ATOMIC.store(next, Ordering::Relaxed);
+        }),
+    );
+
+    syst.disable_counter();
+}
@@ -0,0 +1,125 @@
+// 2色LED（赤/緑）の色をゆっくり入れ替えるクロスフェード
+//
+// 配線
+//   赤 : GPIO6（PWM3A）
+//   緑 : GPIO7（PWM3B）
+//   それぞれ電流制限の抵抗を通して2色LEDの赤と緑の端子につなぐ。同じスライス（PWM3）の
+//   2つのチャンネルなので、周期はそろっていて、デューティだけを別々に変えられる。
+//   カソードコモン（共通端子をGNDにつなぐ）ならBICOLOR_COMMON_ANODEをfalseのままにする。
+//   アノードコモン（共通端子を3.3 Vにつなぐ）ならtrueにする。出力を反転させ、Lowの間を点灯にする。
+//
+// クロスフェード
+//   赤だけが点灯 → 緑だけが点灯 → 赤だけが点灯 をくり返す。片道（赤から緑まで）の時間が
+//   set_crossfade_speed()で決める時間で、往復がその2倍になる。
+//   緑の割合gを三角形の波形（rp2040_project_template::waveform_table）で決め、赤は1 - gにする。
+//   1つが上がるのと同じだけもう1つが下がるのは「見た目の明るさ」で、それぞれをガンマ補正してから
+//   デューティにする（ガンマ補正の前に足すと、途中の色が暗く沈んで見えるため）。
+//
+// 進めるのはsampler（SAMPLE_PERIOD_MSごと）で、時間はSAMPLE_PERIOD_MS単位に丸める。
+// 起動時は消灯。UARTの bicolor on / off / speed MS で切り替える。
+
+use crate::cs_trace::free;
+use crate::output::{self, OutputId};
+use crate::sampler::SAMPLE_PERIOD_MS;
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use embedded_hal::pwm::SetDutyCycle;
+use rp2040_project_template::waveform_table::{self, Waveform};
+use rp_pico::hal::pwm;
+
+pub type BicolorPwm = pwm::Slice<pwm::Pwm3, pwm::FreeRunning>;
+
+pub const BICOLOR_COMMON_ANODE: bool = false;
+// 起動時の片道の時間
+pub const CROSSFADE_MS: u32 = 2000;
+pub const CROSSFADE_MIN_MS: u32 = 10 * SAMPLE_PERIOD_MS;
+pub const CROSSFADE_MAX_MS: u32 = 60_000;
+
+static BICOLOR_PWM: GlobalPeripheral<BicolorPwm> = initial_global_peripheral();
+static ENABLED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+static CROSSFADE_SPEED_MS: Mutex<Cell<u32>> = Mutex::new(Cell::new(CROSSFADE_MS));
+// 往復の中で、赤だけが点灯したところからの時間
+static ELAPSED_MS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+// スライスはすでにenable()済みで、チャンネルAにGPIO6、BにGPIO7を割り当てたものを渡す
+pub fn init(cs: &CriticalSection, mut slice: BicolorPwm) {
+    // 極性はoutput.rsの表で決める
+    if output::is_active_low(OutputId::BicolorRed) {
+        slice.channel_a.set_inverted();
+    }
+    if output::is_active_low(OutputId::BicolorGreen) {
+        slice.channel_b.set_inverted();
+    }
+    BICOLOR_PWM.borrow(cs).replace(Some(slice));
+    write(cs, 0, 0);
+}
+
+fn write(cs: &CriticalSection, red: u16, green: u16) {
+    if let Some(slice) = BICOLOR_PWM.borrow(cs).borrow_mut().as_mut() {
+        let red = waveform_table::gamma_correct(red);
+        let green = waveform_table::gamma_correct(green);
+        slice
+            .channel_a
+            .set_duty_cycle_fraction(red, u16::MAX)
+            .unwrap();
+        slice
+            .channel_b
+            .set_duty_cycle_fraction(green, u16::MAX)
+            .unwrap();
+    }
+}
+
+pub fn is_enabled(cs: &CriticalSection) -> bool {
+    ENABLED.borrow(cs).get()
+}
+
+pub fn crossfade_speed(cs: &CriticalSection) -> u32 {
+    CROSSFADE_SPEED_MS.borrow(cs).get()
+}
+
+// クロスフェードを始める（赤から）／やめて消灯する
+pub fn set_crossfade(enabled: bool) {
+    free(|cs| {
+        ENABLED.borrow(cs).set(enabled);
+        ELAPSED_MS.borrow(cs).set(0);
+        if enabled {
+            write(cs, u16::MAX, 0);
+        } else {
+            write(cs, 0, 0);
+        }
+    });
+}
+
+// 赤から緑までの片道の時間をmsにする。CROSSFADE_MIN_MS〜CROSSFADE_MAX_MSに収め、
+// SAMPLE_PERIOD_MS単位に丸めた時間を返す。
+pub fn set_crossfade_speed(ms: u32) -> u32 {
+    let ms = ms.clamp(CROSSFADE_MIN_MS, CROSSFADE_MAX_MS) / SAMPLE_PERIOD_MS * SAMPLE_PERIOD_MS;
+    free(|cs| {
+        CROSSFADE_SPEED_MS.borrow(cs).set(ms);
+        // 往復の途中の位置が新しい往復より後ろにならないように
+        let elapsed = ELAPSED_MS.borrow(cs);
+        elapsed.set(elapsed.get() % (2 * ms));
+    });
+    ms
+}
+
+// 次のtick()で、往復の最初（赤だけが点灯）に戻るようにする（phase_sync.rs）
+pub fn restart_phase(cs: &CriticalSection) {
+    let period_ms = 2 * crossfade_speed(cs);
+    ELAPSED_MS.borrow(cs).set(period_ms - SAMPLE_PERIOD_MS);
+}
+
+// samplerから呼ぶ。SAMPLE_PERIOD_MSだけ進め、2つのデューティを書き換える。
+pub fn tick(cs: &CriticalSection) {
+    if !is_enabled(cs) {
+        return;
+    }
+    let period_ms = 2 * crossfade_speed(cs);
+    let elapsed = ELAPSED_MS.borrow(cs);
+    let now = (elapsed.get() + SAMPLE_PERIOD_MS) % period_ms;
+    elapsed.set(now);
+    let phase = (u64::from(now) * 0x10000 / u64::from(period_ms)) as u16;
+    let green = waveform_table::level(Waveform::Triangle, phase);
+    write(cs, u16::MAX - green, green);
+}
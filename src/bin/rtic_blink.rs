@@ -0,0 +1,143 @@
+// 点滅とカウントだけをRTIC（rtic 2）で書いた版
+//
+// ふだんのファームウェア（main.rs）は、割り込みとメインループで共有する値をMutex<RefCell>やMutex<Cell>の
+// グローバル変数に置き、free()で割り込みを止めてから触っている（shared.rs、counter.rs）。
+// RTICでは、共有する値を#[shared]と#[local]のリソースとして宣言し、フレームワークに借用を任せる。
+//   ・#[local]  : 1つのタスクだけが使う値。ロックなしで&mutとして使える（ALARM0、LEDのピン、Timer、期限）。
+//   ・#[shared] : 複数のタスクが使う値。lock()の中だけで触れる（割り込みカウンタ）。
+//               lock()は、そのリソースを使うタスクのうち最も高い優先度まで割り込みを止める（Stack Resource Policy）。
+//               RP2040（Cortex-M0+）にはBASEPRIがないので、thumbv6のバックエンドはNVICのマスクで止める。
+// 同じリソースを2回借りるようなコードはコンパイルで断られるので、shared.rsの借用の決まりを人が守る必要がない。
+//
+// 動き（main.rsの点滅とカウントと同じ）
+//   ・TIMER_IRQ_0をハードウェアタスク（#[task(binds = TIMER_IRQ_0)]）にして、ALARM0_INTERVAL_MSごとにLEDを反転し、
+//     割り込みカウンタを1つ進める。ALARM0は前の期限から数えてscheduleし直す（main.rsのreschedule_alarm0()と同じtime::reschedule()）。
+//   ・idleでカウンタを見張り、増えたらdefmtに出す。
+//   PWM、モード、UARTなど、main.rsのほかの機能は入れていない。2つの書き方を比べるためのもの。
+//
+// ビルドと実行（rticフィーチャーが必要。ふだんのcargo runはmain.rsのファームウェアを動かす）
+//   cargo run --bin rtic_blink --features rtic
+
+#![no_std]
+#![no_main]
+
+use defmt_rtt as _;
+use panic_probe as _;
+
+#[rtic::app(device = rp_pico::hal::pac)]
+mod app {
+    use defmt::info;
+    // ピンの出力トグルメソッドを使用するために必要
+    use embedded_hal::digital::StatefulOutputPin;
+    use rp2040_project_template::time;
+    use rp_pico::hal::clocks::init_clocks_and_plls;
+    use rp_pico::hal::gpio;
+    use rp_pico::hal::sio::Sio;
+    use rp_pico::hal::timer::{Alarm, Alarm0, Instant, Timer};
+    use rp_pico::hal::watchdog::Watchdog;
+
+    // main.rsのALARM0_INTERVAL_MSと同じ
+    const ALARM0_INTERVAL_MS: u32 = 1000;
+
+    type LedPin = gpio::Pin<gpio::bank0::Gpio25, gpio::FunctionSioOutput, gpio::PullDown>;
+
+    #[shared]
+    struct Shared {
+        // 割り込みカウンタ（main.rsではcounter.rsのMutex<Cell<u32>>）
+        counter: u32,
+    }
+
+    #[local]
+    struct Local {
+        alarm0: Alarm0,
+        led: LedPin,
+        timer: Timer,
+        // ALARM0の今の期限（タイマーカウンタ）
+        deadline: u64,
+    }
+
+    // 割り込みを止めたまま呼ばれる。返したリソースを置いてから、バインドした割り込みを有効にする。
+    #[init]
+    fn init(cx: init::Context) -> (Shared, Local) {
+        let mut pac = cx.device;
+        let sio = Sio::new(pac.SIO);
+        let mut watchdog = Watchdog::new(pac.WATCHDOG);
+        let clocks = init_clocks_and_plls(
+            rp_pico::XOSC_CRYSTAL_FREQ,
+            pac.XOSC,
+            pac.CLOCKS,
+            pac.PLL_SYS,
+            pac.PLL_USB,
+            &mut pac.RESETS,
+            &mut watchdog,
+        )
+        .ok()
+        .unwrap();
+
+        let pins = rp_pico::Pins::new(
+            pac.IO_BANK0,
+            pac.PADS_BANK0,
+            sio.gpio_bank0,
+            &mut pac.RESETS,
+        );
+        let led = pins.led.into_push_pull_output();
+
+        let mut timer = Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
+        let mut alarm0 = timer.alarm_0().unwrap();
+        alarm0.enable_interrupt();
+        alarm0.clear_interrupt();
+        let deadline = time::add_interval(timer.get_counter().ticks(), ALARM0_INTERVAL_MS * 1000);
+        alarm0.schedule_at(Instant::from_ticks(deadline)).unwrap();
+
+        info!("Program start (rtic)");
+        (
+            Shared { counter: 0 },
+            Local {
+                alarm0,
+                led,
+                timer,
+                deadline,
+            },
+        )
+    }
+
+    // main.rsのメインループにあたる。割り込みのない間はここを回る。
+    #[idle(shared = [counter])]
+    fn idle(mut cx: idle::Context) -> ! {
+        let mut counter_old = cx.shared.counter.lock(|counter| *counter);
+        loop {
+            let interrupt_count = cx.shared.counter.lock(|counter| *counter);
+            if counter_old != interrupt_count {
+                info!(
+                    "interrupt count incremented! {} - {}",
+                    counter_old, interrupt_count
+                );
+                counter_old = interrupt_count;
+            }
+        }
+    }
+
+    // ALARM0の割り込み。main.rsのTIMER_IRQ_0にあたる。
+    // localのリソースはこのタスクだけのものなので、ロックもfree()もなしに触れる。
+    #[task(binds = TIMER_IRQ_0, local = [alarm0, led, timer, deadline], shared = [counter])]
+    fn timer_irq_0(mut cx: timer_irq_0::Context) {
+        let alarm0 = cx.local.alarm0;
+        // scheduleし直す前に消す（tests/alarm_reschedule.rs参照）
+        alarm0.clear_interrupt();
+        let next = time::reschedule(
+            alarm0,
+            *cx.local.deadline,
+            ALARM0_INTERVAL_MS * 1000,
+            cx.local.timer.get_counter().ticks(),
+        );
+        *cx.local.deadline = next.deadline;
+
+        // RP2040のGPIOの操作はエラーを返さない（Infallible）
+        cx.local.led.toggle().unwrap();
+
+        // idleより優先度が高いので、このタスクの中ではlock()しても割り込みは止まらない
+        cx.shared
+            .counter
+            .lock(|counter| *counter = counter.wrapping_add(1));
+    }
+}
@@ -0,0 +1,103 @@
+// RTTの2つ目のチャンネルに、ホストのツールで読むためのバイナリのレコードを流す
+//
+// defmtのログはRTTのアップチャンネル0に出る。ここではアップチャンネル1（名前"pico-timer-bin"）を開き、
+// ALARM0の処理（do_tick()）のたびに1つずつ決まった形のレコードを書く。
+// テキストのログと違って文字列の整形をしないので、点滅の周期が短くても負担が小さく、
+// ホスト側でそのままCSVやグラフにできる。
+//
+// defmt-rttとrtt-targetはどちらもRTTの制御ブロック（_SEGGER_RTT）を持つので一緒には使えない。
+// そのためdefmtもrtt-targetのチャンネルに出し（rtt_logger.rs）、制御ブロックはmain()の最初のrtt_init!で1つだけ作る。
+//
+// レコードの形式（RECORD_LEN = 10バイト、数値はリトルエンディアン）
+//   0     : 0xA5（区切り。ホスト側はここを目印に読み始める位置を合わせる）
+//   1..5  : タイマーの下位32bit（µs、約71分で一周する）
+//   5..9  : 割り込みカウンタ（counter.rs）
+//   9     : 状態。bit0 = LEDが点灯扱い（duty > OFF_BRIGHTNESS）、bit4..6 = モード（0: solid, 1: blink, 2: number, 3: off, 4: heartbeat, 5: bar, 6: egg）
+//
+// ホストが読み出していない（プローブをつないでいない、ツールが止まっている）とバッファがいっぱいになる。
+// チャンネルはNoBlockSkipで開いているので、入りきらないレコードは丸ごと捨てる（途中までは書かない）。
+// 捨てた数はdropped()で数えてdiagに出す。
+
+use crate::cs_trace::free;
+use crate::led;
+use crate::mode::{self, LedMode};
+use crate::rtt_logger;
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp_pico::hal::timer::Timer;
+use rtt_target::UpChannel;
+
+pub const RECORD_LEN: usize = 10;
+const RECORD_SYNC: u8 = 0xA5;
+
+static CHANNEL: GlobalPeripheral<UpChannel> = initial_global_peripheral();
+static BINARY_TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+static DROPPED: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+// RTTの制御ブロックを作り、アップチャンネル0をdefmtに使う。バイナリ用のアップチャンネル1を返す。
+// main()の最初に1回だけ呼ぶ（rtt_init!を2回使うとリンクできない）。
+pub fn init_rtt() -> UpChannel {
+    let channels = rtt_target::rtt_init! {
+        up: {
+            0: {
+                size: 1024,
+                name: "defmt"
+            }
+            1: {
+                size: 1024,
+                name: "pico-timer-bin"
+            }
+        }
+    };
+    rtt_logger::init(channels.up.0);
+    channels.up.1
+}
+
+pub fn init(cs: &CriticalSection, channel: UpChannel, timer: Timer) {
+    CHANNEL.borrow(cs).replace(Some(channel));
+    BINARY_TIMER.borrow(cs).set(Some(timer));
+}
+
+pub fn dropped(cs: &CriticalSection) -> u32 {
+    DROPPED.borrow(cs).get()
+}
+
+// レコードをバイナリのチャンネルに書く。入りきらなければ捨てる。
+// 割り込みの中からも呼べる（free()は割り込みの中で呼んでも、抜けるときに元の状態に戻す）。
+pub fn log_binary(record: &[u8]) {
+    free(|cs| {
+        let mut channel = CHANNEL.borrow(cs).borrow_mut();
+        let Some(channel) = channel.as_mut() else {
+            return;
+        };
+        if channel.write(record) < record.len() {
+            let dropped = DROPPED.borrow(cs);
+            dropped.set(dropped.get().wrapping_add(1));
+        }
+    });
+}
+
+// do_tick()から呼ぶ
+pub fn record_tick(cs: &CriticalSection, count: u32) {
+    let Some(timer) = BINARY_TIMER.borrow(cs).get() else {
+        return;
+    };
+    let mode = match mode::mode(cs) {
+        LedMode::Solid => 0,
+        LedMode::Blink => 1,
+        LedMode::Number => 2,
+        LedMode::Off => 3,
+        LedMode::Heartbeat => 4,
+        LedMode::Bar => 5,
+        LedMode::EggTimer => 6,
+    };
+    let state = u8::from(led::is_lit(led::duty(cs))) | (mode << 4);
+
+    let mut record = [0; RECORD_LEN];
+    record[0] = RECORD_SYNC;
+    record[1..5].copy_from_slice(&timer.get_counter_low().to_le_bytes());
+    record[5..9].copy_from_slice(&count.to_le_bytes());
+    record[9] = state;
+    log_binary(&record);
+}
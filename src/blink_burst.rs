@@ -0,0 +1,68 @@
+// 決まった時間だけ点滅の間隔を変え、その後は元の間隔に戻す
+//
+// blink_burst_for(interval_ms, duration_ms)で点滅の間隔をinterval_msにし、duration_ms後に元の間隔に戻す。
+// 何かが起きたときに2秒だけ速く点滅させる、といった一時的な知らせに使う。
+// 戻すのはソフトウェアタイマー（soft_timer.rs）のBLINK_BURST_IDで、TIMER_IRQ_1の中で戻す（Work::EndBlinkBurst）。
+// ワンショットタイマー（oneshot.rs）とは別のidなので、afterの予約とは干渉しない。
+//
+// 変えている最中にもう一度呼んだとき
+//   新しい間隔と時間で置き換える（前の残りの時間は足さず、新しく呼んだときからduration_ms）。
+//   戻す先は最初に変える前の間隔のままなので、何回重ねても最後には元の間隔に戻る。
+//
+// 戻すときは、覚えておいた元の間隔（モードではなく間隔だけ）に戻す。変えている間にモードが変わっても、
+// 間隔だけが元に戻り、モードはそのまま。
+// ただし、変えている間にUARTなどで間隔そのものを変えたときは、その間隔を残して戻さない
+// （今の間隔がblink_burst_for()で変えた間隔のままのときだけ戻す）。
+
+use crate::cs_trace::free;
+use crate::interval;
+use crate::soft_timer::{self, TimerError, BLINK_BURST_ID};
+use crate::work::Work;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+
+#[derive(Clone, Copy)]
+struct Burst {
+    // 変える前の間隔
+    saved_ms: u32,
+    // 変えた間隔（丸めた後）
+    burst_ms: u32,
+}
+
+// 変えている間はSome
+static BURST: Mutex<Cell<Option<Burst>>> = Mutex::new(Cell::new(None));
+
+// duration_msの間だけ点滅の間隔をinterval_msにする。実際に使う間隔（丸めた後）を返す。
+pub fn blink_burst_for(interval_ms: u32, duration_ms: u32) -> Result<u32, TimerError> {
+    free(|cs| {
+        soft_timer::set_timer_with(duration_ms, BLINK_BURST_ID, Work::EndBlinkBurst)?;
+        let burst = BURST.borrow(cs);
+        let saved_ms = burst
+            .get()
+            .map_or_else(|| interval::interval_ms(cs), |b| b.saved_ms);
+        interval::set_interval_now(cs, interval_ms);
+        let burst_ms = interval::interval_ms(cs);
+        burst.set(Some(Burst { saved_ms, burst_ms }));
+        // 今の点灯/消灯が終わるのを待たずに、新しい間隔で点滅し直す
+        crate::restart_blink(cs);
+        Ok(burst_ms)
+    })
+}
+
+// Work::EndBlinkBurstから呼ぶ（TIMER_IRQ_1の中）。元の間隔に戻す。
+pub fn end(cs: &CriticalSection) {
+    let Some(burst) = BURST.borrow(cs).take() else {
+        return;
+    };
+    if interval::interval_ms(cs) == burst.burst_ms {
+        interval::set_interval_now(cs, burst.saved_ms);
+    } else {
+        defmt::debug!("blink burst: interval was changed meanwhile, not restored");
+    }
+}
+
+// 変えているのを取り消す。間隔は戻さない（設定のリセットで戻すため）。
+pub fn cancel() {
+    soft_timer::cancel_timer(BLINK_BURST_ID);
+    free(|cs| BURST.borrow(cs).set(None));
+}
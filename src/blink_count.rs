@@ -0,0 +1,78 @@
+// 決まった回数だけ点滅して止まる（通知用）
+//
+// blink_times(n)でLedMode::Blinkにし、n回点滅したら消灯（LedMode::Off）して
+// ALARM0の割り込みを止める。実質的には「回数付きの一時的なBlinkモード」で、
+// モードの仕組みとは次のように関わる。
+//   ・数えるのはBlinkモードで点灯に切り替わった回数。n回目の点灯の後の消灯まで済んでから止まる
+//   ・数えている途中で別のモードに切り替える（set_mode()でモードが変わる）と、回数の指定は取り消す
+//   ・止まった後にモードが変わったり素早い点滅（burst）が始まったりすると、ALARM0を動かし直す
+//   ・止まっている間は割り込みカウンタも進まない。heartbeatはこの間、カウンタの停止を異常とみなさない
+// n = 0ならすぐに消灯して止める。
+// もう一度blink_times()を呼ぶと、止まっていてもいなくてもn回数え直して最初の点灯から始める。
+
+use crate::cs_trace::free;
+use crate::led;
+use crate::mode::{self, LedMode};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+
+// 残りの点灯回数。回数の指定がなければNone。
+static REMAINING: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+// 点滅し終わってALARM0の割り込みを止めているか
+static STOPPED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+pub fn blink_times(n: u32) {
+    free(|cs| {
+        if n == 0 {
+            stop(cs);
+            led::write_led(cs, led::LED_OFF_DUTY);
+            return;
+        }
+        mode::set_mode(cs, LedMode::Blink);
+        // set_mode()は回数の指定を取り消すので、モードを変えてから設定する
+        REMAINING.borrow(cs).set(Some(n));
+        crate::restart_blink(cs);
+        STOPPED.borrow(cs).set(false);
+    });
+}
+
+// Blinkモードで点灯に切り替える前にTIMER_IRQ_0から呼ぶ。
+// 点灯してよければtrue。回数分点滅し終わっていればfalseを返し、消灯したまま止める。
+pub fn take_blink(cs: &CriticalSection) -> bool {
+    let remaining = REMAINING.borrow(cs);
+    match remaining.get() {
+        None => true,
+        Some(0) => {
+            stop(cs);
+            false
+        }
+        Some(n) => {
+            remaining.set(Some(n - 1));
+            true
+        }
+    }
+}
+
+fn stop(cs: &CriticalSection) {
+    mode::set_mode(cs, LedMode::Off);
+    REMAINING.borrow(cs).set(None);
+    STOPPED.borrow(cs).set(true);
+}
+
+// TIMER_IRQ_0はtrueの間、次のALARMをスケジュールせずに割り込みを止める
+pub fn is_stopped(cs: &CriticalSection) -> bool {
+    STOPPED.borrow(cs).get()
+}
+
+// モードが変わったときにmode::set_mode()から呼ぶ
+pub fn on_mode_changed(cs: &CriticalSection) {
+    REMAINING.borrow(cs).set(None);
+    resume(cs);
+}
+
+// 止まっていればALARM0を動かし直す
+pub fn resume(cs: &CriticalSection) {
+    if STOPPED.borrow(cs).replace(false) {
+        crate::restart_blink(cs);
+    }
+}
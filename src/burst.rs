@@ -0,0 +1,35 @@
+// 今のモードに割り込んでLEDを素早く数回点滅させる
+//
+// 点滅している間はモードよりこちらが優先され、終わるとモードどおりの表示に戻る。
+// モードそのものは変えないので、元のモードを覚えておく必要はない。
+// 始まるのはTIMER_IRQ_0が次にLEDを更新するとき（最大で今の点滅間隔だけ遅れる）。
+
+use crate::blink_count;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::decimal_blink::Step;
+
+// 1回の点灯と消灯の時間
+pub const BURST_FLASH_MS: u32 = 80;
+
+// 残りのステップ数（点灯と消灯で1回の点滅につき2ステップ）
+static BURST_STEPS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+// flashes回の点滅を始める。点滅中に呼ぶと残りの回数を置き換える。
+pub fn start(cs: &CriticalSection, flashes: u32) {
+    BURST_STEPS.borrow(cs).set(flashes.saturating_mul(2));
+    // blink_times()で止まっていても点滅させる
+    blink_count::resume(cs);
+}
+
+// TIMER_IRQ_0から呼ぶ。点滅中なら次のステップを返す。
+pub fn next_step(cs: &CriticalSection) -> Option<Step> {
+    let steps = BURST_STEPS.borrow(cs);
+    let remaining = steps.get().checked_sub(1)?;
+    steps.set(remaining);
+    Some(Step {
+        // 残りが奇数のときが点灯（最後のステップは消灯になる）
+        on: remaining % 2 == 1,
+        duration_ms: BURST_FLASH_MS,
+    })
+}
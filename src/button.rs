@@ -0,0 +1,153 @@
+// タクトスイッチの入力
+//
+// GPIO15とGNDの間にスイッチをつなぐ。内部プルアップを使うので、押すとLowになる。
+// メインループからポーリングし、押した瞬間・短押し・長押しをイベントとして返す。
+//
+// ピンはTIMER_IRQ_3がsampler::SAMPLE_PERIOD_MSごとにサンプリングし（sample()）、
+// BUTTON_DEBOUNCEの方式でチャタリングを除いたレベルだけをメインループに渡す。
+// 方式の違い（Lockout/Integrator）はrp2040_project_template::debounceを参照。
+// 接点の汚いスイッチでも誤動作しにくいよう、Integratorにしている（押してから約20 ms遅れる）。
+//
+// 押している時間で短押しと長押しを区別する。
+//   BUTTON_SHORT_PRESS_MS未満                    : 短押し
+//   BUTTON_SHORT_PRESS_MS〜BUTTON_HOLD_MS         : どちらとも言えないが、短押しとして扱う
+//   BUTTON_HOLD_MSを超える                        : 長押し
+// 短押しは離したときに確定する。
+// 長押しはBUTTON_HOLD_FIRES_WHILE_HELDがtrueなら押したままBUTTON_HOLD_MSに達した時点で、
+// falseなら離したときに発生する。どちらの場合も、長押しのあとに短押しは発生しない。
+//
+// 押したとき/離したときの処理
+//   on_press()/on_release()で処理（Work）を決めておくと、チャタリングを除いたレベルが変わったときに
+//   遅延実行キュー（deferred.rs）に積む（押したら「押している」表示を始め、離したら確定する、など）。
+//   押す方はBUTTON_DEBOUNCE、離す方はBUTTON_RELEASE_DEBOUNCEの方式で確かめる（PressRelease）。
+//   上のイベント（短押し・長押し）はそのまま発生するので、両方で同じことをしないように選ぶこと。
+//   起動時はどちらも何もしない。
+
+use crate::cs_trace::free;
+use crate::deferred;
+use crate::pull::{self, Pull};
+use crate::sampler;
+use crate::work::Work;
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::{Cell, RefCell};
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use embedded_hal::digital::InputPin;
+use rp2040_project_template::debounce::{Debounce, Edge, PressRelease};
+use rp2040_project_template::time;
+use rp_pico::hal::{gpio, timer::Instant};
+
+// スイッチはGNDとの間につなぐので、離しているときにHighになるようプルアップする
+pub const BUTTON_PULL: Pull = Pull::Up;
+
+pub type ButtonPin = pull::InputPin<gpio::bank0::Gpio15>;
+
+// SAMPLE_PERIOD_MS（5 ms）× 4回で約20 ms
+pub const BUTTON_DEBOUNCE: Debounce = Debounce::Integrator(4);
+// 離したときのチャタリング除去
+pub const BUTTON_RELEASE_DEBOUNCE: Debounce = Debounce::Integrator(4);
+pub const BUTTON_SHORT_PRESS_MS: u32 = 500;
+pub const BUTTON_HOLD_MS: u32 = 1000;
+// 長押しを離すのを待たずに知らせるか
+pub const BUTTON_HOLD_FIRES_WHILE_HELD: bool = true;
+
+const _: () = assert!(BUTTON_SHORT_PRESS_MS <= BUTTON_HOLD_MS);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ButtonEvent {
+    // 押した瞬間（タップテンポ用）
+    Pressed,
+    // 短押しを離した
+    ShortPress,
+    // 長押し
+    Hold,
+}
+
+static BUTTON_PIN: GlobalPeripheral<ButtonPin> = initial_global_peripheral();
+static DEBOUNCER: Mutex<RefCell<PressRelease<Work>>> = Mutex::new(RefCell::new(PressRelease::new(
+    BUTTON_DEBOUNCE,
+    BUTTON_RELEASE_DEBOUNCE,
+    sampler::SAMPLE_PERIOD_MS,
+)));
+// チャタリングを除いた押下の状態
+static PRESSED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+pub fn init(cs: &CriticalSection, pin: ButtonPin) {
+    BUTTON_PIN.borrow(cs).replace(Some(pin));
+}
+
+// TIMER_IRQ_3からSAMPLE_PERIOD_MSごとに呼ぶ（sampler.rs）
+pub fn sample(cs: &CriticalSection) {
+    let Some(pin) = BUTTON_PIN.borrow(cs).borrow_mut().as_mut().map(|pin| {
+        // RP2040のGPIOの読み取りはエラーを返さない（Infallible）
+        pin.is_low().unwrap()
+    }) else {
+        return;
+    };
+    let mut debouncer = DEBOUNCER.borrow(cs).borrow_mut();
+    let edge = debouncer.sample(pin);
+    PRESSED.borrow(cs).set(debouncer.is_pressed());
+    drop(debouncer);
+    if let Some((edge, Some(work))) = edge {
+        let edge = match edge {
+            Edge::Press => "press",
+            Edge::Release => "release",
+        };
+        defmt::debug!("button: {=str} action", edge);
+        deferred::push(cs, work);
+    }
+}
+
+// 押したときに実行する処理を決める。Noneなら何もしない。
+pub fn on_press(action: Option<Work>) {
+    free(|cs| DEBOUNCER.borrow(cs).borrow_mut().on_press(action));
+}
+
+// 離したときに実行する処理を決める。Noneなら何もしない。
+pub fn on_release(action: Option<Work>) {
+    free(|cs| DEBOUNCER.borrow(cs).borrow_mut().on_release(action));
+}
+
+pub struct Button {
+    pressed: bool,
+    last_change: Instant,
+    // 今の押下ですでに長押しを知らせたか
+    hold_reported: bool,
+}
+
+impl Button {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            pressed: false,
+            last_change: now,
+            hold_reported: false,
+        }
+    }
+
+    // イベントがあれば返す。1回の呼び出しで返すイベントは1つまで。
+    pub fn poll(&mut self, now: Instant) -> Option<ButtonEvent> {
+        let held_ms = time::elapsed_us(now.ticks(), self.last_change.ticks()) / 1000;
+        let pressed = free(|cs| PRESSED.borrow(cs).get());
+        let is_hold = held_ms > u64::from(BUTTON_HOLD_MS);
+        if pressed == self.pressed {
+            // 押したままBUTTON_HOLD_MSに達した
+            if pressed && is_hold && BUTTON_HOLD_FIRES_WHILE_HELD && !self.hold_reported {
+                self.hold_reported = true;
+                return Some(ButtonEvent::Hold);
+            }
+            return None;
+        }
+        self.pressed = pressed;
+        self.last_change = now;
+
+        if pressed {
+            self.hold_reported = false;
+            return Some(ButtonEvent::Pressed);
+        }
+        // 離したときはlast_changeからの時間が押していた時間になる
+        match (is_hold, core::mem::take(&mut self.hold_reported)) {
+            (_, true) => None,
+            (true, false) => Some(ButtonEvent::Hold),
+            (false, false) => Some(ButtonEvent::ShortPress),
+        }
+    }
+}
@@ -0,0 +1,65 @@
+// 起動時に設定したクロックの周波数を覚えておき、clocksコマンドで返す
+//
+// init_clocks_and_plls()が返すClocksManagerには各クロックの周波数が入っているが、
+// main()の中でペリフェラルの初期化に使ったあとは参照されない。
+// ここでは周波数だけをコピーしてグローバル変数に入れておき、あとから確かめられるようにする。
+//
+// タイマーの1 µsの基準（clk_tick）は、tick-sourceフィーチャー（tick_source.rs）で実行中にも変わるので、
+// 覚えておかずに読むたびにWATCHDOGのTICKレジスタの分周比から求める。
+//   タイマーの周波数 = clk_refの周波数 ÷ CYCLES（通常はちょうど1 MHz）
+
+use crate::cs_trace::free;
+use core::cell::Cell;
+use core::fmt;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp_pico::hal::clocks::{Clock, ClocksManager};
+use rp_pico::hal::pac;
+
+#[derive(Clone, Copy)]
+pub struct ClockFreqs {
+    pub sys_hz: u32,
+    pub ref_hz: u32,
+    pub peri_hz: u32,
+    pub usb_hz: u32,
+    pub adc_hz: u32,
+    pub rtc_hz: u32,
+}
+
+static CLOCK_FREQS: Mutex<Cell<Option<ClockFreqs>>> = Mutex::new(Cell::new(None));
+
+pub fn init(cs: &CriticalSection, clocks: &ClocksManager) {
+    CLOCK_FREQS.borrow(cs).set(Some(ClockFreqs {
+        sys_hz: clocks.system_clock.freq().to_Hz(),
+        ref_hz: clocks.reference_clock.freq().to_Hz(),
+        peri_hz: clocks.peripheral_clock.freq().to_Hz(),
+        usb_hz: clocks.usb_clock.freq().to_Hz(),
+        adc_hz: clocks.adc_clock.freq().to_Hz(),
+        rtc_hz: clocks.rtc_clock.freq().to_Hz(),
+    }));
+}
+
+pub fn clock_freqs() -> Option<ClockFreqs> {
+    free(|cs| CLOCK_FREQS.borrow(cs).get())
+}
+
+// タイマーが数える周波数（Hz）。clk_tickの分周比は今のレジスタの値を使う。
+pub fn timer_tick_hz(freqs: &ClockFreqs) -> u32 {
+    // 読み出すだけなので、WATCHDOGの状態には影響しない
+    let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+    let cycles = u32::from(watchdog.tick().read().cycles().bits()).max(1);
+    freqs.ref_hz / cycles
+}
+
+// 周波数を1 MHz以上ならMHz、それ未満ならkHzで、小数点以下3桁まで表示する
+pub struct Freq(pub u32);
+
+impl fmt::Display for Freq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hz = self.0;
+        if hz >= 1_000_000 {
+            write!(f, "{}.{:03} MHz", hz / 1_000_000, hz % 1_000_000 / 1000)
+        } else {
+            write!(f, "{}.{:03} kHz", hz / 1000, hz % 1000)
+        }
+    }
+}
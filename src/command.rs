@@ -0,0 +1,1421 @@
+// UART0から受け取ったコマンドを解釈するモジュール
+//
+// 1行（CRまたはLFで終わる）を1コマンドとして扱い、応答はStatusTxで送り返す。
+// 受信はメインループからのポーリングで、FIFOにたまっている分だけを読むのでブロックしない。
+//
+// 受信があふれたとき
+//   1行はCOMMAND_LINE_LEN（32）バイトまで。UARTの受信FIFOは32バイト。
+//   行バッファに入りきらない行が来たり、メインループが読むより速く届いて受信FIFOが
+//   あふれた（オーバーラン）りすると、その行は途中が欠けているので実行してはいけない。
+//   その場合は行の残りを改行まで読み捨て、改行が来たところで
+//   "error: line too long"または"error: rx overrun"を返して次の行から受信し直す。
+//   読み捨てたバイト数は数えておき、diag コマンドで確認できる。
+//
+// 対応しているコマンド
+//   version                      : ファームウェアのバージョン、gitハッシュ、ビルド日時を返す
+//   features                     : このビルドで有効になっているCargoのフィーチャーを返す（features.rs参照）
+//   resetcause                   : 最後にリセットされた原因と、判断に使ったレジスタの値を返す（reset_cause.rs参照）
+//   resetlog                     : フラッシュに記録したリセットの履歴を古い順に返す（reset-logフィーチャー、reset_log.rs参照）
+//   shutdown [now]               : LEDをSHUTDOWN_FADE_MSかけて消してから、出力をすべて切って止まる。nowならすぐに切る（shutdown.rs参照）
+//   time                         : DS3231から読んだ現在の日時を返す
+//   settime YYYY-MM-DD HH:MM:SS  : DS3231に日時を設定する
+//   clock [HH:MM:SS[.mmm]]       : ソフトウェアの時計の時刻を返す／合わせる（soft_rtc.rs参照）
+//   oled                         : OLED（SSD1306）がつながっているかを返す。つながっていなければもう一度探す（ssd1306.rs参照）
+//   number N                     : 数値Nを10進数の点滅回数で表示する
+//   bar [PERCENT]                : PERCENT（0-100）をLEDの点灯している時間の割合で表示するモードにする（bar_graph.rs参照）。
+//                                  引数がなければ今の値を返す
+//   egg [SECONDS]                : SECONDS秒を数えるエッグタイマーを始める。引数がなければ残りの秒数を返す（egg_timer.rs参照）
+//   egg final N                  : 残りN秒から速い点滅にする
+//   egg done solid|triple        : 数え終わったときに点灯したままにするか、長く3回点滅するかを選ぶ
+//   slew [RATE|off]              : 点滅間隔を1秒あたりRATEミリ秒までしか変えないようにする。offで制限しない（interval.rs参照）。
+//                                  引数がなければ今の制限と、目標と今の間隔を返す
+//   blinkfor MS DURATION         : DURATIONミリ秒の間だけ点滅の間隔をMSミリ秒にし、その後は元に戻す（blink_burst.rs参照）
+//   blinks N                     : N回点滅したら消灯して止まる（0ならすぐ消灯）
+//   notify MODE                  : ボタンで確認するまでMODEで速く点滅して知らせる（notify.rs参照）
+//   ack                          : ボタンの代わりに知らせを確認して、前のモードに戻す
+//   after MS WORK                : MSミリ秒後にWORKを1回実行する
+//   after cancel                 : afterの予約を取り消す
+//   timer [ID MS|cancel ID]      : ソフトウェアタイマーIDをMSミリ秒後に発火させる／取り消す。
+//                                  引数がなければ予約中の数と、前回から発火したIDを返す（soft_timer.rs参照）
+//   at N WORK                    : 割り込みカウンタがNになったらWORKを実行する
+//   button press|release WORK    : GPIO15のボタンを押したとき/離したときにWORKを実行する（noneで何もしない、button.rs参照）
+//     WORKはモード名（solid, blink, number, off, heartbeat, bar, egg）か、flash K（K回素早く点滅）
+//   tone HZ|on|off               : 点滅に合わせて鳴らすブザーの周波数を変える／鳴らすかを切り替える
+//   bicolor [on|off|speed MS]    : 2色LEDの赤と緑のクロスフェードを始める／やめる／片道の時間をMSミリ秒にする（bicolor.rs参照）
+//   brightness N [MS]            : 全体の明るさをN（0-65535）にする。MSを付けるとMSミリ秒かけて変える
+//   autodim                      : 調光が有効かと曲線を出力する
+//   autodim on|off               : 周囲の明るさに合わせてLEDを調光するかを切り替える（ambient.rs参照）
+//   autodim linear|quadratic|log : 調光の曲線を変える（response_curve.rs参照）
+//   autodim steps N              : 調光をN段（2〜255）の階段状にする
+//   automode                     : 明るさによるモードの自動の切り替えが有効かを出力する
+//   automode on|off              : Solid/Blinkを周囲の明るさで自動で切り替えるかを変える（ambient.rs参照）
+//   ledpin [N]                   : LEDの出力をGPIO Nに移す（8, 9, 25のどれか。led.rs参照）。Nがなければ今のGPIOを返す
+//   cap N                        : LEDのデューティの上限をN（0-65535）にする。どのモードでもこれを超えない
+//   estop [reset]                : 非常停止で止まっているかを返す／GPIO21を戻したあとで停止を解除する（estop.rs参照）
+//   enable                       : 出力の許可の入力（GPIO3）で出力が許可されているかを返す（output_enable.rs参照）
+//   post                         : 起動時の自己診断の結果のワードと項目ごとの合否、前回の起動の結果を返す（post.rs参照）
+//   idle [SEC|off]               : SEC秒操作がなければ点滅を止めて消灯する。offで止めない（idle_off.rs参照）
+//   dim [SEC LEVEL|off]          : SEC秒操作がなければLEDを明るさの倍率LEVEL（0-65535）までゆっくり暗くする。
+//                                  offで暗くしない。引数がなければ今の設定を返す（idle_dim.rs参照）
+//   step on|off                  : コマ送りのデバッグモード。タクトスイッチの短押しで点滅を1回ずつ進める（step_mode.rs参照）
+//   test                         : 製造時の検査用のLEDのパターンをくり返す。リセットするまで止まらない（mfg_test.rs参照）
+//   breathe [triangle|sine]      : 検査のパターンのBreatheの波形を変える。引数がなければ今の波形を返す
+//   rec start|stop|play          : トグルボタンで点灯/消灯させたパターンを記録する／記録を終える／くり返し再生する
+//   glitch [US]                  : トグルボタンのエッジのグリッチフィルタをUSマイクロ秒にする（0で無効、glitch_filter.rs参照）
+//   pulses HZ N [every MS]       : GPIO12にHZの周波数でN個のパルスをPIOで出す。everyを付けるとMSミリ秒ごとにくり返す
+//   pulses stop                  : くり返しを止める（pulse_train.rs参照）
+//   ir                           : 赤外線リモコンから最後に受け取ったコマンドを返す（ir_nec.rs参照）
+//   edges [div N]                : GPIO11のパルスの周波数を返す／N回のエッジで1回だけ処理する（edge_counter.rs参照）
+//   mains [on|off|div N]         : 商用電源のゼロクロスに合わせて点滅させるか／何回のゼロクロスで1ステップ進めるか。
+//                                  引数がなければ検出した周波数を返す（mains_sync.rs参照）
+//   manch [US]                   : マンチェスター符号の受信のビット周期をUSマイクロ秒にする。USがなければ今の周期を返す
+//   reg [ADDR [VALUE]]           : I2Cのスレーブ向けのレジスタの表を読む／書く（register_map.rs参照）。
+//                                  ADDRとVALUEは10進数か0xで始まる16進数。引数がなければ表の全部を読む
+//   schedule on|off              : RTCの時刻に合わせてモードを切り替える1日のスケジュールを有効/無効にする
+//   wave on|off                  : LEDの点灯/消灯をdefmtに波形として出す（waveform.rs参照）
+//   nested                       : 優先度の低い割り込みに高い割り込みが割り込むデモを1回行い、回数を返す（nested_irq.rs参照）
+//   next                         : ALARM0が次に発火するまでの時間（µs）を返す
+//   sync                         : LEDの点滅、2色LED、くり返しのパルスの位相を少し後の同じ時刻にそろえる（phase_sync.rs参照）
+//   jitter [US|off]              : ALARM0の間隔に±USマイクロ秒のばらつきを足し、頼んだとおりに発火するかを確かめる（jitter_test.rs参照）
+//   rate [MHZ|off]               : Blinkのときに、ALARM0が発火する頻度をMHZ（mHz）ちょうどに補正する（rate_control.rs参照）
+//   encoder [quarter|half|full]  : ロータリーエンコーダーの分解能を変える。引数がなければ今の分解能を返す（encoder.rs参照）
+//   config                       : 点滅間隔やプリスケール値など、今の設定と、起動時にフラッシュの設定を読めたか、DIPスイッチの値を返す
+//   config blob                  : 今の設定をフラッシュに保存する形式の16進数で返す（config.rs参照）
+//   mem                          : スタック、静的変数、フラッシュの大きさと、スタックの最大使用量を返す（footprint.rs参照）
+//   clocks                       : 起動時に設定した各クロックとタイマーの周波数を返す（clock_info.rs参照）
+//   diag                         : 診断用のカウンタ（diagnostics.rs）と最後に測ったパルス幅、LEDが点いているか（覚えている値と実際の出力）を返す
+
+use crate::ambient;
+use crate::bar_graph;
+use crate::bicolor;
+use crate::blink_burst;
+use crate::blink_count;
+use crate::button;
+use crate::clock_info::{self, Freq};
+use crate::config;
+use crate::cs_trace::free;
+use crate::diagnostics;
+use crate::dip_switch::DipSetting;
+use crate::ds3231::{self, DateTime};
+use crate::edge_counter;
+use crate::egg_timer::{self, EggDone, Progress};
+use crate::encoder;
+use crate::estop;
+use crate::fade;
+use crate::features::EnabledFeatures;
+use crate::footprint;
+use crate::glitch_filter;
+use crate::idle_dim;
+use crate::idle_off;
+use crate::interval;
+use crate::ir_nec;
+use crate::jitter_test;
+use crate::led;
+use crate::logging::{self, Event};
+use crate::mains_sync;
+use crate::manchester;
+use crate::mfg_test;
+use crate::milestone;
+use crate::mode::{self, LedMode};
+use crate::nested_irq;
+use crate::notify;
+use crate::number;
+use crate::oneshot;
+use crate::output_enable;
+use crate::phase_sync;
+use crate::post;
+use crate::prescaler;
+use crate::pulse_train;
+use crate::pulse_width;
+use crate::rate_control;
+use crate::recorder;
+use crate::register_map;
+use crate::reset_cause;
+#[cfg(feature = "reset-log")]
+use crate::reset_log;
+use crate::schedule;
+use crate::shutdown;
+use crate::soft_rtc;
+use crate::soft_timer;
+use crate::ssd1306;
+use crate::status_tx::{StatusTx, UartPins};
+use crate::step_mode;
+use crate::tone;
+use crate::version;
+use crate::waveform;
+use crate::work::Work;
+use core::cell::Cell;
+use core::fmt;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::config_blob;
+use rp2040_project_template::quadrature::Resolution;
+use rp2040_project_template::response_curve::ResponseCurve;
+use rp2040_project_template::waveform_table::Waveform;
+use rp_pico::hal::{pac, uart};
+use uart::ReadErrorType;
+
+pub type UartReader = uart::Reader<pac::UART0, UartPins>;
+
+// 1行の最大長。これを超えた行は丸ごと捨てる。
+pub const COMMAND_LINE_LEN: usize = 32;
+
+// 受信した行を捨てている理由
+#[derive(Clone, Copy)]
+enum Overflow {
+    // 行バッファに入りきらなかった
+    LineTooLong,
+    // 受信FIFOがあふれたなど、受信エラーで途中が欠けた
+    RxError,
+}
+
+// 読み捨てたバイト数（起動してからの累計）
+static RX_DROPPED_BYTES: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+pub fn rx_dropped_bytes(cs: &CriticalSection) -> u32 {
+    RX_DROPPED_BYTES.borrow(cs).get()
+}
+
+fn count_dropped(bytes: u32) {
+    free(|cs| {
+        let dropped = RX_DROPPED_BYTES.borrow(cs);
+        dropped.set(dropped.get().wrapping_add(bytes));
+    });
+}
+
+pub struct CommandReader {
+    reader: UartReader,
+    line: [u8; COMMAND_LINE_LEN],
+    len: usize,
+    // Someの間は改行まで読み捨てる
+    overflow: Option<Overflow>,
+}
+
+impl CommandReader {
+    pub fn new(reader: UartReader) -> Self {
+        Self {
+            reader,
+            line: [0; COMMAND_LINE_LEN],
+            len: 0,
+            overflow: None,
+        }
+    }
+
+    // メインループから毎周呼ぶ。
+    // 受信FIFOにある分を読み、行が揃っていればコマンドを実行する。
+    pub fn poll(&mut self, tx: &mut StatusTx) {
+        let mut buf = [0u8; 16];
+        loop {
+            match self.reader.read_raw(&mut buf) {
+                Ok(n) => {
+                    for &byte in &buf[..n] {
+                        self.push(byte, tx);
+                    }
+                }
+                // FIFOが空になったので終わる
+                Err(nb::Error::WouldBlock) => break,
+                // 受信エラーの前に読めていた分も、行の途中が欠けているので捨てる
+                Err(nb::Error::Other(e)) => {
+                    let discarded = e.discarded.len() as u32;
+                    if matches!(e.err_type, ReadErrorType::Overrun) {
+                        logging::log_event(Event::Error(logging::ERR_UART_RX_OVERRUN));
+                    }
+                    self.discard(Overflow::RxError, discarded);
+                }
+            }
+        }
+    }
+
+    // 今の行を捨て、改行まで読み捨てる状態にする
+    fn discard(&mut self, reason: Overflow, bytes: u32) {
+        let len = core::mem::take(&mut self.len) as u32;
+        count_dropped(len + bytes);
+        // 先に起きた理由を残す
+        self.overflow.get_or_insert(reason);
+    }
+
+    fn push(&mut self, byte: u8, tx: &mut StatusTx) {
+        match byte {
+            b'\r' | b'\n' => {
+                // 捨てていた行はここで終わり。エラーを返して次の行から受信し直す。
+                if let Some(reason) = self.overflow.take() {
+                    match reason {
+                        Overflow::LineTooLong => tx.write_line(format_args!(
+                            "error: line too long (max {} bytes)",
+                            COMMAND_LINE_LEN
+                        )),
+                        Overflow::RxError => tx.write_line(format_args!("error: rx overrun")),
+                    };
+                    return;
+                }
+                let len = core::mem::take(&mut self.len);
+                // 非ASCIIなどでUTF-8として不正な行は空行と同じく無視する
+                if let Ok(line) = core::str::from_utf8(&self.line[..len]) {
+                    execute(line.trim(), tx);
+                }
+            }
+            _ if self.overflow.is_some() => count_dropped(1),
+            _ if self.len < COMMAND_LINE_LEN => {
+                self.line[self.len] = byte;
+                self.len += 1;
+            }
+            _ => self.discard(Overflow::LineTooLong, 1),
+        }
+    }
+}
+
+fn execute(command: &str, tx: &mut StatusTx) {
+    // 最初の空白までをコマンド名、残りを引数とする
+    let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+    if !name.is_empty() {
+        // UARTからのコマンドも操作とみなす（idle_off.rs）
+        free(idle_off::note_activity);
+    }
+    match name {
+        "" => {}
+        "resetcause" => {
+            let (cause, reason, chip_reset) = reset_cause::read();
+            tx.write_line(format_args!(
+                "reset cause {} (reason=0x{:x} chip_reset=0x{:x})",
+                cause.name(),
+                reason,
+                chip_reset
+            ));
+        }
+        #[cfg(feature = "reset-log")]
+        "resetlog" => reset_log::dump(tx),
+        #[cfg(not(feature = "reset-log"))]
+        "resetlog" => {
+            tx.write_line(format_args!("error: built without the reset-log feature"));
+        }
+        "shutdown" => match args.trim() {
+            "" => shutdown::shutdown(tx, true),
+            "now" => shutdown::shutdown(tx, false),
+            _ => {
+                tx.write_line(format_args!("usage: shutdown [now]"));
+            }
+        },
+        "version" => {
+            tx.write_line(format_args!(
+                "version {} git {} built {}",
+                version::VERSION,
+                version::GIT_HASH,
+                version::BUILD_TIMESTAMP
+            ));
+        }
+        "features" => {
+            tx.write_line(format_args!("features {}", EnabledFeatures));
+        }
+        "time" => match ds3231::read_datetime() {
+            Ok(dt) => {
+                tx.write_line(format_args!("time {}", dt));
+            }
+            Err(e) => {
+                tx.write_line(format_args!("error: rtc {}", e.name()));
+            }
+        },
+        "settime" => match DateTime::parse(args.trim()) {
+            Some(dt) => match ds3231::set_datetime(&dt) {
+                Ok(()) => {
+                    tx.write_line(format_args!("time set to {}", dt));
+                }
+                Err(e) => {
+                    tx.write_line(format_args!("error: rtc {}", e.name()));
+                }
+            },
+            None => {
+                tx.write_line(format_args!("usage: settime YYYY-MM-DD HH:MM:SS"));
+            }
+        },
+        "oled" => match ssd1306::check() {
+            Ok(()) => {
+                tx.write_line(format_args!("oled: present"));
+            }
+            Err(e) => {
+                tx.write_line(format_args!("error: oled {}", e.name()));
+            }
+        },
+        "clock" if args.trim().is_empty() => {
+            let (hour, minute, second, millis) = soft_rtc::soft_time();
+            tx.write_line(format_args!(
+                "clock {:02}:{:02}:{:02}.{:03}",
+                hour, minute, second, millis
+            ));
+        }
+        "clock" => match soft_rtc::parse_soft_time(args.trim()) {
+            Some((hour, minute, second, millis))
+                if soft_rtc::set_soft_time(hour, minute, second, millis) =>
+            {
+                tx.write_line(format_args!(
+                    "clock set to {:02}:{:02}:{:02}.{:03}",
+                    hour, minute, second, millis
+                ));
+            }
+            _ => {
+                tx.write_line(format_args!("usage: clock [HH:MM:SS[.mmm]]"));
+            }
+        },
+        "number" => match args.trim().parse() {
+            Ok(n) => {
+                number::blink_number(n);
+                tx.write_line(format_args!("blinking number {}", n));
+            }
+            Err(_) => {
+                tx.write_line(format_args!("usage: number N (0-4294967295)"));
+            }
+        },
+        "bar" if args.trim().is_empty() => {
+            let percent = free(bar_graph::bar_value);
+            tx.write_line(format_args!("bar {}%", percent));
+        }
+        "bar" => match args.trim().parse::<u8>() {
+            Ok(percent) => {
+                let percent = bar_graph::set_bar_value(percent);
+                free(|cs| mode::set_mode(cs, LedMode::Bar));
+                tx.write_line(format_args!("bar {}%", percent));
+            }
+            Err(_) => {
+                tx.write_line(format_args!("usage: bar [PERCENT]"));
+            }
+        },
+        "egg" if args.trim().is_empty() => {
+            let (progress, final_s, done) = free(|cs| {
+                (
+                    egg_timer::progress(cs),
+                    egg_timer::final_seconds(cs),
+                    egg_timer::done(cs),
+                )
+            });
+            match progress {
+                Progress::Ticking(s) => tx.write_line(format_args!("egg: {}s left", s)),
+                Progress::Final(s) => tx.write_line(format_args!("egg: {}s left (final)", s)),
+                Progress::Done => tx.write_line(format_args!("egg: done")),
+            };
+            tx.write_line(format_args!("egg: final {}s done {}", final_s, done.name()));
+        }
+        "egg" => {
+            let args = args.trim();
+            if let Some(n) = args.strip_prefix("final ") {
+                match n.trim().parse() {
+                    Ok(n) => {
+                        egg_timer::set_final_seconds(n);
+                        tx.write_line(format_args!("egg: final {}s", n));
+                    }
+                    Err(_) => {
+                        tx.write_line(format_args!("usage: egg final N"));
+                    }
+                }
+            } else if let Some(done) = args.strip_prefix("done ") {
+                match EggDone::from_name(done.trim()) {
+                    Some(done) => {
+                        egg_timer::set_done(done);
+                        tx.write_line(format_args!("egg: done {}", done.name()));
+                    }
+                    None => {
+                        tx.write_line(format_args!("usage: egg done solid|triple"));
+                    }
+                }
+            } else {
+                match args.parse() {
+                    Ok(seconds) => {
+                        egg_timer::egg_timer(seconds);
+                        tx.write_line(format_args!("egg: counting {}s", seconds));
+                    }
+                    Err(_) => {
+                        tx.write_line(format_args!(
+                            "usage: egg [SECONDS|final N|done solid|triple]"
+                        ));
+                    }
+                }
+            }
+        }
+        "slew" if args.trim().is_empty() => {
+            let (rate, target, current) = free(|cs| {
+                (
+                    interval::interval_slew(cs),
+                    interval::interval_ms(cs),
+                    interval::current_ms(cs),
+                )
+            });
+            if rate == interval::INTERVAL_SLEW_INSTANT {
+                tx.write_line(format_args!("slew off interval {} ms", target));
+            } else {
+                tx.write_line(format_args!(
+                    "slew {} ms/s interval {} ms (now {} ms)",
+                    rate, target, current
+                ));
+            }
+        }
+        "slew" => {
+            let rate = match args.trim() {
+                "off" => Some(interval::INTERVAL_SLEW_INSTANT),
+                rate => rate.parse().ok(),
+            };
+            match rate {
+                Some(rate) => {
+                    interval::set_interval_slew(rate);
+                    if rate == interval::INTERVAL_SLEW_INSTANT {
+                        tx.write_line(format_args!("slew off"));
+                    } else {
+                        tx.write_line(format_args!("slew {} ms/s", rate));
+                    }
+                }
+                None => {
+                    tx.write_line(format_args!("usage: slew [RATE|off]"));
+                }
+            }
+        }
+        "blinks" => match args.trim().parse() {
+            Ok(n) => {
+                blink_count::blink_times(n);
+                tx.write_line(format_args!("blinking {} times", n));
+            }
+            Err(_) => {
+                tx.write_line(format_args!("usage: blinks N (0-4294967295)"));
+            }
+        },
+        "notify" => match LedMode::from_name(args.trim()) {
+            Some(pattern) => {
+                if notify::notify(pattern) {
+                    tx.write_line(format_args!("notifying with {}", pattern.name()));
+                } else {
+                    tx.write_line(format_args!("notify: already notifying"));
+                }
+            }
+            None => {
+                tx.write_line(format_args!(
+                    "usage: notify MODE (solid, blink, number, off, heartbeat, bar, egg)"
+                ));
+            }
+        },
+        "ack" => {
+            if notify::acknowledge() {
+                tx.write_line(format_args!("notification acknowledged"));
+            } else {
+                tx.write_line(format_args!("ack: not notifying"));
+            }
+        }
+        "after" => match args.trim() {
+            "cancel" => {
+                if oneshot::cancel() {
+                    tx.write_line(format_args!("after cancelled"));
+                } else {
+                    tx.write_line(format_args!("after: nothing pending"));
+                }
+            }
+            args => match parse_count_and_work(args) {
+                Some((delay_ms, work, what)) => {
+                    // 予約中のものがあれば置き換わる
+                    oneshot::after(delay_ms, work);
+                    tx.write_line(format_args!("{} in {} ms", what, delay_ms));
+                }
+                None => {
+                    tx.write_line(format_args!("usage: after MS WORK"));
+                }
+            },
+        },
+        "timer" if args.trim().is_empty() => {
+            let pending = soft_timer::pending();
+            let fired = soft_timer::take_fired();
+            tx.write_line(format_args!(
+                "timers pending {}/{} fired 0x{:08x}",
+                pending,
+                soft_timer::SOFT_TIMER_CAPACITY,
+                fired
+            ));
+        }
+        "timer" => match args.trim().split_once(' ') {
+            Some(("cancel", id)) => match id.trim().parse() {
+                Ok(id) if soft_timer::cancel_timer(id) => {
+                    tx.write_line(format_args!("timer {} cancelled", id));
+                }
+                _ => {
+                    tx.write_line(format_args!("timer: nothing pending"));
+                }
+            },
+            Some((id, delay_ms)) => match (id.parse(), delay_ms.trim().parse()) {
+                (Ok(id), Ok(delay_ms)) => match soft_timer::set_timer(delay_ms, id) {
+                    Ok(()) => {
+                        tx.write_line(format_args!("timer {} in {} ms", id, delay_ms));
+                    }
+                    Err(e) => {
+                        tx.write_line(format_args!("error: {}", e.name()));
+                    }
+                },
+                _ => {
+                    tx.write_line(format_args!("usage: timer [ID MS|cancel ID]"));
+                }
+            },
+            None => {
+                tx.write_line(format_args!("usage: timer [ID MS|cancel ID]"));
+            }
+        },
+        "at" => match parse_count_and_work(args.trim()) {
+            Some((target, work, what)) => {
+                if milestone::at_count(target, work) {
+                    tx.write_line(format_args!("{} at count {}", what, target));
+                } else {
+                    tx.write_line(format_args!(
+                        "error: at most {} milestones",
+                        milestone::MAX_MILESTONES
+                    ));
+                }
+            }
+            None => {
+                tx.write_line(format_args!("usage: at N WORK"));
+            }
+        },
+        "tone" => match args.trim() {
+            "on" => {
+                tone::set_enabled(true);
+                tx.write_line(format_args!("tone on"));
+            }
+            "off" => {
+                tone::set_enabled(false);
+                tx.write_line(format_args!("tone off"));
+            }
+            hz => match hz.parse() {
+                Ok(hz) => {
+                    let hz = tone::set_tone_freq(hz);
+                    tx.write_line(format_args!("tone {} Hz", hz));
+                }
+                Err(_) => {
+                    tx.write_line(format_args!(
+                        "usage: tone HZ ({}-{})|on|off",
+                        tone::TONE_MIN_HZ,
+                        tone::TONE_MAX_HZ
+                    ));
+                }
+            },
+        },
+        "encoder" if args.trim().is_empty() => {
+            let resolution = free(encoder::resolution);
+            tx.write_line(format_args!("encoder {}", resolution.name()));
+        }
+        "encoder" => match Resolution::from_name(args.trim()) {
+            Some(resolution) => {
+                encoder::set_resolution(resolution);
+                tx.write_line(format_args!("encoder {}", resolution.name()));
+            }
+            None => {
+                tx.write_line(format_args!("usage: encoder [quarter|half|full]"));
+            }
+        },
+        "config" if args.trim().is_empty() => {
+            let (interval_ms, prescale, mode, tone_hz, stored) = free(|cs| {
+                (
+                    interval::interval_ms(cs),
+                    prescaler::prescale(cs),
+                    mode::mode(cs),
+                    tone::tone_freq(cs),
+                    config::load_result(cs),
+                )
+            });
+            tx.write_line(format_args!(
+                "config: interval={}ms prescale={} mode={} tone={}Hz",
+                interval_ms,
+                prescale,
+                mode.name(),
+                tone_hz
+            ));
+            match stored {
+                Ok(None) => tx.write_line(format_args!("stored: loaded")),
+                Ok(Some(version)) => {
+                    tx.write_line(format_args!("stored: migrated from version {}", version))
+                }
+                Err(e) => tx.write_line(format_args!("stored: defaults ({})", e.name())),
+            };
+            let dip = DipSetting::from_bits(config::config().dip_switch);
+            tx.write_line(format_args!(
+                "dip: {:04b} mode {} mfg-test {} ignore-flash {}",
+                dip.bits,
+                dip.mode.map_or("unchanged", LedMode::name),
+                if dip.mfg_test { "on" } else { "off" },
+                if dip.ignore_flash { "on" } else { "off" }
+            ));
+        }
+        "config" if args.trim() == "blob" => {
+            let current = free(|cs| config::Config {
+                blink_interval_ms: interval::interval_ms(cs),
+                prescale: prescaler::prescale(cs),
+                mode: mode::mode(cs),
+                tone_hz: tone::tone_freq(cs),
+                led_gpio: led::led_gpio(cs),
+                ..*config::config()
+            });
+            let blob = config_blob::encode(&current.to_stored());
+            tx.write_line(format_args!(
+                "blob @{:#x}: {}",
+                config::CONFIG_FLASH_OFFSET,
+                Hex(&blob)
+            ));
+        }
+        "config" => {
+            tx.write_line(format_args!("usage: config [blob]"));
+        }
+        "nested" => {
+            let stats = nested_irq::trigger();
+            tx.write_line(format_args!(
+                "nested: low={} high={} preempted={} missed={}",
+                stats.low_runs, stats.high_runs, stats.preemptions, stats.missed
+            ));
+        }
+        "next" => match crate::time_to_next_alarm_us() {
+            Some(us) => {
+                tx.write_line(format_args!("next alarm0 in {} us", us));
+            }
+            None => {
+                tx.write_line(format_args!("alarm0 not armed"));
+            }
+        },
+        "sync" => match phase_sync::sync_phase() {
+            Some(reference) => {
+                tx.write_line(format_args!(
+                    "sync at {} us (in {} us)",
+                    reference,
+                    phase_sync::SYNC_LEAD_US
+                ));
+            }
+            None => {
+                tx.write_line(format_args!("error: sync not ready"));
+            }
+        },
+        "jitter" if args.trim().is_empty() => {
+            let (amplitude, samples) = free(|cs| {
+                (
+                    jitter_test::amplitude_us(cs),
+                    jitter_test::pending_samples(cs),
+                )
+            });
+            if amplitude == 0 {
+                tx.write_line(format_args!("jitter off"));
+            } else {
+                tx.write_line(format_args!(
+                    "jitter +-{} us ({}/{} samples)",
+                    amplitude,
+                    samples,
+                    jitter_test::JITTER_REPORT_SAMPLES
+                ));
+            }
+        }
+        "jitter" => {
+            let amplitude = match args.trim() {
+                "off" => Some(0),
+                n => n.parse::<u32>().ok(),
+            };
+            match amplitude.map(|us| (us, jitter_test::set_amplitude_us(us))) {
+                Some((us, Ok(()))) => {
+                    tx.write_line(format_args!("jitter +-{} us", us));
+                }
+                Some((_, Err(e))) => {
+                    tx.write_line(format_args!("error: {}", e.name()));
+                }
+                None => {
+                    tx.write_line(format_args!("usage: jitter [US|off]"));
+                }
+            }
+        }
+        "rate" if args.trim().is_empty() => match free(rate_control::target_rate) {
+            Some(millihz) => {
+                tx.write_line(format_args!("rate target {} mHz", millihz));
+            }
+            None => {
+                tx.write_line(format_args!("rate off"));
+            }
+        },
+        "rate" => {
+            let millihz = match args.trim() {
+                "off" => Ok(0),
+                n => n.parse(),
+            };
+            match millihz.map(rate_control::set_target_rate) {
+                Ok(Ok(0)) => {
+                    tx.write_line(format_args!("rate off"));
+                }
+                Ok(Ok(period_us)) => {
+                    tx.write_line(format_args!("rate target period {} us", period_us));
+                }
+                Ok(Err(e)) => {
+                    tx.write_line(format_args!("error: {}", e.name()));
+                }
+                Err(_) => {
+                    tx.write_line(format_args!("usage: rate [MHZ|off]"));
+                }
+            }
+        }
+        "mem" => {
+            let f = footprint::footprint();
+            tx.write_line(format_args!(
+                "mem: stack={}B statics={}B flash={}B",
+                f.stack_bytes, f.static_bytes, f.flash_bytes
+            ));
+            match f.stack_high_water {
+                Some(used) => tx.write_line(format_args!("stack high water: {}B", used)),
+                None => tx.write_line(format_args!("stack high water: needs paint-stack feature")),
+            };
+        }
+        "clocks" => match clock_info::clock_freqs() {
+            Some(freqs) => {
+                // 1行ずつ送り終わるのを待って書く（diagと同じ）
+                for (name, hz) in [
+                    ("sys_clk", freqs.sys_hz),
+                    ("ref_clk", freqs.ref_hz),
+                    ("peri_clk", freqs.peri_hz),
+                    ("usb_clk", freqs.usb_hz),
+                    ("adc_clk", freqs.adc_hz),
+                    ("rtc_clk", freqs.rtc_hz),
+                    ("timer_tick", clock_info::timer_tick_hz(&freqs)),
+                ] {
+                    defmt::info!("clock {=str} {=u32} Hz", name, hz);
+                    tx.write_line_blocking(format_args!("clock {} {}", name, Freq(hz)));
+                }
+            }
+            None => {
+                tx.write_line(format_args!("clocks not initialized"));
+            }
+        },
+        "diag" => {
+            // 行を合わせるとバッファ（LINE_BUF_LEN）に入りきらないことがあるので、送り終わるのを待って書く
+            let diag = diagnostics::snapshot();
+            defmt::info!("{}", diag);
+            tx.write_line_blocking(format_args!(
+                "diag: count={} rx_dropped={} deferred_dropped={} summaries_dropped={} i2c_resets={}",
+                diag.interrupt_count,
+                diag.rx_dropped_bytes,
+                diag.deferred_dropped,
+                diag.summaries_dropped,
+                diag.i2c_recoveries
+            ));
+            let pulse_us = free(pulse_width::last_width_us);
+            match pulse_us {
+                Some(us) => tx.write_line_blocking(format_args!(
+                    "diag: spurious_irqs={} throttled={} healthy={} bin_dropped={} pulse_width={}us",
+                    diag.spurious_timer_irqs, diag.throttled, diag.healthy, diag.binary_dropped, us
+                )),
+                None => tx.write_line_blocking(format_args!(
+                    "diag: spurious_irqs={} throttled={} healthy={} bin_dropped={} pulse_width=none",
+                    diag.spurious_timer_irqs, diag.throttled, diag.healthy, diag.binary_dropped
+                )),
+            };
+            tx.write_line_blocking(format_args!(
+                "diag: log_dropped={} irq_latency_max={}us",
+                diag.log_dropped, diag.irq_latency_max_us
+            ));
+            // 覚えているLEDの状態と、レジスタから読んだ実際の出力（led.rs）
+            let (tracked, actual) = (free(led::is_on), led::led_is_on());
+            tx.write_line_blocking(format_args!(
+                "diag: led tracked={} hw={}{}",
+                if tracked { "on" } else { "off" },
+                if actual { "on" } else { "off" },
+                if tracked == actual { "" } else { " MISMATCH" }
+            ));
+        }
+        "bicolor" => {
+            let mut words = args.split_whitespace();
+            match (words.next(), words.next().map(str::parse::<u32>)) {
+                (None, _) => {
+                    let (enabled, speed_ms) =
+                        free(|cs| (bicolor::is_enabled(cs), bicolor::crossfade_speed(cs)));
+                    tx.write_line(format_args!(
+                        "bicolor {} speed {} ms",
+                        if enabled { "on" } else { "off" },
+                        speed_ms
+                    ));
+                }
+                (Some("on"), None) => {
+                    bicolor::set_crossfade(true);
+                    tx.write_line(format_args!("bicolor on"));
+                }
+                (Some("off"), None) => {
+                    bicolor::set_crossfade(false);
+                    tx.write_line(format_args!("bicolor off"));
+                }
+                (Some("speed"), Some(Ok(ms))) => {
+                    let ms = bicolor::set_crossfade_speed(ms);
+                    tx.write_line(format_args!("bicolor speed {} ms", ms));
+                }
+                _ => {
+                    tx.write_line(format_args!("usage: bicolor [on|off|speed MS]"));
+                }
+            }
+        }
+        "brightness" => {
+            let mut args = args.split_whitespace();
+            let level = args.next().and_then(|n| n.parse().ok());
+            let duration_ms = args.next().map(|ms| ms.parse::<u32>());
+            match (level, duration_ms) {
+                (Some(level), None) => {
+                    led::set_led_brightness(level);
+                    tx.write_line(format_args!("brightness {}", level));
+                }
+                (Some(level), Some(Ok(ms))) => {
+                    fade::set_brightness_smooth(level, ms);
+                    tx.write_line(format_args!("brightness {} in {} ms", level, ms));
+                }
+                _ => {
+                    tx.write_line(format_args!("usage: brightness N (0-65535) [MS]"));
+                }
+            }
+        }
+        "autodim" if args.trim().is_empty() => {
+            let (enabled, curve) = free(|cs| (ambient::is_dimming(cs), ambient::dim_curve(cs)));
+            let state = if enabled { "on" } else { "off" };
+            match curve {
+                ResponseCurve::Stepped(steps) => {
+                    tx.write_line(format_args!(
+                        "autodim {}, curve {}, {} steps",
+                        state,
+                        curve.name(),
+                        steps
+                    ));
+                }
+                _ => {
+                    tx.write_line(format_args!("autodim {}, curve {}", state, curve.name()));
+                }
+            }
+        }
+        "autodim" => match args.trim() {
+            "on" => {
+                ambient::set_dimming(true);
+                tx.write_line(format_args!("autodim on"));
+            }
+            "off" => {
+                ambient::set_dimming(false);
+                tx.write_line(format_args!("autodim off"));
+            }
+            "linear" => {
+                ambient::set_dim_curve(ResponseCurve::Linear);
+                tx.write_line(format_args!("autodim curve linear"));
+            }
+            "quadratic" => {
+                ambient::set_dim_curve(ResponseCurve::Quadratic);
+                tx.write_line(format_args!("autodim curve quadratic"));
+            }
+            "log" => {
+                ambient::set_dim_curve(ResponseCurve::Log);
+                tx.write_line(format_args!("autodim curve log"));
+            }
+            other => match other.strip_prefix("steps").map(|n| n.trim().parse::<u8>()) {
+                Some(Ok(steps)) if steps >= 2 => {
+                    ambient::set_dim_curve(ResponseCurve::Stepped(steps));
+                    tx.write_line(format_args!("autodim curve stepped, {} steps", steps));
+                }
+                _ => {
+                    tx.write_line(format_args!(
+                        "usage: autodim on|off|linear|quadratic|log|steps N (2-255)"
+                    ));
+                }
+            },
+        },
+        "automode" => match args.trim() {
+            "" => {
+                let enabled = free(ambient::is_auto_mode);
+                tx.write_line(format_args!(
+                    "automode {}",
+                    if enabled { "on" } else { "off" }
+                ));
+            }
+            "on" => {
+                ambient::set_auto_mode(true);
+                tx.write_line(format_args!("automode on"));
+            }
+            "off" => {
+                ambient::set_auto_mode(false);
+                tx.write_line(format_args!("automode off"));
+            }
+            _ => {
+                tx.write_line(format_args!("usage: automode [on|off]"));
+            }
+        },
+        "ledpin" if args.trim().is_empty() => {
+            let gpio = free(led::led_gpio);
+            tx.write_line(format_args!("led on GPIO{}", gpio));
+        }
+        "ledpin" => match args.trim().parse() {
+            Ok(n) => match led::set_led_gpio(n) {
+                Ok(()) => {
+                    tx.write_line(format_args!("led on GPIO{}", n));
+                }
+                Err(e) => {
+                    tx.write_line(format_args!("error: ledpin {}", e.name()));
+                }
+            },
+            Err(_) => {
+                tx.write_line(format_args!("usage: ledpin N (8, 9, 25)"));
+            }
+        },
+        "cap" => match args.trim().parse() {
+            Ok(max) => match led::set_brightness_cap(max) {
+                Ok(()) => {
+                    tx.write_line(format_args!("brightness cap {}", max));
+                }
+                Err(e) => {
+                    tx.write_line(format_args!("error: {}", e.name()));
+                }
+            },
+            Err(_) => {
+                tx.write_line(format_args!("usage: cap N (0-65535)"));
+            }
+        },
+        "blinkfor" => match args
+            .trim()
+            .split_once(' ')
+            .map(|(ms, duration)| (ms.parse(), duration.trim().parse()))
+        {
+            Some((Ok(ms), Ok(duration))) => match blink_burst::blink_burst_for(ms, duration) {
+                Ok(ms) => {
+                    tx.write_line(format_args!("interval {} ms for {} ms", ms, duration));
+                }
+                Err(e) => {
+                    tx.write_line(format_args!("error: {}", e.name()));
+                }
+            },
+            _ => {
+                tx.write_line(format_args!("usage: blinkfor MS DURATION"));
+            }
+        },
+        "button" => match args.trim().split_once(' ') {
+            Some((edge @ ("press" | "release"), what)) => {
+                let what = what.trim();
+                let action = match what {
+                    "none" => Some(None),
+                    what => Work::parse(what).map(Some),
+                };
+                match action {
+                    Some(action) => {
+                        if edge == "press" {
+                            button::on_press(action);
+                        } else {
+                            button::on_release(action);
+                        }
+                        tx.write_line(format_args!("button {}: {}", edge, what));
+                    }
+                    None => {
+                        tx.write_line(format_args!("usage: button press|release WORK|none"));
+                    }
+                }
+            }
+            _ => {
+                tx.write_line(format_args!("usage: button press|release WORK|none"));
+            }
+        },
+        "reg" if args.trim().is_empty() => {
+            // 表の全部はバッファ（LINE_BUF_LEN）に入りきらないので、送り終わるのを待って書く
+            for register in register_map::REGISTERS {
+                let access = if register.is_read_only() { "ro" } else { "rw" };
+                tx.write_line_blocking(format_args!(
+                    "reg 0x{:02x} {} = 0x{:02x} {}",
+                    register.addr,
+                    register.name,
+                    register_map::read(register.addr),
+                    access
+                ));
+            }
+        }
+        "reg" => {
+            let mut words = args.split_whitespace().map(parse_u8);
+            match (words.next(), words.next(), words.next()) {
+                (Some(Some(addr)), None, None) => {
+                    tx.write_line(format_args!(
+                        "reg 0x{:02x} = 0x{:02x}",
+                        addr,
+                        register_map::read(addr)
+                    ));
+                }
+                (Some(Some(addr)), Some(Some(value)), None) => {
+                    register_map::write(addr, value);
+                    // 読み出し専用や表にないアドレスは無視されるので、読み直した値を返す
+                    tx.write_line(format_args!(
+                        "reg 0x{:02x} = 0x{:02x}",
+                        addr,
+                        register_map::read(addr)
+                    ));
+                }
+                _ => {
+                    tx.write_line(format_args!("usage: reg [ADDR [VALUE]]"));
+                }
+            }
+        }
+        "schedule" => match args.trim() {
+            "on" => {
+                schedule::set_enabled(true);
+                tx.write_line(format_args!("schedule on"));
+            }
+            "off" => {
+                schedule::set_enabled(false);
+                tx.write_line(format_args!("schedule off"));
+            }
+            _ => {
+                tx.write_line(format_args!("usage: schedule on|off"));
+            }
+        },
+        "estop" => match args.trim() {
+            "" => {
+                let state = if estop::is_latched() {
+                    "stopped"
+                } else {
+                    "running"
+                };
+                tx.write_line(format_args!("estop {}", state));
+            }
+            "reset" => match estop::reset() {
+                Ok(()) => {
+                    tx.write_line(format_args!("estop released"));
+                }
+                Err(e) => {
+                    tx.write_line(format_args!("error: estop {}", e.name()));
+                }
+            },
+            _ => {
+                tx.write_line(format_args!("usage: estop [reset]"));
+            }
+        },
+        "enable" if args.trim().is_empty() => {
+            let enabled = free(output_enable::is_enabled);
+            tx.write_line(format_args!(
+                "enable {}",
+                if enabled {
+                    "on"
+                } else {
+                    "off (outputs forced off)"
+                }
+            ));
+        }
+        "post" => {
+            // 項目ごとに1行ずつ書くので、送り終わるのを待って書く
+            let (result, previous) = free(|cs| (post::result(cs), post::previous(cs)));
+            match result {
+                Some(result) => {
+                    tx.write_line_blocking(format_args!(
+                        "post: {} result=0x{:04x}",
+                        if result.passed() { "pass" } else { "fail" },
+                        result.0
+                    ));
+                    for (check, name) in post::CHECKS {
+                        tx.write_line_blocking(format_args!(
+                            "post {} {}",
+                            name,
+                            match result.check(check) {
+                                Some(true) => "pass",
+                                Some(false) => "fail",
+                                None => "skipped",
+                            }
+                        ));
+                    }
+                }
+                None => {
+                    tx.write_line_blocking(format_args!("post: not run"));
+                }
+            }
+            match previous {
+                Some(previous) => {
+                    tx.write_line(format_args!("post previous: result=0x{:04x}", previous.0))
+                }
+                None => tx.write_line(format_args!("post previous: none")),
+            };
+        }
+        "idle" if args.trim().is_empty() => {
+            let (timeout_ms, idle) = free(|cs| (idle_off::timeout_ms(cs), idle_off::is_idle(cs)));
+            if timeout_ms == 0 {
+                tx.write_line(format_args!("idle off"));
+            } else {
+                tx.write_line(format_args!(
+                    "idle {} s (stopped: {})",
+                    timeout_ms / 1000,
+                    idle
+                ));
+            }
+        }
+        "idle" => {
+            let timeout_ms = match args.trim() {
+                "off" => Some(0),
+                n => n
+                    .parse::<u32>()
+                    .ok()
+                    .and_then(|secs| secs.checked_mul(1000)),
+            };
+            match timeout_ms {
+                Some(ms) => {
+                    idle_off::set_timeout_ms(ms);
+                    tx.write_line(format_args!("idle {} s", ms / 1000));
+                }
+                None => {
+                    tx.write_line(format_args!("usage: idle [SEC|off]"));
+                }
+            }
+        }
+        "dim" if args.trim().is_empty() => {
+            let (timeout_ms, level, dimmed) = free(|cs| {
+                (
+                    idle_dim::timeout_ms(cs),
+                    idle_dim::level(cs),
+                    idle_dim::is_dimmed(cs),
+                )
+            });
+            if timeout_ms == 0 {
+                tx.write_line(format_args!("dim off"));
+            } else {
+                tx.write_line(format_args!(
+                    "dim {} s level {} (dimmed: {})",
+                    timeout_ms / 1000,
+                    level,
+                    dimmed
+                ));
+            }
+        }
+        "dim" => {
+            let mut words = args.split_whitespace();
+            let setting = match (words.next(), words.next(), words.next()) {
+                (Some("off"), None, None) => Some((0, idle_dim::IDLE_DIM_LEVEL)),
+                (Some(secs), Some(level), None) => secs
+                    .parse::<u32>()
+                    .ok()
+                    .and_then(|secs| secs.checked_mul(1000))
+                    .zip(level.parse::<u16>().ok()),
+                _ => None,
+            };
+            match setting {
+                Some((0, _)) => {
+                    idle_dim::set_idle_dim(0, idle_dim::IDLE_DIM_LEVEL);
+                    tx.write_line(format_args!("dim off"));
+                }
+                Some((ms, level)) => {
+                    idle_dim::set_idle_dim(ms, level);
+                    tx.write_line(format_args!("dim {} s level {}", ms / 1000, level));
+                }
+                None => {
+                    tx.write_line(format_args!("usage: dim [SEC LEVEL|off]"));
+                }
+            }
+        }
+        "step" => match args.trim() {
+            "on" => {
+                step_mode::set_enabled(true);
+                tx.write_line(format_args!("step mode on"));
+            }
+            "off" => {
+                step_mode::set_enabled(false);
+                tx.write_line(format_args!("step mode off"));
+            }
+            _ => {
+                tx.write_line(format_args!("usage: step on|off"));
+            }
+        },
+        "breathe" if args.trim().is_empty() => {
+            let waveform = free(mfg_test::breathe_waveform);
+            tx.write_line(format_args!("breathe {}", waveform.name()));
+        }
+        "breathe" => match Waveform::from_name(args.trim()) {
+            Some(waveform) => {
+                mfg_test::set_breathe_waveform(waveform);
+                tx.write_line(format_args!("breathe {}", waveform.name()));
+            }
+            None => {
+                tx.write_line(format_args!("usage: breathe [triangle|sine]"));
+            }
+        },
+        "test" => {
+            mfg_test::start();
+            tx.write_line(format_args!("mfg test running, reset to stop"));
+        }
+        "rec" => match args.trim() {
+            "start" => {
+                recorder::start_record();
+                tx.write_line(format_args!("recording"));
+            }
+            "stop" => {
+                let steps = recorder::stop_record();
+                tx.write_line(format_args!("recorded {} steps", steps));
+            }
+            "play" => {
+                if recorder::play_recorded() {
+                    tx.write_line(format_args!("playing"));
+                } else {
+                    tx.write_line(format_args!("error: nothing recorded"));
+                }
+            }
+            _ => {
+                tx.write_line(format_args!("usage: rec start|stop|play"));
+            }
+        },
+        "pulses" if args.trim() == "stop" => {
+            let _ = pulse_train::set_repeat(0, 0, 0);
+            tx.write_line(format_args!("pulse repeat stopped"));
+        }
+        "pulses" => {
+            let mut args = args.split_whitespace();
+            let freq = args.next().map(str::parse::<u32>);
+            let count = args.next().map(str::parse::<u32>);
+            let every = match (args.next(), args.next().map(str::parse::<u32>)) {
+                (None, None) => Some(None),
+                (Some("every"), Some(Ok(ms))) => Some(Some(ms)),
+                _ => None,
+            };
+            match (freq, count, every) {
+                (Some(Ok(freq)), Some(Ok(count)), Some(None)) => {
+                    match pulse_train::emit_pulses(freq, count) {
+                        Ok(hz) => tx.write_line(format_args!("pulses {} at {} Hz", count, hz)),
+                        Err(e) => tx.write_line(format_args!("error: {}", e.name())),
+                    };
+                }
+                (Some(Ok(freq)), Some(Ok(count)), Some(Some(ms))) => {
+                    match pulse_train::set_repeat(freq, count, ms) {
+                        Ok(ms) => tx.write_line(format_args!(
+                            "pulses {} at {} Hz every {} ms",
+                            count, freq, ms
+                        )),
+                        Err(e) => tx.write_line(format_args!("error: {}", e.name())),
+                    };
+                }
+                _ => {
+                    tx.write_line(format_args!("usage: pulses HZ N [every MS] | pulses stop"));
+                }
+            }
+        }
+        "ir" => match free(ir_nec::last_command) {
+            Some(command) => {
+                tx.write_line(format_args!("ir command 0x{:02x}", command));
+            }
+            None => {
+                tx.write_line(format_args!("ir nothing received"));
+            }
+        },
+        "edges" if args.trim().is_empty() => {
+            let (rate, period, divider) = free(|cs| {
+                (
+                    edge_counter::rate_hz(cs),
+                    edge_counter::period_us(cs),
+                    edge_counter::divider(cs),
+                )
+            });
+            match (rate, period) {
+                (Some(hz), Some(us)) => tx.write_line(format_args!(
+                    "edges {} Hz div={} period({} edges)={} us",
+                    hz, divider, divider, us
+                )),
+                (Some(hz), None) => tx.write_line(format_args!("edges {} Hz div={}", hz, divider)),
+                (None, _) => tx.write_line(format_args!("edges measuring div={}", divider)),
+            };
+        }
+        "edges" => {
+            let mut args = args.split_whitespace();
+            match (args.next(), args.next().map(str::parse::<u32>)) {
+                (Some("div"), Some(Ok(n))) => {
+                    edge_counter::set_edge_divider(n);
+                    let divider = free(edge_counter::divider);
+                    tx.write_line(format_args!("edge divider {}", divider));
+                }
+                _ => {
+                    tx.write_line(format_args!("usage: edges [div N]"));
+                }
+            }
+        }
+        "mains" if args.trim().is_empty() => {
+            let (enabled, freq, divider) = free(|cs| {
+                (
+                    mains_sync::is_enabled(cs),
+                    mains_sync::frequency(cs),
+                    mains_sync::divider(cs),
+                )
+            });
+            let state = if enabled { "on" } else { "off" };
+            match freq {
+                Some(freq) => tx.write_line(format_args!(
+                    "mains sync {} div={} locked {} Hz",
+                    state,
+                    divider,
+                    freq.hz()
+                )),
+                None => tx.write_line(format_args!(
+                    "mains sync {} div={} no signal",
+                    state, divider
+                )),
+            };
+        }
+        "mains" => {
+            let mut args = args.split_whitespace();
+            match (args.next(), args.next().map(str::parse::<u32>)) {
+                (Some("on"), None) => {
+                    mains_sync::set_enabled(true);
+                    tx.write_line(format_args!("mains sync on"));
+                }
+                (Some("off"), None) => {
+                    mains_sync::set_enabled(false);
+                    tx.write_line(format_args!("mains sync off"));
+                }
+                (Some("div"), Some(Ok(divider))) => {
+                    let divider = mains_sync::set_divider(divider);
+                    tx.write_line(format_args!("mains divider {}", divider));
+                }
+                _ => {
+                    tx.write_line(format_args!("usage: mains [on|off|div N]"));
+                }
+            }
+        }
+        "glitch" if args.trim().is_empty() => {
+            let us = free(glitch_filter::glitch_filter_us);
+            tx.write_line(format_args!("glitch filter {} us", us));
+        }
+        "glitch" => match args.trim().parse() {
+            Ok(us) => {
+                let us = glitch_filter::set_glitch_filter_us(us);
+                tx.write_line(format_args!("glitch filter {} us", us));
+            }
+            Err(_) => {
+                tx.write_line(format_args!("usage: glitch [US]"));
+            }
+        },
+        "manch" if args.trim().is_empty() => {
+            let bit_us = free(manchester::bit_period_us);
+            tx.write_line(format_args!("manchester bit period {} us", bit_us));
+        }
+        "manch" => match args.trim().parse() {
+            Ok(us) => {
+                let bit_us = manchester::set_bit_period_us(us);
+                tx.write_line(format_args!("manchester bit period {} us", bit_us));
+            }
+            Err(_) => {
+                tx.write_line(format_args!("usage: manch [US]"));
+            }
+        },
+        "wave" => match args.trim() {
+            "on" => {
+                waveform::set_enabled(true);
+                tx.write_line(format_args!("waveform on"));
+            }
+            "off" => {
+                waveform::set_enabled(false);
+                tx.write_line(format_args!("waveform off"));
+            }
+            _ => {
+                tx.write_line(format_args!("usage: wave on|off"));
+            }
+        },
+        _ => {
+            tx.write_line(format_args!("unknown command: {}", command));
+        }
+    }
+}
+
+// 10進数か、0xで始まる16進数の1バイトを読み取る
+fn parse_u8(s: &str) -> Option<u8> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+// "N WORK"の形の引数を読み取る。応答に使うためWORKの部分の文字列も返す。
+fn parse_count_and_work(args: &str) -> Option<(u32, Work, &str)> {
+    let (n, what) = args.split_once(' ')?;
+    let what = what.trim();
+    Some((n.parse().ok()?, Work::parse(what)?, what))
+}
+
+// バイト列を区切りなしの16進数で書き出す（config blob の応答）
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
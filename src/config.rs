@@ -0,0 +1,210 @@
+// 起動時に1回だけ決めて、あとは変えない設定
+//
+// 設定は次の2つに分けている。
+//   起動時の設定（Config、このモジュール）
+//     電源を入れたときの値と、配線で決まっていて動いている間は変えられない値。
+//     main()の最初で1回だけ作り、以後は読むだけ。config()で&'static Configを返すので、
+//     どこからでも借用やクリティカルセクションを気にせずに読める（書き換える手段はない）。
+//   実行時の状態（各モジュールのMutex<Cell>）
+//     UARTのコマンドやボタンで変えられる値。点滅間隔（interval.rs）、モード（mode.rs）、
+//     プリスケール値（prescaler.rs）、ブザーの周波数（tone.rs）、LEDのGPIO（led.rs）など。
+//     起動時にConfigの値を入れ、reset_settings()（main.rs）でもConfigの値に戻す。
+// つまり「戻す先」がConfigで、「今の値」が各モジュールのセル。
+// 新しい設定を足すときは、動いている間に変えるならセルに、変えないならここに置く。
+// 変えるものでも、起動時の値はここに置いてセルの初期化に使うこと（定数を各所で直接使わない）。
+//
+// 作り方はboot()にまとめている。各モジュールのDEFAULT系の定数から始め、フラッシュに保存した設定
+// （load_config()）で上書きし、起動時に読んだDIPスイッチ（dip_switch.rs）、最後にフィーチャーで上書きする。
+//
+// フラッシュに保存した設定
+//   フラッシュの最後のセクタ（CONFIG_FLASH_OFFSETから4 KB、memory.xでプログラムの領域から外している）に
+//   バージョンとCRC付きで置く。形式と、レイアウトを変えたときの移行のしかたはconfig_blob.rsを参照。
+//   CRCが合わない、知らないバージョン、値が範囲の外のときは、保存した設定を使わずに初期値で起動する。
+//   ファームウェアからはまだ書き込まないので、UARTの config blob で今の設定を書き出し、
+//   picotoolなどでCONFIG_FLASH_OFFSETに書き込む。
+
+use crate::cs_trace::free;
+use crate::dip_switch::DipSetting;
+use crate::led;
+use crate::mode::{self, LedMode};
+use crate::prescaler;
+use crate::tone;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::config_blob::{self, BlobError, StoredConfig};
+
+// 保存した設定を置くセクタの、フラッシュの先頭からの位置（2 MBの最後の4 KB）
+pub const CONFIG_FLASH_OFFSET: u32 = 2048 * 1024 - CONFIG_SECTOR_LEN as u32;
+const CONFIG_SECTOR_LEN: usize = 4096;
+// フラッシュをメモリとして読める（XIP）アドレスの先頭
+const XIP_BASE: u32 = 0x1000_0000;
+
+// 保存するときのモードの番号。番号は保存した設定に残るので、並べ替えずに後ろに足すだけにすること。
+const STORED_MODES: [LedMode; 6] = [
+    LedMode::Solid,
+    LedMode::Blink,
+    LedMode::Number,
+    LedMode::Off,
+    LedMode::Heartbeat,
+    LedMode::Bar,
+];
+
+#[derive(Clone, Copy)]
+pub struct Config {
+    // 点滅間隔の初期値
+    pub blink_interval_ms: u32,
+    // 最初のALARM0だけを遅らせる時間（main.rsのPHASE_OFFSET_MS参照）
+    pub phase_offset_ms: u32,
+    // 起動時のモード
+    pub mode: LedMode,
+    // プリスケール値の初期値
+    pub prescale: u32,
+    // ブザーの周波数の初期値
+    pub tone_hz: u32,
+    // 起動時にLEDを出すGPIO（led::LED_GPIOSのどれか）
+    pub led_gpio: u8,
+    // 起動した直後から製造時の検査のパターンを出す（mfg-testフィーチャー、mfg_test.rs）
+    pub mfg_test: bool,
+    // 起動時に読んだDIPスイッチ（dip_switch.rs）の4bit
+    pub dip_switch: u8,
+}
+
+impl Config {
+    // モジュールごとの定数から作った設定。フィーチャーは反映しない。
+    pub const DEFAULT: Config = Config {
+        blink_interval_ms: crate::ALARM0_INTERVAL_MS,
+        phase_offset_ms: crate::PHASE_OFFSET_MS,
+        mode: mode::DEFAULT_MODE,
+        prescale: prescaler::PRESCALE,
+        tone_hz: tone::TONE_FREQ_HZ,
+        led_gpio: led::LED_DEFAULT_GPIO,
+        mfg_test: false,
+        dip_switch: 0,
+    };
+
+    // 起動時の設定を作る。dipは起動時に読んだDIPスイッチ。
+    pub fn boot(dip: DipSetting) -> Config {
+        let stored = if dip.ignore_flash {
+            defmt::info!("config: flash ignored by the dip switch");
+            Config::DEFAULT
+        } else {
+            load_config()
+        };
+        Config {
+            mode: dip.mode.unwrap_or(stored.mode),
+            mfg_test: dip.mfg_test || cfg!(feature = "mfg-test"),
+            dip_switch: dip.bits,
+            ..stored
+        }
+    }
+
+    // フラッシュに保存する値
+    pub fn to_stored(self) -> StoredConfig {
+        StoredConfig {
+            blink_interval_ms: self.blink_interval_ms,
+            mode: STORED_MODES
+                .iter()
+                .position(|&m| m == self.mode)
+                .unwrap_or(0) as u8,
+            prescale: self.prescale,
+            tone_hz: self.tone_hz,
+            led_gpio: self.led_gpio,
+        }
+    }
+
+    // フラッシュから読んだ値をDEFAULTに入れる。範囲の外の値があればNone。
+    fn from_stored(stored: &StoredConfig) -> Option<Config> {
+        let valid = stored.blink_interval_ms > 0
+            && stored.blink_interval_ms <= crate::MAX_ALARM_INTERVAL_MS
+            && stored.prescale >= 1
+            && (tone::TONE_MIN_HZ..=tone::TONE_MAX_HZ).contains(&stored.tone_hz)
+            && led::LED_GPIOS.contains(&stored.led_gpio);
+        if !valid {
+            return None;
+        }
+        Some(Config {
+            blink_interval_ms: stored.blink_interval_ms,
+            mode: *STORED_MODES.get(usize::from(stored.mode))?,
+            prescale: stored.prescale,
+            tone_hz: stored.tone_hz,
+            led_gpio: stored.led_gpio,
+            ..Config::DEFAULT
+        })
+    }
+
+    // 初期化が終わってから最初のALARM0までの時間
+    pub fn first_alarm0_ms(&self) -> u32 {
+        self.blink_interval_ms
+            .saturating_add(self.phase_offset_ms)
+            .min(crate::MAX_ALARM_INTERVAL_MS)
+    }
+}
+
+const DEFAULT_FIRST_ALARM0_MS: u32 =
+    Config::DEFAULT.blink_interval_ms + Config::DEFAULT.phase_offset_ms;
+assert_alarm_interval_ms!(DEFAULT_FIRST_ALARM0_MS);
+const _: () = assert!(
+    Config::DEFAULT.led_gpio == led::LED_GPIOS[0]
+        || Config::DEFAULT.led_gpio == led::LED_GPIOS[1]
+        || Config::DEFAULT.led_gpio == led::LED_GPIOS[2],
+    "led_gpio must be one of led::LED_GPIOS"
+);
+
+static CONFIG: Mutex<Cell<Option<&'static Config>>> = Mutex::new(Cell::new(None));
+// load_config()で保存した設定を読めたか。読めたなら移行する前のバージョン（今のバージョンならNone）。
+static LOAD_RESULT: Mutex<Cell<Result<Option<u8>, BlobError>>> =
+    Mutex::new(Cell::new(Err(BlobError::NotFound)));
+
+// フラッシュに保存した設定を読む。CRCとバージョンを確かめ、古いバージョンなら今の形式に移す。
+// 読めないときや値が範囲の外のときはConfig::DEFAULTを返す。フィーチャーは反映しない（boot()で上書きする）。
+pub fn load_config() -> Config {
+    // 読むだけなのでXIPのまま読める（フラッシュに書き込んでいる間でなければ）
+    let sector = unsafe {
+        core::slice::from_raw_parts(
+            (XIP_BASE + CONFIG_FLASH_OFFSET) as *const u8,
+            CONFIG_SECTOR_LEN,
+        )
+    };
+    let result = config_blob::decode(sector, &Config::DEFAULT.to_stored()).and_then(|loaded| {
+        match Config::from_stored(&loaded.config) {
+            Some(config) => Ok((config, loaded.migrated_from)),
+            None => Err(BlobError::OutOfRange),
+        }
+    });
+    free(|cs| LOAD_RESULT.borrow(cs).set(result.map(|(_, from)| from)));
+    match result {
+        Ok((config, None)) => {
+            defmt::info!("config: loaded from flash");
+            config
+        }
+        Ok((config, Some(version))) => {
+            defmt::info!(
+                "config: migrated from version {=u8} to {=u8}",
+                version,
+                config_blob::CONFIG_VERSION
+            );
+            config
+        }
+        Err(e) => {
+            defmt::info!("config: using defaults ({=str})", e.name());
+            Config::DEFAULT
+        }
+    }
+}
+
+// 起動時にフラッシュの設定を読めたか（load_config()の結果）
+pub fn load_result(cs: &CriticalSection) -> Result<Option<u8>, BlobError> {
+    LOAD_RESULT.borrow(cs).get()
+}
+
+// main()でDIPスイッチを読んだ直後に1回だけ呼ぶ。2回呼ぶとsingleton!がNoneを返すので止まる。
+pub fn init(config: Config) -> &'static Config {
+    let config: &'static Config = cortex_m::singleton!(: Config = config).unwrap();
+    free(|cs| CONFIG.borrow(cs).set(Some(config)));
+    config
+}
+
+// 起動時の設定。init()より前に呼ぶと止まる。
+pub fn config() -> &'static Config {
+    free(|cs| CONFIG.borrow(cs).get()).expect("config::init() has not been called")
+}
@@ -0,0 +1,243 @@
+// フラッシュに保存する設定の形式（バージョンとCRC付き）
+//
+// 形式（リトルエンディアン）
+//   0..4   : CONFIG_MAGIC。消去したままのフラッシュ（0xFF）や別の用途のデータと区別する
+//   4      : バージョン（CONFIG_VERSION）
+//   5      : 中身の長さ（バイト）
+//   6..    : 中身（バージョンごとに決まった並び）
+//   最後4  : 先頭から中身の終わりまでのCRC-32（crc::crc32）
+// 書き込みの途中で電源が切れたものや、ビットが化けたものはCRCで見つけて捨てる。
+//
+// バージョンごとの中身
+//   1 : 点滅間隔 u32, モード u8
+//   2 : 点滅間隔 u32, モード u8, プリスケール値 u32, ブザーの周波数 u32, LEDのGPIO u8（今の形式）
+//
+// 移行のしかた
+//   中身の並びを変えるときは、今の並びを書き換えずにCONFIG_VERSIONを1つ上げ、新しい並びを足す。
+//   古いバージョンはdecode()で読み、そのバージョンにない値は呼び出し側が渡す初期値で埋める。
+//   項目は後ろに足すだけにしておけば、古い並びは新しい並びの先頭と同じなので、読めた分だけ使えばよい。
+//   意味や単位を変えた項目は、そのバージョンを読むところで変換する。
+//   CONFIG_VERSIONより新しいもの（ファームウェアを古いものに戻したとき）は、読み方がわからないので
+//   UnknownVersionで断り、呼び出し側は初期値を使う。書き直すまでフラッシュの中身は消さない。
+//   保存するときはいつも今のバージョンで書くので、1回保存し直せば古い形式は残らない。
+//
+// モードなどのファームウェアの型はこのクレートにないので、中身は数値のまま扱い、範囲の確認は呼び出し側で行う。
+
+use crate::crc::crc32;
+
+pub const CONFIG_MAGIC: u32 = 0x4743_5450;
+pub const CONFIG_VERSION: u8 = 2;
+
+const HEADER_LEN: usize = 6;
+const CRC_LEN: usize = 4;
+// バージョンごとの中身の長さ（添字がバージョン - 1）
+const PAYLOAD_LEN: [usize; CONFIG_VERSION as usize] = [5, 14];
+// 今のバージョンで書いたときの全体の長さ
+pub const CONFIG_BLOB_LEN: usize = HEADER_LEN + PAYLOAD_LEN[CONFIG_VERSION as usize - 1] + CRC_LEN;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StoredConfig {
+    pub blink_interval_ms: u32,
+    pub mode: u8,
+    pub prescale: u32,
+    pub tone_hz: u32,
+    pub led_gpio: u8,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlobError {
+    // CONFIG_MAGICがない（一度も保存していない、消去したまま）
+    NotFound,
+    // 長さがバージョンの中身と合わない、またはデータが短い
+    BadLength,
+    // CRCが合わない（書き込みの途中で切れた、化けた）
+    BadCrc,
+    // 読み方を知らないバージョン
+    UnknownVersion(u8),
+    // 読めたが値が範囲の外（確かめるのは呼び出し側）
+    OutOfRange,
+}
+
+impl BlobError {
+    // UARTなどに出力するときの説明
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlobError::NotFound => "not found",
+            BlobError::BadLength => "bad length",
+            BlobError::BadCrc => "bad crc",
+            BlobError::UnknownVersion(_) => "unknown version",
+            BlobError::OutOfRange => "value out of range",
+        }
+    }
+}
+
+// decode()の結果
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Loaded {
+    pub config: StoredConfig,
+    // 古いバージョンから移したときはそのバージョン
+    pub migrated_from: Option<u8>,
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+// 今のバージョンで書き出す
+pub fn encode(config: &StoredConfig) -> [u8; CONFIG_BLOB_LEN] {
+    let mut blob = [0u8; CONFIG_BLOB_LEN];
+    blob[0..4].copy_from_slice(&CONFIG_MAGIC.to_le_bytes());
+    blob[4] = CONFIG_VERSION;
+    blob[5] = (CONFIG_BLOB_LEN - HEADER_LEN - CRC_LEN) as u8;
+    blob[6..10].copy_from_slice(&config.blink_interval_ms.to_le_bytes());
+    blob[10] = config.mode;
+    blob[11..15].copy_from_slice(&config.prescale.to_le_bytes());
+    blob[15..19].copy_from_slice(&config.tone_hz.to_le_bytes());
+    blob[19] = config.led_gpio;
+    let crc = crc32(&blob[..CONFIG_BLOB_LEN - CRC_LEN]);
+    blob[CONFIG_BLOB_LEN - CRC_LEN..].copy_from_slice(&crc.to_le_bytes());
+    blob
+}
+
+// 保存した設定を読む。bytesは後ろに余分があってもよい（フラッシュのセクタをそのまま渡せる）。
+// 古いバージョンにない値はdefaultsで埋める。
+pub fn decode(bytes: &[u8], defaults: &StoredConfig) -> Result<Loaded, BlobError> {
+    if bytes.len() < HEADER_LEN || u32_at(bytes, 0) != CONFIG_MAGIC {
+        return Err(BlobError::NotFound);
+    }
+    let version = bytes[4];
+    let len = usize::from(bytes[5]);
+    let end = HEADER_LEN + len;
+    if bytes.len() < end + CRC_LEN {
+        return Err(BlobError::BadLength);
+    }
+    // 長さが化けていてもCRCで見つかるように、バージョンより先にCRCを確かめる
+    if crc32(&bytes[..end]) != u32_at(bytes, end) {
+        return Err(BlobError::BadCrc);
+    }
+    let expected_len = match version {
+        1..=CONFIG_VERSION => PAYLOAD_LEN[usize::from(version) - 1],
+        _ => return Err(BlobError::UnknownVersion(version)),
+    };
+    if len != expected_len {
+        return Err(BlobError::BadLength);
+    }
+    let payload = &bytes[HEADER_LEN..end];
+    // バージョン1は2の先頭と同じ並び
+    let mut config = StoredConfig {
+        blink_interval_ms: u32_at(payload, 0),
+        mode: payload[4],
+        ..*defaults
+    };
+    if version >= 2 {
+        config.prescale = u32_at(payload, 5);
+        config.tone_hz = u32_at(payload, 9);
+        config.led_gpio = payload[13];
+    }
+    Ok(Loaded {
+        config,
+        migrated_from: (version != CONFIG_VERSION).then_some(version),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULTS: StoredConfig = StoredConfig {
+        blink_interval_ms: 500,
+        mode: 1,
+        prescale: 1,
+        tone_hz: 2000,
+        led_gpio: 25,
+    };
+
+    const SAVED: StoredConfig = StoredConfig {
+        blink_interval_ms: 250,
+        mode: 3,
+        prescale: 4,
+        tone_hz: 440,
+        led_gpio: 15,
+    };
+
+    // バージョン1の形式で書いたもの（今のファームウェアはもう書かない）
+    fn encode_v1(interval_ms: u32, mode: u8) -> Vec<u8> {
+        let mut blob = CONFIG_MAGIC.to_le_bytes().to_vec();
+        blob.extend([1, 5]);
+        blob.extend(interval_ms.to_le_bytes());
+        blob.push(mode);
+        let crc = crc32(&blob);
+        blob.extend(crc.to_le_bytes());
+        blob
+    }
+
+    #[test]
+    fn crc32_matches_the_standard_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn round_trips_the_current_version() {
+        let mut sector = [0xFF; 64];
+        sector[..CONFIG_BLOB_LEN].copy_from_slice(&encode(&SAVED));
+        let loaded = decode(&sector, &DEFAULTS).unwrap();
+        assert_eq!(loaded.config, SAVED);
+        assert_eq!(loaded.migrated_from, None);
+    }
+
+    #[test]
+    fn rejects_erased_flash_and_corrupted_data() {
+        assert_eq!(decode(&[0xFF; 64], &DEFAULTS), Err(BlobError::NotFound));
+        assert_eq!(decode(&[], &DEFAULTS), Err(BlobError::NotFound));
+
+        let blob = encode(&SAVED);
+        // 中身もCRCも、どのビットが化けても見つかる
+        for byte in 4..CONFIG_BLOB_LEN {
+            for bit in 0..8 {
+                let mut corrupted = blob;
+                corrupted[byte] ^= 1 << bit;
+                assert!(matches!(
+                    decode(&corrupted, &DEFAULTS),
+                    Err(BlobError::BadCrc | BlobError::BadLength)
+                ));
+            }
+        }
+        // 書き込みの途中で切れた
+        assert_eq!(decode(&blob[..12], &DEFAULTS), Err(BlobError::BadLength));
+    }
+
+    #[test]
+    fn migrates_version_1_and_fills_new_fields_with_defaults() {
+        let loaded = decode(&encode_v1(250, 3), &DEFAULTS).unwrap();
+        assert_eq!(loaded.migrated_from, Some(1));
+        assert_eq!(
+            loaded.config,
+            StoredConfig {
+                blink_interval_ms: 250,
+                mode: 3,
+                ..DEFAULTS
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_versions_and_mismatched_lengths() {
+        // 新しいファームウェアが書いたもの。CRCは合っている。
+        let mut newer = encode(&SAVED).to_vec();
+        newer[4] = CONFIG_VERSION + 1;
+        let crc = crc32(&newer[..CONFIG_BLOB_LEN - 4]);
+        newer[CONFIG_BLOB_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(
+            decode(&newer, &DEFAULTS),
+            Err(BlobError::UnknownVersion(CONFIG_VERSION + 1))
+        );
+
+        // バージョン2を名乗っているのに中身がバージョン1の長さ
+        let mut mislabeled = encode_v1(250, 3);
+        mislabeled[4] = 2;
+        let crc = crc32(&mislabeled[..11]);
+        mislabeled[11..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(decode(&mislabeled, &DEFAULTS), Err(BlobError::BadLength));
+    }
+}
@@ -0,0 +1,106 @@
+// ALARM0の割り込みの回数を数えるカウンタ
+//
+// TIMER_IRQ_0の処理（do_tick()）だけが増やし、メインループ、milestone、diagnosticsが読む。
+// do_tick()はコマ送り（step_mode.rs）のときにメインループのfree()の中からも呼ぶ。
+// 実装は2つあり、atomic-counterフィーチャーで選ぶ。どちらも同じ関数で使える。
+//
+//   Mutex<Cell<u32>>（通常）
+//     ほかのグローバル変数と同じ書き方。読むときもクリティカルセクションが要るので、
+//     メインループで読むたびにfree()で割り込みを止める。
+//   AtomicU32（atomic-counter）
+//     割り込みを止めずに読み書きできる。メインループのload()はfree()を使わない。
+//
+// アトミックにしたときの注意
+//   ・RP2040のコア（Cortex-M0+、ARMv6-M）にはLDREX/STREXがないので、fetch_add()のような
+//     読み出しと書き込みを1命令で行う操作は使えない。使えるのはload()とstore()だけ。
+//     そこで、loadして1足してstoreする。書くのはTIMER_IRQ_0か、割り込みを止めたfree()の中だけで、
+//     TIMER_IRQ_0は多重に入らないので、2つの書き込みがぶつかって1回分消えることはない。
+//     ほかの場所から書き込むようにするなら、この前提が崩れるのでMutexの方を使うこと。
+//   ・順序はRelaxedで足りる。このカウンタは回数そのものを伝えるだけで、
+//     カウンタを見てから別のデータを読む（カウンタで別のデータの準備ができたことを知らせる）
+//     ような使い方をしていないから。Relaxedでも、値が途中まで書かれた状態で読まれることはない。
+//   ・milestoneの登録（at_count()）は、カウンタを読んでから登録するまでの間に割り込みが
+//     入らないよう、もともとfree()の中で読んでいる。アトミックにしてもそこは変わらない。
+//
+// どちらが速いかはbenchフィーチャーで測れる（bench.rs）。
+
+use cortex_m::interrupt::CriticalSection;
+
+#[cfg(not(feature = "atomic-counter"))]
+mod imp {
+    use crate::cs_trace::free;
+    use core::cell::Cell;
+    use cortex_m::interrupt::{CriticalSection, Mutex};
+
+    static INTERRUPT_COUNTER: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+    pub fn increment(cs: &CriticalSection) -> u32 {
+        // Copyトレイトが実装されている型はRefCellの変わりにCellが使える。
+        // 生値を取り出すことができるため、とりだしたあとは書き換えでも何でもできる。
+        // ※Copyトレイトが実装されている型のみなのはCellのgetメソッドにCopyのトレイト制約があるから。
+        // ※つまり、Copyトレイトを実装した型でしかgetメソッドは使えなくなっている。
+        //
+        // ※LEDなどのペリフェラル用structにはCopyトレイトは実装されていないので、このメソッドは使えない。
+        // ※Copyトレイトが実装されている=実行時にペリフェラルが複製される=ハードのクローンが物理的に湧いてでるなので
+        // ※Copyトレイトが実装されていないのはイメージ的にも正しい。
+        //
+        // ※ちなみにRustの制約として、
+        // ※すでに別ライブラリ（標準ライブラリ含む）で定義されているstructとトレイトを使って
+        // ※新しくトレイトの実装をすることはできなくなっている。
+        // ※今回の場合だと、LED用のペリフェラルの型（rp2040-pacライブラリ）に
+        // ※無理やりCopyトレイト（coreライブラリ）を実装して
+        // ※getメソッドを使えるようにしてやる！みたいなことはできず、コンパイルエラーになる。
+        let counter = INTERRUPT_COUNTER.borrow(cs);
+        let next = counter.get().wrapping_add(1);
+        counter.set(next);
+        next
+    }
+
+    pub fn count(cs: &CriticalSection) -> u32 {
+        INTERRUPT_COUNTER.borrow(cs).get()
+    }
+
+    // free()は値を返すこともできます。
+    // ※ジェネリクスの機能で同じ関数でも異なる戻り値の型を扱うことができる
+    pub fn load() -> u32 {
+        free(|cs| INTERRUPT_COUNTER.borrow(cs).get())
+    }
+}
+
+#[cfg(feature = "atomic-counter")]
+mod imp {
+    use core::sync::atomic::{AtomicU32, Ordering};
+    use cortex_m::interrupt::CriticalSection;
+
+    static INTERRUPT_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    // 書くのはTIMER_IRQ_0かfree()の中だけなので、loadとstoreに分かれていてもよい（上の注意を参照）
+    pub fn increment(_cs: &CriticalSection) -> u32 {
+        let next = INTERRUPT_COUNTER.load(Ordering::Relaxed).wrapping_add(1);
+        INTERRUPT_COUNTER.store(next, Ordering::Relaxed);
+        next
+    }
+
+    pub fn count(_cs: &CriticalSection) -> u32 {
+        load()
+    }
+
+    pub fn load() -> u32 {
+        INTERRUPT_COUNTER.load(Ordering::Relaxed)
+    }
+}
+
+// do_tick()から呼ぶ。増やした後の値を返す。
+pub fn increment(cs: &CriticalSection) -> u32 {
+    imp::increment(cs)
+}
+
+// クリティカルセクションの中で読む
+pub fn count(cs: &CriticalSection) -> u32 {
+    imp::count(cs)
+}
+
+// クリティカルセクションの外から読む
+pub fn load() -> u32 {
+    imp::load()
+}
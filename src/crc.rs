@@ -0,0 +1,38 @@
+// 誤り検出用のCRC
+
+// 1-Wireデバイス（DS18B20など）が使うCRC-8（Dallas/Maxim、多項式 x^8 + x^5 + x^4 + 1）。
+// データの最後に付いているCRCまで含めて計算すると0になる。
+pub fn crc8_maxim(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        let mut b = byte;
+        for _ in 0..8 {
+            let mix = (crc ^ b) & 0x01;
+            crc >>= 1;
+            if mix != 0 {
+                // 多項式0x31をビット反転した値
+                crc ^= 0x8C;
+            }
+            b >>= 1;
+        }
+    }
+    crc
+}
+
+// フラッシュに保存した設定などに使うCRC-32（IEEE 802.3、zipと同じ。多項式0x04C11DB7、ビット反転）。
+// 初期値と最後の反転は0xFFFFFFFF。"123456789"の結果は0xCBF43926。
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mix = crc & 0x01;
+            crc >>= 1;
+            if mix != 0 {
+                // 多項式0x04C11DB7をビット反転した値
+                crc ^= 0xEDB8_8320;
+            }
+        }
+    }
+    !crc
+}
@@ -0,0 +1,67 @@
+// 割り込みを止めている区間（クリティカルセクション）の間だけGPIO3をHighにする
+//
+// グローバル変数はfree()の中で触るので、free()の中にいる間は割り込みが止まり、
+// その間に来た割り込みは出口まで待たされる。どの区間がどれだけ長く、どれくらいの頻度で
+// 割り込みを止めているかをロジックアナライザやオシロスコープで直接見られるように、
+// cs-traceフィーチャーではfree()に入ったらGPIO3をHighに、出るときにLowにする。
+//   ・Highの幅 : 割り込みを止めていた時間（割り込みの応答が遅れうる最大の時間）
+//   ・Highの間隔 : free()を呼んだ頻度
+//
+// このファームウェアのモジュールは、cortex_m::interrupt::freeではなくこのモジュールのfree()を使う。
+// フィーチャーを無効にしたときは、cortex_m::interrupt::freeをそのまま使うのと同じで、余分な処理はない。
+//
+// 測れるもの、測れないもの
+//   ・割り込みハンドラの中のfree()も測れる（割り込みの中でもPRIMASKは立っていないので、
+//     free()でほかの割り込みを止めている）。
+//   ・割り込みハンドラがunsafe { CriticalSection::new() }で作るCriticalSectionは、割り込みを止めないので測らない。
+//   ・HALやcortex-mの中で直接割り込みを止めているところ（singleton!、defmtのロガーなど）は測れない。
+//   ・すでに割り込みが止まっているところでfree()を呼んだ（入れ子になった）ときはピンを触らない。
+//     Highの幅はいちばん外側のfree()の分になる。
+//
+// 測定への影響
+//   ピンはoutput::write_output()でSIOのGPIO_OUT_SET/GPIO_OUT_CLRに1回書くだけで変える（読み書きし直さないので、
+//   ほかのピンの出力とぶつからない）。書き込みは1サイクルで、前後を合わせても数サイクル（125 MHzで数十ns）。
+//   割り込みを止めてからHighにし、Lowにしてから割り込みを戻すので、Highの幅は
+//   割り込みを止めていた時間より数サイクル短くなるが、長く見えることはない。
+//   ただしfree()のたびに数サイクル増えるので、CPUの負荷は少し上がる。計測のとき以外は無効にしておくこと。
+
+#[cfg(not(feature = "cs-trace"))]
+pub use cortex_m::interrupt::free;
+
+#[cfg(feature = "cs-trace")]
+pub use trace::{free, init};
+
+#[cfg(feature = "cs-trace")]
+mod trace {
+    use crate::output::{self, OutputId};
+    use cortex_m::interrupt::{self, CriticalSection};
+    use cortex_m::register::primask;
+    use rp_pico::hal::gpio;
+
+    pub type CsTracePin = gpio::Pin<gpio::bank0::Gpio3, gpio::FunctionSioOutput, gpio::PullDown>;
+
+    // ピンを出力にする。これより前のfree()はピンを変えない（SIOの出力がピンにつながっていないため）。
+    pub fn init(pin: CsTracePin) {
+        // 以後はSIOのレジスタに直接書くので、ピンは出力に設定したままにしておくだけでよい
+        let _ = pin;
+    }
+
+    // cortex_m::interrupt::freeと同じ。割り込みを止めている間だけピンをHighにする。
+    #[inline(always)]
+    pub fn free<F, R>(f: F) -> R
+    where
+        F: FnOnce(&CriticalSection) -> R,
+    {
+        let primask = primask::read();
+        interrupt::disable();
+        if primask.is_active() {
+            output::write_output(OutputId::CsTrace, true);
+        }
+        let r = f(unsafe { &CriticalSection::new() });
+        if primask.is_active() {
+            output::write_output(OutputId::CsTrace, false);
+            unsafe { interrupt::enable() }
+        }
+        r
+    }
+}
@@ -0,0 +1,319 @@
+// スイッチのチャタリング除去
+//
+// 一定の周期でサンプリングしたピンのレベルを1つずつsample()に渡すと、
+// チャタリングを除いたレベルを返す。方式は2つから選べる。
+//
+//   Lockout(ms)      : レベルが変わったら、すぐにその変化を採用し、その後msの間は変化を無視する。
+//                      最初のエッジで反応するので遅れがほぼない。
+//                      ただし1サンプルだけのノイズ（静電気や長い配線に乗ったスパイク）でも
+//                      変化として採用してしまい、その後msの間は戻れない。
+//   Integrator(n)    : 今のレベルと違うサンプルがn回続いたときだけ変化を採用する。
+//                      途中で今のレベルと同じサンプルが来たら数え直す。
+//                      n回より短いノイズやチャタリングは出力に出ないので、汚い接点にも強い。
+//                      そのかわり、変化の採用が（n × サンプリング周期）だけ遅れる。
+//
+// 時間はサンプリングの回数で数えるので、サンプリング周期は呼び出し側で一定に保つこと。
+//
+// 押したときと離したときの処理（PressRelease）
+//   レベルのtrueを「押している」として、チャタリングを除いたレベルがfalse→trueになったらPress、
+//   true→falseになったらReleaseを返し、on_press()/on_release()で決めた処理（A）も返す。
+//   押すときと離すときで別の方式にできる。離している間は押す方の方式で押したことを確かめ、
+//   押している間は離す方の方式で離したことを確かめる。
+//   チャタリングを除いたレベルが変わったときにしか返さないので、PressとReleaseは必ず交互になり、
+//   チャタリングの途中で押した/離したの組が余計に出ることはない。
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Debounce {
+    // 変化を採用してから無視する時間（ms）
+    Lockout(u32),
+    // 変化の採用に必要な、続けて一致するサンプルの数
+    Integrator(u8),
+}
+
+pub struct Debouncer {
+    strategy: Debounce,
+    sample_period_ms: u32,
+    state: bool,
+    // Lockout: 変化を無視する残りのサンプル数 / Integrator: 今のレベルと違うサンプルが続いた数
+    count: u32,
+}
+
+impl Debouncer {
+    // グローバル変数の初期値に使えるようにconst fnにしている。
+    // sample_period_msはsample()を呼ぶ周期、initialは最初の（チャタリングのない）レベル。
+    pub const fn new(strategy: Debounce, sample_period_ms: u32, initial: bool) -> Self {
+        Self {
+            strategy,
+            sample_period_ms,
+            state: initial,
+            count: 0,
+        }
+    }
+
+    pub fn state(&self) -> bool {
+        self.state
+    }
+
+    // サンプリングしたレベルを渡し、チャタリングを除いたレベルを返す
+    pub fn sample(&mut self, raw: bool) -> bool {
+        match self.strategy {
+            Debounce::Lockout(ms) => {
+                if self.count > 0 {
+                    self.count -= 1;
+                } else if raw != self.state {
+                    self.state = raw;
+                    // 変化したこのサンプルも含めて、msの間は次の変化を採用しない
+                    let lockout = ms.div_ceil(self.sample_period_ms.max(1));
+                    self.count = lockout.saturating_sub(1);
+                }
+            }
+            Debounce::Integrator(n) => {
+                if raw == self.state {
+                    self.count = 0;
+                } else {
+                    self.count += 1;
+                    if self.count >= u32::from(n.max(1)) {
+                        self.state = raw;
+                        self.count = 0;
+                    }
+                }
+            }
+        }
+        self.state
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Edge {
+    // false→true
+    Press,
+    // true→false
+    Release,
+}
+
+pub struct PressRelease<A> {
+    debouncer: Debouncer,
+    press: Debounce,
+    release: Debounce,
+    on_press: Option<A>,
+    on_release: Option<A>,
+}
+
+impl<A: Copy> PressRelease<A> {
+    // 押したことを確かめる方式と離したことを確かめる方式をそれぞれ渡す。最初は離している。
+    pub const fn new(press: Debounce, release: Debounce, sample_period_ms: u32) -> Self {
+        Self {
+            debouncer: Debouncer::new(press, sample_period_ms, false),
+            press,
+            release,
+            on_press: None,
+            on_release: None,
+        }
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.debouncer.state()
+    }
+
+    // 押したときの処理。Noneなら何もしない。
+    pub fn on_press(&mut self, action: Option<A>) {
+        self.on_press = action;
+    }
+
+    // 離したときの処理。Noneなら何もしない。
+    pub fn on_release(&mut self, action: Option<A>) {
+        self.on_release = action;
+    }
+
+    pub fn action(&self, edge: Edge) -> Option<A> {
+        match edge {
+            Edge::Press => self.on_press,
+            Edge::Release => self.on_release,
+        }
+    }
+
+    // サンプリングしたレベルを渡す。押した/離したことが確かになったら、その変化と処理を返す。
+    pub fn sample(&mut self, raw: bool) -> Option<(Edge, Option<A>)> {
+        let before = self.debouncer.state();
+        let after = self.debouncer.sample(raw);
+        if before == after {
+            return None;
+        }
+        // 次の変化は反対向きなので、その方式に切り替える。
+        // どちらもLockoutなら、無視する残りは変わったときの方式のまま数える（押した直後のチャタリングは押す方で無視する）。
+        // Integratorに切り替えるときは、Integrator自身がチャタリングを除くので数え直す。
+        let (edge, next) = if after {
+            (Edge::Press, self.release)
+        } else {
+            (Edge::Release, self.press)
+        };
+        if !matches!(
+            (self.debouncer.strategy, next),
+            (Debounce::Lockout(_), Debounce::Lockout(_))
+        ) {
+            self.debouncer.count = 0;
+        }
+        self.debouncer.strategy = next;
+        Some((edge, self.action(edge)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD_MS: u32 = 5;
+
+    // 順にサンプルを与えて、出力の列を返す
+    fn run(strategy: Debounce, initial: bool, samples: &[u8]) -> Vec<u8> {
+        let mut debouncer = Debouncer::new(strategy, PERIOD_MS, initial);
+        samples
+            .iter()
+            .map(|&s| debouncer.sample(s != 0) as u8)
+            .collect()
+    }
+
+    // 出力が変化した回数
+    fn transitions(initial: bool, output: &[u8]) -> usize {
+        let mut last = initial as u8;
+        output
+            .iter()
+            .filter(|&&o| core::mem::replace(&mut last, o) != o)
+            .count()
+    }
+
+    // 押したときにチャタリングしてから安定し、離すときにもチャタリングする
+    const BOUNCY_PRESS: [u8; 24] = [
+        0, 0, 1, 0, 1, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 1, 0, 0, 0, 0, 0, 0,
+    ];
+
+    #[test]
+    fn integrator_gives_one_press_and_one_release() {
+        let output = run(Debounce::Integrator(3), false, &BOUNCY_PRESS);
+        assert_eq!(transitions(false, &output), 2);
+        assert_eq!(output.last(), Some(&0));
+        // 1が3回続いた（index 6, 7, 8）ところで押したことになる
+        assert_eq!(output.iter().position(|&o| o == 1), Some(8));
+    }
+
+    #[test]
+    fn integrator_ignores_short_glitches() {
+        let samples = [0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 0];
+        let output = run(Debounce::Integrator(3), false, &samples);
+        assert!(output.iter().all(|&o| o == 0));
+    }
+
+    #[test]
+    fn lockout_reacts_on_first_edge_and_ignores_bounce() {
+        // 20 msのロックアウトは4サンプル
+        let output = run(Debounce::Lockout(20), false, &BOUNCY_PRESS);
+        assert_eq!(transitions(false, &output), 2);
+        assert_eq!(output.last(), Some(&0));
+        // 最初に1になったサンプルで押したことになる
+        assert_eq!(output.iter().position(|&o| o == 1), Some(2));
+    }
+
+    #[test]
+    fn lockout_holds_the_new_level_for_the_lockout_time() {
+        // 1サンプルだけのスパイクでも変化として採用し、ロックアウトの間は戻らない
+        let samples = [1, 0, 0, 0, 0, 0];
+        let output = run(Debounce::Lockout(20), false, &samples);
+        assert_eq!(output, [1, 1, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn stable_input_never_changes_output() {
+        for strategy in [Debounce::Lockout(20), Debounce::Integrator(4)] {
+            assert!(run(strategy, true, &[1; 16]).iter().all(|&o| o == 1));
+        }
+    }
+
+    // 順にサンプルを与えて、(サンプルの位置, 変化, 処理)の列を返す
+    fn edges(
+        press: Debounce,
+        release: Debounce,
+        samples: &[u8],
+    ) -> Vec<(usize, Edge, Option<char>)> {
+        let mut button = PressRelease::new(press, release, PERIOD_MS);
+        button.on_press(Some('p'));
+        button.on_release(Some('r'));
+        samples
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &s)| button.sample(s != 0).map(|(edge, a)| (i, edge, a)))
+            .collect()
+    }
+
+    #[test]
+    fn press_release_fires_each_action_once_through_bounce() {
+        let result = edges(
+            Debounce::Integrator(3),
+            Debounce::Integrator(3),
+            &BOUNCY_PRESS,
+        );
+        assert_eq!(
+            result,
+            [(8, Edge::Press, Some('p')), (20, Edge::Release, Some('r'))]
+        );
+    }
+
+    // 離すときにも押すときと同じくらいチャタリングする
+    const BOUNCY_RELEASE: [u8; 24] = [
+        0, 1, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    #[test]
+    fn press_release_edges_alternate_with_every_strategy() {
+        for strategy in [
+            Debounce::Integrator(2),
+            Debounce::Integrator(4),
+            Debounce::Lockout(20),
+            Debounce::Lockout(40),
+        ] {
+            for samples in [&BOUNCY_PRESS, &BOUNCY_RELEASE] {
+                let result = edges(strategy, strategy, samples);
+                let kinds: Vec<Edge> = result.iter().map(|&(_, e, _)| e).collect();
+                assert_eq!(kinds, [Edge::Press, Edge::Release], "{strategy:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn press_and_release_use_their_own_strategy() {
+        // 押すのはすぐ（ロックアウト）、離すのは3回続いたら
+        let result = edges(
+            Debounce::Lockout(20),
+            Debounce::Integrator(3),
+            &BOUNCY_RELEASE,
+        );
+        // 最初の1で押し、0が3回続いた（index 16, 17, 18）ところで離す
+        assert_eq!(
+            result,
+            [(1, Edge::Press, Some('p')), (18, Edge::Release, Some('r'))]
+        );
+        // 逆にすると、押すのは1が3回続いた（index 3, 4, 5）ところで、離すのは最初の0
+        let result = edges(
+            Debounce::Integrator(3),
+            Debounce::Lockout(20),
+            &BOUNCY_RELEASE,
+        );
+        assert_eq!(
+            result,
+            [(5, Edge::Press, Some('p')), (12, Edge::Release, Some('r'))]
+        );
+    }
+
+    #[test]
+    fn press_release_ignores_glitches_and_unset_actions() {
+        let samples = [0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 0];
+        assert!(edges(Debounce::Integrator(3), Debounce::Integrator(3), &samples).is_empty());
+
+        let mut button =
+            PressRelease::<char>::new(Debounce::Integrator(1), Debounce::Integrator(1), PERIOD_MS);
+        assert_eq!(button.sample(true), Some((Edge::Press, None)));
+        assert!(button.is_pressed());
+        assert_eq!(button.sample(true), None);
+        button.on_release(Some('r'));
+        assert_eq!(button.sample(false), Some((Edge::Release, Some('r'))));
+    }
+}
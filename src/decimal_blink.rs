@@ -0,0 +1,197 @@
+// 数値を10進数の桁ごとの点滅回数で表す
+//
+// 例えば305なら「3回点滅 → 桁の区切り → 長い1回点灯（0を表す） → 桁の区切り → 5回点滅」
+// を繰り返す。繰り返しの間にはさらに長い休みを入れて、どこが先頭の桁かわかるようにする。
+// 0は点滅回数では表せないので、1回だけ長く点灯させて表す。
+
+// 1回の点滅の点灯時間
+pub const NUMBER_FLASH_MS: u32 = 200;
+// 0を表す長い点灯の時間
+pub const NUMBER_ZERO_FLASH_MS: u32 = 800;
+// 同じ桁の中の点滅と点滅の間
+pub const NUMBER_FLASH_GAP_MS: u32 = 300;
+// 桁と桁の間
+pub const NUMBER_DIGIT_PAUSE_MS: u32 = 1000;
+// 最後の桁から先頭の桁に戻るまでの間
+pub const NUMBER_REPEAT_PAUSE_MS: u32 = 3000;
+
+// u32::MAXは4294967295で10桁
+pub const MAX_DIGITS: usize = 10;
+
+// nの10進数の各桁を上の桁から順にdigitsへ書き込み、桁数を返す。
+// 上位の0（先行ゼロ）は含めないが、n = 0のときは1桁の0になる。
+pub fn decimal_digits(mut n: u32, digits: &mut [u8; MAX_DIGITS]) -> usize {
+    // 下の桁から求まるので、配列の後ろから詰めてから先頭に寄せる
+    let mut start = MAX_DIGITS;
+    loop {
+        start -= 1;
+        digits[start] = (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    digits.copy_within(start.., 0);
+    MAX_DIGITS - start
+}
+
+// LEDをどうするかと、その状態を続ける時間
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Step {
+    pub on: bool,
+    pub duration_ms: u32,
+}
+
+pub struct DecimalBlinker {
+    digits: [u8; MAX_DIGITS],
+    len: usize,
+    // 今表示している桁
+    digit: usize,
+    // 今の桁で何回点滅し終わったか
+    flash: u8,
+    // 直前のステップで点灯したか
+    lit: bool,
+}
+
+impl Default for DecimalBlinker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecimalBlinker {
+    // グローバル変数の初期値に使えるようにconst fnにしている。最初は0を表示する。
+    pub const fn new() -> Self {
+        Self {
+            digits: [0; MAX_DIGITS],
+            len: 1,
+            digit: 0,
+            flash: 0,
+            lit: false,
+        }
+    }
+
+    // 表示する数値を変え、先頭の桁の最初の点滅からやり直す
+    pub fn set(&mut self, n: u32) {
+        self.len = decimal_digits(n, &mut self.digits);
+        self.digit = 0;
+        self.flash = 0;
+        self.lit = false;
+    }
+
+    // 表示を1周する間の点灯時間の合計と、1周の時間（どちらもms）
+    pub fn cycle_ms(&self) -> (u32, u32) {
+        let mut on_ms = 0;
+        let mut total_ms = 0;
+        for (i, &d) in self.digits[..self.len].iter().enumerate() {
+            let (flashes, flash_ms) = if d == 0 {
+                (1, NUMBER_ZERO_FLASH_MS)
+            } else {
+                (u32::from(d), NUMBER_FLASH_MS)
+            };
+            let pause_ms = if i + 1 < self.len {
+                NUMBER_DIGIT_PAUSE_MS
+            } else {
+                NUMBER_REPEAT_PAUSE_MS
+            };
+            on_ms += flashes * flash_ms;
+            total_ms += flashes * flash_ms + (flashes - 1) * NUMBER_FLASH_GAP_MS + pause_ms;
+        }
+        (on_ms, total_ms)
+    }
+
+    // 次のステップに進む。点灯と消灯が交互に返ってくる。
+    pub fn next_step(&mut self) -> Step {
+        let d = self.digits[self.digit];
+
+        if !self.lit {
+            self.lit = true;
+            let duration_ms = if d == 0 {
+                NUMBER_ZERO_FLASH_MS
+            } else {
+                NUMBER_FLASH_MS
+            };
+            return Step {
+                on: true,
+                duration_ms,
+            };
+        }
+
+        // 消灯。この桁の点滅が終わったかどうかで休みの長さが変わる。
+        self.lit = false;
+        self.flash += 1;
+        let flashes = d.max(1);
+        let duration_ms = if self.flash < flashes {
+            NUMBER_FLASH_GAP_MS
+        } else {
+            self.flash = 0;
+            self.digit += 1;
+            if self.digit < self.len {
+                NUMBER_DIGIT_PAUSE_MS
+            } else {
+                self.digit = 0;
+                NUMBER_REPEAT_PAUSE_MS
+            }
+        };
+        Step {
+            on: false,
+            duration_ms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digits_of(n: u32) -> Vec<u8> {
+        let mut digits = [0; MAX_DIGITS];
+        let len = decimal_digits(n, &mut digits);
+        digits[..len].to_vec()
+    }
+
+    #[test]
+    fn zero_is_a_single_digit() {
+        assert_eq!(digits_of(0), [0]);
+    }
+
+    #[test]
+    fn digits_are_most_significant_first_without_leading_zeros() {
+        assert_eq!(digits_of(7), [7]);
+        assert_eq!(digits_of(10), [1, 0]);
+        assert_eq!(digits_of(305), [3, 0, 5]);
+        assert_eq!(digits_of(1_000_000), [1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn max_value_uses_all_digits() {
+        assert_eq!(digits_of(u32::MAX), [4, 2, 9, 4, 9, 6, 7, 2, 9, 5]);
+    }
+
+    #[test]
+    fn sequence_pauses_between_digits_and_repeats() {
+        let mut blinker = DecimalBlinker::new();
+        blinker.set(20);
+
+        let on = |duration_ms| Step {
+            on: true,
+            duration_ms,
+        };
+        let off = |duration_ms| Step {
+            on: false,
+            duration_ms,
+        };
+        let expected = [
+            on(NUMBER_FLASH_MS),
+            off(NUMBER_FLASH_GAP_MS),
+            on(NUMBER_FLASH_MS),
+            off(NUMBER_DIGIT_PAUSE_MS),
+            on(NUMBER_ZERO_FLASH_MS),
+            off(NUMBER_REPEAT_PAUSE_MS),
+            on(NUMBER_FLASH_MS),
+        ];
+        for step in expected {
+            assert_eq!(blinker.next_step(), step);
+        }
+    }
+}
@@ -0,0 +1,67 @@
+// 割り込みからメインループに処理を渡すキュー
+//
+// 割り込みの中では短い処理しかしたくないので、Workを積むだけにしておき、
+// 実行はメインループのrun_pending()で行う。
+// キューはリングバッファで、DEFERRED_QUEUE_LEN個まで積める。
+// あふれた分は捨ててdefmtで警告を出し、捨てた数を数えておく。
+
+use crate::cs_trace::free;
+use crate::logging::{self, Event};
+use crate::work::Work;
+use core::cell::{Cell, RefCell};
+use cortex_m::interrupt::{CriticalSection, Mutex};
+
+pub const DEFERRED_QUEUE_LEN: usize = 8;
+
+struct Queue {
+    items: [Option<Work>; DEFERRED_QUEUE_LEN],
+    head: usize,
+    len: usize,
+}
+
+static QUEUE: Mutex<RefCell<Queue>> = Mutex::new(RefCell::new(Queue {
+    items: [None; DEFERRED_QUEUE_LEN],
+    head: 0,
+    len: 0,
+}));
+
+// キューがいっぱいで捨てた処理の数（起動してからの累計）
+static DROPPED: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+pub fn dropped(cs: &CriticalSection) -> u32 {
+    DROPPED.borrow(cs).get()
+}
+
+// 処理を積む。キューがいっぱいならfalseを返して捨てる。
+pub fn push(cs: &CriticalSection, work: Work) -> bool {
+    let mut queue = QUEUE.borrow(cs).borrow_mut();
+    if queue.len == DEFERRED_QUEUE_LEN {
+        logging::log_event(Event::Error(logging::ERR_DEFERRED_QUEUE_FULL));
+        let dropped = DROPPED.borrow(cs);
+        dropped.set(dropped.get().wrapping_add(1));
+        return false;
+    }
+    let tail = (queue.head + queue.len) % DEFERRED_QUEUE_LEN;
+    queue.items[tail] = Some(work);
+    queue.len += 1;
+    true
+}
+
+fn pop(cs: &CriticalSection) -> Option<Work> {
+    let mut queue = QUEUE.borrow(cs).borrow_mut();
+    if queue.len == 0 {
+        return None;
+    }
+    let head = queue.head;
+    queue.head = (head + 1) % DEFERRED_QUEUE_LEN;
+    queue.len -= 1;
+    queue.items[head].take()
+}
+
+// メインループから呼ぶ。積まれている処理を順に実行する。
+// 1つ実行するたびにクリティカルセクションを抜けるので、割り込みを長く止めない。
+pub fn run_pending() {
+    while let Some(work) = free(pop) {
+        free(|cs| work.run(cs));
+    }
+}
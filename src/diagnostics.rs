@@ -0,0 +1,56 @@
+// 診断用のカウンタをまとめて読む
+//
+// カウンタは各モジュールが持っていて、割り込みの中でも増える。1つずつfree()で読むと、
+// 読んでいる間に割り込みが入って、ほかのカウンタだけが進んだ値の組み合わせになることがある。
+// snapshot()は全部を1つのクリティカルセクションで読むので、同じ瞬間の値が揃う。
+// 読むのはCellのget()だけなので、割り込みを止める時間は数十サイクルで済む。
+// ログやUARTへの出力はクリティカルセクションを抜けてから行うこと。
+//
+// カウンタを追加したら、ここにも追加する。
+
+use crate::cs_trace::free;
+use crate::{
+    binary_log, command, deferred, heartbeat, i2c_bus, irq_latency, logging, rtt_logger, thermal,
+};
+use defmt::Format;
+
+#[derive(Clone, Copy, Format)]
+pub struct Diagnostics {
+    // 割り込みカウンタ（ALARM0）
+    pub interrupt_count: u32,
+    // ALARM0が発火していないのにTIMER_IRQ_0に入った回数
+    pub spurious_timer_irqs: u32,
+    // UARTの受信で読み捨てたバイト数
+    pub rx_dropped_bytes: u32,
+    // キューがいっぱいで捨てた割り込みからの処理の数
+    pub deferred_dropped: u32,
+    // バッファに入らず捨てたUARTの要約の行の数
+    pub summaries_dropped: u32,
+    // I2C0をリセットして復旧させた回数
+    pub i2c_recoveries: u32,
+    // RTTのバイナリのチャンネルに入りきらず捨てたレコードの数
+    pub binary_dropped: u32,
+    // RTTのdefmtのチャンネルに入りきらず捨てたログの数
+    pub log_dropped: u32,
+    // ALARM0の割り込みの最大の遅れ（µs、irq_latency.rs）
+    pub irq_latency_max_us: u32,
+    pub throttled: bool,
+    // falseなら外付けのWDへのハートビートを止めている
+    pub healthy: bool,
+}
+
+pub fn snapshot() -> Diagnostics {
+    free(|cs| Diagnostics {
+        interrupt_count: crate::counter::count(cs),
+        spurious_timer_irqs: crate::SPURIOUS_TIMER_IRQS.borrow(cs).get(),
+        rx_dropped_bytes: command::rx_dropped_bytes(cs),
+        deferred_dropped: deferred::dropped(cs),
+        summaries_dropped: logging::summaries_dropped(cs),
+        i2c_recoveries: i2c_bus::recoveries(cs),
+        binary_dropped: binary_log::dropped(cs),
+        log_dropped: rtt_logger::dropped(cs),
+        irq_latency_max_us: irq_latency::max_latency_us(cs),
+        throttled: thermal::is_throttled(cs),
+        healthy: heartbeat::is_healthy(cs),
+    })
+}
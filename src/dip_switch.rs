@@ -0,0 +1,173 @@
+// 起動時に4連のDIPスイッチを読み、起動時の設定（config.rs）を選ぶ
+//
+// 同じファームウェアのまま、基板のスイッチで起動時のモードなどを変えられるようにする。
+// 読むのは起動時の1回だけで、動いている間にスイッチを切り替えても何も変わらない（変えるにはリセットする）。
+//
+// 配線
+//   GPIOはすべて使っているので、起動時だけスイッチを読み、そのあとは本来の出力に使う（ストラップピン）。
+//     GPIO2  : bit0（外付けのWDへのハートビートと共用）
+//     GPIO16 : bit1（圧電ブザーと共用）
+//     GPIO8  : bit2（LEDの出力先の候補Aと共用）
+//     GPIO9  : bit3（LEDの出力先の候補Bと共用）
+//   スイッチはピンとGNDの間に10 kΩを通してつなぎ、内部プルアップで読む。ONが1、OFFが0。
+//   抵抗を通すのは、あとでピンを出力にしたとき、ONのスイッチでHighの出力がGNDに短絡しないようにするため
+//   （3.3 Vで0.33 mA流れるだけ）。
+//
+// DIPスイッチに使えるピン
+//   起動時にスイッチ以外の部品がピンの電圧を引っ張らないピンだけを使う。
+//   LEDをつないだピンは、スイッチがOFFでもプルアップの電圧がLEDの順方向電圧（赤で約1.8 V）に抑えられ、
+//   Highと読めずにONになってしまう（内部プルアップは50 kΩ程度と弱く、LEDと抵抗の方が勝つ）。
+//   このため、2色LED（カソードコモン）をつないでいるGPIO6/7は使わない。
+//   ・GPIO2はWD ICのWDI端子（CMOSの入力で、電流を流さない）にしかつながっていない。
+//     読んでいる間（最大でDIP_SETTLE_US + DIP_MAX_READS × DIP_READ_INTERVAL_US、約60 ms）は蹴らないが、
+//     WDのタイムアウト（TPS3823で最小0.9 s）より十分短い。
+//   ・GPIO16の圧電ブザーは直流を流さない（コンデンサのようなもの）ので電圧を引っ張らないが、数十nFの容量を
+//     プルアップで充電するのに数ms かかる。DIP_SETTLE_USはその分長くしている。
+//     トランジスタで駆動するブザーや、スピーカーをつないだ基板ではDIPスイッチを載せないこと（ベースやコイルが電圧を引っ張る）。
+//   ・GPIO8/9はLEDの出力先の候補で、ふだんはLEDをつけない。実際にGPIO8/9にLEDをつけた基板（ledpinで移して使うもの）では、
+//     DIPスイッチを載せないこと。
+//
+// 読み方
+//   4本を入力にしてプルアップし、DIP_SETTLE_USだけ待ってから、DIP_READ_INTERVAL_USごとに読む。
+//   同じ値がDIP_STABLE_READS回続いたらその値を採用する（スイッチの接点やプルアップの立ち上がりを待つ）。
+//   DIP_MAX_READS回読んでも揃わなければ、すべてOFFとみなす。
+//   読み終えたら4本を起動直後の状態（機能なし、プルダウン）に戻して返すので、main()はそのまま本来の用途に使える。
+//
+// スイッチと設定（bit3 bit2 bit1 bit0）
+//   bit1 bit0 : 起動時のモード
+//     0    0  : 変えない（フラッシュに保存した設定か初期値のまま）
+//     0    1  : Solid
+//     1    0  : Off
+//     1    1  : Heartbeat
+//   bit2      : 1なら、起動した直後から製造時の検査のパターンを出す（mfg_test.rs。mfg-testフィーチャーと同じ）
+//   bit3      : 1なら、フラッシュに保存した設定を読まずに初期値で起動する（保存した設定で困ったときに戻すため）
+//   すべてOFF（0000）なら、DIPスイッチをつけていない基板と同じに動く。
+
+use crate::mode::LedMode;
+use crate::pull::{self, Pull};
+use embedded_hal::digital::InputPin;
+use rp_pico::hal::gpio;
+
+pub const DIP_PULL: Pull = Pull::Up;
+// プルアップにしてから最初に読むまで（圧電ブザーの容量を充電するのを待つ）
+pub const DIP_SETTLE_US: u32 = 10_000;
+pub const DIP_READ_INTERVAL_US: u32 = 1000;
+pub const DIP_STABLE_READS: u32 = 5;
+pub const DIP_MAX_READS: u32 = 50;
+
+const _: () = assert!(DIP_STABLE_READS > 0 && DIP_STABLE_READS <= DIP_MAX_READS);
+
+// bit1 bit0をインデックスにしたモードの表（Noneは変えない）
+const DIP_MODES: [Option<LedMode>; 4] = [
+    None,
+    Some(LedMode::Solid),
+    Some(LedMode::Off),
+    Some(LedMode::Heartbeat),
+];
+const MFG_TEST_BIT: u8 = 1 << 2;
+const IGNORE_FLASH_BIT: u8 = 1 << 3;
+
+// 起動直後のピンの型（bsp::Pinsの各ピン）
+type BootPin<I> = gpio::Pin<I, gpio::FunctionNull, gpio::PullDown>;
+
+pub type DipPins = (
+    BootPin<gpio::bank0::Gpio2>,
+    BootPin<gpio::bank0::Gpio16>,
+    BootPin<gpio::bank0::Gpio8>,
+    BootPin<gpio::bank0::Gpio9>,
+);
+
+// スイッチで選んだ起動時の設定
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct DipSetting {
+    // 読んだ4bit（bit0がGPIO2）
+    pub bits: u8,
+    // 起動時のモード。Noneなら変えない。
+    pub mode: Option<LedMode>,
+    pub mfg_test: bool,
+    pub ignore_flash: bool,
+}
+
+impl DipSetting {
+    // すべてOFF。DIPスイッチがないのと同じ。
+    pub const OFF: DipSetting = DipSetting::from_bits(0);
+
+    pub const fn from_bits(bits: u8) -> DipSetting {
+        DipSetting {
+            bits: bits & 0x0F,
+            mode: DIP_MODES[(bits & 0b11) as usize],
+            mfg_test: bits & MFG_TEST_BIT != 0,
+            ignore_flash: bits & IGNORE_FLASH_BIT != 0,
+        }
+    }
+}
+
+// 読んでいる間のピン
+type DipInputs = (
+    pull::InputPin<gpio::bank0::Gpio2>,
+    pull::InputPin<gpio::bank0::Gpio16>,
+    pull::InputPin<gpio::bank0::Gpio8>,
+    pull::InputPin<gpio::bank0::Gpio9>,
+);
+
+fn read_bits(pins: &mut DipInputs) -> u8 {
+    // RP2040のGPIOの読み取りはエラーを返さない（Infallible）。ONでGNDにつながるとLowになる。
+    u8::from(pins.0.is_low().unwrap())
+        | (u8::from(pins.1.is_low().unwrap()) << 1)
+        | (u8::from(pins.2.is_low().unwrap()) << 2)
+        | (u8::from(pins.3.is_low().unwrap()) << 3)
+}
+
+// スイッチを読み、選んだ設定とピン（起動直後の状態に戻したもの）を返す。
+// 割り込みを使わずに待つ（クロックの設定が済んでいて、system_clock_hzで動いていること）。
+pub fn read(pins: DipPins, system_clock_hz: u32) -> (DipSetting, DipPins) {
+    let mut inputs: DipInputs = (
+        pull::into_input(pins.0, DIP_PULL),
+        pull::into_input(pins.1, DIP_PULL),
+        pull::into_input(pins.2, DIP_PULL),
+        pull::into_input(pins.3, DIP_PULL),
+    );
+    let cycles_per_us = system_clock_hz / 1_000_000;
+    cortex_m::asm::delay(cycles_per_us * DIP_SETTLE_US);
+
+    let mut value = read_bits(&mut inputs);
+    let mut same = 1;
+    let mut reads = 1;
+    while same < DIP_STABLE_READS && reads < DIP_MAX_READS {
+        cortex_m::asm::delay(cycles_per_us * DIP_READ_INTERVAL_US);
+        let next = read_bits(&mut inputs);
+        reads += 1;
+        if next == value {
+            same += 1;
+        } else {
+            value = next;
+            same = 1;
+        }
+    }
+
+    let setting = if same >= DIP_STABLE_READS {
+        DipSetting::from_bits(value)
+    } else {
+        defmt::warn!(
+            "dip switch: did not settle in {=u32} reads, using all off",
+            reads
+        );
+        DipSetting::OFF
+    };
+    defmt::info!(
+        "dip switch: {=u8:04b} mode {=str} mfg test {=bool} ignore flash {=bool}",
+        setting.bits,
+        setting.mode.map_or("unchanged", LedMode::name),
+        setting.mfg_test,
+        setting.ignore_flash
+    );
+
+    // プルアップを外し、起動直後の状態に戻す
+    let pins = (
+        inputs.0.reconfigure(),
+        inputs.1.reconfigure(),
+        inputs.2.reconfigure(),
+        inputs.3.reconfigure(),
+    );
+    (setting, pins)
+}
@@ -0,0 +1,54 @@
+// LedMode::Heartbeatの「トクン、トクン」という2回続けた点滅
+//
+// 心電図のモニターやLinuxのheartbeatトリガーのLEDのような、生きていることを示す点滅。
+//   点灯 HEARTBEAT_ON_MS → 消灯 HEARTBEAT_GAP_MS → 点灯 HEARTBEAT_ON_MS → 消灯 HEARTBEAT_PAUSE_MS
+// をくり返す。1周はHEARTBEAT_PERIOD_MS（1150 ms）で、点滅間隔（interval.rs）には関係しない。
+// ふつうの点滅と違って点灯が2回ずつ固まって見えるので、離れていても見分けやすい。
+//
+// 今どの段階にいるか（STEP）はモードがHeartbeatに切り替わったときにreset()で0に戻すので、
+// 別のモードから戻ってくると必ず1回目の点灯から始まる。
+
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::decimal_blink::Step;
+
+pub const HEARTBEAT_ON_MS: u32 = 100;
+pub const HEARTBEAT_GAP_MS: u32 = 150;
+pub const HEARTBEAT_PAUSE_MS: u32 = 800;
+pub const HEARTBEAT_PERIOD_MS: u32 = 2 * HEARTBEAT_ON_MS + HEARTBEAT_GAP_MS + HEARTBEAT_PAUSE_MS;
+assert_alarm_interval_ms!(HEARTBEAT_PERIOD_MS);
+
+const PATTERN: [Step; 4] = [
+    Step {
+        on: true,
+        duration_ms: HEARTBEAT_ON_MS,
+    },
+    Step {
+        on: false,
+        duration_ms: HEARTBEAT_GAP_MS,
+    },
+    Step {
+        on: true,
+        duration_ms: HEARTBEAT_ON_MS,
+    },
+    Step {
+        on: false,
+        duration_ms: HEARTBEAT_PAUSE_MS,
+    },
+];
+
+// 次に出すPATTERNの番号
+static STEP: Mutex<Cell<usize>> = Mutex::new(Cell::new(0));
+
+// モードがHeartbeatに切り替わったときにmode::set_mode()から呼ぶ（位相をそろえるときはphase_sync.rsからも）
+pub fn reset(cs: &CriticalSection) {
+    STEP.borrow(cs).set(0);
+}
+
+// TIMER_IRQ_0から呼ぶ。次に出す点灯/消灯とその長さを返し、段階を1つ進める。
+pub fn next_step(cs: &CriticalSection) -> Step {
+    let step = STEP.borrow(cs);
+    let index = step.get();
+    step.set((index + 1) % PATTERN.len());
+    PATTERN[index]
+}
@@ -0,0 +1,74 @@
+// 1-Wire接続の温度センサー DS18B20
+//
+// バスにはDS18B20が1つだけつながっている前提で、ROMコマンドはSkip ROM（0xCC）を使う。
+// 温度の読み出しは次の手順。
+//   1. リセット → Skip ROM → Convert T（0x44）で変換を始める
+//   2. 12bitの分解能では変換に最大750msかかる。その間はメインループを止めずに待つ
+//   3. リセット → Skip ROM → Read Scratchpad（0xBE）で9バイトを読む
+//   4. 9バイト目のCRCを確かめ、先頭2バイト（1/16°C単位の符号付き整数）を温度にする
+
+use crate::onewire::OneWire;
+use rp2040_project_template::crc::crc8_maxim;
+
+const CMD_SKIP_ROM: u8 = 0xCC;
+const CMD_CONVERT_T: u8 = 0x44;
+const CMD_READ_SCRATCHPAD: u8 = 0xBE;
+
+// 12bitの分解能での最大の変換時間
+pub const DS18B20_CONVERSION_MS: u32 = 750;
+// 温度を測る周期（変換時間を含む）
+pub const DS18B20_SAMPLE_INTERVAL_MS: u32 = 5000;
+assert_alarm_interval_ms!(DS18B20_SAMPLE_INTERVAL_MS);
+
+const _: () = assert!(DS18B20_CONVERSION_MS < DS18B20_SAMPLE_INTERVAL_MS);
+
+#[derive(defmt::Format)]
+pub enum Ds18b20Error {
+    // リセットにプレゼンスパルスが返ってこない（つながっていない）
+    NoDevice,
+    // スクラッチパッドのCRCが合わない（線がLowに張り付いていて全部0のときも含む）
+    Crc,
+}
+
+pub struct Ds18b20 {
+    bus: OneWire,
+}
+
+impl Ds18b20 {
+    pub fn new(bus: OneWire) -> Self {
+        Self { bus }
+    }
+
+    // 温度の変換を始める。結果はDS18B20_CONVERSION_MS後にread_millicelsius()で読む。
+    pub fn start_conversion(&mut self) -> Result<(), Ds18b20Error> {
+        self.select()?;
+        self.bus.write_byte(CMD_CONVERT_T);
+        Ok(())
+    }
+
+    // 変換した温度（m°C）を読む
+    pub fn read_millicelsius(&mut self) -> Result<i32, Ds18b20Error> {
+        self.select()?;
+        self.bus.write_byte(CMD_READ_SCRATCHPAD);
+        let mut scratchpad = [0u8; 9];
+        for byte in scratchpad.iter_mut() {
+            *byte = self.bus.read_byte();
+        }
+        // 線が切れていると全部0xFFになり、CRCで弾かれる。
+        // 線がLowに張り付いていると全部0になり、CRCも0になってしまうので別に弾く。
+        if crc8_maxim(&scratchpad) != 0 || scratchpad.iter().all(|&b| b == 0) {
+            return Err(Ds18b20Error::Crc);
+        }
+        let raw = i16::from_le_bytes([scratchpad[0], scratchpad[1]]);
+        // 1/16°C単位をm°Cにする
+        Ok(i32::from(raw) * 1000 / 16)
+    }
+
+    fn select(&mut self) -> Result<(), Ds18b20Error> {
+        if !self.bus.reset() {
+            return Err(Ds18b20Error::NoDevice);
+        }
+        self.bus.write_byte(CMD_SKIP_ROM);
+        Ok(())
+    }
+}
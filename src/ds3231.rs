@@ -0,0 +1,103 @@
+// I2C接続の外付けRTC DS3231
+//
+// RP2040内蔵のRTCは電源を切ると時刻が消え、精度も水晶次第でずれていく。
+// DS3231は温度補償付きの発振器とバックアップ電池を持っているので、
+// 電源を入れ直しても正しい日時が読める。
+//
+// 時刻のレジスタ（BCD）と日時の変換はライブラリのbcd_datetime.rsにあり、ホストでテストしている。
+//
+// 0x0Fのステータスレジスタのbit7（OSF）は発振器が一度止まったことを示す。
+// バックアップ電池が切れて電源を落とした場合などに立つので、立っていたら時刻は信用できない。
+
+use crate::i2c_bus;
+use defmt::Format;
+use embedded_hal::i2c::I2c;
+pub use rp2040_project_template::bcd_datetime::DateTime;
+use rp2040_project_template::bcd_datetime::{decode, encode};
+use rp_pico::hal::i2c;
+
+const DS3231_ADDR: u8 = 0x68;
+const REG_SECONDS: u8 = 0x00;
+const REG_STATUS: u8 = 0x0F;
+const STATUS_OSF: u8 = 1 << 7;
+
+#[derive(Format)]
+pub enum RtcError {
+    // I2Cバスが初期化されていないか、他で使われている
+    BusUnavailable,
+    // デバイスが応答しないなどI2Cの転送に失敗した
+    Bus,
+    // 発振器が止まったことがあるので時刻は正しくない。set_datetime()で設定し直すこと。
+    OscillatorStopped,
+    // BCDとして不正な値や範囲外の日時
+    InvalidData,
+}
+
+impl RtcError {
+    // UARTなどに出力するときの説明
+    pub fn name(&self) -> &'static str {
+        match self {
+            RtcError::BusUnavailable => "bus unavailable",
+            RtcError::Bus => "no response",
+            RtcError::OscillatorStopped => "oscillator stopped, time not set",
+            RtcError::InvalidData => "invalid data",
+        }
+    }
+}
+
+impl From<i2c::Error> for RtcError {
+    fn from(_: i2c::Error) -> Self {
+        RtcError::Bus
+    }
+}
+
+// 転送の結果をi2c_busに報告する。バスを借りられなかったときは転送していないので数えない。
+fn record<T>(result: Result<T, RtcError>) -> Result<T, RtcError> {
+    match result {
+        Err(RtcError::BusUnavailable) => {}
+        Err(RtcError::Bus) => i2c_bus::record_transfer(false),
+        _ => i2c_bus::record_transfer(true),
+    }
+    result
+}
+
+pub fn read_datetime() -> Result<DateTime, RtcError> {
+    record(
+        i2c_bus::with_bus(|bus| {
+            let mut status = [0u8];
+            bus.write_read(DS3231_ADDR, &[REG_STATUS], &mut status)?;
+            if status[0] & STATUS_OSF != 0 {
+                return Err(RtcError::OscillatorStopped);
+            }
+
+            let mut regs = [0u8; 7];
+            bus.write_read(DS3231_ADDR, &[REG_SECONDS], &mut regs)?;
+            decode(&regs).ok_or(RtcError::InvalidData)
+        })
+        .unwrap_or(Err(RtcError::BusUnavailable)),
+    )
+}
+
+// 日時を設定し、OSFフラグを下ろして時刻が有効になったことを示す
+pub fn set_datetime(dt: &DateTime) -> Result<(), RtcError> {
+    if !dt.is_valid() {
+        return Err(RtcError::InvalidData);
+    }
+
+    record(
+        i2c_bus::with_bus(|bus| {
+            // 先頭に書き込み開始アドレスを付けて、7バイトを連続で書き込む
+            let regs = encode(dt);
+            let mut frame = [0u8; 8];
+            frame[0] = REG_SECONDS;
+            frame[1..].copy_from_slice(&regs);
+            bus.write(DS3231_ADDR, &frame)?;
+
+            let mut status = [0u8];
+            bus.write_read(DS3231_ADDR, &[REG_STATUS], &mut status)?;
+            bus.write(DS3231_ADDR, &[REG_STATUS, status[0] & !STATUS_OSF])?;
+            Ok(())
+        })
+        .unwrap_or(Err(RtcError::BusUnavailable)),
+    )
+}
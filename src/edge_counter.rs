@@ -0,0 +1,158 @@
+// GPIO11に入ってくる速いパルスの立ち上がりを数え、周波数を求める
+//
+// 割り込み（IO_IRQ_BANK0）ではエッジを数えるだけにして、数えた回数はsampler（SAMPLE_PERIOD_MSごと）で
+// EDGE_GATE_MSごとにまとめて読み、そのあいだの実際の経過時間で割って周波数にする（rate_hz()）。
+// ログに出したり割り算をしたりするのはこのまとめて読むときだけなので、1回のエッジの処理は短い。
+//
+// 分周（set_edge_divider()）
+//   さらにdivider回に1回だけ、タイマーを読んで前回からの時間（divider回分の周期、period_us()）を測り、
+//   まとめて読む回数を1つ増やす。それ以外のエッジでは、残りの回数を1つ減らして戻るだけにする。
+//   ・dividerを大きくすると割り込みの中の仕事が減るが、回数はdivider回単位でしか数えられない。
+//     EDGE_GATE_MSの間の回数の分解能はdivider回なので、周波数の分解能は divider ÷ EDGE_GATE_MS になる。
+//     （EDGE_GATE_MS = 1000なら、divider = 1で1 Hz、divider = 100で100 Hz刻み）
+//   ・周期はdivider回分を1回で測るので、1回ごとの割り込みの遅れのばらつきが平均されて細かくなるが、
+//     周波数の変化に気づくのはdivider回分遅れる。
+//   ・分周しても割り込みに入る回数そのものは減らない。エッジごとに入口と出口の処理と、
+//     同じIO_IRQ_BANK0を使うほかのモジュール（パルス幅、マンチェスター、ゼロクロス、トグルボタン）の
+//     要因の確認は必ず行う。減るのは、そのうえに乗っているこのモジュールの仕事だけ。
+//
+// 数えられる速さの見積もり（125 MHz、目安）
+//   割り込みの入口と出口で約30サイクル、IO_IRQ_BANK0でほかのモジュールの要因を確かめるのに約150サイクル、
+//   このモジュールで数えるのに約30サイクル（分周しない回はタイマーを読む分約30サイクル増える）。
+//   1回のエッジに約200〜250サイクル、つまり2 µsほどかかるので、
+//     ・100 kHzでCPUの約20%を使う
+//     ・400 kHzあたりでほぼすべての時間を割り込みに取られ、メインループが進まなくなる
+//   割り込みの処理が終わる前に次のエッジが来ると、GPIOのエッジの要因は1つしか覚えていないので、
+//   2つのエッジが1回に数えられてしまい、回数は実際より少なくなる。
+//   目安を超える速さでは、外付けの分周器（カウンタIC）で落としてから入れること。
+//   実際にかかるサイクル数はほかの割り込みの状況で変わるので、必要ならオシロで確かめること。
+
+use crate::cs_trace::free;
+use crate::pull::{self, Pull};
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::time;
+use rp_pico::hal::gpio;
+use rp_pico::hal::timer::Timer;
+
+// プッシュプルの出力をつなぐ前提。つないでいないときはLowに落ち着かせる。
+pub const EDGE_PULL: Pull = Pull::Down;
+// 何msごとに回数をまとめて周波数にするか
+pub const EDGE_GATE_MS: u32 = 1000;
+pub const EDGE_DIVIDER: u32 = 1;
+
+pub type EdgePin = pull::InputPin<gpio::bank0::Gpio11>;
+
+static EDGE_PIN: GlobalPeripheral<EdgePin> = initial_global_peripheral();
+static EDGE_TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+static DIVIDER: Mutex<Cell<u32>> = Mutex::new(Cell::new(EDGE_DIVIDER));
+// 次にタイマーを読むまでの残りのエッジの数
+static SKIP: Mutex<Cell<u32>> = Mutex::new(Cell::new(EDGE_DIVIDER));
+// 前回まとめて読んでから数えた回数（divider回で1）
+static UNITS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+// 前回divider回目のエッジが来た時刻（タイマーの下位32bit）
+static LAST_UNIT_AT: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+// divider回分の周期（µs）
+static PERIOD_US: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+// 前回まとめて読んだ時刻（µs）と、そのとき求めた周波数
+static GATE_STARTED_AT: Mutex<Cell<Option<u64>>> = Mutex::new(Cell::new(None));
+static RATE_HZ: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+
+pub fn init(cs: &CriticalSection, pin: EdgePin, timer: Timer) {
+    pin.set_interrupt_enabled(gpio::Interrupt::EdgeHigh, true);
+    EDGE_PIN.borrow(cs).replace(Some(pin));
+    EDGE_TIMER.borrow(cs).set(Some(timer));
+    GATE_STARTED_AT
+        .borrow(cs)
+        .set(Some(timer.get_counter().ticks()));
+}
+
+pub fn divider(cs: &CriticalSection) -> u32 {
+    DIVIDER.borrow(cs).get()
+}
+
+// 前回までのEDGE_GATE_MSの間の周波数（Hz）。まだ求めていなければNone。
+pub fn rate_hz(cs: &CriticalSection) -> Option<u32> {
+    RATE_HZ.borrow(cs).get()
+}
+
+// divider回分の周期（µs）。divider回のエッジがまだ2回来ていなければNone。
+pub fn period_us(cs: &CriticalSection) -> Option<u32> {
+    PERIOD_US.borrow(cs).get()
+}
+
+// 何回のエッジで1回タイマーを読むかを変える。0は1にする（分周しない）。
+// 数えかけの回数と周期は捨てて、次のEDGE_GATE_MSから新しい分周で数え直す。
+pub fn set_edge_divider(n: u32) {
+    let n = n.max(1);
+    free(|cs| {
+        DIVIDER.borrow(cs).set(n);
+        SKIP.borrow(cs).set(n);
+        UNITS.borrow(cs).set(0);
+        LAST_UNIT_AT.borrow(cs).set(None);
+        PERIOD_US.borrow(cs).set(None);
+        RATE_HZ.borrow(cs).set(None);
+        if let Some(timer) = EDGE_TIMER.borrow(cs).get() {
+            GATE_STARTED_AT
+                .borrow(cs)
+                .set(Some(timer.get_counter().ticks()));
+        }
+    });
+}
+
+// IO_IRQ_BANK0から呼ぶ。ほかのモジュールより先に呼んで、エッジごとの遅れを短くする。
+pub fn on_interrupt(cs: &CriticalSection) {
+    let mut pin = EDGE_PIN.borrow(cs).borrow_mut();
+    let Some(pin) = pin.as_mut() else {
+        return;
+    };
+    if !pin.interrupt_status(gpio::Interrupt::EdgeHigh) {
+        return;
+    }
+    pin.clear_interrupt(gpio::Interrupt::EdgeHigh);
+
+    let skip = SKIP.borrow(cs);
+    let remaining = skip.get() - 1;
+    if remaining > 0 {
+        skip.set(remaining);
+        return;
+    }
+    skip.set(divider(cs));
+
+    let units = UNITS.borrow(cs);
+    units.set(units.get().wrapping_add(1));
+    let Some(timer) = EDGE_TIMER.borrow(cs).get() else {
+        return;
+    };
+    let now = timer.get_counter_low();
+    if let Some(last) = LAST_UNIT_AT.borrow(cs).replace(Some(now)) {
+        PERIOD_US.borrow(cs).set(Some(now.wrapping_sub(last)));
+    }
+}
+
+// samplerから呼ぶ。EDGE_GATE_MSが経っていれば、数えた回数を周波数にする。
+pub fn latch(cs: &CriticalSection) {
+    let Some(timer) = EDGE_TIMER.borrow(cs).get() else {
+        return;
+    };
+    let Some(started_at) = GATE_STARTED_AT.borrow(cs).get() else {
+        return;
+    };
+    let now = timer.get_counter().ticks();
+    let elapsed_us = time::elapsed_us(now, started_at);
+    if elapsed_us < u64::from(EDGE_GATE_MS) * 1000 {
+        return;
+    }
+    let edges = u64::from(UNITS.borrow(cs).replace(0)) * u64::from(divider(cs));
+    let rate_hz = (edges * 1_000_000 / elapsed_us) as u32;
+    GATE_STARTED_AT.borrow(cs).set(Some(now));
+    if rate_hz == 0 {
+        // エッジが来なくなったら、古い周期を出し続けないように消す
+        LAST_UNIT_AT.borrow(cs).set(None);
+        PERIOD_US.borrow(cs).set(None);
+    }
+    if RATE_HZ.borrow(cs).replace(Some(rate_hz)) != Some(rate_hz) {
+        defmt::debug!("edge counter: {} Hz", rate_hz);
+    }
+}
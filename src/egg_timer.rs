@@ -0,0 +1,264 @@
+// LedMode::EggTimerの「エッグタイマー」
+//
+// egg_timer(seconds)で、seconds秒を数えてから終わったことを知らせる。
+//   1. 1秒に1回、短く点灯する（点灯 EGG_TICK_ON_MS → 消灯 残り）
+//   2. 残りがfinal_seconds（起動時はEGG_FINAL_SECONDS）秒になったら、EGG_FAST_STEP_MSごとに点灯/消灯を切り替え続ける
+//   3. 数え終わったら、done（起動時はEGG_DONE）の表示をする
+//        Solid  : 明るく点灯したまま
+//        Triple : 長く3回点滅して（点灯 EGG_DONE_FLASH_ON_MS → 消灯 EGG_DONE_FLASH_OFF_MS）、消灯したまま
+//   終わった後もモードはEggTimerのまま。別のモードに切り替えるか、egg_timer()で数え直す。
+// seconds = 0なら、数えずにすぐ3.の表示をする。final_secondsがseconds以上なら、最初から2.の速い点滅になる。
+//
+// 時間はALARM0の点灯/消灯の長さを足していって数える（ほかのモードと同じく、割り込みの遅れの分だけ少し長くなる）。
+// どのフェーズも1秒の点灯/消灯の長さの合計がちょうど1000 msになるので、1秒ごとに残りを1つ減らせばよい。
+// 温度が高くて遅くしている間（thermal.rs）は、その分だけ遅れる。
+//
+// モードがEggTimerに切り替わったとき（UARTの mode egg などでも）は、最後に頼んだ秒数から数え直す（reset()）。
+// ブザーはほかの点滅するモードと同じく、点灯している間だけ鳴る。
+
+use crate::cs_trace::free;
+use crate::mode::{self, LedMode};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::decimal_blink::Step;
+
+pub const EGG_TICK_ON_MS: u32 = 100;
+pub const EGG_FAST_STEP_MS: u32 = 100;
+pub const EGG_FINAL_SECONDS: u32 = 3;
+pub const EGG_DONE: EggDone = EggDone::Solid;
+pub const EGG_DONE_FLASH_ON_MS: u32 = 600;
+pub const EGG_DONE_FLASH_OFF_MS: u32 = 400;
+// 最初にモードをEggTimerにしたときの秒数
+pub const EGG_DEFAULT_SECONDS: u32 = 60;
+
+const SECOND_MS: u32 = 1000;
+const FAST_STEPS: u32 = SECOND_MS / EGG_FAST_STEP_MS;
+const DONE_FLASHES: u32 = 3;
+
+// 1秒の点灯/消灯がちょうど1000 msになり、速い点滅が1秒の中で点灯と消灯の組で終わるように
+const _: () = assert!(EGG_TICK_ON_MS > 0 && EGG_TICK_ON_MS < SECOND_MS);
+const _: () = assert!(
+    SECOND_MS.is_multiple_of(EGG_FAST_STEP_MS) && FAST_STEPS.is_multiple_of(2) && FAST_STEPS >= 2
+);
+assert_alarm_interval_ms!(SECOND_MS);
+
+// 数え終わったときの表示
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EggDone {
+    Solid,
+    Triple,
+}
+
+impl EggDone {
+    // UARTなどに出力するときの名前
+    pub fn name(self) -> &'static str {
+        match self {
+            EggDone::Solid => "solid",
+            EggDone::Triple => "triple",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "solid" => Some(EggDone::Solid),
+            "triple" => Some(EggDone::Triple),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    // 残りの秒数と、その1秒の中で次に出すステップ。fastはその1秒が速い点滅か（1秒の始まりで決める）。
+    Counting {
+        remaining_s: u32,
+        index: u32,
+        fast: bool,
+    },
+    // 数え終わった後に出したステップの数
+    Done(u32),
+}
+
+// 今の残りとフェーズ（UARTの egg の応答）
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    Ticking(u32),
+    Final(u32),
+    Done,
+}
+
+static SECONDS: Mutex<Cell<u32>> = Mutex::new(Cell::new(EGG_DEFAULT_SECONDS));
+static FINAL_SECONDS: Mutex<Cell<u32>> = Mutex::new(Cell::new(EGG_FINAL_SECONDS));
+static DONE: Mutex<Cell<EggDone>> = Mutex::new(Cell::new(EGG_DONE));
+static STATE: Mutex<Cell<State>> = Mutex::new(Cell::new(State::Counting {
+    remaining_s: EGG_DEFAULT_SECONDS,
+    index: 0,
+    fast: false,
+}));
+
+// secondsを数え始める。数えている途中や終わった後でも、seconds秒から数え直す。
+pub fn egg_timer(seconds: u32) {
+    free(|cs| {
+        SECONDS.borrow(cs).set(seconds);
+        if mode::mode(cs) == LedMode::EggTimer {
+            reset(cs);
+        } else {
+            // set_mode()がreset()を呼ぶ
+            mode::set_mode(cs, LedMode::EggTimer);
+        }
+        // 前のモードのステップの途中からではなく、ここから1秒目を始める
+        crate::restart_blink(cs);
+    });
+}
+
+// 最後の速い点滅にする秒数を変える。次の1秒から使う。
+pub fn set_final_seconds(seconds: u32) {
+    free(|cs| FINAL_SECONDS.borrow(cs).set(seconds));
+}
+
+pub fn final_seconds(cs: &CriticalSection) -> u32 {
+    FINAL_SECONDS.borrow(cs).get()
+}
+
+// 数え終わったときの表示を変える。終わった後に変えたら、その表示を最初からする。
+pub fn set_done(done: EggDone) {
+    free(|cs| {
+        DONE.borrow(cs).set(done);
+        let state = STATE.borrow(cs);
+        if matches!(state.get(), State::Done(_)) {
+            state.set(State::Done(0));
+        }
+    });
+}
+
+pub fn done(cs: &CriticalSection) -> EggDone {
+    DONE.borrow(cs).get()
+}
+
+// 今の1秒を速い点滅にするか。1秒の始まり（index == 0）で決め、その1秒の間は変えない。
+fn is_fast(cs: &CriticalSection, remaining_s: u32, index: u32, fast: bool) -> bool {
+    if index == 0 {
+        remaining_s <= final_seconds(cs)
+    } else {
+        fast
+    }
+}
+
+pub fn progress(cs: &CriticalSection) -> Progress {
+    match STATE.borrow(cs).get() {
+        State::Counting { remaining_s: 0, .. } | State::Done(_) => Progress::Done,
+        State::Counting {
+            remaining_s,
+            index,
+            fast,
+        } => {
+            if is_fast(cs, remaining_s, index, fast) {
+                Progress::Final(remaining_s)
+            } else {
+                Progress::Ticking(remaining_s)
+            }
+        }
+    }
+}
+
+// モードがEggTimerに切り替わったときにmode::set_mode()から呼ぶ
+pub fn reset(cs: &CriticalSection) {
+    STATE.borrow(cs).set(State::Counting {
+        remaining_s: SECONDS.borrow(cs).get(),
+        index: 0,
+        fast: false,
+    });
+}
+
+// 今のフェーズの1周の点灯時間と1周の時間（ms）。消費電流の見積もり（power.rs）に使う。
+pub fn cycle_ms(cs: &CriticalSection) -> (u32, u32) {
+    match progress(cs) {
+        Progress::Ticking(_) => (EGG_TICK_ON_MS, SECOND_MS),
+        Progress::Final(_) => (SECOND_MS / 2, SECOND_MS),
+        Progress::Done => match done(cs) {
+            EggDone::Solid => (SECOND_MS, SECOND_MS),
+            // 3回点滅した後は消灯したまま
+            EggDone::Triple => (0, SECOND_MS),
+        },
+    }
+}
+
+fn done_step(cs: &CriticalSection, index: u32) -> Step {
+    match done(cs) {
+        EggDone::Solid => Step {
+            on: true,
+            duration_ms: SECOND_MS,
+        },
+        EggDone::Triple if index < 2 * DONE_FLASHES => {
+            let on = index.is_multiple_of(2);
+            Step {
+                on,
+                duration_ms: if on {
+                    EGG_DONE_FLASH_ON_MS
+                } else {
+                    EGG_DONE_FLASH_OFF_MS
+                },
+            }
+        }
+        EggDone::Triple => Step {
+            on: false,
+            duration_ms: SECOND_MS,
+        },
+    }
+}
+
+// TIMER_IRQ_0から呼ぶ。次に出す点灯/消灯とその長さを返す。
+pub fn next_step(cs: &CriticalSection) -> Step {
+    let state = STATE.borrow(cs);
+    let (remaining_s, index, fast) = match state.get() {
+        State::Counting { remaining_s: 0, .. } => {
+            defmt::info!("egg timer: done");
+            state.set(State::Done(1));
+            return done_step(cs, 0);
+        }
+        State::Counting {
+            remaining_s,
+            index,
+            fast,
+        } => (remaining_s, index, fast),
+        State::Done(index) => {
+            state.set(State::Done(index.saturating_add(1)));
+            return done_step(cs, index);
+        }
+    };
+    let fast = is_fast(cs, remaining_s, index, fast);
+    let (step, steps) = if fast {
+        let step = Step {
+            on: index.is_multiple_of(2),
+            duration_ms: EGG_FAST_STEP_MS,
+        };
+        (step, FAST_STEPS)
+    } else {
+        let step = match index {
+            0 => Step {
+                on: true,
+                duration_ms: EGG_TICK_ON_MS,
+            },
+            _ => Step {
+                on: false,
+                duration_ms: SECOND_MS - EGG_TICK_ON_MS,
+            },
+        };
+        (step, 2)
+    };
+    // 1秒分のステップを出し終えたら、残りを1つ減らす
+    if index + 1 < steps {
+        state.set(State::Counting {
+            remaining_s,
+            index: index + 1,
+            fast,
+        });
+    } else {
+        state.set(State::Counting {
+            remaining_s: remaining_s - 1,
+            index: 0,
+            fast,
+        });
+    }
+    step
+}
@@ -0,0 +1,109 @@
+// ロータリーエンコーダー（GPIO27: A相、GPIO28: B相）で点滅間隔を変える
+//
+// 配線
+//   エンコーダーのAとBをGPIO27とGPIO28に、共通端子（C）をGNDにつなぐ。内部プルアップ。
+//   接点が閉じている間がLowになる。押しボタン付きのものでも、押しボタンは使わない。
+//
+// 読み方
+//   AとBの両方のエッジで割り込み（IO_IRQ_BANK0）を入れ、そのたびに両方の相を読み直して
+//   状態遷移表（rp2040_project_template::quadrature）でステップ数に直す。
+//   チャタリングは片方の相の行ったり来たりなので、表の+1と-1で打ち消し合う。時間で無視する区間は設けない
+//   （速く回したときのエッジまで捨ててしまうため）。
+//   分解能は起動時はENCODER_RESOLUTIONで、UARTの encoder quarter|half|full で変えられる。
+//
+// 点滅間隔への反映
+//   割り込みではステップ数をためるだけにして、メインループがtake_steps()で取り出して間隔を変える。
+//   時計回り1ステップで間隔をENCODER_STEP_MSだけ短く（速く）、反時計回りで長く（遅く）する。
+//   間隔はENCODER_MIN_INTERVAL_MS〜ENCODER_MAX_INTERVAL_MSに収める。
+
+use crate::cs_trace::free;
+use crate::idle_off;
+use crate::pull::{self, Pull};
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::{Cell, RefCell};
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use embedded_hal::digital::InputPin;
+use rp2040_project_template::quadrature::{QuadratureDecoder, Resolution};
+use rp_pico::hal::gpio;
+
+pub const ENCODER_PULL: Pull = Pull::Up;
+pub const ENCODER_RESOLUTION: Resolution = Resolution::Full;
+pub const ENCODER_STEP_MS: u32 = 10;
+pub const ENCODER_MIN_INTERVAL_MS: u32 = 20;
+pub const ENCODER_MAX_INTERVAL_MS: u32 = 5000;
+
+pub type EncoderPinA = pull::InputPin<gpio::bank0::Gpio27>;
+pub type EncoderPinB = pull::InputPin<gpio::bank0::Gpio28>;
+
+static ENCODER_PINS: GlobalPeripheral<(EncoderPinA, EncoderPinB)> = initial_global_peripheral();
+static DECODER: Mutex<RefCell<QuadratureDecoder>> = Mutex::new(RefCell::new(
+    QuadratureDecoder::new(ENCODER_RESOLUTION, true, true),
+));
+// メインループがまだ取り出していないステップ数（時計回りが正）
+static STEPS: Mutex<Cell<i32>> = Mutex::new(Cell::new(0));
+
+pub fn init(cs: &CriticalSection, mut a: EncoderPinA, mut b: EncoderPinB) {
+    // 今の位置から数え始める
+    let (level_a, level_b) = (a.is_high().unwrap(), b.is_high().unwrap());
+    DECODER
+        .borrow(cs)
+        .replace(QuadratureDecoder::new(ENCODER_RESOLUTION, level_a, level_b));
+    for interrupt in [gpio::Interrupt::EdgeHigh, gpio::Interrupt::EdgeLow] {
+        a.set_interrupt_enabled(interrupt, true);
+        b.set_interrupt_enabled(interrupt, true);
+    }
+    ENCODER_PINS.borrow(cs).replace(Some((a, b)));
+}
+
+pub fn resolution(cs: &CriticalSection) -> Resolution {
+    DECODER.borrow(cs).borrow().resolution()
+}
+
+pub fn set_resolution(resolution: Resolution) {
+    free(|cs| DECODER.borrow(cs).borrow_mut().set_resolution(resolution));
+}
+
+// IO_IRQ_BANK0から呼ぶ
+pub fn on_interrupt(cs: &CriticalSection) {
+    let mut pins = ENCODER_PINS.borrow(cs).borrow_mut();
+    let Some((a, b)) = pins.as_mut() else {
+        return;
+    };
+    let mut any = false;
+    for interrupt in [gpio::Interrupt::EdgeHigh, gpio::Interrupt::EdgeLow] {
+        if a.interrupt_status(interrupt) {
+            a.clear_interrupt(interrupt);
+            any = true;
+        }
+        if b.interrupt_status(interrupt) {
+            b.clear_interrupt(interrupt);
+            any = true;
+        }
+    }
+    if !any {
+        return;
+    }
+    let steps = DECODER
+        .borrow(cs)
+        .borrow_mut()
+        .update(a.is_high().unwrap(), b.is_high().unwrap());
+    if steps != 0 {
+        let total = STEPS.borrow(cs);
+        total.set(total.get().saturating_add(i32::from(steps)));
+        idle_off::note_activity(cs);
+    }
+}
+
+// 前に呼んでからのステップ数を返し、0に戻す
+pub fn take_steps() -> i32 {
+    free(|cs| STEPS.borrow(cs).replace(0))
+}
+
+// 今の間隔をstepsだけ動かした間隔（時計回りで短くなる）
+pub fn adjusted_interval_ms(interval_ms: u32, steps: i32) -> u32 {
+    let delta = i64::from(steps) * i64::from(ENCODER_STEP_MS);
+    (i64::from(interval_ms) - delta).clamp(
+        i64::from(ENCODER_MIN_INTERVAL_MS),
+        i64::from(ENCODER_MAX_INTERVAL_MS),
+    ) as u32
+}
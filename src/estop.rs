@@ -0,0 +1,215 @@
+// 非常停止の入力（GPIO21）。入ったらいちばん優先度の高い割り込みでLEDとブザーをすぐに止める
+//
+// 配線
+//   GPIO21とGNDの間に、普段は閉じている（NC接点の）非常停止スイッチをつなぐ。内部プルアップ。
+//   普段はLow、スイッチを押すか配線が切れるとHighになり、どちらでも停止する（断線しても安全側に倒れる）。
+//   電源を入れたときにすでにHighなら、起動した直後に停止する。
+//
+// 割り込みの優先度
+//   GPIOの割り込みはどのピンもIO_IRQ_BANK0の1本にまとまっていて、トグルボタンなどと共用なので、
+//   これだけを高い優先度にはできない。そこでPIO0のステートマシン0でピンを見張り、
+//   Highになったら`irq 0`を立ててPIO0_IRQ_0という専用の割り込みに入る。
+//   Cortex-M0+の優先度は上位2bitだけが有効で、0x00が最も高い。
+//     PIO0_IRQ_0、SysTick（watchdogフィーチャー）  : ESTOP_PRIORITY（0x00、SysTickはlast_gasp.rs）
+//     TIMER_IRQ_0〜3、IO_IRQ_BANK0、PIO1_IRQ_0、SysTick : NORMAL_PRIORITY（0x40）
+//     SW1_IRQ、SW0_IRQ（多重割り込みのデモ）       : 0x80、0xC0（nested_irq.rs）
+//   ほかの割り込みは今までどおり同じ優先度どうしなので、互いには割り込まない。
+//   PIO0_IRQ_0だけがそれらの処理の途中に割り込む。
+//
+// 注意：ほかの割り込みはCriticalSection::new()で「多重に入らない」ことを前提にグローバル変数を
+// 借りているので、on_estop()はそれらのグローバル変数（led.rsのPWMなど）に触ってはいけない。
+// そのため、出力はIO_BANK0のGPIOx_CTRLのオーバーライドで直接Lowにし、ラッチはAtomicBoolにしている。
+//   OUTOVER = 2（Lowにする）、OEOVER = 3（出力にする）
+//   アクティブLowの出力（output.rsのOUTPUT_POLARITY）は、オフの電圧のOUTOVER = 3（Highにする）にする。
+//   極性の表は定数なので、on_estop()から読んでもほかの割り込みとぶつからない。
+//   PWMや機能の設定には触らないので、PWMは裏で動き続けるがピンには出ない。
+//   書き込みにはアトミックなセット/クリアのエイリアスを使うので、割り込まれた側の読み書きと混ざらない。
+// 止めるピンは、LEDを出せるGPIO（led::LED_GPIOS）、ブザー（pin_table::TONE）、PIO1のパルス（pin_table::PULSE_TRAIN）。
+//
+// ラッチと解除
+//   一度止まると、入力がLowに戻っても止まったまま。TIMER_IRQ_0は止まっている間、LEDを更新せずに
+//   ALARM0の割り込みを止める。解除する手順は次のとおり。
+//     1. 非常停止スイッチを戻し、GPIO21をLowにする
+//     2. UARTで estop reset を送る（入力がHighのままなら断る）
+//   解除するとオーバーライドを外し、点滅を最初からやり直す。
+
+use crate::cs_trace::free;
+use crate::output::{self, OutputId};
+use crate::output_enable;
+use crate::pin_table;
+use crate::pull::{self, Pull};
+use crate::{initial_global_peripheral, led, GlobalPeripheral};
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::interrupt::CriticalSection;
+use cortex_m::peripheral::scb::SystemHandler;
+use cortex_m::peripheral::{NVIC, SCB};
+use embedded_hal::digital::InputPin;
+use rp_pico::hal::gpio;
+use rp_pico::hal::pac;
+use rp_pico::hal::pio::{PIOBuilder, PIOExt, Running, StateMachine, PIO, SM0};
+
+pub const ESTOP_PULL: Pull = Pull::Up;
+pub const ESTOP_PRIORITY: u8 = 0x00;
+pub const NORMAL_PRIORITY: u8 = 0x40;
+
+pub type EstopPin = pull::InputPin<gpio::bank0::Gpio21>;
+
+// 止めるピンと、オフの電圧を決める出力
+const FORCED_GPIOS: [(u8, OutputId); 5] = [
+    (led::LED_GPIOS[0], OutputId::Led),
+    (led::LED_GPIOS[1], OutputId::Led),
+    (led::LED_GPIOS[2], OutputId::Led),
+    (pin_table::TONE, OutputId::Tone),
+    (pin_table::PULSE_TRAIN, OutputId::PulseTrain),
+];
+// GPIOx_CTRLのOUTOVER（bit 9:8）とOEOVER（bit 13:12）
+const OUTOVER_MASK: u32 = 0x3 << 8;
+const OUTOVER_LOW: u32 = 0x2 << 8;
+const OUTOVER_HIGH: u32 = 0x3 << 8;
+const OEOVER_ENABLE: u32 = 0x3 << 12;
+// RP2040のレジスタはアドレスに足すとアトミックなセット/クリアになる
+const ALIAS_SET: usize = 0x2000;
+const ALIAS_CLEAR: usize = 0x3000;
+
+type EstopPio = (
+    PIO<pac::PIO0>,
+    StateMachine<(pac::PIO0, SM0), Running>,
+    EstopPin,
+);
+
+static ESTOP: GlobalPeripheral<EstopPio> = initial_global_peripheral();
+// 非常停止で止まっているか。優先度の高いon_estop()から書くのでMutexではなくアトミックにしている。
+static LATCHED: AtomicBool = AtomicBool::new(false);
+
+// 割り込みの優先度を設定する。NVICのunmaskより前に呼ぶ。
+pub fn set_priorities(nvic: &mut NVIC, scb: &mut SCB) {
+    // 優先度を変えると、同じ優先度で多重に入らないという前提が変わりうるのでunsafeになっている。
+    // ここでは上の表のとおり、PIO0_IRQ_0以外はすべて同じ優先度にそろえている。
+    unsafe {
+        for irq in [
+            pac::Interrupt::TIMER_IRQ_0,
+            pac::Interrupt::TIMER_IRQ_1,
+            pac::Interrupt::TIMER_IRQ_2,
+            pac::Interrupt::TIMER_IRQ_3,
+            pac::Interrupt::IO_IRQ_BANK0,
+            pac::Interrupt::PIO1_IRQ_0,
+        ] {
+            nvic.set_priority(irq, NORMAL_PRIORITY);
+        }
+        nvic.set_priority(pac::Interrupt::PIO0_IRQ_0, ESTOP_PRIORITY);
+        scb.set_priority(SystemHandler::SysTick, NORMAL_PRIORITY);
+    }
+}
+
+// PIO0のステートマシン0で、GPIO21がHighになるたびにirq 0を立てる
+pub fn init(cs: &CriticalSection, pio0: pac::PIO0, resets: &mut pac::RESETS, pin: EstopPin) {
+    let mut assembler = pio::Assembler::<{ pio::RP2040_MAX_PROGRAM_SIZE }>::new();
+    let mut wrap_target = assembler.label();
+    let mut wrap_source = assembler.label();
+    assembler.bind(&mut wrap_target);
+    // Highになるまで待ち、irq 0を立て、Lowに戻るまで待ってから次のHighを待つ
+    assembler.wait(1, pio::WaitSource::PIN, 0, false);
+    assembler.irq(false, false, 0, false);
+    assembler.wait(0, pio::WaitSource::PIN, 0, false);
+    assembler.bind(&mut wrap_source);
+    let program = assembler.assemble_with_wrap(wrap_source, wrap_target);
+
+    let (mut pio, sm0, _, _, _) = pio0.split(resets);
+    let installed = pio.install(&program).unwrap();
+    let (sm, _, _) = PIOBuilder::from_installed_program(installed)
+        .in_pin_base(pin_table::ESTOP)
+        .build(sm0);
+    pio.irq0().enable_sm_interrupt(0);
+    let sm = sm.start();
+    ESTOP.borrow(cs).replace(Some((pio, sm, pin)));
+}
+
+pub fn is_latched() -> bool {
+    LATCHED.load(Ordering::Relaxed)
+}
+
+fn write_ctrl(gpio: u8, alias: usize, bits: u32) {
+    let io = unsafe { &*pac::IO_BANK0::ptr() };
+    let ctrl = io.gpio(usize::from(gpio)).gpio_ctrl().as_ptr() as usize;
+    unsafe { core::ptr::write_volatile((ctrl + alias) as *mut u32, bits) };
+}
+
+// FORCED_GPIOSのピンを、オーバーライドでオフの電圧に固定する。
+// 非常停止のほか、出力の許可の入力（output_enable.rs）と電源を切る前の片付け（shutdown.rs）でも使う。
+pub fn force_outputs_off() {
+    for (gpio, id) in FORCED_GPIOS {
+        let outover = if output::off_level(id) {
+            OUTOVER_HIGH
+        } else {
+            OUTOVER_LOW
+        };
+        write_ctrl(gpio, ALIAS_CLEAR, OUTOVER_MASK);
+        write_ctrl(gpio, ALIAS_SET, outover | OEOVER_ENABLE);
+    }
+}
+
+// ほかに出力を止めている理由（非常停止、出力の許可の入力）がなければ、オーバーライドを外す。外したらtrue。
+// free()の中で呼ぶこと（間にon_estop()が入ると、止めたばかりのピンのオーバーライドを外してしまう）。
+pub fn release_outputs(cs: &CriticalSection) -> bool {
+    if is_latched() || !output_enable::is_enabled(cs) {
+        return false;
+    }
+    for (gpio, _) in FORCED_GPIOS {
+        write_ctrl(gpio, ALIAS_CLEAR, OUTOVER_MASK | OEOVER_ENABLE);
+    }
+    true
+}
+
+// PIO0_IRQ_0から呼ぶ。ほかのどの割り込みの途中でも入ってくる（上の注意を参照）。
+pub fn on_estop() {
+    let pio = unsafe { &*pac::PIO0::ptr() };
+    // irq 0のフラグは1を書くと消える
+    pio.irq().write(|w| unsafe { w.irq().bits(1) });
+
+    force_outputs_off();
+    // Cortex-M0+ではswap()が使えないので、loadとstoreに分けている。
+    // この割り込みにはほかの割り込みが入ってこないので、間で値が変わることはない。
+    if !LATCHED.load(Ordering::Relaxed) {
+        LATCHED.store(true, Ordering::Relaxed);
+        defmt::error!("EMERGENCY STOP: outputs forced low, send 'estop reset' after releasing");
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ResetError {
+    // 止まっていない
+    NotLatched,
+    // 入力がまだHigh
+    StillAsserted,
+}
+
+impl ResetError {
+    // UARTなどに出力するときの説明
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResetError::NotLatched => "not stopped",
+            ResetError::StillAsserted => "input still asserted",
+        }
+    }
+}
+
+// 止まっている状態を解除する。入力がLowに戻っていなければ解除しない。
+pub fn reset() -> Result<(), ResetError> {
+    free(|cs| {
+        if !is_latched() {
+            return Err(ResetError::NotLatched);
+        }
+        let mut estop = ESTOP.borrow(cs).borrow_mut();
+        if let Some((_, _, pin)) = estop.as_mut() {
+            if pin.is_high().unwrap() {
+                return Err(ResetError::StillAsserted);
+            }
+        }
+        // free()の中なのでon_estop()は入ってこない。ラッチを消してからオーバーライドを外す。
+        // 出力の許可の入力（output_enable.rs）が禁止なら、許可に戻るまで外さない。
+        LATCHED.store(false, Ordering::Relaxed);
+        release_outputs(cs);
+        crate::restart_blink(cs);
+        Ok(())
+    })
+}
@@ -0,0 +1,93 @@
+// 明るさを徐々に変える（フェード）
+//
+// set_brightness_smooth(target, duration_ms)で、今の明るさからtargetまでduration_msかけて変える。
+// ALARM2をFADE_TICK_MSごとに発火させ、TIMER_IRQ_2で1ステップずつtargetに近づける。
+// targetに着いたらALARM2はscheduleし直さずに止める。
+//
+// 1ステップの変化量は (差 × FADE_TICK_MS / duration_ms) で、最低でもFADE_MIN_STEP（1）にする。
+// 差が小さく時間が長いと計算上は0になり、いつまでもtargetに着かなくなるため。
+// その場合は指定より早く（差の分のステップ数で）終わる。
+// フェードの途中で新しいtargetが来たら、その時点の明るさから新しいtargetに向かって計算し直すので、
+// 明るさが飛ぶことはない。
+
+use crate::cs_trace::free;
+use crate::led;
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp_pico::hal::timer::{Alarm, Alarm2};
+
+pub const FADE_TICK_MS: u32 = 10;
+pub const FADE_MIN_STEP: u16 = 1;
+
+#[derive(Clone, Copy)]
+struct Fade {
+    target: u16,
+    step: u16,
+}
+
+static ALARM2: GlobalPeripheral<Alarm2> = initial_global_peripheral();
+// フェード中ならSome
+static FADE: Mutex<Cell<Option<Fade>>> = Mutex::new(Cell::new(None));
+
+pub fn init(cs: &CriticalSection, mut alarm: Alarm2) {
+    alarm.enable_interrupt();
+    alarm.clear_interrupt();
+    ALARM2.borrow(cs).replace(Some(alarm));
+}
+
+pub fn set_brightness_smooth(target: u16, duration_ms: u32) {
+    free(|cs| {
+        let current = led::brightness(cs);
+        let diff = u32::from(current.abs_diff(target));
+        let ticks = duration_ms / FADE_TICK_MS;
+        if ticks == 0 || diff == 0 {
+            cancel(cs);
+            led::set_brightness(cs, target);
+            return;
+        }
+
+        let step = ((diff / ticks) as u16).max(FADE_MIN_STEP);
+        let running = FADE
+            .borrow(cs)
+            .replace(Some(Fade { target, step }))
+            .is_some();
+        // フェード中ならALARM2はすでに動いているので、次の割り込みから新しいtargetに向かう
+        if !running {
+            if let Some(alarm) = ALARM2.borrow(cs).borrow_mut().as_mut() {
+                crate::schedule_alarm_ms(alarm, FADE_TICK_MS);
+            }
+        }
+    });
+}
+
+// フェードを取り消す。ALARM2がもう1回だけ発火することがあるが、tick()は何もせずに止まる。
+pub fn cancel(cs: &CriticalSection) {
+    FADE.borrow(cs).set(None);
+}
+
+// TIMER_IRQ_2から呼ぶ
+pub fn tick(cs: &CriticalSection) {
+    let mut alarm = ALARM2.borrow(cs).borrow_mut();
+    let Some(alarm) = alarm.as_mut() else {
+        return;
+    };
+    alarm.clear_interrupt();
+
+    let Some(fade) = FADE.borrow(cs).get() else {
+        return;
+    };
+    let current = led::brightness(cs);
+    let next = if current < fade.target {
+        current.saturating_add(fade.step).min(fade.target)
+    } else {
+        current.saturating_sub(fade.step).max(fade.target)
+    };
+    led::set_brightness(cs, next);
+
+    if next == fade.target {
+        FADE.borrow(cs).set(None);
+    } else {
+        crate::schedule_alarm_ms(alarm, FADE_TICK_MS);
+    }
+}
@@ -0,0 +1,62 @@
+// このビルドで有効になっているCargoのフィーチャーの一覧
+//
+// 同じソースからフィーチャーを変えて何種類も書き込むと、つないだ個体がどのビルドなのか
+// 見た目ではわからない。起動時のログ（report_features()）とUARTのfeaturesコマンドで、
+// 有効になっているフィーチャーの名前を出す。
+// 一覧はcfg!で作るので、Cargo.tomlの[features]にフィーチャーを足したら、ここにも1行足すこと
+// （ここにないフィーチャーは、有効にしても出力されない）。
+
+use core::fmt;
+
+// (フィーチャーの名前, 有効か)。Cargo.tomlの[features]と同じ順にする。
+pub const FEATURES: &[(&str, bool)] = &[
+    ("banner", cfg!(feature = "banner")),
+    ("tick-source", cfg!(feature = "tick-source")),
+    ("bench", cfg!(feature = "bench")),
+    ("atomic-counter", cfg!(feature = "atomic-counter")),
+    ("mfg-test", cfg!(feature = "mfg-test")),
+    ("led-active-low", cfg!(feature = "led-active-low")),
+    ("periodic-reboot", cfg!(feature = "periodic-reboot")),
+    ("cs-trace", cfg!(feature = "cs-trace")),
+    ("paint-stack", cfg!(feature = "paint-stack")),
+    ("watchdog", cfg!(feature = "watchdog")),
+    ("reset-log", cfg!(feature = "reset-log")),
+    ("on-target-test", cfg!(feature = "on-target-test")),
+    ("rtic", cfg!(feature = "rtic")),
+];
+
+pub fn enabled() -> impl Iterator<Item = &'static str> {
+    FEATURES.iter().filter(|(_, on)| *on).map(|(name, _)| *name)
+}
+
+// 有効なフィーチャーを1つずつログに出す
+pub fn report_features() {
+    let mut count = 0;
+    for name in enabled() {
+        defmt::info!("feature: {=str}", name);
+        count += 1;
+    }
+    if count == 0 {
+        defmt::info!("feature: none");
+    }
+}
+
+// 有効なフィーチャーを空白で区切って表示する（UARTのfeaturesコマンド用）。なければ"none"。
+pub struct EnabledFeatures;
+
+impl fmt::Display for EnabledFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut any = false;
+        for name in enabled() {
+            if any {
+                f.write_str(" ")?;
+            }
+            f.write_str(name)?;
+            any = true;
+        }
+        if !any {
+            f.write_str("none")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+// RAMとフラッシュの使用量を調べて報告する
+//
+// 機能を足していくと、静的変数（.data、.bss）もスタックも少しずつ増える。どれだけ余裕があるかを
+// 起動時のログ（report()）とUARTの mem コマンドで見られるようにする。
+//
+// メモリの配置（flip-linkでリンクしている）
+//   RAMの先頭（0x2000_0000）から_stack_startまでがスタック、その上に静的変数が並ぶ。
+//   スタックは_stack_startから下に伸び、あふれるとRAMの先頭を越えてHardFaultになる
+//   （静的変数を壊さずに止まる。それがflip-linkを使う理由）。
+//   どちらの境目もリンカが決めるシンボル（_stack_end、_stack_start、__sdata、__euninitなど）から読む。
+//
+// 静的変数とフラッシュ
+//   リンクしたときに大きさが決まるので、シンボルの差でいつでも正確にわかる。
+//   フラッシュは先頭（0x1000_0000、boot2を含む）から、.dataの初期値（__sidataから.dataの大きさ分）の終わりまで。
+//
+// スタックの最大使用量（paint-stackフィーチャー）
+//   起動したときに、スタックの領域全体をcortex-m-rtのReset（main()より前）で
+//   STACK_PAINT_VALUE（0xCCCC_CCCC）で塗っておく（cortex-m-rtのpaint-stackフィーチャー）。
+//   スタックが伸びたところは塗った値が上書きされるので、stack_high_water()でRAMの先頭から上へ見ていき、
+//   最初に塗った値でなくなったところを、今までにスタックが伸びたいちばん深いところとみなす。
+//   塗るのは起動時の1回だけで、クロックを設定する前なので、およそ250 KBを塗るのに数十msかかる。
+//
+// 注意（測れるのは「今までに実際に使った量」で、最悪の場合ではない）
+//   ・まだ通っていない経路（めったに起きないエラーの処理、同時に重なったことのない割り込み）の分は入らない。
+//     最悪の場合を知るには、呼び出しの深さを静的に解析する（cargo-call-stackなど）必要がある。
+//   ・大きな配列をスタックに取っても、書き込んでいない部分は塗った値が残るので、使っていないように見える。
+//   ・たまたま0xCCCC_CCCCを書いた場所は境目を見誤るが、外側から見ていくので、低く見えるのは
+//     その値がいちばん深いところにあったときだけ。
+//   ・数えるのはメインスタック（MSP）だけ。このファームウェアはPSPもコア1も使っていない。
+//   ・スタックの領域全体を読むので、mem コマンドは1回に1 ms近くかかる（割り込みは止めない）。
+
+// リンカが決めるシンボル。値ではなくアドレスに意味がある。
+extern "C" {
+    static _stack_end: u32;
+    static _stack_start: u32;
+    static __sdata: u32;
+    static __euninit: u32;
+    static __sidata: u32;
+    static __edata: u32;
+}
+
+const FLASH_START: usize = 0x1000_0000;
+
+fn addr(symbol: *const u32) -> usize {
+    symbol as usize
+}
+
+#[derive(Clone, Copy)]
+pub struct Footprint {
+    // スタックに取ってある領域
+    pub stack_bytes: usize,
+    // 静的変数（.data、.bss、.uninit）
+    pub static_bytes: usize,
+    // フラッシュに書き込んだプログラムと初期値
+    pub flash_bytes: usize,
+    // スタックの最大使用量（paint-stackフィーチャーで塗ったときだけ）
+    pub stack_high_water: Option<usize>,
+}
+
+pub fn footprint() -> Footprint {
+    // アドレスを取るだけなのでunsafeは要らない（読むのはstack_high_water()だけ）
+    let (stack_end, stack_start, sdata, euninit, sidata, edata) = (
+        addr(core::ptr::addr_of!(_stack_end)),
+        addr(core::ptr::addr_of!(_stack_start)),
+        addr(core::ptr::addr_of!(__sdata)),
+        addr(core::ptr::addr_of!(__euninit)),
+        addr(core::ptr::addr_of!(__sidata)),
+        addr(core::ptr::addr_of!(__edata)),
+    );
+    #[cfg(feature = "paint-stack")]
+    let stack_high_water = Some(stack_high_water());
+    #[cfg(not(feature = "paint-stack"))]
+    let stack_high_water = None;
+    Footprint {
+        stack_bytes: stack_start - stack_end,
+        static_bytes: euninit - sdata,
+        flash_bytes: sidata + (edata - sdata) - FLASH_START,
+        stack_high_water,
+    }
+}
+
+// 起動してから今までにスタックが伸びたいちばん深いところまでの大きさ（バイト）
+#[cfg(feature = "paint-stack")]
+pub fn stack_high_water() -> usize {
+    let (stack_end, stack_start) = (
+        core::ptr::addr_of!(_stack_end),
+        core::ptr::addr_of!(_stack_start),
+    );
+    let mut word = stack_end;
+    while word < stack_start {
+        // Safety: _stack_endから_stack_startまではRAMで、4バイトにそろっている
+        if unsafe { core::ptr::read_volatile(word) } != cortex_m_rt::STACK_PAINT_VALUE {
+            break;
+        }
+        word = word.wrapping_add(1);
+    }
+    stack_start as usize - word as usize
+}
+
+// 起動時にログへ出す
+pub fn report() {
+    let f = footprint();
+    defmt::info!(
+        "memory: stack {=usize} B, statics {=usize} B, flash {=usize} B",
+        f.stack_bytes,
+        f.static_bytes,
+        f.flash_bytes
+    );
+    if let Some(used) = f.stack_high_water {
+        defmt::info!(
+            "memory: stack high water {=usize} B of {=usize} B",
+            used,
+            f.stack_bytes
+        );
+    }
+}
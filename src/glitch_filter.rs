@@ -0,0 +1,57 @@
+// GPIOのエッジの割り込みに付ける、最小パルス幅のフィルタ（グリッチフィルタ）
+//
+// RP2040の入力パッドにはハードウェアのグリッチフィルタがないので、数十nsのスパイクでも
+// エッジとしてINTRレジスタに残り、IO_IRQ_BANK0に入ってくる。
+// ここではエッジの割り込みに入ったあと、ピンのレベルがエッジの後のレベルのまま
+// set_glitch_filter_us()で設定したµsの間続くかを確かめ、続いたときだけエッジを受け付ける。
+//
+// チャタリング除去（debounce.rs、toggle_buttonのTOGGLE_DEBOUNCE_MS）との違い
+//   ・チャタリング除去はスイッチの接点が数msの間バタつくのをまとめるもので、
+//     最初のエッジを受け付けたあとのエッジを無視する（または落ち着くまで待つ）
+//   ・グリッチフィルタは静電気や長い配線に乗るµs以下のノイズを、そもそもエッジとして受け付けないもの。
+//     チャタリング除去だけだと、ノイズの1発目を「押した」として受け付けてしまう
+//   数µsのフィルタでは、ボタンを実際に押したときの反応の遅れは気にならない。
+//
+// 確かめ方
+//   ALARMは4つとも使っているので、ワンショットのALARMで後から確かめるのではなく、
+//   割り込みの中でタイマーを見ながらフィルタの時間だけピンを読み続ける。
+//   µsの単位ではALARMの割り込みに入るまでの遅れも同じくらいかかるので、待ち方としても差はない。
+//   割り込みの中で待つので、時間はGLITCH_FILTER_MAX_USまでにしている。
+//   割り込みに入るまでの遅れより短いスパイクは、入った時点ですでに元のレベルに戻っているので、
+//   フィルタの時間によらず最初の読み出しで捨てられる。0 µsにすると確かめずに受け付ける。
+
+use crate::cs_trace::free;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp_pico::hal::timer::Timer;
+
+pub const GLITCH_FILTER_US: u32 = 5;
+pub const GLITCH_FILTER_MAX_US: u32 = 100;
+
+static FILTER_US: Mutex<Cell<u32>> = Mutex::new(Cell::new(GLITCH_FILTER_US));
+
+// フィルタの時間を変える。GLITCH_FILTER_MAX_USまでに切り詰め、設定した値を返す。
+pub fn set_glitch_filter_us(us: u32) -> u32 {
+    let us = us.min(GLITCH_FILTER_MAX_US);
+    free(|cs| FILTER_US.borrow(cs).set(us));
+    us
+}
+
+pub fn glitch_filter_us(cs: &CriticalSection) -> u32 {
+    FILTER_US.borrow(cs).get()
+}
+
+// エッジの割り込みの中で呼ぶ。is_activeはピンがエッジの後のレベルにあるかを返す。
+// フィルタの時間の間ずっとそのレベルだったらtrue。
+pub fn is_stable(cs: &CriticalSection, timer: &Timer, mut is_active: impl FnMut() -> bool) -> bool {
+    let filter_us = glitch_filter_us(cs);
+    let start = timer.get_counter_low();
+    loop {
+        if !is_active() {
+            return false;
+        }
+        if timer.get_counter_low().wrapping_sub(start) >= filter_us {
+            return true;
+        }
+    }
+}
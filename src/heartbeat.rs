@@ -0,0 +1,130 @@
+// 外付けのウォッチドッグIC向けのハートビート出力
+//
+// TPS3823のような外付けのウォッチドッグICは、WDI端子が一定時間（タイムアウト）以上変化しないと
+// ボードをリセットする。そこでGPIO2をHEARTBEAT_TOGGLE_MSごとに反転させ（sampler.rsがALARM3で呼ぶ）、
+// 正常に動いている間だけWDIを蹴り続ける。LEDの点滅（ALARM0）とは独立しているので、
+// 点滅の間隔をどれだけ伸ばしてもハートビートの速さは変わらない。
+//
+// 正常とみなす条件（どちらかが崩れたら反転をやめ、外付けのWDにリセットしてもらう）
+//   ・メインループがHEALTH_REPORT_TIMEOUT_MS以内にreport()を呼んでいる
+//     （TIMER_IRQ_3だけが動いていて、メインループが固まっている場合を検出する）
+//   ・割り込みカウンタ（ALARM0）が進んでいる。点滅の間隔の2倍（最低STALL_MIN_MS）進まなければ異常
+//     ただしblink_times()で点滅し終わったときや、コマ送り（step_mode.rs）、操作がないとき（idle_off.rs）で
+//     ALARM0を止めている間は、
+//     進まなくて正常なので数えない
+// 一度異常を検出したら、戻ったように見えても反転は再開しない（リセットされるまで止めたまま）。
+//
+// 外付けのWDのタイムアウトとの関係
+//   ・HEARTBEAT_TOGGLE_MSは外付けのWDのタイムアウト（最小値）より十分短くすること。
+//     割り込みの遅れを見込んで、タイムアウトの1/4以下を目安にする。
+//     例えばTPS3823はタイムアウトが最小0.9 s（標準1.6 s）なので、200 msなら余裕がある。
+//   ・異常になってから実際にリセットされるまでは、異常の検出にかかる時間
+//     （最大でHEALTH_REPORT_TIMEOUT_MSか、点滅の間隔の2倍）に外付けのWDのタイムアウトを足した時間。
+
+use crate::cs_trace::free;
+use crate::interval;
+use crate::output::{self, OutputId};
+use crate::{blink_count, idle_off, step_mode};
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::time;
+use rp_pico::hal::gpio;
+use rp_pico::hal::timer::Timer;
+
+// WDI端子を反転させる間隔
+pub const HEARTBEAT_TOGGLE_MS: u32 = 200;
+assert_alarm_interval_ms!(HEARTBEAT_TOGGLE_MS);
+// メインループからの報告がこれより長く途絶えたら異常
+pub const HEALTH_REPORT_TIMEOUT_MS: u32 = 3000;
+assert_alarm_interval_ms!(HEALTH_REPORT_TIMEOUT_MS);
+// 割り込みカウンタが進まなくても許す最短の時間。数値の表示の休み（3 s）などでも異常にしない。
+pub const STALL_MIN_MS: u32 = 5000;
+assert_alarm_interval_ms!(STALL_MIN_MS);
+
+pub type HeartbeatPin = gpio::Pin<gpio::bank0::Gpio2, gpio::FunctionSioOutput, gpio::PullDown>;
+
+#[derive(Clone, Copy)]
+struct Health {
+    // 最後にreport()が呼ばれた時刻
+    reported_at: u64,
+    // 最後に見た割り込みカウンタの値と、それが進んだ時刻
+    count: u32,
+    advanced_at: u64,
+}
+
+static HEARTBEAT_PIN: GlobalPeripheral<HeartbeatPin> = initial_global_peripheral();
+static HEARTBEAT_TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+static HEALTH: Mutex<Cell<Option<Health>>> = Mutex::new(Cell::new(None));
+// 一度立ったら下ろさない
+static FAULT: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+// 最後にWDIに書いた状態（output::write_output()のオン/オフ）
+static HEARTBEAT_ON: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+// ピンはoutput::initial_state()の状態で出力にして渡す
+pub fn init(cs: &CriticalSection, pin: HeartbeatPin, timer: Timer) {
+    HEARTBEAT_PIN.borrow(cs).replace(Some(pin));
+    HEARTBEAT_TIMER.borrow(cs).set(Some(timer));
+}
+
+// メインループから定期的に呼ぶ。異常を検出したときだけtrueを返す（ログ用）。
+pub fn report(now: u64, count: u32) -> bool {
+    free(|cs| {
+        if FAULT.borrow(cs).get() {
+            return false;
+        }
+        let health = HEALTH.borrow(cs);
+        let mut next = health.get().unwrap_or(Health {
+            reported_at: now,
+            count,
+            advanced_at: now,
+        });
+        next.reported_at = now;
+        if next.count != count
+            || blink_count::is_stopped(cs)
+            || step_mode::is_enabled(cs)
+            || idle_off::is_idle(cs)
+        {
+            next.count = count;
+            next.advanced_at = now;
+        }
+        health.set(Some(next));
+
+        let stall_ms = interval::current_ms(cs).saturating_mul(2).max(STALL_MIN_MS);
+        let stalled = time::elapsed_us(now, next.advanced_at) > u64::from(stall_ms) * 1000;
+        if stalled {
+            FAULT.borrow(cs).set(true);
+        }
+        stalled
+    })
+}
+
+pub fn is_healthy(cs: &CriticalSection) -> bool {
+    !FAULT.borrow(cs).get()
+}
+
+// TIMER_IRQ_3からHEARTBEAT_TOGGLE_MSごとに呼ぶ（sampler.rs）
+pub fn tick(cs: &CriticalSection) {
+    let Some(timer) = HEARTBEAT_TIMER.borrow(cs).get() else {
+        return;
+    };
+    let now = timer.get_counter().ticks();
+    // まだ一度も報告がなければ、起動直後として反転を続ける
+    let reported = HEALTH.borrow(cs).get().is_none_or(|h| {
+        time::elapsed_us(now, h.reported_at) <= u64::from(HEALTH_REPORT_TIMEOUT_MS) * 1000
+    });
+    if !reported {
+        FAULT.borrow(cs).set(true);
+    }
+    if !is_healthy(cs) {
+        // ピンはそのままの状態で残り、外付けのWDがリセットする
+        return;
+    }
+
+    // ピンはHEARTBEAT_PINに持っておき、書くのはoutput.rsに任せる
+    if HEARTBEAT_PIN.borrow(cs).borrow().is_some() {
+        let on = !HEARTBEAT_ON.borrow(cs).get();
+        HEARTBEAT_ON.borrow(cs).set(on);
+        output::write_output(OutputId::Heartbeat, on);
+    }
+}
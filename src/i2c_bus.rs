@@ -0,0 +1,125 @@
+// I2C0バス（GPIO4: SDA, GPIO5: SCL）
+//
+// 複数のデバイスで同じバスを共有するので、バス本体はグローバル変数に置いて
+// 使うたびにクリティカルセクションの中で借りる。
+//
+// 転送に失敗し続けるときは、RESETSでI2C0ブロックだけをリセットして復旧させる（recover()）。
+// 復旧の手順
+//   1. I2C::free()でI2C0ブロックをリセット状態にし、ピンを取り戻す
+//   2. SCLを一時的にGPIO出力にして9回クロックを送る。
+//      転送の途中でマスター側が止まると、スレーブがSDAをLowにしたまま次のクロックを待ち続ける。
+//      9回あれば残りのビットとACKを送り切れるので、スレーブがSDAを離す。
+//   3. SCLをI2Cの機能に戻し、I2C::i2c0()でブロックのリセットを解除して設定し直す
+// TIMERやほかのブロックには触れないので、割り込みカウンタや起動してからの時間はそのまま残る。
+
+use crate::cs_trace::free;
+use crate::{initial_global_peripheral, resets, GlobalPeripheral};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use embedded_hal::digital::OutputPin;
+use fugit::RateExtU32;
+use rp_pico::hal::{gpio, i2c, pac};
+
+pub type SdaPin = gpio::Pin<gpio::bank0::Gpio4, gpio::FunctionI2C, gpio::PullUp>;
+pub type SclPin = gpio::Pin<gpio::bank0::Gpio5, gpio::FunctionI2C, gpio::PullUp>;
+pub type I2cBus = i2c::I2C<pac::I2C0, (SdaPin, SclPin)>;
+
+// DS3231などの一般的なデバイスはどれも対応しているので標準モードの100kHzにする
+pub const I2C_FREQ_KHZ: u32 = 100;
+
+// 連続してこの回数だけ転送に失敗したら、recover()でバスを復旧させる
+pub const I2C_RECOVERY_ERRORS: u32 = 3;
+
+static I2C_BUS: GlobalPeripheral<I2cBus> = initial_global_peripheral();
+// I2C0を設定し直すときに使うシステムクロックの周波数
+static SYSTEM_CLOCK_HZ: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+// 連続して転送に失敗した回数
+static CONSECUTIVE_ERRORS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+// recover()でI2C0をリセットした回数（起動してからの累計）
+static RECOVERIES: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+pub fn init(bus: I2cBus, system_clock_hz: u32) {
+    free(|cs| {
+        I2C_BUS.borrow(cs).replace(Some(bus));
+        SYSTEM_CLOCK_HZ.borrow(cs).set(system_clock_hz);
+    });
+}
+
+// バスを借りて処理を行う。バスが初期化されていない（または使用中の）ときはNoneを返す。
+//
+// 100kHzだと数バイトの転送でも数百µsかかるので、クリティカルセクションの中で転送すると
+// その間TIMER_IRQ_0が待たされてしまう。
+// そこでバスをグローバル変数から一旦取り出し、割り込みを許可したまま転送してから戻す。
+// 取り出している間に別の場所からwith_bus()を呼ぶとNoneになる。
+pub fn with_bus<R>(f: impl FnOnce(&mut I2cBus) -> R) -> Option<R> {
+    let mut bus = free(|cs| I2C_BUS.borrow(cs).take())?;
+    let result = f(&mut bus);
+    free(|cs| I2C_BUS.borrow(cs).replace(Some(bus)));
+    Some(result)
+}
+
+// 各デバイスのドライバが転送の結果を報告する
+pub fn record_transfer(ok: bool) {
+    free(|cs| {
+        let errors = CONSECUTIVE_ERRORS.borrow(cs);
+        errors.set(if ok { 0 } else { errors.get() + 1 });
+    });
+}
+
+// 失敗が続いていて、recover()を呼ぶべきときにtrue
+pub fn needs_recovery() -> bool {
+    free(|cs| CONSECUTIVE_ERRORS.borrow(cs).get() >= I2C_RECOVERY_ERRORS)
+}
+
+// I2C0ブロックをリセットして設定し直す。バスかRESETSが使用中で復旧できなかったときはfalse。
+pub fn recover() -> bool {
+    let Some(bus) = free(|cs| I2C_BUS.borrow(cs).take()) else {
+        return false;
+    };
+    let system_clock_hz = free(|cs| SYSTEM_CLOCK_HZ.borrow(cs).get());
+
+    // RESETSが借りられないときはFreeのためのResetsもないので、バスをそのまま戻す
+    let mut bus = Some(bus);
+    let recovered = resets::with_resets(|resets| {
+        let (block, (sda, scl)) = bus.take().unwrap().free(resets);
+        let scl = clock_out_stuck_slave(scl, system_clock_hz);
+        bus = Some(i2c::I2C::i2c0(
+            block,
+            sda,
+            scl,
+            I2C_FREQ_KHZ.kHz(),
+            resets,
+            system_clock_hz.Hz(),
+        ));
+    })
+    .is_some();
+
+    free(|cs| {
+        I2C_BUS.borrow(cs).replace(bus);
+        if recovered {
+            CONSECUTIVE_ERRORS.borrow(cs).set(0);
+            let recoveries = RECOVERIES.borrow(cs);
+            recoveries.set(recoveries.get().wrapping_add(1));
+        }
+    });
+    recovered
+}
+
+pub fn recoveries(cs: &CriticalSection) -> u32 {
+    RECOVERIES.borrow(cs).get()
+}
+
+// SCLを9回クロックして、SDAをLowに保持しているスレーブを解放させる
+fn clock_out_stuck_slave(scl: SclPin, system_clock_hz: u32) -> SclPin {
+    // I2C_FREQ_KHZの周期の半分だけ待つ。delay()の引数はCPUのサイクル数。
+    let half_period_cycles = system_clock_hz / (I2C_FREQ_KHZ * 1000 * 2);
+
+    let mut scl = scl.into_function::<gpio::FunctionSioOutput>();
+    for _ in 0..9 {
+        scl.set_low().unwrap();
+        cortex_m::asm::delay(half_period_cycles);
+        scl.set_high().unwrap();
+        cortex_m::asm::delay(half_period_cycles);
+    }
+    scl.into_function()
+}
@@ -0,0 +1,105 @@
+// しばらく操作がなければLEDを暗くする（スクリーンセーバー）
+//
+// IDLE_DIM_TIMEOUT_MSの間どの入力からも操作がなければ、LEDの明るさをIDLE_DIM_LEVELまでゆっくり下げる。
+// 操作があれば、またゆっくり元の明るさに戻す。
+// 点滅を止めて消灯するidle_off.rsとは別のもので、こちらは暗くするだけで点滅（ALARM0）は止めない。
+// 両方を有効にすると、先に暗くなり、idle_off.rsのタイムアウトで消灯する（暗くする方を短くしておく）。
+//
+// 操作とみなすものはidle_off.rsと同じ（idle_off::note_activity()を呼んでいる入力）で、最後の操作の時刻も
+// idle_off.rsのもの（idle_off::last_activity()）を使う。入力を足したときは、idle_off.rsの説明のとおりにすればよい。
+// idle_off.rsのタイムアウトを止めていても（idle off）、最後の操作の時刻は記録し続けている。
+//
+// 暗くする方法
+//   LEDの出力の計算（led.rs）の中で、周囲の明るさによる調光の次に倍率（led::set_idle_dim_scale()）を掛ける。
+//   全体の明るさ（set_led_brightness()、フェード）とは別の倍率なので、暗くしている間に明るさやフェードを変えても
+//   混ざらず、戻るときは変えた後の明るさに戻る。上限（set_brightness_cap()）はこの後にかかる。
+//   倍率はsampler（SAMPLE_PERIOD_MSごと）で1段ずつ動かし、下げるときも上げるときもIDLE_DIM_RAMP_MSかけて変える。
+//   1回に変える量は (u16::MAX - IDLE_DIM_LEVEL) × SAMPLE_PERIOD_MS / IDLE_DIM_RAMP_MS。
+//   途中で操作があれば、その時点の倍率から上げ始めるので、明るさが飛ぶことはない。
+//
+// 点滅のモードとの関係
+//   どのモードでも点滅はそのまま続き、点灯も消灯（OFF_BRIGHTNESS）も同じ倍率で暗くなる。
+//   点滅の間隔やパターン、ブザー、割り込みカウンタは変わらない。
+//   Offモードやidle_off.rsで消灯しているときは、倍率を掛けても消灯のまま。
+//   暗くするのはLED（PWM4）だけで、2色LED（bicolor.rs）などほかの出力は変えない。
+//
+// 起動時はIDLE_DIM_TIMEOUT_MSとIDLE_DIM_LEVEL。UARTの dim SEC LEVEL|off で変えられる（SECが0かoffで暗くしない）。
+
+use crate::cs_trace::free;
+use crate::idle_off;
+use crate::led;
+use crate::sampler::SAMPLE_PERIOD_MS;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::time;
+use rp_pico::hal::timer::Timer;
+
+pub const IDLE_DIM_TIMEOUT_MS: u32 = 60 * 1000;
+// 暗くしたときの倍率（u16::MAXで元の明るさ）
+pub const IDLE_DIM_LEVEL: u16 = u16::MAX / 8;
+// 暗くするとき、戻すときにかける時間
+pub const IDLE_DIM_RAMP_MS: u32 = 1000;
+const _: () = assert!(IDLE_DIM_RAMP_MS >= SAMPLE_PERIOD_MS);
+
+static DIM_TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+// 0なら暗くしない
+static TIMEOUT_MS: Mutex<Cell<u32>> = Mutex::new(Cell::new(IDLE_DIM_TIMEOUT_MS));
+static LEVEL: Mutex<Cell<u16>> = Mutex::new(Cell::new(IDLE_DIM_LEVEL));
+// 今の倍率
+static SCALE: Mutex<Cell<u16>> = Mutex::new(Cell::new(u16::MAX));
+
+pub fn init(cs: &CriticalSection, timer: Timer) {
+    DIM_TIMER.borrow(cs).set(Some(timer));
+}
+
+pub fn timeout_ms(cs: &CriticalSection) -> u32 {
+    TIMEOUT_MS.borrow(cs).get()
+}
+
+pub fn level(cs: &CriticalSection) -> u16 {
+    LEVEL.borrow(cs).get()
+}
+
+// 暗くしているか、暗くしている途中か
+pub fn is_dimmed(cs: &CriticalSection) -> bool {
+    SCALE.borrow(cs).get() != u16::MAX
+}
+
+// 操作がないとみなすまでの時間と、暗くしたときの倍率を変える。timeout_msが0なら暗くしない（暗ければ戻す）。
+pub fn set_idle_dim(timeout_ms: u32, level: u16) {
+    free(|cs| {
+        TIMEOUT_MS.borrow(cs).set(timeout_ms);
+        LEVEL.borrow(cs).set(level);
+    });
+}
+
+// samplerからSAMPLE_PERIOD_MSごとに呼ぶ。倍率を目標（暗くする／元に戻す）に1段近づける。
+pub fn tick(cs: &CriticalSection) {
+    let Some(timer) = DIM_TIMER.borrow(cs).get() else {
+        return;
+    };
+    let timeout_ms = timeout_ms(cs);
+    let level = level(cs);
+    let idle = timeout_ms != 0
+        && time::elapsed_us(timer.get_counter().ticks(), idle_off::last_activity(cs))
+            >= u64::from(timeout_ms) * 1000;
+    let target = if idle { level } else { u16::MAX };
+
+    let scale = SCALE.borrow(cs);
+    let current = scale.get();
+    if current == target {
+        return;
+    }
+    // LEVELが元の明るさに近くても止まらないように、1回に少なくとも1は変える
+    let step = ((u32::from(u16::MAX - level) * SAMPLE_PERIOD_MS / IDLE_DIM_RAMP_MS) as u16).max(1);
+    let next = if target > current {
+        current.saturating_add(step).min(target)
+    } else {
+        current.saturating_sub(step).max(target)
+    };
+    scale.set(next);
+    led::set_idle_dim_scale(cs, next);
+    if next == target {
+        defmt::info!("idle dim: {=str}", if idle { "dimmed" } else { "restored" });
+    }
+}
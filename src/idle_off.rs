@@ -0,0 +1,113 @@
+// しばらく操作がなければ点滅を止めて消灯する
+//
+// IDLE_OFF_TIMEOUT_MSの間どの入力からも操作がなければ、LEDを消灯してALARM0の割り込みを止める。
+// 点滅し続ける表示が要らない場所で電力を節約するためのもの。
+// 止めるのは点滅（ALARM0）だけで、CPUは眠らない。メインループ、UART、センサーの読み取り、
+// ほかのALARM（sampler、フェード、ソフトウェアタイマー）は止まっている間もそのまま動く。
+// CPUを止めて割り込みを待つ省電力とは別のもので、組み合わせてもよい（こちらはALARM0の割り込みが来なくなるだけ）。
+//
+// 操作とみなすもの（どれもnote_activity()を呼ぶ）
+//   ・タクトスイッチを押した（main.rs）
+//   ・トグルボタンを押した（toggle_button.rs）
+//   ・ロータリーエンコーダーを回した（encoder.rs）
+//   ・赤外線リモコンのコマンドを受け取った（ir_nec.rs）
+//   ・UARTからコマンドを受け取った（command.rs）
+// 入力を足したときは、そこからもnote_activity()を呼ぶこと。
+//
+// 止まっている間に操作があると、点滅を最初の点灯からやり直す。止めてもモードは変えないので、
+// 止める前のモード（止まっている間にUARTなどで変えたならそのモード）で再開する。
+// 止めたのを戻したタクトスイッチの押下は再開だけに使い、短押しのモードの切り替えはしない（take_wake_press()）。
+// 止まっている間は割り込みカウンタが進まないが、外付けのWDへのハートビートは止めない（heartbeat.rs）。
+//
+// 起動時はIDLE_OFF_TIMEOUT_MS。UARTの idle SEC|off で変えられる（0かoffで止めない）。
+
+use crate::cs_trace::free;
+use crate::led;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::time;
+use rp_pico::hal::timer::Timer;
+
+pub const IDLE_OFF_TIMEOUT_MS: u32 = 10 * 60 * 1000;
+
+static IDLE_TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+// 0なら止めない
+static TIMEOUT_MS: Mutex<Cell<u32>> = Mutex::new(Cell::new(IDLE_OFF_TIMEOUT_MS));
+static LAST_ACTIVITY: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+static IDLE: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+// 止めていたのをタクトスイッチで戻したとき、その押下の短押しを捨てるための印
+static WAKE_PRESS: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+pub fn init(cs: &CriticalSection, timer: Timer) {
+    IDLE_TIMER.borrow(cs).set(Some(timer));
+    LAST_ACTIVITY.borrow(cs).set(timer.get_counter().ticks());
+}
+
+pub fn is_idle(cs: &CriticalSection) -> bool {
+    IDLE.borrow(cs).get()
+}
+
+// 最後に操作があった時刻（タイマーカウンタ）。操作がないときの減光（idle_dim.rs）もこれを使う。
+pub fn last_activity(cs: &CriticalSection) -> u64 {
+    LAST_ACTIVITY.borrow(cs).get()
+}
+
+pub fn timeout_ms(cs: &CriticalSection) -> u32 {
+    TIMEOUT_MS.borrow(cs).get()
+}
+
+// 操作がないとみなすまでの時間を変える。0なら止めない（止まっていれば再開する）。
+pub fn set_timeout_ms(ms: u32) {
+    free(|cs| {
+        TIMEOUT_MS.borrow(cs).set(ms);
+        note_activity(cs);
+    });
+}
+
+// 操作があったときに呼ぶ。止まっていれば点滅を再開してtrueを返す。
+pub fn note_activity(cs: &CriticalSection) -> bool {
+    if let Some(timer) = IDLE_TIMER.borrow(cs).get() {
+        LAST_ACTIVITY.borrow(cs).set(timer.get_counter().ticks());
+    }
+    if !IDLE.borrow(cs).replace(false) {
+        return false;
+    }
+    defmt::info!("idle off: activity, blinking resumed");
+    crate::restart_blink(cs);
+    true
+}
+
+// タクトスイッチを押したときにメインループから呼ぶ。止まっていたのを戻したなら、
+// 続く短押しをtake_wake_press()で捨てられるように覚えておく。
+pub fn note_button_press(cs: &CriticalSection) {
+    if note_activity(cs) {
+        WAKE_PRESS.borrow(cs).set(true);
+    }
+}
+
+// 止まっていたのを戻した押下の短押しならtrue（印は消す）
+pub fn take_wake_press() -> bool {
+    free(|cs| WAKE_PRESS.borrow(cs).replace(false))
+}
+
+// メインループから呼ぶ。最後の操作からタイムアウトの時間がたっていれば、消灯してALARM0を止める。
+pub fn check(now_us: u64) {
+    free(|cs| {
+        let timeout_ms = timeout_ms(cs);
+        if timeout_ms == 0 || is_idle(cs) {
+            return;
+        }
+        let last = LAST_ACTIVITY.borrow(cs).get();
+        if time::elapsed_us(now_us, last) < u64::from(timeout_ms) * 1000 {
+            return;
+        }
+        // ALARM0の割り込みは、次に入ったときにTIMER_IRQ_0が止める
+        IDLE.borrow(cs).set(true);
+        led::write_led(cs, led::LED_OFF_DUTY);
+        crate::tone::gate(cs, false);
+        defmt::info!(
+            "idle off: no activity for {=u32} ms, blinking stopped",
+            timeout_ms
+        );
+    });
+}
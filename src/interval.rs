@@ -0,0 +1,106 @@
+// 実行時に変更できる点滅間隔
+//
+// ALARM0_INTERVAL_MSは起動時の値で、実際にスケジュールに使うのはこちらの値。
+// TIMER_IRQ_0は毎回ここから間隔を読んで次のALARMをスケジュールするので、
+// 変更は次の割り込みから反映される。
+//
+// 間隔の変化の速さの制限（スルーレート）
+//   ボタンのタップテンポやエンコーダー、レジスタ（register_map.rs）で間隔を大きく変えると、点滅の速さが急に変わって目につく。
+//   set_interval_slew(rate)で、間隔を1秒あたりrate msまでしか変えないようにできる。
+//     ・set_interval_ms()は目標の間隔（interval_ms()）を変えるだけで、実際に使う間隔（current_ms()）は
+//       TIMER_IRQ_0のたびにadvance()で目標に近づける。目標に届いたらそこで止まる（行き過ぎない）。
+//     ・1回の割り込みの間には、今の間隔の分だけ時間がたったとみなす（Blinkでは実際にそうなる）。
+//       1回に変えられる量は rate × 今の間隔 / 1000 ms で、1 msに満たない端数は次の割り込みに持ち越す。
+//     ・rateをINTERVAL_SLEW_INSTANT（u32::MAX、起動時の値）にすると、制限をやめて目標をすぐに使う（これまでの動き）。
+//       制限している途中でINTERVAL_SLEW_INSTANTにすると、その場で目標の間隔になる。rateを0にすると間隔は変わらなくなる。
+//   知らせ（notify.rs）や時間を決めた点滅（blink_burst.rs）、設定のリセットのように、決まった間隔にすぐ変えたいときは
+//   set_interval_now()を使う（制限しない）。長押しの設定リセットでは、制限もINTERVAL_SLEW_INSTANTに戻す。
+
+use crate::cs_trace::free;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+
+// 変化の速さを制限しない
+pub const INTERVAL_SLEW_INSTANT: u32 = u32::MAX;
+
+// 目標の間隔
+static BLINK_INTERVAL_MS: Mutex<Cell<u32>> = Mutex::new(Cell::new(crate::ALARM0_INTERVAL_MS));
+// 実際に使う間隔
+static CURRENT_MS: Mutex<Cell<u32>> = Mutex::new(Cell::new(crate::ALARM0_INTERVAL_MS));
+// 1秒あたりに変えてよい間隔（ms）
+static SLEW_MS_PER_S: Mutex<Cell<u32>> = Mutex::new(Cell::new(INTERVAL_SLEW_INSTANT));
+// 1 msに満たないので持ち越した変化量（1/1000 ms単位）
+static SLEW_CARRY: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+// 目標の間隔（UARTなどで設定した値）
+pub fn interval_ms(cs: &CriticalSection) -> u32 {
+    BLINK_INTERVAL_MS.borrow(cs).get()
+}
+
+// 実際にスケジュールに使っている間隔。制限していなければinterval_ms()と同じ。
+pub fn current_ms(cs: &CriticalSection) -> u32 {
+    CURRENT_MS.borrow(cs).get()
+}
+
+// 目標の間隔を変える。制限していれば、TIMER_IRQ_0のたびに少しずつ近づける。
+// ALARMでスケジュールできない長さはMAX_ALARM_INTERVAL_MSに丸める
+pub fn set_interval_ms(cs: &CriticalSection, ms: u32) {
+    BLINK_INTERVAL_MS
+        .borrow(cs)
+        .set(ms.min(crate::MAX_ALARM_INTERVAL_MS));
+    if SLEW_MS_PER_S.borrow(cs).get() == INTERVAL_SLEW_INSTANT {
+        jump_to_target(cs);
+    }
+}
+
+// 制限せずに、すぐにmsの間隔にする
+pub fn set_interval_now(cs: &CriticalSection, ms: u32) {
+    set_interval_ms(cs, ms);
+    jump_to_target(cs);
+}
+
+fn jump_to_target(cs: &CriticalSection) {
+    CURRENT_MS.borrow(cs).set(interval_ms(cs));
+    SLEW_CARRY.borrow(cs).set(0);
+}
+
+// 1秒あたりに変えてよい間隔をrate msにする。INTERVAL_SLEW_INSTANTなら制限しない。
+pub fn set_interval_slew(rate: u32) {
+    free(|cs| {
+        SLEW_MS_PER_S.borrow(cs).set(rate);
+        if rate == INTERVAL_SLEW_INSTANT {
+            jump_to_target(cs);
+        }
+    });
+}
+
+pub fn interval_slew(cs: &CriticalSection) -> u32 {
+    SLEW_MS_PER_S.borrow(cs).get()
+}
+
+// TIMER_IRQ_0から1回ずつ呼ぶ。実際に使う間隔を目標に近づける。
+pub fn advance(cs: &CriticalSection) {
+    let current = current_ms(cs);
+    let target = interval_ms(cs);
+    if current == target {
+        SLEW_CARRY.borrow(cs).set(0);
+        return;
+    }
+    let rate = SLEW_MS_PER_S.borrow(cs).get();
+    // 今の間隔の分だけ時間がたったとみなしたときの変化量（1/1000 ms単位）
+    let carry = SLEW_CARRY.borrow(cs);
+    // 間隔が0でも止まらないように、少なくとも1 msたったとみなす
+    let change = u64::from(rate) * u64::from(current.max(1)) + u64::from(carry.get());
+    let step = (change / 1000).min(u64::from(current.abs_diff(target))) as u32;
+    carry.set((change % 1000) as u32);
+    let next = if target > current {
+        current + step
+    } else {
+        current - step
+    };
+    CURRENT_MS.borrow(cs).set(next);
+    if next == target {
+        carry.set(0);
+        defmt::debug!("interval: reached {=u32} ms", target);
+    }
+}
@@ -0,0 +1,246 @@
+// 赤外線リモコン（NECフォーマット）の受信（GPIO13）
+//
+// 配線
+//   38 kHzの赤外線受信モジュール（TSOP38238、VS1838Bなど）の出力をGPIO13につなぐ。内部プルアップ。
+//   モジュールは搬送波を受けている間（マーク）Low、受けていない間（スペース）Highを出す。
+//
+// NECフォーマット
+//   フレーム : リーダー（マーク9 ms + スペース4.5 ms）→ 32ビット → ストップ（マーク562.5 µs）
+//   ビット   : マーク562.5 µsのあと、スペースが562.5 µsなら0、1687.5 µsなら1
+//   32ビット : アドレス、アドレスの反転、コマンド、コマンドの反転の順に1バイトずつ、それぞれLSBから
+//   リピート : ボタンを押し続けると約110 msごとに、マーク9 ms + スペース2.25 ms + マーク562.5 µsだけが来る
+//   反転のバイトが合わないフレームは捨てる。アドレスを16bitに広げた拡張NECのリモコンは
+//   アドレスの反転が合わないので、このモジュールでは受け付けない。
+//
+// 受信のしかた
+//   両方のエッジで割り込み（IO_IRQ_BANK0）を入れ、前のエッジからの時間を測る。
+//   立ち上がりで終わった時間はマーク、立ち下がりで終わった時間はスペースの長さになる。
+//   どの長さにも入らない時間が来たら、そのフレームは捨ててリーダーを待ち直す。
+//
+// 許容する時間のずれ
+//   受信モジュールは搬送波の立ち上がりを検出するまでに数周期かかるので、マークは100 µsほど短く、
+//   スペースはその分長く出ることがある。そのため、どの長さもNEC_TOLERANCE_PERCENT（25%）までずれていても受け付ける。
+//     562.5 µs  : 422〜703 µs
+//     1687.5 µs : 1266〜2109 µs
+//     2.25 ms   : 1.69〜2.81 ms
+//     4.5 ms    : 3.38〜5.63 ms
+//     9 ms      : 6.75〜11.25 ms
+//   同じところに来うる長さどうし（ビットのスペースの0と1、リーダーの後のリピートとフレームのスペース）は、
+//   25%なら範囲が重ならない（それぞれ3倍と2倍違う）。
+//
+// 受け取ったコマンドはIR_KEYMAPで処理（Work）に変え、遅延実行キュー（deferred.rs）に積む。
+// ボタン1〜4は、よく出回っている21キーのNECリモコン（"CAR MP3"と書いてあるもの）のコード。
+// 点滅がゆっくり明るくなったり暗くなったりする「呼吸」のモードはないので、今あるモードに割り当てている。
+// リピートは最後のコマンドをログに出すだけで、処理はくり返さない（押し続けてもモードが何度も変わらないように）。
+// 最後のフレームかリピートからNEC_REPEAT_TIMEOUT_MSより後のリピートは、何のリピートかわからないので捨てる。
+
+use crate::deferred;
+use crate::idle_off;
+use crate::mode::LedMode;
+use crate::pull::{self, Pull};
+use crate::work::Work;
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::{Cell, RefCell};
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp_pico::hal::gpio;
+use rp_pico::hal::timer::Timer;
+
+pub const IR_PULL: Pull = Pull::Up;
+pub const NEC_TOLERANCE_PERCENT: u32 = 25;
+pub const NEC_REPEAT_TIMEOUT_MS: u32 = 150;
+
+// 時間はµsの10倍で持つ（562.5 µsを整数で表すため）
+const BIT_MARK_X10: u32 = 5625;
+const ZERO_SPACE_X10: u32 = 5625;
+const ONE_SPACE_X10: u32 = 16875;
+const REPEAT_SPACE_X10: u32 = 22500;
+const LEADER_SPACE_X10: u32 = 45000;
+const LEADER_MARK_X10: u32 = 90000;
+
+// 同じところに来うる長さの範囲が重ならないように（長い方の下限 > 短い方の上限）
+const fn separated(short: u32, long: u32) -> bool {
+    short * (100 + NEC_TOLERANCE_PERCENT) < long * (100 - NEC_TOLERANCE_PERCENT)
+}
+const _: () = assert!(
+    separated(ZERO_SPACE_X10, ONE_SPACE_X10)
+        && separated(REPEAT_SPACE_X10, LEADER_SPACE_X10)
+        && separated(BIT_MARK_X10, LEADER_MARK_X10)
+);
+
+// 受け取ったコマンドと実行する処理。リモコンを変えたときはここを書き換える。
+const IR_KEYMAP: &[(u8, Work)] = &[
+    // 1
+    (0x0C, Work::SetMode(LedMode::Blink)),
+    // 2
+    (0x18, Work::SetMode(LedMode::Solid)),
+    // 3
+    (0x5E, Work::SetMode(LedMode::Off)),
+    // 4
+    (0x08, Work::Flash(5)),
+];
+
+pub type IrPin = pull::InputPin<gpio::bank0::Gpio13>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    // リーダーのマークを待っている
+    Idle,
+    // リーダーのマークを受けた。次のスペースでフレームかリピートかが決まる。
+    Leader,
+    // n個のビットを受けた
+    Bits(u8),
+    // リピートのスペースを受けた。次のマークで終わる。
+    Repeat,
+}
+
+struct Decoder {
+    state: State,
+    bits: u32,
+    last_edge: Option<u32>,
+    // 最後に受け取ったコマンドと、そのフレームかリピートの時刻
+    last_command: Option<(u8, u32)>,
+}
+
+impl Decoder {
+    fn is(duration_us: u32, expected_x10: u32) -> bool {
+        // スペースはボタンを押していない間ずっと続くので、掛け算であふれないようにする
+        let duration_x10 = duration_us.saturating_mul(10);
+        duration_x10.abs_diff(expected_x10) <= expected_x10 * NEC_TOLERANCE_PERCENT / 100
+    }
+
+    // マーク（Low）が終わったとき。受け取ったコマンドがあれば、(コマンド, リピートか)を返す。
+    fn mark(&mut self, duration_us: u32, now: u32) -> Option<(u8, bool)> {
+        if Self::is(duration_us, LEADER_MARK_X10) {
+            self.state = State::Leader;
+            return None;
+        }
+        if !Self::is(duration_us, BIT_MARK_X10) {
+            self.state = State::Idle;
+            return None;
+        }
+        match self.state {
+            // ビットの前のマーク
+            State::Bits(n) if n < 32 => None,
+            // ストップのマーク
+            State::Bits(_) => {
+                self.state = State::Idle;
+                let [address, address_inv, command, command_inv] = self.bits.to_le_bytes();
+                if address != !address_inv || command != !command_inv {
+                    defmt::debug!("ir: bad complement {=u32:08x}", self.bits);
+                    return None;
+                }
+                self.last_command = Some((command, now));
+                defmt::info!("ir: address {=u8:02x} command {=u8:02x}", address, command);
+                Some((command, false))
+            }
+            State::Repeat => {
+                self.state = State::Idle;
+                let (command, at) = self.last_command?;
+                if now.wrapping_sub(at) > NEC_REPEAT_TIMEOUT_MS * 1000 {
+                    self.last_command = None;
+                    return None;
+                }
+                self.last_command = Some((command, now));
+                Some((command, true))
+            }
+            _ => {
+                self.state = State::Idle;
+                None
+            }
+        }
+    }
+
+    // スペース（High）が終わったとき
+    fn space(&mut self, duration_us: u32) {
+        self.state = match self.state {
+            State::Leader if Self::is(duration_us, LEADER_SPACE_X10) => {
+                self.bits = 0;
+                State::Bits(0)
+            }
+            State::Leader if Self::is(duration_us, REPEAT_SPACE_X10) => State::Repeat,
+            State::Bits(n) if n < 32 => {
+                let bit = if Self::is(duration_us, ONE_SPACE_X10) {
+                    1
+                } else if Self::is(duration_us, ZERO_SPACE_X10) {
+                    0
+                } else {
+                    self.state = State::Idle;
+                    return;
+                };
+                self.bits |= bit << n;
+                State::Bits(n + 1)
+            }
+            // リーダーの前の長いスペース（ボタンを押していない間）など
+            _ => State::Idle,
+        };
+    }
+}
+
+static IR_PIN: GlobalPeripheral<IrPin> = initial_global_peripheral();
+static IR_TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+static DECODER: Mutex<RefCell<Decoder>> = Mutex::new(RefCell::new(Decoder {
+    state: State::Idle,
+    bits: 0,
+    last_edge: None,
+    last_command: None,
+}));
+// 最後に受け取ったコマンド（リピートは含まない）
+static LAST_COMMAND: Mutex<Cell<Option<u8>>> = Mutex::new(Cell::new(None));
+
+pub fn init(cs: &CriticalSection, pin: IrPin, timer: Timer) {
+    pin.set_interrupt_enabled(gpio::Interrupt::EdgeHigh, true);
+    pin.set_interrupt_enabled(gpio::Interrupt::EdgeLow, true);
+    IR_PIN.borrow(cs).replace(Some(pin));
+    IR_TIMER.borrow(cs).set(Some(timer));
+}
+
+pub fn last_command(cs: &CriticalSection) -> Option<u8> {
+    LAST_COMMAND.borrow(cs).get()
+}
+
+// IO_IRQ_BANK0から呼ぶ
+pub fn on_interrupt(cs: &CriticalSection) {
+    let mut pin = IR_PIN.borrow(cs).borrow_mut();
+    let Some(pin) = pin.as_mut() else {
+        return;
+    };
+    let rose = pin.interrupt_status(gpio::Interrupt::EdgeHigh);
+    let fell = pin.interrupt_status(gpio::Interrupt::EdgeLow);
+    if !rose && !fell {
+        return;
+    }
+    pin.clear_interrupt(gpio::Interrupt::EdgeHigh);
+    pin.clear_interrupt(gpio::Interrupt::EdgeLow);
+
+    let Some(timer) = IR_TIMER.borrow(cs).get() else {
+        return;
+    };
+    let now = timer.get_counter_low();
+    let mut decoder = DECODER.borrow(cs).borrow_mut();
+    let Some(last) = decoder.last_edge.replace(now) else {
+        return;
+    };
+    let duration_us = now.wrapping_sub(last);
+    match (rose, fell) {
+        (true, false) => {
+            let Some((command, repeat)) = decoder.mark(duration_us, now) else {
+                return;
+            };
+            if repeat {
+                defmt::debug!("ir: repeat {=u8:02x}", command);
+                return;
+            }
+            LAST_COMMAND.borrow(cs).set(Some(command));
+            idle_off::note_activity(cs);
+            match IR_KEYMAP.iter().find(|(code, _)| *code == command) {
+                Some((_, work)) => {
+                    deferred::push(cs, *work);
+                }
+                None => defmt::debug!("ir: command {=u8:02x} is not mapped", command),
+            }
+        }
+        (false, true) => decoder.space(duration_us),
+        // 区別できないほど短い間隔で2つのエッジが来た。そのフレームは捨てる。
+        _ => decoder.state = State::Idle,
+    }
+}
@@ -0,0 +1,73 @@
+// ALARM0の割り込みの応答時間（遅れ）を測る
+//
+// ALARM0の比較レジスタには発火させたい時刻が入っている。TIMER_IRQ_0に入ったらいちばん最初に
+// カウンタ（TIMERAWL）を読み、その差を「発火するはずだった時刻から割り込みの処理を始めるまでの時間」とする。
+//   遅れ = 割り込みの入り口で読んだカウンタ - 比較レジスタの値
+// ほかの割り込みの処理中や、free()で割り込みを止めている間に発火すると、その分だけ遅れる。
+// 優先度の違う割り込みを混ぜたときに、ALARM0がどれだけ待たされているかを数字で確かめるためのもの。
+//
+// 測った値に含まれるもの
+//   ・Cortex-M0+が割り込みに入るまでの固定の時間（レジスタの退避などで16サイクル）と、
+//     入り口からカウンタを読むまでの数命令。125 MHzでは0.2 µs程度なので、カウンタの1 µsの分解能では
+//     ほとんど0か1 µsとして見える。遅れが0 µsにならないのはこの分が入っているため。
+//   ・カウンタは1 µs単位なので、値には最大1 µsの丸めがある。
+//
+// カウンタの下位32bitの一周
+//   比較レジスタはカウンタの下位32bitと比べるので、ここでも下位32bitどうしをwrapping_sub()で引く。
+//   発火してから読むまでは一周（約71分）よりずっと短いので、一周をまたいでも正しい差になる。
+//   差が2^31以上なら、発火する前の時刻を読んだ（発火していないのに呼ばれた）ものとして数えない。
+//
+// 最大値は起動してからのものと、前に報告してからのものを持つ。
+// メインループがLATENCY_REPORT_INTERVAL_MSごとにreport()で前回からの最大値をログに出す。
+
+use crate::cs_trace::free;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp_pico::hal::pac;
+
+pub const LATENCY_REPORT_INTERVAL_MS: u32 = 10_000;
+
+// 起動してからの最大値
+static MAX_LATENCY_US: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+// 前にreport()してからの最大値。測っていなければNone。
+static WINDOW_MAX_US: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+
+// TIMER_IRQ_0のいちばん最初で呼ぶ。カウンタの下位32bitを読むだけ。
+#[inline(always)]
+pub fn entry_timestamp() -> u32 {
+    // 読み出すだけなので、Timerの所有とは関係なく読める
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    timer.timerawl().read().bits()
+}
+
+// ALARM0が発火していたときに、入り口で読んだカウンタの値で遅れを記録する
+pub fn record_alarm0(cs: &CriticalSection, entered_at: u32) {
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    let target = timer.alarm0().read().bits();
+    let latency_us = entered_at.wrapping_sub(target);
+    if latency_us >= 1 << 31 {
+        return;
+    }
+    let max = MAX_LATENCY_US.borrow(cs);
+    max.set(max.get().max(latency_us));
+    let window = WINDOW_MAX_US.borrow(cs);
+    window.set(Some(window.get().map_or(latency_us, |m| m.max(latency_us))));
+}
+
+// 起動してからの最大の遅れ（µs）
+pub fn max_latency_us(cs: &CriticalSection) -> u32 {
+    MAX_LATENCY_US.borrow(cs).get()
+}
+
+// メインループから呼ぶ。前に呼んでからの最大の遅れをログに出す。
+pub fn report() {
+    let (window, max) = free(|cs| (WINDOW_MAX_US.borrow(cs).take(), max_latency_us(cs)));
+    match window {
+        Some(window) => defmt::info!(
+            "alarm0 irq latency: max {=u32} us (since boot {=u32} us)",
+            window,
+            max
+        ),
+        None => defmt::debug!("alarm0 irq latency: no samples"),
+    }
+}
@@ -0,0 +1,260 @@
+// ALARM0の間隔にわざとばらつき（ジッタ）を足して、scheduleし直す処理を試す
+//
+// ふだんの点滅は同じ間隔でscheduleし直すので、間隔が毎回変わるときにだけ出る不具合
+// （丸め、範囲の外の間隔、前の値が残るなど）は見つからない。このテストを有効にすると、
+// do_tick()が決めた間隔に-振れ幅〜+振れ幅の疑似乱数（xorshift32）を足してscheduleし、
+// 実際に発火した時刻から「頼んだ間隔どおりに発火したか」を確かめる。
+// 有効にしている間は、どのモードの点滅にもばらつきが入る。rate_control.rsで頻度を補正している間は足さない。
+//
+// 範囲
+//   振れ幅はJITTER_MAX_US以下。足した結果はtime::clamp_interval()と同じくMIN_INTERVAL_US〜MAX_INTERVAL_USに収め、
+//   収めたあとの値を「頼んだ間隔」として記録する（丸めた分がずれとして見えないように）。
+//
+// 測り方（1回の発火ごと）
+//   前の割り込みの入り口の時刻    entered      （irq_latency::entry_timestamp()）
+//   前の割り込みの比較レジスタ    previous     （前の期限）
+//   今の割り込みの比較レジスタ    target       （発火するはずだった時刻）
+//   今の割り込みの入り口の時刻    now
+//   ALARM0は前の期限から数えてscheduleし直す（main.rsのreschedule_alarm0()）ので、target - previousが頼んだ間隔になる。
+//   実際の間隔 = now - entered には、頼んだ間隔のほかに、発火してから入り口までの遅れの差が入る。
+//     処理の時間 = now - target（発火してから入り口まで）
+//   そこで 残差 = (target - previous) - 頼んだ間隔 を求める。
+//   期限がすでに迫っていたときだけ、ALARMに書く時刻がtime::SCHEDULE_MARGIN_USだけ後になるので、
+//   残差は0か、数µsだけ正になる。絶対値がJITTER_TOLERANCE_USを超えたら、scheduleの計算が間違っている。
+//
+// JITTER_REPORT_SAMPLES回ごとに、頼んだずれ（間隔 - ばらつきを足す前の間隔）と、測ったずれ
+// （実際の間隔 - ばらつきを足す前の間隔）の最小・最大・平均、処理の時間の平均、残差の最大をログに出す。
+// 振れ幅の一様分布なら、どちらのずれも平均がほぼ0、最小と最大がほぼ±振れ幅になり、
+// 遅れは前の期限に加わらないので、測ったずれは頼んだずれと、遅れの差（数µs）しか違わない。
+//
+// 起動時は無効。UARTの jitter US で振れ幅を決めて始め、jitter off でやめる。
+
+use crate::cs_trace::free;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::time;
+use rp_pico::hal::pac;
+
+pub const JITTER_MAX_US: u32 = 500_000;
+pub const JITTER_REPORT_SAMPLES: u32 = 32;
+pub const JITTER_TOLERANCE_US: u32 = 5;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum JitterError {
+    // 振れ幅がJITTER_MAX_USより大きい
+    OutOfRange,
+}
+
+impl JitterError {
+    // UARTなどに出力するときの説明
+    pub fn name(&self) -> &'static str {
+        match self {
+            JitterError::OutOfRange => "jitter out of range",
+        }
+    }
+}
+
+// 1つの値の最小・最大・合計
+#[derive(Clone, Copy)]
+pub struct Spread {
+    pub min: i32,
+    pub max: i32,
+    sum: i64,
+}
+
+impl Spread {
+    const EMPTY: Spread = Spread {
+        min: i32::MAX,
+        max: i32::MIN,
+        sum: 0,
+    };
+
+    fn add(&mut self, value: i32) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += i64::from(value);
+    }
+
+    pub fn mean(&self, samples: u32) -> i32 {
+        (self.sum / i64::from(samples.max(1))) as i32
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct JitterStats {
+    pub samples: u32,
+    // 頼んだずれと、測ったずれ（µs）
+    pub requested: Spread,
+    pub measured: Spread,
+    // 発火してから割り込みに入るまでの時間（µs）
+    pub overhead: Spread,
+    // 残差の絶対値の最大（µs）
+    pub max_residual_us: u32,
+}
+
+impl JitterStats {
+    const EMPTY: JitterStats = JitterStats {
+        samples: 0,
+        requested: Spread::EMPTY,
+        measured: Spread::EMPTY,
+        overhead: Spread::EMPTY,
+        max_residual_us: 0,
+    };
+
+    pub fn passed(&self) -> bool {
+        self.max_residual_us <= JITTER_TOLERANCE_US
+    }
+}
+
+// 前の割り込みでscheduleした間隔
+#[derive(Clone, Copy)]
+struct Scheduled {
+    entered: u32,
+    // 前の割り込みの比較レジスタ（前の期限）
+    previous: u32,
+    // ばらつきを足す前の間隔と、足して範囲に収めた間隔
+    nominal_us: u32,
+    requested_us: u32,
+}
+
+// 0なら無効
+static AMPLITUDE_US: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+static RNG_STATE: Mutex<Cell<u32>> = Mutex::new(Cell::new(0x2545_F491));
+// 今の割り込みの入り口の時刻と比較レジスタ
+static ENTERED: Mutex<Cell<Option<(u32, u32)>>> = Mutex::new(Cell::new(None));
+// next_interval_us()で決めて、まだscheduleしていない間隔（ばらつきを足す前, 足した後）
+static CHOSEN: Mutex<Cell<Option<(u32, u32)>>> = Mutex::new(Cell::new(None));
+static LAST: Mutex<Cell<Option<Scheduled>>> = Mutex::new(Cell::new(None));
+static STATS: Mutex<Cell<JitterStats>> = Mutex::new(Cell::new(JitterStats::EMPTY));
+// 集め終わってまだログに出していない統計
+static FINISHED: Mutex<Cell<Option<JitterStats>>> = Mutex::new(Cell::new(None));
+
+// 振れ幅を決めて始める（0でやめる）。途中までの統計は捨てる。
+pub fn set_amplitude_us(us: u32) -> Result<(), JitterError> {
+    if us > JITTER_MAX_US {
+        return Err(JitterError::OutOfRange);
+    }
+    free(|cs| {
+        AMPLITUDE_US.borrow(cs).set(us);
+        LAST.borrow(cs).set(None);
+        CHOSEN.borrow(cs).set(None);
+        STATS.borrow(cs).set(JitterStats::EMPTY);
+    });
+    Ok(())
+}
+
+pub fn amplitude_us(cs: &CriticalSection) -> u32 {
+    AMPLITUDE_US.borrow(cs).get()
+}
+
+fn next_random(cs: &CriticalSection) -> u32 {
+    let state = RNG_STATE.borrow(cs);
+    let mut x = state.get();
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    state.set(x);
+    x
+}
+
+// TIMER_IRQ_0でALARM0が発火していたときに、入り口で読んだカウンタの値で呼ぶ
+pub fn record_fire(cs: &CriticalSection, entered_at: u32) {
+    if amplitude_us(cs) == 0 {
+        return;
+    }
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    let target = timer.alarm0().read().bits();
+    ENTERED.borrow(cs).set(Some((entered_at, target)));
+    let Some(last) = LAST.borrow(cs).take() else {
+        return;
+    };
+    let actual_us = entered_at.wrapping_sub(last.entered);
+    let overhead_us = entered_at.wrapping_sub(target);
+    let residual = target.wrapping_sub(last.previous) as i32 - last.requested_us as i32;
+
+    let stats_cell = STATS.borrow(cs);
+    let mut stats = stats_cell.get();
+    stats.samples += 1;
+    stats
+        .requested
+        .add(last.requested_us as i32 - last.nominal_us as i32);
+    stats
+        .measured
+        .add(actual_us as i32 - last.nominal_us as i32);
+    stats.overhead.add(overhead_us as i32);
+    stats.max_residual_us = stats.max_residual_us.max(residual.unsigned_abs());
+    if stats.samples >= JITTER_REPORT_SAMPLES {
+        FINISHED.borrow(cs).set(Some(stats));
+        stats = JitterStats::EMPTY;
+    }
+    stats_cell.set(stats);
+}
+
+// do_tick()から呼ぶ。有効なら、next_msにばらつきを足した間隔（µs）を返す。
+pub fn next_interval_us(cs: &CriticalSection, next_ms: u32) -> Option<u32> {
+    let amplitude = amplitude_us(cs);
+    if amplitude == 0 {
+        return None;
+    }
+    let nominal_us = time::clamp_interval(next_ms.saturating_mul(1000));
+    // -amplitude〜+amplitudeの一様分布
+    let offset = (next_random(cs) % (2 * amplitude + 1)) as i64 - i64::from(amplitude);
+    let requested = (i64::from(nominal_us) + offset).clamp(
+        i64::from(time::MIN_INTERVAL_US),
+        i64::from(time::MAX_INTERVAL_US),
+    ) as u32;
+    CHOSEN.borrow(cs).set(Some((nominal_us, requested)));
+    Some(requested)
+}
+
+// do_tick()でALARM0をscheduleし直した直後に呼ぶ
+pub fn after_schedule(cs: &CriticalSection) {
+    let Some((nominal_us, requested_us)) = CHOSEN.borrow(cs).take() else {
+        return;
+    };
+    let Some((entered, previous)) = ENTERED.borrow(cs).take() else {
+        return;
+    };
+    LAST.borrow(cs).set(Some(Scheduled {
+        entered,
+        previous,
+        nominal_us,
+        requested_us,
+    }));
+}
+
+// メインループから呼ぶ。JITTER_REPORT_SAMPLES回分が集まっていればログに出す。
+pub fn report() {
+    let Some(stats) = free(|cs| FINISHED.borrow(cs).take()) else {
+        return;
+    };
+    let n = stats.samples;
+    defmt::info!(
+        "jitter: requested {=i32}..{=i32} mean {=i32} us, measured {=i32}..{=i32} mean {=i32} us",
+        stats.requested.min,
+        stats.requested.max,
+        stats.requested.mean(n),
+        stats.measured.min,
+        stats.measured.max,
+        stats.measured.mean(n)
+    );
+    if stats.passed() {
+        defmt::info!(
+            "jitter: {=u32} samples ok, irq overhead mean {=i32} us, residual max {=u32} us",
+            n,
+            stats.overhead.mean(n),
+            stats.max_residual_us
+        );
+    } else {
+        defmt::error!(
+            "jitter: residual {=u32} us exceeds {=u32} us, alarm scheduled off target",
+            stats.max_residual_us,
+            JITTER_TOLERANCE_US
+        );
+    }
+}
+
+// 今集めている途中の回数（UARTの jitter で返す）
+pub fn pending_samples(cs: &CriticalSection) -> u32 {
+    STATS.borrow(cs).get().samples
+}
@@ -0,0 +1,137 @@
+// ウォッチドッグでリセットされる直前に、最後のログを出す（watchdogフィーチャー）
+//
+// watchdogフィーチャーを有効にすると、WATCHDOG_TIMEOUT_USのウォッチドッグを動かし、メインループがfeed()する。
+// メインループが止まる（無限ループ、待ちっぱなしなど）とウォッチドッグがチップをリセットするが、
+// リセットされると何が起きていたのかはわからない。そこでリセットの直前に、
+// 「ウォッチドッグでリセットされる」ことと診断用のカウンタ（diagnostics.rs）をdefmtに出しておく。
+//
+// 時間の関係
+//   ウォッチドッグのカウンタは、最後のfeed()からWATCHDOG_TIMEOUT_USで0になりリセットする。
+//   SysTickでLAST_GASP_POLL_MSごとにカウンタの残りを読み、残りがLAST_GASP_MARGIN_US以下なら最後のログを出す。
+//
+//     feed()          最後のログ                               リセット
+//     |---------------|~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~|
+//     0               TIMEOUT - MARGIN 〜 TIMEOUT - MARGIN + POLL  TIMEOUT
+//
+//   ログはリセットのMARGIN - POLL〜MARGIN前（40〜50 ms前）に出る。defmt（RTT）はホストが読み出すまで
+//   RAMのバッファに残り、起動し直すと消えるので、この間にプローブが読み出せるだけの余裕をとっている。
+//   ALARMは4つとも使っているので、別のALARMではなくSysTickを使う。
+//   tick-sourceフィーチャーではSysTickが1 msごとに入る（tick_source.rs）ので、同じ割り込みから確かめる。
+//
+// 短く、確実に終わるように
+//   ・SysTickはLAST_GASP_PRIORITYで、ほかの割り込み（NORMAL_PRIORITY）より優先度が高い。
+//     メインループだけでなく、ほかの割り込みの中で止まっていても入れる。
+//     Cortex-M0+の優先度は4段階しかないので、非常停止の割り込みと同じ段（0x00）にする（互いには割り込まない）。
+//   ・そのため、ほかの割り込みがRefCellを借りている途中に入ることがある。ここではRefCellには触らず、
+//     WATCHDOGのレジスタとアトミックの印、Cellのカウンタ（diagnostics::snapshot()）だけを読む。
+//   ・ログは1回だけ出す。リセットまでにfeed()されたら（止まっていたのが戻ったら）、印を消してそのことも出す。
+//   割り込みを止めたまま（free()の中で）止まっているときは、SysTickも入れないので最後のログは出ない。
+//
+// 起動し直したあとは、reset_cause.rsが「watchdog timeout」と出す。
+
+use crate::cs_trace::free;
+use crate::diagnostics;
+use crate::estop::{ESTOP_PRIORITY, NORMAL_PRIORITY};
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::peripheral::scb::SystemHandler;
+use cortex_m::peripheral::SCB;
+#[cfg(not(feature = "tick-source"))]
+use cortex_m::peripheral::{syst::SystClkSource, SYST};
+use fugit::ExtU32;
+use rp_pico::hal::pac;
+use rp_pico::hal::watchdog::Watchdog;
+
+// main.rsのコメントにあった1.05秒
+pub const WATCHDOG_TIMEOUT_US: u32 = 1_050_000;
+pub const LAST_GASP_MARGIN_US: u32 = 50_000;
+pub const LAST_GASP_POLL_MS: u32 = 10;
+pub const LAST_GASP_PRIORITY: u8 = ESTOP_PRIORITY;
+
+// 確かめる間隔より余裕が短いと、1回も確かめないうちにリセットされることがある
+const _: () = assert!(LAST_GASP_POLL_MS * 1000 < LAST_GASP_MARGIN_US);
+const _: () = assert!(LAST_GASP_MARGIN_US < WATCHDOG_TIMEOUT_US);
+// 数字が小さいほど優先度が高い
+const _: () = assert!(LAST_GASP_PRIORITY < NORMAL_PRIORITY);
+
+static WATCHDOG: GlobalPeripheral<Watchdog> = initial_global_peripheral();
+// 最後のログを出したか
+static LOGGED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_priority(scb: &mut SCB) {
+    // ほかの割り込みに割り込むようになるのでunsafe。上のとおりRefCellには触らない。
+    unsafe {
+        scb.set_priority(SystemHandler::SysTick, LAST_GASP_PRIORITY);
+    }
+}
+
+// ウォッチドッグを動かし始める。ここからWATCHDOG_TIMEOUT_USごとにfeed()しなければリセットされる。
+pub fn start(mut watchdog: Watchdog) {
+    // デバッガで止めている間にリセットされないように
+    watchdog.pause_on_debug(true);
+    watchdog.start(WATCHDOG_TIMEOUT_US.micros());
+    free(|cs| WATCHDOG.borrow(cs).replace(Some(watchdog)));
+    defmt::info!(
+        "watchdog: {=u32} us, last gasp {=u32} us before reset",
+        WATCHDOG_TIMEOUT_US,
+        LAST_GASP_MARGIN_US
+    );
+}
+
+// SysTickをclk_sysでLAST_GASP_POLL_MSごとに割り込ませる（tick-sourceフィーチャーでは使わない）
+#[cfg(not(feature = "tick-source"))]
+pub fn start_poll(mut syst: SYST, system_clock_hz: u32) {
+    syst.set_clock_source(SystClkSource::Core);
+    // 24bitなので、125 MHzなら134 msまで
+    syst.set_reload(system_clock_hz / 1000 * LAST_GASP_POLL_MS - 1);
+    syst.clear_current();
+    syst.enable_interrupt();
+    syst.enable_counter();
+}
+
+// メインループから呼ぶ
+pub fn feed() {
+    free(|cs| {
+        if let Some(watchdog) = WATCHDOG.borrow(cs).borrow().as_ref() {
+            watchdog.feed();
+        }
+    });
+    if LOGGED.load(Ordering::Relaxed) {
+        LOGGED.store(false, Ordering::Relaxed);
+        defmt::warn!("watchdog: fed again after the last gasp, not resetting");
+    }
+}
+
+// ウォッチドッグを止める（shutdown.rsで止まる前に）
+pub fn stop() {
+    free(|cs| {
+        if let Some(watchdog) = WATCHDOG.borrow(cs).borrow().as_ref() {
+            watchdog.disable();
+        }
+    });
+}
+
+// リセットまでの残り（µs）。ウォッチドッグが動いていなければNone。
+fn remaining_us() -> Option<u32> {
+    // 読み出すだけなので、ウォッチドッグの状態には影響しない
+    let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+    let ctrl = watchdog.ctrl().read();
+    // RP2040-E1：カウンタは1 µsに2つずつ減る
+    ctrl.enable().bit_is_set().then(|| ctrl.time().bits() / 2)
+}
+
+// SysTickから呼ぶ。リセットが近ければ最後のログを出す。
+pub fn check() {
+    let Some(remaining) = remaining_us() else {
+        return;
+    };
+    if remaining > LAST_GASP_MARGIN_US || LOGGED.load(Ordering::Relaxed) {
+        return;
+    }
+    LOGGED.store(true, Ordering::Relaxed);
+    defmt::error!(
+        "watchdog about to reset in {=u32} us: {}",
+        remaining,
+        diagnostics::snapshot()
+    );
+}
@@ -0,0 +1,337 @@
+// オンボードLED（GPIO25）をPWMで駆動するモジュール
+//
+// GPIO25はPWMスライス4のチャンネルBにつながっている。
+// PWMにすることで点灯/消灯だけでなく明るさも変えられるようになる。
+//
+// 出力するピンはset_led_gpio()で実行中に変えられる。選べるのはLED_GPIOSのピンだけ。
+//   GPIO8  : PWMスライス4のチャンネルA
+//   GPIO9  : PWMスライス4のチャンネルB
+//   GPIO25 : PWMスライス4のチャンネルB（オンボードLED、起動時の出力先）
+// どのGPIOがどのPWMスライスの出力になるかはハードウェアで決まっているので、スライス4の
+// ピンに限っている（チャンネルAとBには同じデューティを書く）。例えばGPIO16はスライス0で
+// ブザー（tone.rs）が使っているので選べない。GPIO24もスライス4だが、Picoでは
+// VBUSの検出に使われている入力なので除いている。
+// 種類の違うGPIOを同じグローバル変数に入れるため、ピンはDynPinIdの型で持ち、
+// 機能（PWM/入力）は実行時にtry_set_function()で切り替える。
+// 出力をやめたピンはプルダウン付きの入力に戻す。
+//
+// 各モードが決めたデューティに、全体の明るさ（set_led_brightness()）を掛けてから出力する。
+// 明るさを変えたときは最後に書いたデューティで出力し直すので、次の点滅を待たずに反映される。
+//
+// 出力するデューティは次の順で決まる。
+//   1. モード（点滅、フェードなど）が決めたデューティ
+//   2. 全体の明るさを掛ける
+//   3. 周囲の明るさによる調光（ambient.rs、set_ambient_scale()）を掛ける
+//   3'. しばらく操作がないときの減光（idle_dim.rs、set_idle_dim_scale()）を掛ける
+//   4. （ガンマ補正などの見た目の補正を入れる場合はここ）
+//   5. 上限（set_brightness_cap()）で頭打ちにする
+// 上限は必ず最後にかける。補正の後でかけないと、補正で値が持ち上がったときに上限を超えてしまう。
+//
+// 点滅の点灯と消灯のデューティはON_BRIGHTNESSとOFF_BRIGHTNESS（blink_duty()）。
+// OFF_BRIGHTNESSを0より大きくすると、消灯の間も真っ暗にならずにうっすら光り、LEDの位置がいつも見える。
+// OFF_BRIGHTNESS = 0（LED_OFF_DUTY）にすれば、点灯と消灯を切り替えるこれまでの点滅になる。
+// 上限がOFF_BRIGHTNESS以下だと点灯と消灯が同じ明るさになって点滅が見えなくなるので、
+// OFF_BRIGHTNESSが0でないときはそのような上限は受け付けない（set_brightness_cap()）。
+// Offモードと、blink_times()の回数を終えたあとは、OFF_BRIGHTNESSに関係なく消灯する。
+// 抵抗を付け替えずに外付けのLEDを電流の上限近くで駆動する場合の安全装置なので、
+// 長押しの設定リセットでも上限は戻さない。
+//
+// LEDが点いているかの読み返し（自己診断用）
+//   is_on()     : 覚えている値から求める。最後にwrite_led()で書いたデューティに明るさと上限を掛けた値が0でなければ点灯。
+//   led_is_on() : ハードウェアを読む。PWMのピンの電圧は周期の途中でHighとLowを行き来するので、ピンの電圧ではなく
+//                 PWM4のスライスが有効か、今の出力先のチャンネルのレジスタ（CC）が0でないか、ピンがPWMの機能か、を読む。
+//                 非常停止（estop.rs）や出力の許可の入力（output_enable.rs）で出力をオーバーライドしていれば、
+//                 オーバーライドした電圧で決める。
+//   どちらもOFF_BRIGHTNESSのうっすらした光も点灯として扱う（is_lit()とは違い、電気的に出ているか）。
+//   アクティブLowでもチャンネルの出力を反転しているだけなので、CCはそのまま点灯している割合になる。
+//   オーバーライドの電圧だけは極性（output.rs）を見てオン/オフにする。
+//   2つが食い違えば、覚えている値と実際の出力がずれている（UARTの diag で確かめる）。
+//   ただし非常停止の間と出力が禁止の間は、覚えている値が点灯でも実際の出力は消灯になるのが正しい。
+
+use crate::config::Config;
+use crate::cs_trace::free;
+use crate::output::{self, OutputId};
+use crate::{fade, pin_table, shared, waveform};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp_pico::hal::gpio::{self, DynFunction, DynPinId, DynPullType, DynSioConfig, PinId};
+use rp_pico::hal::pac::io_bank0::gpio::gpio_ctrl::OUTOVER_A;
+use rp_pico::hal::{pac, pwm};
+
+// SetDutyCycleトレイトのset_duty_cycleメソッドを使うために必要。
+use embedded_hal::pwm::SetDutyCycle;
+
+pub type LedPwm = pwm::Slice<pwm::Pwm4, pwm::FreeRunning>;
+
+pub type LedPin = gpio::Pin<DynPinId, DynFunction, DynPullType>;
+
+// LEDを出力できるGPIOの番号。init()に渡すピンもこの順に並べる。
+pub const LED_GPIOS: [u8; 3] = [pin_table::LED_ALT_A, pin_table::LED_ALT_B, pin_table::LED];
+// 起動時の出力先の初期値（実際の出力先は起動時の設定のled_gpio、config.rs）
+pub const LED_DEFAULT_GPIO: u8 = pin_table::LED;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LedPinError {
+    // LED_GPIOSにないGPIO
+    NotAllowed,
+    // ピンの機能を切り替えられなかった
+    InvalidFunction,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CapError {
+    // 上限がOFF_BRIGHTNESS以下で、点滅が見えなくなる
+    BelowOffBrightness,
+}
+
+impl CapError {
+    // UARTなどに出力するときの説明
+    pub fn name(&self) -> &'static str {
+        match self {
+            CapError::BelowOffBrightness => "cap must be above OFF_BRIGHTNESS",
+        }
+    }
+}
+
+impl LedPinError {
+    // UARTなどに出力するときの説明
+    pub fn name(&self) -> &'static str {
+        match self {
+            LedPinError::NotAllowed => "gpio not allowed",
+            LedPinError::InvalidFunction => "cannot switch pin function",
+        }
+    }
+}
+
+// PWMスライスとピンはALARM0と一緒にshared.rsのSHAREDに置いている
+// 今出力しているGPIO
+static LED_GPIO: Mutex<Cell<u8>> = Mutex::new(Cell::new(LED_DEFAULT_GPIO));
+
+// デューティ比はTOP（デフォルトは0xFFFF）に対する割合になる。
+pub const LED_BRIGHT_DUTY: u16 = u16::MAX;
+pub const LED_DIM_DUTY: u16 = u16::MAX / 16;
+pub const LED_OFF_DUTY: u16 = 0;
+// 点滅の点灯と消灯のデューティ
+pub const ON_BRIGHTNESS: u16 = LED_BRIGHT_DUTY;
+pub const OFF_BRIGHTNESS: u16 = LED_OFF_DUTY;
+const _: () = assert!(
+    OFF_BRIGHTNESS < ON_BRIGHTNESS,
+    "OFF_BRIGHTNESS must be dimmer than ON_BRIGHTNESS"
+);
+
+// 全体の明るさ。u16::MAXならモードが決めたデューティのまま出す。
+static LED_BRIGHTNESS: Mutex<Cell<u16>> = Mutex::new(Cell::new(u16::MAX));
+// 周囲の明るさによる調光の倍率。u16::MAXなら調光しない。
+static LED_AMBIENT_SCALE: Mutex<Cell<u16>> = Mutex::new(Cell::new(u16::MAX));
+// 操作がないときの減光の倍率。u16::MAXなら減光しない。
+static LED_IDLE_DIM_SCALE: Mutex<Cell<u16>> = Mutex::new(Cell::new(u16::MAX));
+// デューティの上限
+static LED_BRIGHTNESS_CAP: Mutex<Cell<u16>> = Mutex::new(Cell::new(u16::MAX));
+// 最後にwrite_led()で書いたデューティ（明るさを掛ける前）
+static LED_DUTY: Mutex<Cell<u16>> = Mutex::new(Cell::new(LED_OFF_DUTY));
+
+// LED_GPIOSのピンをinit()に渡せる型にする
+pub fn into_led_pin<I, F, P>(pin: gpio::Pin<I, F, P>) -> LedPin
+where
+    I: PinId + gpio::ValidFunction<DynFunction>,
+    F: gpio::Function,
+    P: gpio::PullType,
+{
+    pin.into_function().into_pull_type().into_dyn_pin()
+}
+
+// スライスとピンはshared::init()でSHAREDに置いてから呼ぶ。スライスはenable()済み、
+// ピンはLED_GPIOSの順に並べておく。
+// 起動時の設定のled_gpioをPWMの出力にし、ほかのピンは入力にしておく。
+// LEDがアクティブLow（output.rs）なら両方のチャンネルの出力を反転するので、デューティはそのまま点灯している割合になる。
+// 反転してからピンをPWMにするので、電源を入れた直後に一瞬点くことはない。
+pub fn init(cs: &CriticalSection, config: &Config) {
+    shared::with(cs, |shared| {
+        let slice = &mut shared.led_pwm;
+        if output::is_active_low(OutputId::Led) {
+            slice.channel_a.set_inverted();
+            slice.channel_b.set_inverted();
+        }
+        for (pin, gpio) in shared.led_pins.iter_mut().zip(LED_GPIOS) {
+            let result = if gpio == config.led_gpio {
+                enable_output(pin)
+            } else {
+                release(pin)
+            };
+            // LED_GPIOSのピンはどれもPWMとSIOの機能を持っている
+            result.ok().unwrap();
+        }
+    });
+    LED_GPIO.borrow(cs).set(config.led_gpio);
+}
+
+fn enable_output(pin: &mut LedPin) -> Result<(), gpio::InvalidFunction> {
+    pin.set_pull_type(DynPullType::None);
+    pin.try_set_function(DynFunction::Pwm)
+}
+
+fn release(pin: &mut LedPin) -> Result<(), gpio::InvalidFunction> {
+    pin.set_pull_type(DynPullType::Down);
+    pin.try_set_function(DynFunction::Sio(DynSioConfig::Input))
+}
+
+// LEDの出力をGPIO nに移す。今の明るさと点滅はそのまま引き継ぐ。
+pub fn set_led_gpio(n: u8) -> Result<(), LedPinError> {
+    let to = LED_GPIOS
+        .iter()
+        .position(|&gpio| gpio == n)
+        .ok_or(LedPinError::NotAllowed)?;
+    free(|cs| {
+        let current = LED_GPIO.borrow(cs);
+        if current.get() == n {
+            return Ok(());
+        }
+        // LED_GPIOSにない番号はLED_GPIOに入らない
+        let from = LED_GPIOS
+            .iter()
+            .position(|&gpio| gpio == current.get())
+            .unwrap();
+        shared::with(cs, |shared| {
+            let pins = &mut shared.led_pins;
+            if enable_output(&mut pins[to]).is_err() {
+                // 新しいピンに移せなければ、元のピンで出し続ける
+                let _ = release(&mut pins[to]);
+                return Err(LedPinError::InvalidFunction);
+            }
+            let _ = release(&mut pins[from]);
+            Ok(())
+        })
+        .unwrap_or(Err(LedPinError::InvalidFunction))?;
+        current.set(n);
+        Ok(())
+    })
+}
+
+pub fn led_gpio(cs: &CriticalSection) -> u8 {
+    LED_GPIO.borrow(cs).get()
+}
+
+// 最後にwrite_led()に渡したデューティ（明るさや上限を掛ける前の値）
+pub fn duty(cs: &CriticalSection) -> u16 {
+    LED_DUTY.borrow(cs).get()
+}
+
+// LEDのデューティを書き込む唯一の入り口。
+// LEDの明るさを変えるときは必ずこの関数を通す。
+pub fn write_led(cs: &CriticalSection, duty: u16) {
+    LED_DUTY.borrow(cs).set(duty);
+    let output = output_duty(cs, duty);
+    shared::with(cs, |shared| {
+        // RP2040のPWMチャンネルはエラーを返さない（Infallible）
+        // 出力先がチャンネルAのピン（GPIO8）でも同じになるよう、両方に書く
+        let slice = &mut shared.led_pwm;
+        slice.channel_a.set_duty_cycle(output).unwrap();
+        slice.channel_b.set_duty_cycle(output).unwrap();
+    });
+    waveform::record(cs, is_lit(duty));
+}
+
+// 覚えている値から求めた、LEDが点いているか（上の説明を参照）
+pub fn is_on(cs: &CriticalSection) -> bool {
+    output_duty(cs, duty(cs)) > LED_OFF_DUTY
+}
+
+// ハードウェアのレジスタを読んで、LEDが今点いているか（上の説明を参照）
+pub fn led_is_on() -> bool {
+    let gpio = free(led_gpio);
+    // 読み出すだけなので、ほかのモジュールの設定には影響しない
+    let io = unsafe { &*pac::IO_BANK0::ptr() };
+    let ctrl = io.gpio(usize::from(gpio)).gpio_ctrl().read();
+    let forced_high = match ctrl.outover().variant() {
+        OUTOVER_A::LOW => Some(false),
+        OUTOVER_A::HIGH => Some(true),
+        _ => None,
+    };
+    if let Some(high) = forced_high {
+        return high != output::off_level(OutputId::Led);
+    }
+    if !ctrl.funcsel().is_pwm() {
+        return false;
+    }
+    let pwm = unsafe { &*pac::PWM::ptr() };
+    // LED_GPIOSはどれもスライス4。偶数のGPIOはチャンネルA、奇数はチャンネルB。
+    let slice = pwm.ch(4);
+    if slice.csr().read().en().bit_is_clear() {
+        return false;
+    }
+    let cc = slice.cc().read();
+    let level = if gpio.is_multiple_of(2) {
+        cc.a().bits()
+    } else {
+        cc.b().bits()
+    };
+    level > LED_OFF_DUTY
+}
+
+// 点滅の点灯（on）と消灯のデューティ
+pub fn blink_duty(on: bool) -> u16 {
+    if on {
+        ON_BRIGHTNESS
+    } else {
+        OFF_BRIGHTNESS
+    }
+}
+
+// デューティが点灯している扱いか（OFF_BRIGHTNESSのうっすらした光は消灯として扱う）
+pub fn is_lit(duty: u16) -> bool {
+    duty > OFF_BRIGHTNESS
+}
+
+// モードが決めたデューティから、実際にPWMに書くデューティを求める（上の順番どおり）
+pub fn output_duty(cs: &CriticalSection, duty: u16) -> u16 {
+    let full = u32::from(u16::MAX);
+    let scaled = u32::from(duty) * u32::from(LED_BRIGHTNESS.borrow(cs).get()) / full;
+    let scaled = scaled * u32::from(LED_AMBIENT_SCALE.borrow(cs).get()) / full;
+    let scaled = scaled * u32::from(LED_IDLE_DIM_SCALE.borrow(cs).get()) / full;
+    (scaled as u16).min(LED_BRIGHTNESS_CAP.borrow(cs).get())
+}
+
+// 周囲の明るさによる調光の倍率を変える。今の出力にもすぐにかける。
+pub fn set_ambient_scale(cs: &CriticalSection, scale: u16) {
+    if LED_AMBIENT_SCALE.borrow(cs).replace(scale) != scale {
+        write_led(cs, LED_DUTY.borrow(cs).get());
+    }
+}
+
+// 操作がないときの減光の倍率を変える。今の出力にもすぐにかける。
+pub fn set_idle_dim_scale(cs: &CriticalSection, scale: u16) {
+    if LED_IDLE_DIM_SCALE.borrow(cs).replace(scale) != scale {
+        write_led(cs, LED_DUTY.borrow(cs).get());
+    }
+}
+
+// デューティの上限を変える。今の出力にもすぐにかける。
+pub fn set_brightness_cap(max: u16) -> Result<(), CapError> {
+    // OFF_BRIGHTNESSが0なら、上限が0でも消灯と同じになるだけなので受け付ける
+    if OFF_BRIGHTNESS != LED_OFF_DUTY && max < OFF_BRIGHTNESS.saturating_add(1) {
+        return Err(CapError::BelowOffBrightness);
+    }
+    free(|cs| {
+        LED_BRIGHTNESS_CAP.borrow(cs).set(max);
+        write_led(cs, LED_DUTY.borrow(cs).get());
+    });
+    Ok(())
+}
+
+pub fn brightness(cs: &CriticalSection) -> u16 {
+    LED_BRIGHTNESS.borrow(cs).get()
+}
+
+// フェードの途中でも明るさだけを変える（フェードの各ステップで使う）
+pub fn set_brightness(cs: &CriticalSection, level: u16) {
+    LED_BRIGHTNESS.borrow(cs).set(level);
+    write_led(cs, LED_DUTY.borrow(cs).get());
+}
+
+// 全体の明るさをすぐに変える。フェードの途中なら、フェードは取り消す。
+pub fn set_led_brightness(level: u16) {
+    free(|cs| {
+        fade::cancel(cs);
+        set_brightness(cs, level);
+    });
+}
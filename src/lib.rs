@@ -0,0 +1,21 @@
+// ハードウェアに依存しないロジックをまとめたライブラリ
+//
+// ここに置いたモジュールはペリフェラルを触らないので、PC上でテストできる。
+// ファームウェア本体（main.rs）からは`rp2040_project_template::モジュール名`で使う。
+//
+// テストはthumbv6m-none-eabi向けには実行できないので、ホスト向けにビルドして実行する。
+//   cargo test-host
+// （.cargo/config.tomlのエイリアスで`cargo test --lib --target x86_64-unknown-linux-gnu`になる）
+#![cfg_attr(not(test), no_std)]
+
+pub mod bcd_datetime;
+pub mod config_blob;
+pub mod crc;
+pub mod debounce;
+pub mod decimal_blink;
+pub mod quadrature;
+pub mod response_curve;
+pub mod spsc;
+pub mod time;
+pub mod timer_list;
+pub mod waveform_table;
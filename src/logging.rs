@@ -0,0 +1,102 @@
+// 出力先ごとに頻度を分けたログ
+//
+//   log_fast()    : 割り込みごと。defmt（RTT）へ出す。
+//                   デバッグプローブ経由なので速く、行数が多くても問題ない。
+//   log_summary() : 1秒に1回。UARTへ人が読むための要約を1行だけ出す。
+//                   115200bpsでは1文字に約87µsかかるので、毎回の割り込みで出すと追いつかない。
+//
+// UARTへの書き込みはStatusTxのバッファに詰めるだけで、送信はDMAが行うので待たない。
+// 要約の行は最も長い場合でも約90バイトで、StatusTxのバッファ（LINE_BUF_LEN = 128）に必ず収まる。
+// 前の行がまだ送り終わっていないなどで入らなければ、その回の要約は捨てて数えておく。
+//
+// 主な出来事はEventにまとめてlog_event()で出す。defmtにはEventの値がそのまま構造化されて
+// 記録されるので、ログを読むツールの側で"Tick(12)"や"ModeChanged(Blink)"として取り出せる。
+// 文の形のログは、Eventにない補足の情報（測った値など）を出すときだけに使う。
+
+use crate::cs_trace::free;
+use crate::ds3231::DateTime;
+use crate::mode::LedMode;
+use crate::status_tx::StatusTx;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use defmt::Format;
+
+#[derive(Clone, Copy, Format)]
+pub enum Event {
+    // 割り込みカウンタがこの値に進んだ
+    Tick(u32),
+    // LEDのモードが変わった（mode::set_mode()で変わったときだけ出る）
+    ModeChanged(LedMode),
+    // タップテンポ用のボタンが押された
+    ButtonPress,
+    // 異常が起きた。値は下のERR_*。
+    Error(u8),
+}
+
+// Event::Errorの値
+// UARTの受信FIFOがあふれた
+pub const ERR_UART_RX_OVERRUN: u8 = 1;
+// 割り込みから頼まれた処理がキューに入らず捨てられた
+pub const ERR_DEFERRED_QUEUE_FULL: u8 = 2;
+// UARTの要約の行がバッファに入らず捨てられた
+pub const ERR_STATUS_LINE_DROPPED: u8 = 3;
+// I2Cの転送が失敗し続けたので、I2C0をリセットした
+pub const ERR_I2C_RESET: u8 = 4;
+// DS18B20が読めなかった
+pub const ERR_DS18B20: u8 = 5;
+// 割り込みカウンタが進まなくなった（外付けのWDへのハートビートを止めた）
+pub const ERR_COUNTER_STALLED: u8 = 6;
+
+// Errorはwarn、それ以外はinfoのレベルで出す
+pub fn log_event(event: Event) {
+    match event {
+        Event::Error(_) => defmt::warn!("{}", event),
+        _ => defmt::info!("{}", event),
+    }
+}
+
+// log_summary()に渡す1秒ごとの要約
+pub struct Summary {
+    pub count: u32,
+    pub mode: LedMode,
+    // 実測した割り込みの頻度（mHz）
+    pub rate_mhz: u64,
+    pub interval_ms: u32,
+    pub throttled: bool,
+}
+
+// 割り込みカウンタがcountに進んだことをEvent::Tickで出す。日時が読めていれば先頭につける。
+pub fn log_fast(count: u32, datetime: Option<DateTime>) {
+    let event = Event::Tick(count);
+    match datetime {
+        Some(dt) => defmt::info!("[{}] {}", defmt::Display2Format(&dt), event),
+        None => log_event(event),
+    }
+}
+
+// バッファに入らず捨てた要約の行の数（起動してからの累計）
+static SUMMARIES_DROPPED: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+pub fn summaries_dropped(cs: &CriticalSection) -> u32 {
+    SUMMARIES_DROPPED.borrow(cs).get()
+}
+
+// 要約をUARTへ出す。バッファに入らず捨てたときはfalse。
+pub fn log_summary(tx: &mut StatusTx, summary: &Summary) -> bool {
+    let written = tx.write_line(format_args!(
+        "count={} mode={} rate={}.{:03}Hz interval={}ms throttled={}",
+        summary.count,
+        summary.mode.name(),
+        summary.rate_mhz / 1000,
+        summary.rate_mhz % 1000,
+        summary.interval_ms,
+        if summary.throttled { "yes" } else { "no" }
+    ));
+    if !written {
+        free(|cs| {
+            let dropped = SUMMARIES_DROPPED.borrow(cs);
+            dropped.set(dropped.get().wrapping_add(1));
+        });
+    }
+    written
+}
@@ -2,30 +2,153 @@
 #![no_main]
 
 use defmt::*;
-use defmt_rtt as _;
 use panic_probe as _;
 
+// ALARMは32bitのマイクロ秒でスケジュールするので、
+// 設定できる間隔はu32::MAXマイクロ秒（約71分34秒）まで。
+const MAX_ALARM_INTERVAL_MS: u32 = u32::MAX / 1000;
+
+// ミリ秒の間隔定数がALARMでスケジュールできる範囲に収まっているかをコンパイル時に検査する。
+// 範囲外の値を設定するとschedule().unwrap()での実行時パニックではなくビルドエラーになる。
+// 間隔を表す定数を追加したら、このマクロで検査を追加すること。
+//
+// macro_rules!で定義したマクロは定義より後ろでしか使えないので、modより先に定義している。
+macro_rules! assert_alarm_interval_ms {
+    ($interval:ident) => {
+        const _: () = ::core::assert!(
+            $interval <= $crate::MAX_ALARM_INTERVAL_MS,
+            concat!(
+                stringify!($interval),
+                " exceeds the alarm range: must be <= 4294967 ms (u32::MAX microseconds, ~71 minutes)"
+            )
+        );
+    };
+}
+
+mod ambient;
+mod banner;
+mod bar_graph;
+#[cfg(feature = "bench")]
+mod bench;
+mod bicolor;
+mod binary_log;
+mod blink_burst;
+mod blink_count;
+mod burst;
+mod button;
+mod clock_info;
+mod command;
+mod config;
+mod counter;
+mod cs_trace;
+mod deferred;
+mod diagnostics;
+mod dip_switch;
+mod double_blink;
+mod ds18b20;
+mod ds3231;
+mod edge_counter;
+mod egg_timer;
+mod encoder;
+mod estop;
+mod fade;
+mod features;
+mod footprint;
+mod glitch_filter;
+mod heartbeat;
+mod i2c_bus;
+mod idle_dim;
+mod idle_off;
+mod interval;
+mod ir_nec;
+mod irq_latency;
+mod jitter_test;
+#[cfg(feature = "watchdog")]
+mod last_gasp;
+mod led;
+mod logging;
+mod mains_sync;
+mod manchester;
+mod mfg_test;
+mod milestone;
+mod mode;
+mod nested_irq;
+mod notify;
+mod number;
+mod oneshot;
+mod onewire;
+mod output;
+mod output_enable;
+mod phase_sync;
+mod pin_table;
+mod post;
+mod power;
+mod prescaler;
+mod pull;
+mod pulse_train;
+mod pulse_width;
+mod rate_control;
+#[cfg(feature = "periodic-reboot")]
+mod reboot;
+mod recorder;
+mod register_map;
+mod reset_cause;
+#[cfg(feature = "reset-log")]
+mod reset_log;
+mod resets;
+mod rtt_logger;
+mod sampler;
+mod schedule;
+mod selector;
+mod shared;
+mod shutdown;
+mod soft_rtc;
+mod soft_timer;
+mod ssd1306;
+mod status_tx;
+mod step_mode;
+mod tap_tempo;
+mod thermal;
+#[cfg(feature = "tick-source")]
+mod tick_source;
+mod toggle_button;
+mod tone;
+mod version;
+mod waveform;
+mod work;
+
 // rp_picoクレートをBSPとして使用する
 use rp_pico as bsp;
 
 // rp2040_pacをPAC（Peripheral Access Crate）として使用する
 use bsp::hal::pac;
-use bsp::hal::{clocks::init_clocks_and_plls, gpio, sio::Sio, timer, watchdog};
+use bsp::hal::{
+    adc, clocks::init_clocks_and_plls, dma::DMAExt, gpio, i2c, pwm, sio::Sio, timer, uart,
+    watchdog, Clock,
+};
 use bsp::{entry, hal::timer::Alarm};
 
 use pac::interrupt;
 
-// ピンの出力トグルメソッドを使用するために必要。
-// StatefulOutputPinトレイトで宣言されているtoggleメソッドを使うため。
-use embedded_hal::digital::StatefulOutputPin;
+use ambient::AmbientLight;
+use button::{Button, ButtonEvent};
+use command::CommandReader;
+use logging::Event;
+use mode::LedMode;
+use status_tx::StatusTx;
+use tap_tempo::TapTempo;
+
+use rp2040_project_template::time;
 
+use crate::cs_trace::free;
 use core::cell::{Cell, RefCell};
-use core::ops::DerefMut;
-use cortex_m::interrupt::{free, CriticalSection, Mutex};
+use cortex_m::interrupt::{CriticalSection, Mutex};
 
 // これで100.micros()みたいに整数から時間を表す数値へ変換ができるようになる
 // u32にトレイトを追加して型の機能を拡張したイメージ
 use fugit::ExtU32;
+// 115200.Hz()のように周波数を書くためのトレイト
+use fugit::RateExtU32;
 
 // 冗長な型定義をやめるためにtypeで型を定義
 // C言語のtypedefのようなもの。
@@ -38,19 +161,100 @@ const fn initial_global_peripheral<T>() -> GlobalPeripheral<T> {
     Mutex::new(RefCell::new(None))
 }
 
-// constfnでグローバル変数の初期値を設定。
-// 必ず同じ結果になるのでコンパイル時点で式の評価を行い、結果をグローバル変数の初期値としている。
-static ALARM0: GlobalPeripheral<timer::Alarm0> = initial_global_peripheral();
-static LED: GlobalPeripheral<
-    gpio::Pin<gpio::bank0::Gpio25, gpio::FunctionSioOutput, gpio::PullDown>,
-> = initial_global_peripheral();
+// ALARM0はLEDとブザーのPWMと一緒にshared.rsのSHAREDに置き、shared::with()で触る。
 
-static INTERRUPT_COUNTER: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+// ALARM0が発火していないのにTIMER_IRQ_0に入った回数
+static SPURIOUS_TIMER_IRQS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+// 点滅モードでの現在の点灯状態
+static LED_ON: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
 
 const ALARM0_INTERVAL_MS: u32 = 1000;
+assert_alarm_interval_ms!(ALARM0_INTERVAL_MS);
+
+// 点滅の位相をずらす時間。複数のボードを並べて点滅をそろえたり、わざとずらしたりするのに使う。
+// 初期化が終わって割り込みを有効にする直前から数えて、最初のALARM0だけをこの分遅らせる。
+// 2回目からは今までどおり点滅間隔ごとに発火するので、周期は変わらず位相だけがずれる。
+// ボードごとに電源を入れてから初期化が終わるまでの時間は同じなので、同時に電源を入れたボードは
+// この値の差だけずれて点滅する。
+// 起動時の設定（config.rs）のphase_offset_msの初期値で、最初のALARM0の時間はConfig::first_alarm0_ms()。
+const PHASE_OFFSET_MS: u32 = 0;
+
+// ALARMをscheduleする入り口はこの関数とschedule_alarm_at()だけ。どのALARMもどちらかを通してscheduleすること。
+// 例外はALARM0の周期のscheduleし直し（reschedule_alarm0()）で、前の期限から数えるためにtime::reschedule()を通す。
+// 間隔はtime::clamp_interval()でMIN_INTERVAL_US〜MAX_INTERVAL_USに収める（範囲はtime.rs参照）。
+// すぐに発火させたいときも0ではなくMIN_INTERVAL_USになる（過ぎた時刻だと約71分後まで発火しない）。
+fn schedule_alarm_us(alarm: &mut impl Alarm, us: u32) {
+    // 範囲に収めているので失敗しない
+    alarm.schedule(time::clamp_interval(us).micros()).unwrap();
+}
+
+// 時刻at（タイマーカウンタ）にscheduleする。schedule_alarm_us()と同じ範囲に収め、
+// 今の時刻からMIN_INTERVAL_USより近いか過ぎていれば今 + MIN_INTERVAL_US、MAX_INTERVAL_USより先なら今 + MAX_INTERVAL_USにする。
+// 範囲に収めた時刻を返す。
+fn schedule_alarm_at(alarm: &mut impl Alarm, at: u64) -> u64 {
+    let now = counter_now_us();
+    let at = at.clamp(
+        time::add_interval(now, time::MIN_INTERVAL_US),
+        time::add_interval(now, time::MAX_INTERVAL_US),
+    );
+    // schedule_at()が失敗するのはu32::MAX µsより先のときだけで、nowを読んだ後は時刻が進むだけなので起きない。
+    // 起きたとしてもpanicせず、相対の間隔でscheduleし直す。
+    if alarm.schedule_at(timer::Instant::from_ticks(at)).is_err() {
+        let us = u32::try_from(time::elapsed_us(at, now)).unwrap_or(time::MAX_INTERVAL_US);
+        schedule_alarm_us(alarm, us);
+    }
+    at
+}
+
+// ALARM0の今の期限（タイマーカウンタ）。次の期限はここから数える（reschedule_alarm0()）。
+static ALARM0_DEADLINE: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+
+// ALARM0を前の期限からinterval_usだけ後にscheduleし直す。do_tick()から、clear_interrupt()の後に呼ぶ。
+// 今の時刻から数えると割り込みに入るまでの遅れと処理の時間の分だけ毎回遅れがたまるので、
+// time::reschedule()で前の期限から数える（取りこぼしと二重の発火の扱いはtime.rs、実機のテストはtests/alarm_reschedule.rs）。
+fn reschedule_alarm0(cs: &CriticalSection, alarm0: &mut timer::Alarm0, interval_us: u32) {
+    let deadline = ALARM0_DEADLINE.borrow(cs);
+    let next = time::reschedule(alarm0, deadline.get(), interval_us, counter_now_us());
+    if next.skipped > 0 {
+        debug!("alarm0: skipped {=u32} periods", next.skipped);
+    }
+    deadline.set(next.deadline);
+}
+
+// ALARM0を時刻atから数え直す（起動時、点滅のやり直し、トグルボタン）。以後の期限はatから数える。
+fn schedule_alarm0_at(cs: &CriticalSection, alarm0: &mut timer::Alarm0, at: u64) {
+    ALARM0_DEADLINE
+        .borrow(cs)
+        .set(schedule_alarm_at(alarm0, at));
+}
+
+// タイマーカウンタの今の値。Timerを持っていない場所からも読めるように、レジスタを直接読む（読み出すだけ）。
+// 上位と下位を別々に読むので、上位が読む間に変わっていたら読み直す。
+fn counter_now_us() -> u64 {
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    loop {
+        let high = timer.timerawh().read().bits();
+        let low = timer.timerawl().read().bits();
+        if timer.timerawh().read().bits() == high {
+            return (u64::from(high) << 32) | u64::from(low);
+        }
+    }
+}
+
+fn schedule_alarm_ms(alarm: &mut impl Alarm, ms: u32) {
+    schedule_alarm_us(alarm, ms.saturating_mul(1000));
+}
+const _: () = ::core::assert!(MAX_ALARM_INTERVAL_MS * 1000 == time::MAX_INTERVAL_US);
+
+// 割り込みの頻度を測ってUARTに要約を出す周期
+const RATE_LOG_INTERVAL_MS: u32 = 1000;
+assert_alarm_interval_ms!(RATE_LOG_INTERVAL_MS);
 
 #[entry]
 fn main() -> ! {
+    // RTTを初期化する。ここより前のdefmtのログは出ないので、main()の最初に行う（binary_log.rs）。
+    let binary_channel = binary_log::init_rtt();
+
     // ペリフェラルがまとめて入っている構造体を取得します。
     // ペリフェラルが構造体に入れることで、
     // ペリフェラルを触る際にもRustの所有権機能を利用することになります。
@@ -89,6 +293,29 @@ fn main() -> ! {
     .ok()
     .unwrap();
 
+    // コアのペリフェラル（NVIC、SysTickなど）。割り込みの優先度の設定と、benchとtick-sourceで使う。
+    let mut core = pac::CorePeripherals::take().unwrap();
+    // 非常停止の割り込みだけをほかより高い優先度にする（estop.rs）
+    estop::set_priorities(&mut core.NVIC, &mut core.SCB);
+    nested_irq::set_priorities(&mut core.NVIC);
+    #[cfg(feature = "watchdog")]
+    last_gasp::set_priority(&mut core.SCB);
+
+    // ベンチマーク：クリティカルセクションの手間を測る。割り込みを有効にする前に済ませる。
+    #[cfg(feature = "bench")]
+    bench::run(&mut core.SYST);
+
+    // 実験用：タイマーの1µsの基準を変え、別のクロックで動くSysTickで実際の速さを確かめる
+    #[cfg(feature = "tick-source")]
+    {
+        tick_source::set_tick_divisor(
+            &mut watchdog,
+            clocks.reference_clock.freq().to_Hz(),
+            tick_source::TEST_TICK_CYCLES,
+        );
+        tick_source::start_reference(core.SYST, clocks.system_clock.freq().to_Hz());
+    }
+
     // Watchdogを開始。
     // WDに供給するクロックなどの設定は上のinit_clocks_and_plls()で済ませているので
     // ここではWDリセットまでの時間を設定すればよい。
@@ -96,6 +323,16 @@ fn main() -> ! {
     //
     // WDをリスタートするときはfeed()を使う
     // watchdog.feed();
+    //
+    // watchdogフィーチャーでは、last_gaspがWDを持って開始し、メインループがfeed()する。
+    // リセットの直前に最後のログを出すため、SysTickでWDの残りを確かめる（last_gasp.rs）。
+    // tick-sourceフィーチャーではSysTickがすでに1 msごとに入っているので、それを使う。
+    #[cfg(feature = "watchdog")]
+    {
+        last_gasp::start(watchdog);
+        #[cfg(not(feature = "tick-source"))]
+        last_gasp::start_poll(core.SYST, clocks.system_clock.freq().to_Hz());
+    }
 
     // ピンを扱うインスタンスの作成
     let pins = bsp::Pins::new(
@@ -104,17 +341,196 @@ fn main() -> ! {
         sio.gpio_bank0,
         &mut pac.RESETS,
     );
+    // 起動時だけGPIO2/16/8/9でDIPスイッチを読む。読み終えたピンは下で本来の用途に使う（dip_switch.rs）。
+    let (dip, (gpio2, gpio16, gpio8, gpio9)) = dip_switch::read(
+        (pins.gpio2, pins.gpio16, pins.gpio8, pins.gpio9),
+        clocks.system_clock.freq().to_Hz(),
+    );
+    // 起動時の設定を決める。以後はconfig::config()でどこからでも読める（config.rs）。
+    let config = config::init(config::Config::boot(dip));
+
+    // 起動時のバナーに出すピン番号。各ピンを使う直前に記録するので、実際の割り当てと必ず一致する。
+    let mut pin_map = banner::PinMap::default();
 
     // pins.ledでLEDにつながっているピンを指定する
     // rp-picoではGPIO25のピンにLEDがつながっているため、このような書き方をするよう。
     //
     // ちなみにピン定義にはマクロが使われているので、
     // パッと見でどういう定義になっているのかわかりにくい。
-    let led_pin = pins.led.into_push_pull_output();
+    //
+    // GPIO25はPWMスライス4のチャンネルBなので、そのチャンネルの出力先にする。
+    // 実行中に出力先を移せるよう、スライス4に出力できるほかのピンもまとめてledに渡す（led.rs参照）。
+    let pwm_slices = pwm::Slices::new(pac.PWM, &mut pac.RESETS);
+    let mut led_pwm = pwm_slices.pwm4;
+    led_pwm.enable();
+    pin_map.led = pin_table::registered(pins.led.id().num, pin_table::LED);
+    pin_table::registered(gpio8.id().num, pin_table::LED_ALT_A);
+    pin_table::registered(gpio9.id().num, pin_table::LED_ALT_B);
+    let led_pins = [
+        led::into_led_pin(gpio8),
+        led::into_led_pin(gpio9),
+        led::into_led_pin(pins.led),
+    ];
+
+    // 点滅に合わせて鳴らす圧電ブザー（GPIO16: PWMスライス0のチャンネルA）
+    let mut tone_pwm = pwm_slices.pwm0;
+    tone_pwm.enable();
+    pin_map.tone = pin_table::registered(gpio16.id().num, pin_table::TONE);
+    tone_pwm.channel_a.output_to(gpio16);
+
+    // 2色LEDの赤（GPIO6）と緑（GPIO7）はPWM3の2つのチャンネル（bicolor.rs）
+    let mut bicolor_pwm = pwm_slices.pwm3;
+    bicolor_pwm.enable();
+    pin_map.bicolor = (
+        pin_table::registered(pins.gpio6.id().num, pin_table::BICOLOR_RED),
+        pin_table::registered(pins.gpio7.id().num, pin_table::BICOLOR_GREEN),
+    );
+    bicolor_pwm.channel_a.output_to(pins.gpio6);
+    bicolor_pwm.channel_b.output_to(pins.gpio7);
+
+    // 周囲の明るさを測るためのADC
+    pin_map.ambient = pin_table::registered(pins.gpio26.id().num, pin_table::AMBIENT);
+    let ambient_pin =
+        adc::AdcPin::new(pull::into_input(pins.gpio26, ambient::AMBIENT_PULL)).unwrap();
+    let mut ambient = AmbientLight::new(ambient_pin);
+
+    // ADCは周囲の明るさとチップ内蔵の温度センサーで共有する
+    let mut adc = adc::Adc::new(pac.ADC, &mut pac.RESETS);
+    let mut die_temp = thermal::DieTemp::new(&mut adc);
+
+    // ステータス行を送るUART0（GPIO0: TX, GPIO1: RX）
+    pin_map.uart_tx = pin_table::registered(pins.gpio0.id().num, pin_table::UART_TX);
+    pin_map.uart_rx = pin_table::registered(pins.gpio1.id().num, pin_table::UART_RX);
+    let uart_pins = (pins.gpio0.into_function(), pins.gpio1.into_function());
+    let uart = uart::UartPeripheral::new(pac.UART0, uart_pins, &mut pac.RESETS)
+        .enable(
+            uart::UartConfig::new(
+                115200.Hz(),
+                uart::DataBits::Eight,
+                None,
+                uart::StopBits::One,
+            ),
+            clocks.peripheral_clock.freq(),
+        )
+        .unwrap();
+    let (uart_rx, uart_tx) = uart.split();
+    let mut commands = CommandReader::new(uart_rx);
+
+    // UARTの送信はDMAのチャンネル0に任せる
+    let dma = pac.DMA.split(&mut pac.RESETS);
+    let mut status_tx = StatusTx::new(dma.ch0, uart_tx);
+
+    // DS3231などをつなぐI2C0
+    pin_map.i2c_sda = pin_table::registered(pins.gpio4.id().num, pin_table::I2C_SDA);
+    pin_map.i2c_scl = pin_table::registered(pins.gpio5.id().num, pin_table::I2C_SCL);
+    i2c_bus::init(
+        i2c::I2C::i2c0(
+            pac.I2C0,
+            pins.gpio4.reconfigure(),
+            pins.gpio5.reconfigure(),
+            i2c_bus::I2C_FREQ_KHZ.kHz(),
+            &mut pac.RESETS,
+            clocks.system_clock.freq(),
+        ),
+        clocks.system_clock.freq().to_Hz(),
+    );
+
+    // タップテンポ用のボタン
+    pin_map.button = pin_table::registered(pins.gpio15.id().num, pin_table::BUTTON);
+    let button_pin = pull::into_input(pins.gpio15, button::BUTTON_PULL);
+
+    // IO_IRQ_BANK0でLEDを消灯/点灯させるボタン
+    pin_map.toggle_button = pin_table::registered(pins.gpio14.id().num, pin_table::TOGGLE_BUTTON);
+    let toggle_pin = pull::into_input(pins.gpio14, toggle_button::TOGGLE_BUTTON_PULL);
+
+    // モードを選ぶ2bitのセレクタ（ジャンパー）
+    pin_map.selector = (
+        pin_table::registered(pins.gpio18.id().num, pin_table::SELECTOR_BIT0),
+        pin_table::registered(pins.gpio19.id().num, pin_table::SELECTOR_BIT1),
+    );
+    let selector_pins = (
+        pull::into_input(pins.gpio18, selector::SELECTOR_PULL),
+        pull::into_input(pins.gpio19, selector::SELECTOR_PULL),
+    );
+
+    // パルス幅を測る入力
+    pin_map.pulse = pin_table::registered(pins.gpio17.id().num, pin_table::PULSE);
+    let pulse_pin = pull::into_input(pins.gpio17, pulse_width::PULSE_PULL);
+
+    // マンチェスター符号の受信入力
+    pin_map.manchester = pin_table::registered(pins.gpio20.id().num, pin_table::MANCHESTER);
+    let manchester_pin = pull::into_input(pins.gpio20, manchester::MANCHESTER_PULL);
+
+    // 非常停止の入力。PIO0で見張る。
+    pin_map.estop = pin_table::registered(pins.gpio21.id().num, pin_table::ESTOP);
+    let estop_pin = pull::into_input(pins.gpio21, estop::ESTOP_PULL);
+
+    // 商用電源のゼロクロス検出回路の出力（絶縁した回路を通すこと。mains_sync.rs参照）
+    pin_map.zero_cross = pin_table::registered(pins.gpio10.id().num, pin_table::ZERO_CROSS);
+    let zero_cross_pin = pull::into_input(pins.gpio10, mains_sync::ZERO_CROSS_PULL);
+
+    // 速いパルスの立ち上がりを数える入力
+    pin_map.edge_counter = pin_table::registered(pins.gpio11.id().num, pin_table::EDGE_COUNTER);
+    let edge_pin = pull::into_input(pins.gpio11, edge_counter::EDGE_PULL);
+
+    // 赤外線リモコンの受信モジュールの出力
+    pin_map.ir = pin_table::registered(pins.gpio13.id().num, pin_table::IR_RECEIVER);
+    let ir_pin = pull::into_input(pins.gpio13, ir_nec::IR_PULL);
+
+    // 点滅間隔を変えるロータリーエンコーダーのA相とB相
+    pin_map.encoder = (
+        pin_table::registered(pins.gpio27.id().num, pin_table::ENCODER_A),
+        pin_table::registered(pins.gpio28.id().num, pin_table::ENCODER_B),
+    );
+    let encoder_pins = (
+        pull::into_input(pins.gpio27, encoder::ENCODER_PULL),
+        pull::into_input(pins.gpio28, encoder::ENCODER_PULL),
+    );
+
+    // PIO1で正確なパルスを出す出力
+    pin_map.pulse_train = pin_table::registered(pins.gpio12.id().num, pin_table::PULSE_TRAIN);
+    let pulse_train_pin = pins
+        .gpio12
+        .into_function::<gpio::FunctionPio1>()
+        .into_pull_type::<gpio::PullNone>();
+
+    // 外付けのウォッチドッグICのWDI端子につなぐハートビート出力
+    pin_map.heartbeat = pin_table::registered(gpio2.id().num, pin_table::HEARTBEAT);
+    let heartbeat_pin =
+        gpio2.into_push_pull_output_in_state(output::initial_state(output::OutputId::Heartbeat));
+
+    // cs-traceフィーチャーでは、割り込みを止めている間だけHighにする出力（cs_trace.rs）
+    #[cfg(feature = "cs-trace")]
+    {
+        pin_table::registered(pins.gpio3.id().num, pin_table::CS_TRACE);
+        let state = output::initial_state(output::OutputId::CsTrace);
+        cs_trace::init(pins.gpio3.into_push_pull_output_in_state(state));
+    }
+    // cs-traceフィーチャーでなければ、GPIO3は出力の許可の入力（output_enable.rs）
+    #[cfg(not(feature = "cs-trace"))]
+    let output_enable_pin = {
+        pin_map.output_enable = Some(pin_table::registered(
+            pins.gpio3.id().num,
+            pin_table::OUTPUT_ENABLE,
+        ));
+        pull::into_input(pins.gpio3, output_enable::OUTPUT_ENABLE_PULL)
+    };
 
     // タイマー割り込み用のALARMを取り出す。
     let mut timer = timer::Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
 
+    // I2C0のOLED。つながっていなければ表示しない。
+    ssd1306::init(timer.get_counter().ticks());
+
+    // DS18B20をつなぐ1-Wireバス。出力値をLowにしておき、機能の切り替えでLowに引く/離すを行う。
+    pin_map.onewire = pin_table::registered(pins.gpio22.id().num, pin_table::ONEWIRE);
+    let onewire_pin = pins
+        .gpio22
+        .into_push_pull_output_in_state(gpio::PinState::Low)
+        .into_function()
+        .into_pull_type();
+    let mut ds18b20 = ds18b20::Ds18b20::new(onewire::OneWire::new(onewire_pin, timer));
+
     // alarm_0()は戻り値にOption<T>を使っている。
     // Option<T>は値を持っているかどうかわからないという変数。
     // 値が入っているかどうか（SomeかNoneか）を判定して、
@@ -122,6 +538,12 @@ fn main() -> ! {
     //
     // 初めて取り出す場合は値が入っているのでここではunwrap()で強制的に値を取り出している。
     let mut alarm0 = timer.alarm_0().unwrap();
+    // ALARM1はワンショットタイマーとソフトウェアタイマー用（soft_timer.rs）
+    let alarm1 = timer.alarm_1().unwrap();
+    // ALARM2は明るさのフェード用
+    let alarm2 = timer.alarm_2().unwrap();
+    // ALARM3はボタンのサンプリングと外付けのウォッチドッグ向けのハートビート用（sampler.rs）
+    let alarm3 = timer.alarm_3().unwrap();
 
     // スレッド間でデータ競合が起こらないようにしている
     // free関数はCritialSectionを渡すラムダを要求する。
@@ -131,27 +553,328 @@ fn main() -> ! {
     // borrowメソッドをCriticalSectionととともに呼び出すことで、
     // グローバル変数への参照を手に入れることができる（RefCell）。
     // RefCellには操作のためのメソッドなどが用意されているので、それを利用する。
-    free(|cs| {
+    // 波形のログを取り出す側はメインループで持つ（waveform.rs）
+    let mut wave_rx = free(|cs| {
         alarm0.enable_interrupt();
         alarm0.clear_interrupt();
-        alarm0.schedule(ALARM0_INTERVAL_MS.micros()).unwrap();
 
         // CriticalSectionを使ってMutexの中身を操作している部分
-        ALARM0.borrow(cs).replace(Some(alarm0));
-        LED.borrow(cs).replace(Some(led_pin));
+        // TIMER_IRQ_0が使うペリフェラルは1つにまとめる（shared.rs）。led::init()とtone::init()より先に置く。
+        shared::init(
+            cs,
+            shared::Shared {
+                alarm0,
+                led_pwm,
+                led_pins,
+                tone_pwm,
+            },
+        );
+        led::init(cs, config);
+        let wave_rx = waveform::init(cs, timer);
+        soft_timer::init(cs, alarm1, timer);
+        fade::init(cs, alarm2);
+        heartbeat::init(cs, heartbeat_pin, timer);
+        button::init(cs, button_pin);
+        #[cfg(not(feature = "cs-trace"))]
+        output_enable::init(cs, output_enable_pin);
+        selector::init(cs, selector_pins);
+        sampler::init(cs, alarm3);
+        toggle_button::init(cs, toggle_pin, timer);
+        pulse_width::init(cs, pulse_pin, timer);
+        manchester::init(cs, manchester_pin, timer);
+        mains_sync::init(cs, zero_cross_pin, timer);
+        edge_counter::init(cs, edge_pin, timer);
+        ir_nec::init(cs, ir_pin, timer);
+        encoder::init(cs, encoder_pins.0, encoder_pins.1);
+        idle_off::init(cs, timer);
+        idle_dim::init(cs, timer);
+        shutdown::init(cs, timer);
+        recorder::init(cs, timer);
+        soft_rtc::init(cs, timer);
+        rate_control::init(cs, timer);
+        phase_sync::init(cs, timer);
+        clock_info::init(cs, &clocks);
+        estop::init(cs, pac.PIO0, &mut pac.RESETS, estop_pin);
+        pulse_train::init(
+            cs,
+            pac.PIO1,
+            &mut pac.RESETS,
+            pulse_train_pin,
+            clocks.system_clock.freq().to_Hz(),
+        );
+        binary_log::init(cs, binary_channel, timer);
+        tone::init(cs, clocks.system_clock.freq().to_Hz());
+        bicolor::init(cs, bicolor_pwm);
+        wave_rx
+    });
+
+    // 実行時に変えられる値を、起動時の設定で初期化する
+    prescaler::set_prescale(config.prescale);
+    tone::set_tone_freq(config.tone_hz);
+    free(|cs| {
+        mode::set_mode_keep_auto(cs, config.mode);
+        interval::set_interval_now(cs, config.blink_interval_ms);
     });
 
+    // ここから先はペリフェラルの初期化でRESETSを使わないので、復旧用に預けておく
+    resets::init(pac.RESETS);
+
     info!("Program start");
+    version::log_version();
+    features::report_features();
+    footprint::report();
+    reset_cause::log_reset_cause();
+    // フラッシュに書く間は割り込みを止めるので、割り込みを有効にする前に済ませる
+    #[cfg(feature = "reset-log")]
+    reset_log::record_boot();
+    // 失敗したときの処理を足すときは、結果を書き終えたこの後に置く（post.rs参照）
+    post::run();
+    banner::print_banner(&pin_map, &mut status_tx);
+
+    // mfg-testフィーチャーでは起動した直後から検査のパターンを出す
+    if config.mfg_test {
+        mfg_test::start();
+    }
+
+    // 最初のALARM0は初期化が終わったここから数える（PHASE_OFFSET_MS参照）
+    free(|cs| {
+        let first_us = config.first_alarm0_ms().saturating_mul(1000);
+        let at = time::add_interval(counter_now_us(), first_us);
+        shared::with(cs, |shared| schedule_alarm0_at(cs, &mut shared.alarm0, at));
+    });
 
     unsafe {
         pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_0);
+        pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_1);
+        pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_2);
+        pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_3);
+        pac::NVIC::unmask(pac::Interrupt::IO_IRQ_BANK0);
+        pac::NVIC::unmask(pac::Interrupt::PIO0_IRQ_0);
+        pac::NVIC::unmask(pac::Interrupt::PIO1_IRQ_0);
+        pac::NVIC::unmask(pac::Interrupt::SW0_IRQ);
+        pac::NVIC::unmask(pac::Interrupt::SW1_IRQ);
     }
 
-    // free()は値を返すこともできます。
-    // ※ジェネリクスの機能で同じ関数でも異なる戻り値の型を扱うことができる
-    let get_interrupt_count = || free(|cs| INTERRUPT_COUNTER.borrow(cs).get());
+    // 割り込みの回数はcounter.rsが数えている（atomic-counterフィーチャーならfree()を使わずに読む）
+    let get_interrupt_count = counter::load;
     let mut counter_old = get_interrupt_count();
+    let mut next_ambient_sample = timer.get_counter().ticks();
+    let mut next_thermal_sample = timer.get_counter().ticks();
+    let mut next_ds18b20_sample = timer.get_counter().ticks();
+    let mut next_schedule_check = timer.get_counter().ticks();
+    let mut next_latency_report = time::add_interval(
+        timer.get_counter().ticks(),
+        irq_latency::LATENCY_REPORT_INTERVAL_MS * 1000,
+    );
+    // DS18B20の変換を始めた時刻。変換中でなければNone。
+    let mut ds18b20_converting: Option<u64> = None;
+    let mut button = Button::new(timer.get_counter());
+    let mut tap_tempo = TapTempo::default();
+    // 割り込みの実際の頻度を測るための前回のサンプル（カウンタの値と時刻）
+    let mut rate_sample = (get_interrupt_count(), timer.get_counter().ticks());
+    // 前回ログに出した消費電流の見積もり
+    let mut current_ua_old = None;
+    #[cfg(feature = "tick-source")]
+    let mut tick_reference_ms = tick_source::reference_ms();
     loop {
+        #[cfg(feature = "watchdog")]
+        last_gasp::feed();
+        let now = timer.get_counter();
+        #[cfg(feature = "reset-log")]
+        reset_log::note_uptime(now.ticks());
+
+        // 1秒ごとに割り込みの回数の増分を経過時間で割って、実際の頻度を求める。
+        // メインループの周期だけ測定が遅れるので、1秒ちょうどとはみなさず実際に経過したµsで割る。
+        // 小数点以下3桁まで出すため、mHz単位（1/1000 Hz）の整数で計算する。
+        let (rate_count, rate_time) = rate_sample;
+        if time::deadline_passed(
+            now.ticks(),
+            time::add_interval(rate_time, RATE_LOG_INTERVAL_MS * 1000),
+        ) {
+            let count = get_interrupt_count();
+            let elapsed_us = time::elapsed_us(now.ticks(), rate_time);
+            let rate_mhz = u64::from(count.wrapping_sub(rate_count)) * 1_000_000_000 / elapsed_us;
+            info!(
+                "interrupt rate: {}.{=u64:03} Hz",
+                rate_mhz / 1000,
+                rate_mhz % 1000
+            );
+            rate_sample = (count, now.ticks());
+            #[cfg(feature = "tick-source")]
+            {
+                tick_reference_ms = tick_source::tick_check(elapsed_us, tick_reference_ms);
+            }
+
+            // 割り込みカウンタが進んでいなければ、外付けのWDへのハートビートを止める
+            if heartbeat::report(now.ticks(), count) {
+                logging::log_event(Event::Error(logging::ERR_COUNTER_STALLED));
+            }
+
+            let summary = free(|cs| logging::Summary {
+                count,
+                mode: mode::mode(cs),
+                rate_mhz,
+                interval_ms: interval::current_ms(cs),
+                throttled: thermal::is_throttled(cs),
+            });
+            if !logging::log_summary(&mut status_tx, &summary) {
+                logging::log_event(Event::Error(logging::ERR_STATUS_LINE_DROPPED));
+            }
+
+            // defmtのログが捨てられていれば知らせる（rtt_logger.rs）
+            rtt_logger::report_dropped();
+
+            // 消費電流の見積もりはモードが変わったときだけ出す
+            let current_ua = power::estimate_current_ua();
+            if current_ua_old != Some(current_ua) {
+                info!("estimated current: {} uA", current_ua);
+                current_ua_old = Some(current_ua);
+            }
+        }
+        // ALARM0の割り込みの遅れの最大値を定期的に出す
+        if time::deadline_passed(now.ticks(), next_latency_report) {
+            next_latency_report =
+                time::add_interval(now.ticks(), irq_latency::LATENCY_REPORT_INTERVAL_MS * 1000);
+            irq_latency::report();
+        }
+        // ジッタのテストの統計がたまっていれば出す
+        jitter_test::report();
+        if time::deadline_passed(now.ticks(), next_ambient_sample) {
+            next_ambient_sample =
+                time::add_interval(now.ticks(), ambient::AMBIENT_SAMPLE_INTERVAL_MS * 1000);
+
+            // 自動の切り替えが有効なら、明るい部屋ならSolid、暗い部屋ならBlinkに切り替わり、
+            // set_mode_keep_auto()がModeChangedを出す
+            let reading = ambient.read(&mut adc);
+            ambient.update_dimming(reading);
+            let changed = free(|cs| {
+                if !ambient::is_auto_mode(cs) {
+                    return false;
+                }
+                let current = mode::mode(cs);
+                mode::set_mode_keep_auto(cs, ambient::next_mode(current, reading));
+                mode::mode(cs) != current
+            });
+            if changed {
+                debug!("ambient {}", reading);
+            }
+        }
+
+        // チップの温度が上がりすぎたら点滅を遅く、暗くする
+        if time::deadline_passed(now.ticks(), next_thermal_sample) {
+            next_thermal_sample =
+                time::add_interval(now.ticks(), thermal::THERMAL_SAMPLE_INTERVAL_MS * 1000);
+
+            let temp_mc = die_temp.read_millicelsius(&mut adc);
+            match thermal::update(temp_mc) {
+                Some(true) => warn!("die temperature {} mC: throttling", temp_mc),
+                Some(false) => info!("die temperature {} mC: throttle released", temp_mc),
+                None => {}
+            }
+        }
+
+        // 時刻のスケジュールが有効なら、RTCの時刻に合わせてモードを切り替える
+        if time::deadline_passed(now.ticks(), next_schedule_check) {
+            next_schedule_check =
+                time::add_interval(now.ticks(), schedule::SCHEDULE_CHECK_INTERVAL_MS * 1000);
+            if free(schedule::is_enabled) {
+                match ds3231::read_datetime() {
+                    Ok(dt) => {
+                        if let Some(next) = schedule::update(&dt) {
+                            info!("[{}] schedule: mode {}", Display2Format(&dt), next);
+                        }
+                    }
+                    Err(e) => debug!("schedule: rtc {}", e),
+                }
+            }
+        }
+
+        // DS18B20は変換に時間がかかるので、変換を始めてから待つ間もメインループは回し続ける
+        match ds18b20_converting {
+            None if time::deadline_passed(now.ticks(), next_ds18b20_sample) => {
+                next_ds18b20_sample =
+                    time::add_interval(now.ticks(), ds18b20::DS18B20_SAMPLE_INTERVAL_MS * 1000);
+                match ds18b20.start_conversion() {
+                    Ok(()) => ds18b20_converting = Some(now.ticks()),
+                    Err(e) => debug!("DS18B20: {}", e),
+                }
+            }
+            Some(started)
+                if time::deadline_passed(
+                    now.ticks(),
+                    time::add_interval(started, ds18b20::DS18B20_CONVERSION_MS * 1000),
+                ) =>
+            {
+                ds18b20_converting = None;
+                match ds18b20.read_millicelsius() {
+                    Ok(temp_mc) => info!("DS18B20 temperature {} mC", temp_mc),
+                    Err(e) => {
+                        logging::log_event(Event::Error(logging::ERR_DS18B20));
+                        debug!("DS18B20: {}", e);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        // 押した瞬間はタップテンポ、短押しは点滅の切り替え、長押しは設定の初期化。
+        // タップテンポの2回のタップはそれぞれ短押しにもなるので、モードは2回切り替わって元に戻る。
+        match button.poll(now) {
+            Some(ButtonEvent::Pressed) => {
+                logging::log_event(Event::ButtonPress);
+                free(idle_off::note_button_press);
+                if free(step_mode::is_enabled) || free(notify::is_notifying) {
+                    // コマ送りの間は短押しで1回進め、知らせている間は短押しで確認するので、タップテンポは測らない
+                } else if let Some(interval) = tap_tempo.tap(now) {
+                    free(|cs| interval::set_interval_ms(cs, interval));
+                    info!("tap tempo: blink interval set to {} ms", interval);
+                }
+            }
+            Some(ButtonEvent::ShortPress) if idle_off::take_wake_press() => {
+                // 止めていた点滅を再開させた押下なので、モードは切り替えない（idle_off.rs）
+            }
+            Some(ButtonEvent::ShortPress) if free(step_mode::is_enabled) => {
+                let count = step_mode::step();
+                let (mode, led_on) = free(|cs| (mode::mode(cs), LED_ON.borrow(cs).get()));
+                info!("step: count {} mode {} led_on {}", count, mode, led_on);
+            }
+            Some(ButtonEvent::ShortPress) if notify::acknowledge() => {
+                info!("notification acknowledged");
+            }
+            Some(ButtonEvent::ShortPress) => {
+                free(|cs| {
+                    let next = match mode::mode(cs) {
+                        LedMode::Blink => LedMode::Solid,
+                        _ => LedMode::Blink,
+                    };
+                    mode::set_mode(cs, next);
+                });
+            }
+            Some(ButtonEvent::Hold) => {
+                tap_tempo = TapTempo::default();
+                reset_settings();
+                info!("button held: settings reset to defaults");
+            }
+            None => {}
+        }
+        if tap_tempo.poll(now) {
+            info!("tap tempo: no second tap, measurement cancelled");
+        }
+
+        // しばらく操作がなければ点滅を止める（idle_off.rs）
+        idle_off::check(now.ticks());
+
+        // ロータリーエンコーダーを回した分だけ点滅間隔を変える（encoder.rs）
+        let steps = encoder::take_steps();
+        if steps != 0 {
+            let interval = free(|cs| {
+                let interval = encoder::adjusted_interval_ms(interval::interval_ms(cs), steps);
+                interval::set_interval_ms(cs, interval);
+                interval
+            });
+            info!("encoder: {} steps, blink interval {} ms", steps, interval);
+        }
+
         let interrupt_count = get_interrupt_count();
         if counter_old != interrupt_count {
             // C言語のprintfに相当するprintln!なども一例だが、Rustでは可変長引数というものが存在しない。
@@ -164,21 +887,95 @@ fn main() -> ! {
             // ※可変長引数は関数の呼び出し元が与えた情報（printfならフォーマット文字列）を「信頼して」処理をすすめている。
             // ※そして、その与えられた情報が間違いの場合メモリ破壊などを起こす危険性がある。
             // ※だからGCCやClangではprintfのフォーマット文に引数の型と合わない指定子の記述があったりすると警告がでる。
-            info!(
-                "interrupt count incremented! {} - {}",
-                counter_old, interrupt_count
-            );
+            //
+            // DS3231から日時が読めればログの先頭につける
+            let datetime = ds3231::read_datetime().ok();
+            logging::log_fast(interrupt_count, datetime);
             counter_old = interrupt_count;
         }
+
+        // OLEDの表示の更新（違っている文字だけを少しずつ書く）
+        ssd1306::poll(now.ticks(), || {
+            free(|cs| ssd1306::Status {
+                count: interrupt_count,
+                mode: mode::mode(cs),
+                interval_ms: interval::current_ms(cs),
+                uptime_us: now.ticks(),
+            })
+        });
+
+        // I2Cの転送が失敗し続けていれば、I2C0ブロックだけをリセットして復旧させる
+        if i2c_bus::needs_recovery() {
+            if i2c_bus::recover() {
+                logging::log_event(Event::Error(logging::ERR_I2C_RESET));
+            } else {
+                warn!("I2C0 recovery skipped: bus in use");
+            }
+        }
+
+        // 割り込みから頼まれた処理（マイルストーンなど）の実行
+        deferred::run_pending();
+
+        // 割り込みの中で積んだLEDの波形をdefmtに出す
+        waveform::drain(&mut wave_rx);
+
+        // UARTから届いたコマンドの実行
+        commands.poll(&mut status_tx);
+
+        // DMAの完了確認と次の転送の開始
+        status_tx.poll();
+
+        // periodic-rebootフィーチャーでは、決まった時間動いたら大事な処理の途中でないときにリセットする
+        #[cfg(feature = "periodic-reboot")]
+        if reboot::is_due(now.ticks()) && reboot::can_reboot(ds18b20_converting.is_some()) {
+            reboot::reboot(&mut status_tx, clocks.system_clock.freq().to_Hz());
+        }
     }
 }
 
+// 実行時に変更できる設定をすべて起動時の値（config.rs）に戻す
+fn reset_settings() {
+    let config = config::config();
+    oneshot::cancel();
+    blink_burst::cancel();
+    milestone::clear();
+    notify::clear();
+    prescaler::set_prescale(config.prescale);
+    waveform::set_enabled(false);
+    mfg_test::set_breathe_waveform(mfg_test::BREATHE_WAVEFORM);
+    schedule::set_enabled(false);
+    ambient::set_dimming(false);
+    ambient::set_dim_curve(ambient::AMBIENT_DIM_CURVE);
+    ambient::set_auto_mode(ambient::AMBIENT_AUTO_MODE);
+    led::set_led_brightness(u16::MAX);
+    tone::set_enabled(true);
+    bicolor::set_crossfade(false);
+    bicolor::set_crossfade_speed(bicolor::CROSSFADE_MS);
+    encoder::set_resolution(encoder::ENCODER_RESOLUTION);
+    idle_off::set_timeout_ms(idle_off::IDLE_OFF_TIMEOUT_MS);
+    idle_dim::set_idle_dim(idle_dim::IDLE_DIM_TIMEOUT_MS, idle_dim::IDLE_DIM_LEVEL);
+    bar_graph::set_bar_value(bar_graph::BAR_DEFAULT_PERCENT);
+    // 0は範囲の中なので失敗しない
+    jitter_test::set_amplitude_us(0).ok();
+    button::on_press(None);
+    button::on_release(None);
+    tone::set_tone_freq(config.tone_hz);
+    interval::set_interval_slew(interval::INTERVAL_SLEW_INSTANT);
+    free(|cs| {
+        mode::set_mode_keep_auto(cs, config.mode);
+        interval::set_interval_now(cs, config.blink_interval_ms);
+    });
+}
+
 // #pragma interruptみたいなもの
 // ただし、pragmaディレクティブのように処理系に紐付いたものではなく
 // 属性マクロと呼ばれるマクロの一種。
 // これをつけることで、コンパイル時にASTの操作が行われる（はず）。
 #[interrupt]
 fn TIMER_IRQ_0() {
+    // 割り込みの遅れを測るため、何よりも先にカウンタを読む（irq_latency.rs）
+    let entered_at = irq_latency::entry_timestamp();
+
     // TIMER_IRQ_0は多重割り込みが発生しないので
     // free()を使って割り込み禁止する必要がない。
     // そのため、unsafeではあるが、
@@ -186,31 +983,279 @@ fn TIMER_IRQ_0() {
     // 割り込み禁止の処理を省略する。
     let cs = unsafe { CriticalSection::new() };
 
-    let mut alarm0 = ALARM0.borrow(&cs).borrow_mut();
-    let mut led = LED.borrow(&cs).borrow_mut();
+    // ALARM0の要因が立っていなければ、カウントもLEDの更新もせずに戻る（alarm0_fired()参照）
+    if !alarm0_fired() {
+        let spurious = SPURIOUS_TIMER_IRQS.borrow(&cs);
+        spurious.set(spurious.get().wrapping_add(1));
+        return;
+    }
+    irq_latency::record_alarm0(&cs, entered_at);
+    jitter_test::record_fire(&cs, entered_at);
+    // 非常停止で止まっている間と、操作がなく点滅を止めている間（idle_off.rs）はLEDを更新せず、
+    // 解除されるまでALARM0の割り込みを止める（estop.rs）
+    if estop::is_latched() || idle_off::is_idle(&cs) {
+        shared::with(&cs, |shared| {
+            shared.alarm0.clear_interrupt();
+            shared.alarm0.disable_interrupt();
+        });
+        return;
+    }
 
-    // Copyトレイトが実装されている型はRefCellの変わりにCellが使える。
-    // 生値を取り出すことができるため、とりだしたあとは書き換えでも何でもできる。
-    // ※Copyトレイトが実装されている型のみなのはCellのgetメソッドにCopyのトレイト制約があるから。
-    // ※つまり、Copyトレイトを実装した型でしかgetメソッドは使えなくなっている。
-    //
-    // ※LEDなどのペリフェラル用structにはCopyトレイトは実装されていないので、このメソッドは使えない。
-    // ※Copyトレイトが実装されている=実行時にペリフェラルが複製される=ハードのクローンが物理的に湧いてでるなので
-    // ※Copyトレイトが実装されていないのはイメージ的にも正しい。
-    //
-    // ※ちなみにRustの制約として、
-    // ※すでに別ライブラリ（標準ライブラリ含む）で定義されているstructとトレイトを使って
-    // ※新しくトレイトの実装をすることはできなくなっている。
-    // ※今回の場合だと、LED用のペリフェラルの型（rp2040-pacライブラリ）に
-    // ※無理やりCopyトレイト（coreライブラリ）を実装して
-    // ※getメソッドを使えるようにしてやる！みたいなことはできず、コンパイルエラーになる。
-    let counter = INTERRUPT_COUNTER.borrow(&cs).get();
-    if let (Some(alarm0), Some(led)) = (alarm0.deref_mut(), led.deref_mut()) {
+    do_tick(&cs);
+}
+
+// TIMER_IRQ_0で行う1回分の処理。コマ送り（step_mode.rs）ではボタンの短押しからも呼ぶ。
+// 進めたあとの割り込みカウンタの値を返す。
+fn do_tick(cs: &CriticalSection) -> u32 {
+    // SHAREDを借りるのはALARM0を触る間だけにする。update_led()はLEDとブザーを書くときに
+    // SHAREDを借りるので、借りたまま呼ぶと入れ子の借用になる（shared.rs参照）。
+    if shared::with(cs, |shared| shared.alarm0.clear_interrupt()).is_some() {
+        // プリスケーラで間引かれた回はカウントだけしてLEDは触らず、前の段と同じ長さだけ待つ（prescaler.rs）
+        // 点滅間隔の変化の速さを制限していれば、目標に近づける（interval.rs）
+        interval::advance(cs);
+        let next_ms = if prescaler::tick(cs) {
+            let ms = update_led(cs);
+            prescaler::record_step(cs, ms);
+            ms
+        } else {
+            prescaler::step_ms(cs)
+        };
+        // blink_times()の回数分点滅し終わったら、動かし直すまで割り込みを止める。
+        // コマ送りの間も、次のボタンを押すまで止めておく。
+        // 商用電源のゼロクロスで進めている間（mains_sync.rs）は、次のゼロクロスで進める。
+        let stop =
+            blink_count::is_stopped(cs) || step_mode::is_enabled(cs) || mains_sync::is_driving(cs);
+        // 目標の頻度があれば、実際に測った時刻で補正した間隔にする（rate_control.rs）
+        // ジッタのテスト中なら、間隔にばらつきを足す（jitter_test.rs）
+        let next_us = if stop {
+            None
+        } else {
+            rate_control::next_interval_us(cs)
+                .or_else(|| jitter_test::next_interval_us(cs, next_ms))
+        };
+        shared::with(cs, |shared| {
+            let alarm0 = &mut shared.alarm0;
+            if stop {
+                alarm0.disable_interrupt();
+            } else {
+                let interval_us = next_us.unwrap_or(next_ms.saturating_mul(1000));
+                reschedule_alarm0(cs, alarm0, interval_us);
+            }
+        });
+        jitter_test::after_schedule(cs);
+    }
+
+    let count = counter::increment(cs);
+    milestone::check(cs, count);
+    binary_log::record_tick(cs, count);
+    count
+}
+
+// TIMER_IRQ_0に入ったとき、本当にALARM0が発火しているかを確かめる。
+//
+// NVICの保留ビットはソフトウェアからも立てられる（NVIC::pend()）し、要因を消した直後に
+// 保留が残っていた場合などにも、ALARM0が発火していないのにTIMER_IRQ_0に入ることがある。
+// そのまま処理すると、LEDが余計に1回切り替わり、カウンタも1つ余計に進んでしまう。
+//
+// TIMERのINTSレジスタは、発火した要因（INTR）のうち有効なもの（INTE）と、
+// 強制した要因（INTF）を合わせたもので、TIMER_IRQ_0の本当の要因を表す。
+// ALARM0のビットはclear_interrupt()で消すまで立っているので、ここで見れば判定できる。
+// HALのfinished()はARMED（まだ発火していないか）を見るだけで、
+// 割り込みの要因が残っているかはわからないので使わない。
+fn alarm0_fired() -> bool {
+    // 読み出すだけなので、ほかのALARMやHALの状態には影響しない
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    timer.ints().read().alarm_0().bit_is_set()
+}
+
+// ALARM0が次に発火するまでの時間（µs）。ALARM0が待っていない（発火した後か、まだscheduleしていない）ならNone。
+//
+// ALARM0の比較レジスタに入っている発火時刻から、今のカウンタの値を引いて求める。
+// 比較レジスタはカウンタの下位32bitとしか比べないので、ここでも下位32bit（TIMERAWL）どうしで計算する。
+// 下位32bitは約71分で一周するが、scheduleできる最長の間隔（MAX_ALARM_INTERVAL_MS）は一周より短いので、
+// wrapping_sub()で引けば一周をまたいでいても正しい残り時間になる。
+// 精度は1µsだが、読み出してから使うまでの間にも時間は進むので、その分だけ実際より長めの値になる。
+//
+// 比較レジスタ、カウンタ、ARMEDの順に読む。最後にまだARMEDなら、カウンタを読んだ時点では
+// 発火していなかったので、引き算の結果が負（一周した大きな値）になることはない。
+fn time_to_next_alarm_us() -> Option<u32> {
+    // 読み出すだけなので、ほかのALARMやHALの状態には影響しない
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    let compare = timer.alarm0().read().bits();
+    let now = timer.timerawl().read().bits();
+    let armed = timer.armed().read().armed().bits() & 0x1 != 0;
+    armed.then(|| compare.wrapping_sub(now))
+}
+
+// ワンショットタイマーとソフトウェアタイマー（ALARM1）の割り込み
+#[interrupt]
+fn TIMER_IRQ_1() {
+    // TIMER_IRQ_0と同じく多重割り込みは発生しない
+    let cs = unsafe { CriticalSection::new() };
+    soft_timer::fire(&cs);
+}
+
+// 非常停止（GPIO21をPIO0で見張っている）の割り込み。ほかのどの割り込みよりも優先度が高い。
+// ほかの割り込みの途中にも入ってくるので、CriticalSection::new()は使わず、グローバル変数にも触らない。
+#[interrupt]
+fn PIO0_IRQ_0() {
+    estop::on_estop();
+}
+
+// 多重割り込みのデモ（nested_irq.rs）。SW1_IRQはSW0_IRQより優先度が高く、その途中に割り込む。
+#[interrupt]
+fn SW0_IRQ() {
+    nested_irq::on_low();
+}
+
+#[interrupt]
+fn SW1_IRQ() {
+    nested_irq::on_high();
+}
+
+// PIO1でパルスを出し終わったときの割り込み（pulse_train.rs）
+#[interrupt]
+fn PIO1_IRQ_0() {
+    // ほかのTIMER_IRQやIO_IRQ_BANK0と同じ優先度なので、多重には入らない
+    let cs = unsafe { CriticalSection::new() };
+    pulse_train::on_interrupt(&cs);
+}
+
+// GPIOの割り込み。GPIO14のボタン、GPIO17のパルス幅の測定、GPIO20のマンチェスター符号の受信、
+// GPIO10の商用電源のゼロクロス、GPIO11のエッジの計数、GPIO13の赤外線リモコンが使っている。
+// どのピンの割り込みもこのハンドラに来るので、それぞれが自分のピンの要因を確かめて処理する。
+#[interrupt]
+fn IO_IRQ_BANK0() {
+    // GPIOの割り込みもこのハンドラ1つにまとまっていて、多重には入らない
+    let cs = unsafe { CriticalSection::new() };
+    // いちばん速いエッジが来るので最初に見る（edge_counter.rs）
+    edge_counter::on_interrupt(&cs);
+    pulse_width::on_interrupt(&cs);
+    manchester::on_interrupt(&cs);
+    mains_sync::on_interrupt(&cs);
+    ir_nec::on_interrupt(&cs);
+    encoder::on_interrupt(&cs);
+    if toggle_button::on_interrupt(&cs) {
+        // 点灯に戻したモードの表示をすぐに始めるため、ALARM0をすぐに発火させる
+        let at = time::add_interval(counter_now_us(), time::MIN_INTERVAL_US);
+        shared::with(&cs, |shared| {
+            schedule_alarm0_at(&cs, &mut shared.alarm0, at)
+        });
+    }
+}
+
+// 点滅を最初の点灯からやり直す。止めていたALARM0の割り込みも有効に戻す（blink_count参照）。
+fn restart_blink(cs: &CriticalSection) {
+    restart_blink_at(cs, None);
+}
+
+// restart_blink()と同じだが、atがSomeならその時刻（タイマーカウンタ）に最初の点灯を出す（phase_sync.rs）
+fn restart_blink_at(cs: &CriticalSection, at: Option<u64>) {
+    LED_ON.borrow(cs).set(false);
+    // コマ送りの間はALARM0の割り込みを止めたまま、次のボタンで最初の点灯から進める
+    if step_mode::is_enabled(cs) {
+        return;
+    }
+    shared::with(cs, |shared| {
+        let alarm0 = &mut shared.alarm0;
         alarm0.clear_interrupt();
-        alarm0.schedule(ALARM0_INTERVAL_MS.micros()).unwrap();
+        alarm0.enable_interrupt();
+        let at = at.unwrap_or_else(|| time::add_interval(counter_now_us(), time::MIN_INTERVAL_US));
+        schedule_alarm0_at(cs, alarm0, at);
+    });
+}
 
-        led.toggle().unwrap();
+// 明るさのフェード（ALARM2）の割り込み
+#[interrupt]
+fn TIMER_IRQ_2() {
+    let cs = unsafe { CriticalSection::new() };
+    fade::tick(&cs);
+}
+
+// tick-sourceフィーチャーでタイマーの速さを確かめる基準（SysTick、1 msごと）と、
+// watchdogフィーチャーでリセットの直前に最後のログを出すための確認（last_gasp.rs）
+#[cfg(any(feature = "tick-source", feature = "watchdog"))]
+#[cortex_m_rt::exception]
+fn SysTick() {
+    // watchdogフィーチャーではSysTickがほかの割り込みより優先度が高くなるが、
+    // reference_tick()はCellを1つ進めるだけで、メインループはfree()の中で読むので問題ない
+    #[cfg(feature = "tick-source")]
+    {
+        let cs = unsafe { CriticalSection::new() };
+        tick_source::reference_tick(&cs);
     }
+    #[cfg(feature = "watchdog")]
+    last_gasp::check();
+}
+
+// ボタンのサンプリングとハートビート（ALARM3）の割り込み
+#[interrupt]
+fn TIMER_IRQ_3() {
+    let cs = unsafe { CriticalSection::new() };
+    sampler::tick(&cs);
+}
 
-    INTERRUPT_COUNTER.borrow(&cs).set(counter.wrapping_add(1));
+// モードに応じてLEDのデューティを決めて書き込み、次にLEDを更新するまでの時間（ms）を返す
+fn update_led(cs: &CriticalSection) -> u32 {
+    let interval = interval::current_ms(cs);
+    let mode = mode::mode(cs);
+    // 製造時の検査のパターンはほかのどの表示よりも優先する（mfg_test.rs）
+    if let Some((duty, duration_ms)) = mfg_test::next_step(cs) {
+        led::write_led(cs, duty);
+        tone::gate(cs, false);
+        return duration_ms;
+    }
+    // 素早い点滅の最中はモードより優先する
+    if let Some(step) = burst::next_step(cs) {
+        led::write_led(cs, led::blink_duty(step.on));
+        tone::gate(cs, step.on);
+        return step.duration_ms;
+    }
+    // 記録したパターンの再生中もモードより優先する（recorder.rs）
+    if let Some(step) = recorder::next_step(cs) {
+        led::write_led(cs, led::blink_duty(step.on));
+        tone::gate(cs, step.on);
+        return step.duration_ms;
+    }
+    let (duty, next_ms) = match mode {
+        LedMode::Solid => (led::LED_DIM_DUTY, interval),
+        LedMode::Off => (led::LED_OFF_DUTY, interval),
+        LedMode::Blink => {
+            let led_on = LED_ON.borrow(cs);
+            let on = !led_on.get();
+            // blink_times()の回数分点滅し終わっていれば消灯したまま止まる
+            if on && !blink_count::take_blink(cs) {
+                (led::LED_OFF_DUTY, interval)
+            } else {
+                led_on.set(on);
+                (led::blink_duty(on), interval)
+            }
+        }
+        LedMode::Number => {
+            let step = number::next_step(cs);
+            (led::blink_duty(step.on), step.duration_ms)
+        }
+        LedMode::Heartbeat => {
+            let step = double_blink::next_step(cs);
+            (led::blink_duty(step.on), step.duration_ms)
+        }
+        LedMode::Bar => {
+            let step = bar_graph::next_step(cs);
+            (led::blink_duty(step.on), step.duration_ms)
+        }
+        LedMode::EggTimer => {
+            let step = egg_timer::next_step(cs);
+            (led::blink_duty(step.on), step.duration_ms)
+        }
+    };
+    // 温度が高いときは暗く、ゆっくりにする
+    let (duty, next_ms) = thermal::throttle(cs, duty, next_ms);
+    led::write_led(cs, duty);
+    // ブザーは点滅しているモードで点灯している間だけ鳴らす（Solidで鳴りっぱなしにしない）
+    let blinking = matches!(
+        mode,
+        LedMode::Blink | LedMode::Number | LedMode::Heartbeat | LedMode::Bar | LedMode::EggTimer
+    );
+    tone::gate(cs, blinking && led::is_lit(duty));
+    next_ms
 }
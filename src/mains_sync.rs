@@ -0,0 +1,255 @@
+// 商用電源（50/60 Hz）のゼロクロスに合わせてLEDを点滅させる
+//
+// 安全上の注意
+//   商用電源をGPIOに直接つないではいけない。必ず絶縁されたゼロクロス検出回路
+//   （フォトカプラを使ったモジュールなど）を通し、Picoの側には3.3 Vの信号だけが来るようにすること。
+//   1次側（電源側）の配線は、資格のある人が規格に合った絶縁距離と筐体で行うこと。
+//   Picoの側のGNDを電源側のどこかにつなぐと絶縁がなくなるので、決してつながないこと。
+//
+// 配線
+//   ゼロクロス検出回路のオープンコレクタ出力をGPIO10につなぐ。内部プルアップ。
+//   電源の電圧が0 Vを横切るたびに出力が短くLowになる（全波で検出する回路なので、1周期に2回）。
+//   そのため立ち下がりの間隔は、50 Hzで10 ms、60 Hzで約8.33 msになる。
+//
+// 周波数の判定
+//   立ち下がりの間隔を測り、50 Hzと60 Hzのどちらの半周期からMAINS_TOLERANCE_PERCENT（%）以内かを見る。
+//   同じ周波数の間隔がMAINS_LOCK_CROSSINGS回続いたら、その周波数に同期した（ロックした）とみなす。
+//   ・60 Hzの半周期より明らかに短い間隔のエッジは、ノイズとして無視する（前のエッジの時刻も変えない）
+//   ・どちらにも入らない間隔（ゼロクロスを取りこぼしたなど）が来たら、数え直す
+//   ・MAINS_LOST_MSの間エッジが来なければ信号がなくなったとみなし、ロックを外す（sampler.rs）
+//
+// LEDの駆動
+//   mains on で有効にすると、ロックしている間はALARM0の割り込みを止め、
+//   ゼロクロスのdivider回に1回、TIMER_IRQ_0と同じ処理（do_tick()）をIO_IRQ_BANK0から呼ぶ。
+//   Blinkモードなら、点滅の周波数は (ゼロクロスの回数/秒) ÷ (2 × divider) になる。
+//   （初期値のMAINS_DIVIDER = 50なら、50 Hzで1 Hz、60 Hzで1.2 Hzの点滅）
+//   この間、点滅の間隔やNumberモードの1ステップの時間は使わず、どれもdivider回のゼロクロスで1ステップ進む。
+//   ロックが外れたら点滅を最初からやり直し、ALARM0で今までどおりに点滅させる。
+//   コマ送り（step_mode.rs）の間と非常停止で止まっている間は、ゼロクロスでは進めない。
+
+use crate::cs_trace::free;
+use crate::estop;
+use crate::pull::{self, Pull};
+use crate::step_mode;
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::{Cell, RefCell};
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp_pico::hal::gpio;
+use rp_pico::hal::timer::{Alarm, Timer};
+
+pub const ZERO_CROSS_PULL: Pull = Pull::Up;
+
+// ゼロクロスの間隔（µs）。1周期に2回なので半周期になる。
+const HALF_PERIOD_50HZ_US: u32 = 1_000_000 / 50 / 2;
+const HALF_PERIOD_60HZ_US: u32 = 1_000_000 / 60 / 2;
+pub const MAINS_TOLERANCE_PERCENT: u32 = 8;
+// 50 Hzと60 Hzの許容範囲が重ならないように
+const _: () = assert!(
+    HALF_PERIOD_60HZ_US * (100 + MAINS_TOLERANCE_PERCENT)
+        < HALF_PERIOD_50HZ_US * (100 - MAINS_TOLERANCE_PERCENT)
+);
+// これより短い間隔のエッジはノイズとして無視する
+const MIN_CROSSING_US: u32 = HALF_PERIOD_60HZ_US * (100 - MAINS_TOLERANCE_PERCENT) / 100;
+pub const MAINS_LOCK_CROSSINGS: u8 = 8;
+pub const MAINS_LOST_MS: u32 = 50;
+pub const MAINS_DIVIDER: u32 = 50;
+
+pub type ZeroCrossPin = pull::InputPin<gpio::bank0::Gpio10>;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum MainsFreq {
+    Hz50,
+    Hz60,
+}
+
+impl MainsFreq {
+    pub fn hz(self) -> u32 {
+        match self {
+            MainsFreq::Hz50 => 50,
+            MainsFreq::Hz60 => 60,
+        }
+    }
+
+    fn half_period_us(self) -> u32 {
+        match self {
+            MainsFreq::Hz50 => HALF_PERIOD_50HZ_US,
+            MainsFreq::Hz60 => HALF_PERIOD_60HZ_US,
+        }
+    }
+
+    // 間隔がどちらの周波数の半周期に入るか
+    fn classify(interval_us: u32) -> Option<MainsFreq> {
+        [MainsFreq::Hz50, MainsFreq::Hz60].into_iter().find(|freq| {
+            let expected = freq.half_period_us();
+            interval_us.abs_diff(expected) <= expected * MAINS_TOLERANCE_PERCENT / 100
+        })
+    }
+}
+
+struct Detector {
+    // 前のゼロクロスの時刻（タイマーの下位32bit）
+    last_edge: Option<u32>,
+    // 同じ周波数の間隔が続いた回数
+    candidate: Option<(MainsFreq, u8)>,
+    // ロックしていれば、その周波数
+    locked: Option<MainsFreq>,
+    // ロックしてからのゼロクロスの回数（dividerで割った余り）
+    crossings: u32,
+}
+
+impl Detector {
+    const fn new() -> Self {
+        Self {
+            last_edge: None,
+            candidate: None,
+            locked: None,
+            crossings: 0,
+        }
+    }
+
+    // ゼロクロスが来たときに呼ぶ。ロックしたままならtrue。
+    fn edge(&mut self, now: u32) -> bool {
+        let Some(last) = self.last_edge else {
+            self.last_edge = Some(now);
+            return false;
+        };
+        let interval_us = now.wrapping_sub(last);
+        if interval_us < MIN_CROSSING_US {
+            return self.locked.is_some();
+        }
+        self.last_edge = Some(now);
+
+        let Some(freq) = MainsFreq::classify(interval_us) else {
+            // 取りこぼしか大きなノイズ。ロックしていても、次に続けて入るまで数え直す。
+            self.candidate = None;
+            return self.locked.is_some();
+        };
+        let count = match self.candidate {
+            Some((candidate, count)) if candidate == freq => count.saturating_add(1),
+            _ => 1,
+        };
+        self.candidate = Some((freq, count));
+        if count >= MAINS_LOCK_CROSSINGS && self.locked != Some(freq) {
+            self.locked = Some(freq);
+            self.crossings = 0;
+            defmt::info!("mains: locked to {} Hz", freq.hz());
+        }
+        self.locked.is_some()
+    }
+}
+
+static ZERO_CROSS_PIN: GlobalPeripheral<ZeroCrossPin> = initial_global_peripheral();
+static MAINS_TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+static DETECTOR: Mutex<RefCell<Detector>> = Mutex::new(RefCell::new(Detector::new()));
+static ENABLED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+static DIVIDER: Mutex<Cell<u32>> = Mutex::new(Cell::new(MAINS_DIVIDER));
+
+pub fn init(cs: &CriticalSection, pin: ZeroCrossPin, timer: Timer) {
+    pin.set_interrupt_enabled(gpio::Interrupt::EdgeLow, true);
+    ZERO_CROSS_PIN.borrow(cs).replace(Some(pin));
+    MAINS_TIMER.borrow(cs).set(Some(timer));
+}
+
+// 検出した周波数。ロックしていなければNone。
+pub fn frequency(cs: &CriticalSection) -> Option<MainsFreq> {
+    DETECTOR.borrow(cs).borrow().locked
+}
+
+pub fn is_enabled(cs: &CriticalSection) -> bool {
+    ENABLED.borrow(cs).get()
+}
+
+pub fn divider(cs: &CriticalSection) -> u32 {
+    DIVIDER.borrow(cs).get()
+}
+
+// ゼロクロスでLEDを進めているか。do_tick()はこの間ALARM0をscheduleし直さない。
+pub fn is_driving(cs: &CriticalSection) -> bool {
+    is_enabled(cs) && frequency(cs).is_some() && !step_mode::is_enabled(cs)
+}
+
+// ゼロクロスに合わせるかを切り替える。無効に戻したら点滅をALARM0でやり直す。
+pub fn set_enabled(enabled: bool) {
+    free(|cs| {
+        let was_driving = is_driving(cs);
+        ENABLED.borrow(cs).set(enabled);
+        if was_driving && !is_driving(cs) {
+            crate::restart_blink(cs);
+        }
+    });
+}
+
+// 何回のゼロクロスで1ステップ進めるか。0は1にする。設定した値を返す。
+pub fn set_divider(divider: u32) -> u32 {
+    let divider = divider.max(1);
+    free(|cs| {
+        DIVIDER.borrow(cs).set(divider);
+        DETECTOR.borrow(cs).borrow_mut().crossings = 0;
+    });
+    divider
+}
+
+// IO_IRQ_BANK0から呼ぶ
+pub fn on_interrupt(cs: &CriticalSection) {
+    let mut pin = ZERO_CROSS_PIN.borrow(cs).borrow_mut();
+    let Some(pin) = pin.as_mut() else {
+        return;
+    };
+    if !pin.interrupt_status(gpio::Interrupt::EdgeLow) {
+        return;
+    }
+    pin.clear_interrupt(gpio::Interrupt::EdgeLow);
+
+    let Some(timer) = MAINS_TIMER.borrow(cs).get() else {
+        return;
+    };
+    let now = timer.get_counter_low();
+    let step = {
+        let mut detector = DETECTOR.borrow(cs).borrow_mut();
+        if !detector.edge(now) {
+            return;
+        }
+        detector.crossings += 1;
+        if detector.crossings < divider(cs) {
+            false
+        } else {
+            detector.crossings = 0;
+            true
+        }
+    };
+    if !is_driving(cs) || estop::is_latched() {
+        return;
+    }
+    // ゼロクロスで進めている間はALARM0で進めないようにする（ロックした直後と点滅をやり直した後）
+    crate::shared::with(cs, |shared| {
+        shared.alarm0.disable_interrupt();
+        shared.alarm0.clear_interrupt();
+    });
+    if step {
+        crate::do_tick(cs);
+    }
+}
+
+// samplerから呼ぶ。MAINS_LOST_MSの間ゼロクロスがなければロックを外し、ALARM0の点滅に戻す。
+pub fn check_lost(cs: &CriticalSection) {
+    let Some(timer) = MAINS_TIMER.borrow(cs).get() else {
+        return;
+    };
+    let was_driving = is_driving(cs);
+    {
+        let mut detector = DETECTOR.borrow(cs).borrow_mut();
+        let Some(last) = detector.last_edge else {
+            return;
+        };
+        if timer.get_counter_low().wrapping_sub(last) <= MAINS_LOST_MS * 1000 {
+            return;
+        }
+        if detector.locked.is_some() {
+            defmt::warn!("mains: signal lost, back to timer blink");
+        }
+        *detector = Detector::new();
+    }
+    if was_driving {
+        crate::restart_blink(cs);
+    }
+}
@@ -0,0 +1,264 @@
+// GPIO20に入ってくるマンチェスター符号を受信してバイト列に戻す
+//
+// 信号の形式
+//   ・1ビットをビット周期Tで送り、ビットの真ん中で必ずレベルが変わる（IEEE 802.3と同じ向き）
+//       1 : 前半Low → 後半High（真ん中で立ち上がり）
+//       0 : 前半High → 後半Low（真ん中で立ち下がり）
+//     同じビットが続くときは、ビットの境目でもレベルが変わる
+//   ・送っていないときはLow（PULLでもLowに落ち着かせる）
+//   ・フレームは プリアンブル → SFD → データ（各バイトMSBから）の順
+//       プリアンブル : 1と0を交互に、1から始めて少なくともMANCHESTER_PREAMBLE_BITSビット（0xAAの繰り返し）
+//       SFD          : 0xAB（プリアンブルの最後が11になるところでバイトの区切りを合わせる）
+//       データ       : 最大MANCHESTER_FRAME_LENバイト
+//   ・データを送り終わったら、2ビット周期より長くLowのまま待つとフレームの終わりになる
+//
+// 受信のしかた
+//   両方のエッジで割り込み（IO_IRQ_BANK0）を入れ、タイマーの時刻から前のエッジとの間隔を測る。
+//   間隔はT/2（短い）かT（長い）のどちらかで、どちらでもなければ受信をやめてプリアンブルを探し直す。
+//   ・ビットの真ん中のエッジから短い間隔の次はビットの境目、長い間隔の次はまたビットの真ん中
+//   ・ビットの境目のエッジからは必ず短い間隔で真ん中に来る（長ければ誤り）
+//   ・ビットの真ん中のエッジの向きがそのビットの値になる
+//   プリアンブルは長い間隔だけが続くので、すべてのエッジがビットの真ん中だとわかる。
+//   長い間隔がMANCHESTER_PREAMBLE_BITS回続いたところで同期したとみなし、SFDを待つ。
+//
+// クロックのずれ
+//   送り側のクロックは受け側とぴったり同じではないので、間隔は
+//   推定したT/2のMANCHESTER_TOLERANCE_PERCENT（%）までずれていても受け付ける。
+//   推定したT/2は、受け付けた間隔で少しずつ（1/8ずつ）測った値に寄せていくので、
+//   ゆっくりしたずれならフレームの途中でも追いかけられる。プリアンブルを探し直すときは設定値に戻す。
+//   短い間隔と長い間隔を区別できるよう、許容するずれは33%より小さくすること。
+//
+// フレームの終わりはsampler（SAMPLE_PERIOD_MSごと）のcheck_idle()で見つけ、受け取ったバイトをログに出す。
+// 割り込みに入るまでの遅れがT/2に比べて無視できない速さでは受信できないので、
+// ビット周期はMANCHESTER_MIN_BIT_US以上にしている（上限のMANCHESTER_MAX_BIT_USは計算があふれないため）。
+
+use crate::cs_trace::free;
+use crate::pull::{self, Pull};
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::{Cell, RefCell};
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp_pico::hal::gpio;
+use rp_pico::hal::timer::Timer;
+
+pub const MANCHESTER_PULL: Pull = Pull::Down;
+
+// ビット周期（µs）の初期値。1000 µsで1 kbps。
+pub const MANCHESTER_BIT_US: u32 = 1000;
+pub const MANCHESTER_MIN_BIT_US: u32 = 100;
+pub const MANCHESTER_MAX_BIT_US: u32 = 100_000;
+pub const MANCHESTER_TOLERANCE_PERCENT: u32 = 25;
+const _: () = assert!(MANCHESTER_TOLERANCE_PERCENT < 33);
+pub const MANCHESTER_PREAMBLE_BITS: u8 = 8;
+const MANCHESTER_SFD: u8 = 0xAB;
+// 同期してからSFDが来るまで待つビット数。これを過ぎたらプリアンブルを探し直す。
+const SFD_TIMEOUT_BITS: u8 = 64;
+pub const MANCHESTER_FRAME_LEN: usize = 32;
+
+pub type ManchesterPin = pull::InputPin<gpio::bank0::Gpio20>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    // プリアンブルの長い間隔を数えている
+    Hunting(u8),
+    // 同期してSFDを待っている（待ったビット数）
+    Sync(u8),
+    // データを受け取っている
+    Receiving,
+}
+
+struct Decoder {
+    state: State,
+    // 推定したT/2（µs）
+    half_us: u32,
+    // 最後のエッジの時刻。エッジを見ていなければNone。
+    last_edge: Option<u32>,
+    // 最後のエッジがビットの真ん中だったか
+    at_mid: bool,
+    // 受け取ったビット（下位ビットが最新）
+    shift: u8,
+    bits: u8,
+    frame: [u8; MANCHESTER_FRAME_LEN],
+    len: usize,
+}
+
+impl Decoder {
+    const fn new(bit_us: u32) -> Self {
+        Self {
+            state: State::Hunting(0),
+            half_us: bit_us / 2,
+            last_edge: None,
+            at_mid: true,
+            shift: 0,
+            bits: 0,
+            frame: [0; MANCHESTER_FRAME_LEN],
+            len: 0,
+        }
+    }
+
+    // 受け取っていたフレームを出して、プリアンブルを探し直す
+    fn restart(&mut self, bit_us: u32) {
+        if self.len > 0 {
+            defmt::info!("manchester: frame {=[u8]:02x}", &self.frame[..self.len]);
+        } else if self.state != State::Hunting(0) {
+            defmt::debug!("manchester: lost sync");
+        }
+        *self = Self::new(bit_us);
+    }
+
+    // 間隔がT/2の何倍か（1か2）。どちらでもなければNone。
+    fn classify(&self, interval_us: u32) -> Option<u32> {
+        let tolerance = self.half_us * MANCHESTER_TOLERANCE_PERCENT / 100;
+        [1, 2].into_iter().find(|&halves| {
+            let expected = self.half_us * halves;
+            interval_us.abs_diff(expected) <= tolerance * halves
+        })
+    }
+
+    fn push_bit(&mut self, bit: bool) {
+        self.shift = (self.shift << 1) | bit as u8;
+        match self.state {
+            State::Hunting(_) => {}
+            State::Sync(waited) => {
+                if self.shift == MANCHESTER_SFD {
+                    self.state = State::Receiving;
+                    self.bits = 0;
+                } else if waited >= SFD_TIMEOUT_BITS {
+                    self.state = State::Hunting(0);
+                } else {
+                    self.state = State::Sync(waited + 1);
+                }
+            }
+            State::Receiving => {
+                self.bits += 1;
+                if self.bits == 8 {
+                    self.bits = 0;
+                    self.frame[self.len] = self.shift;
+                    self.len += 1;
+                }
+            }
+        }
+    }
+
+    // エッジごとに呼ぶ。risingは立ち上がりか。
+    fn edge(&mut self, now: u32, rising: bool, bit_us: u32) {
+        let Some(last) = self.last_edge.replace(now) else {
+            // 最初のエッジ。プリアンブルは1から始まるので、ビットの真ん中とみなす。
+            self.push_bit(rising);
+            return;
+        };
+        let interval_us = now.wrapping_sub(last);
+        let Some(halves) = self.classify(interval_us) else {
+            self.restart(bit_us);
+            // このエッジが次のフレームの最初のエッジかもしれない
+            self.last_edge = Some(now);
+            self.push_bit(rising);
+            return;
+        };
+        // 受け付けた間隔でT/2の推定を寄せる
+        let measured = interval_us / halves;
+        self.half_us = (self.half_us * 7 + measured) / 8;
+
+        match self.state {
+            State::Hunting(count) => {
+                if halves == 2 {
+                    let count = count + 1;
+                    self.state = if count >= MANCHESTER_PREAMBLE_BITS {
+                        State::Sync(0)
+                    } else {
+                        State::Hunting(count)
+                    };
+                    self.push_bit(rising);
+                } else {
+                    // プリアンブルではない。数え直す。
+                    self.state = State::Hunting(0);
+                }
+            }
+            _ => {
+                let at_mid = match (self.at_mid, halves) {
+                    (true, 1) => false,
+                    (true, _) | (false, 1) => true,
+                    _ => {
+                        self.restart(bit_us);
+                        return;
+                    }
+                };
+                self.at_mid = at_mid;
+                if at_mid {
+                    self.push_bit(rising);
+                    if self.len == MANCHESTER_FRAME_LEN {
+                        self.restart(bit_us);
+                    }
+                }
+            }
+        }
+    }
+}
+
+static MANCHESTER_PIN: GlobalPeripheral<ManchesterPin> = initial_global_peripheral();
+static MANCHESTER_TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+static BIT_US: Mutex<Cell<u32>> = Mutex::new(Cell::new(MANCHESTER_BIT_US));
+static DECODER: Mutex<RefCell<Decoder>> = Mutex::new(RefCell::new(Decoder::new(MANCHESTER_BIT_US)));
+
+pub fn init(cs: &CriticalSection, pin: ManchesterPin, timer: Timer) {
+    pin.set_interrupt_enabled(gpio::Interrupt::EdgeHigh, true);
+    pin.set_interrupt_enabled(gpio::Interrupt::EdgeLow, true);
+    MANCHESTER_PIN.borrow(cs).replace(Some(pin));
+    MANCHESTER_TIMER.borrow(cs).set(Some(timer));
+}
+
+// ビット周期（µs）を変える。MANCHESTER_MIN_BIT_US〜MANCHESTER_MAX_BIT_USの範囲に丸める。
+// 受信中のフレームは捨てて、新しい周期でプリアンブルを探し直す。設定した周期を返す。
+pub fn set_bit_period_us(us: u32) -> u32 {
+    let us = us.clamp(MANCHESTER_MIN_BIT_US, MANCHESTER_MAX_BIT_US);
+    free(|cs| {
+        BIT_US.borrow(cs).set(us);
+        DECODER.borrow(cs).replace(Decoder::new(us));
+    });
+    us
+}
+
+pub fn bit_period_us(cs: &CriticalSection) -> u32 {
+    BIT_US.borrow(cs).get()
+}
+
+// IO_IRQ_BANK0から呼ぶ
+pub fn on_interrupt(cs: &CriticalSection) {
+    let mut pin = MANCHESTER_PIN.borrow(cs).borrow_mut();
+    let Some(pin) = pin.as_mut() else {
+        return;
+    };
+    let rose = pin.interrupt_status(gpio::Interrupt::EdgeHigh);
+    let fell = pin.interrupt_status(gpio::Interrupt::EdgeLow);
+    if !rose && !fell {
+        return;
+    }
+    pin.clear_interrupt(gpio::Interrupt::EdgeHigh);
+    pin.clear_interrupt(gpio::Interrupt::EdgeLow);
+
+    let Some(timer) = MANCHESTER_TIMER.borrow(cs).get() else {
+        return;
+    };
+    let now = timer.get_counter_low();
+    let bit_us = bit_period_us(cs);
+    let mut decoder = DECODER.borrow(cs).borrow_mut();
+    if rose && fell {
+        // 区別できないほど短い間隔で2つのエッジが来た。pulse_widthと同じく、その回は捨てる。
+        decoder.restart(bit_us);
+        return;
+    }
+    decoder.edge(now, rose, bit_us);
+}
+
+// samplerから呼ぶ。2ビット周期より長くエッジがなければフレームを終わりにする。
+pub fn check_idle(cs: &CriticalSection) {
+    let Some(timer) = MANCHESTER_TIMER.borrow(cs).get() else {
+        return;
+    };
+    let bit_us = bit_period_us(cs);
+    let mut decoder = DECODER.borrow(cs).borrow_mut();
+    if let Some(last) = decoder.last_edge {
+        if timer.get_counter_low().wrapping_sub(last) > bit_us * 2 {
+            decoder.restart(bit_us);
+        }
+    }
+}
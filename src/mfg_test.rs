@@ -0,0 +1,140 @@
+// 製造時の検査用に、LEDを決まったパターンでくり返し光らせる
+//
+// 次のフェーズを順に表示し、最後まで行ったら最初に戻る。リセットするまでずっとくり返す。
+//   1. SolidOn   : 明るく点灯したまま（SOLID_ON_MS）
+//   2. SolidOff  : 消灯したまま（SOLID_OFF_MS）
+//   3. FastBlink : FAST_BLINK_STEP_MSごとに点灯/消灯を切り替える（FAST_BLINK_MS）
+//   4. Breathe   : 消灯から明るい点灯まで上げて、また消灯まで下げる（BREATHE_MS）
+// 点灯した時と消灯した時、PWMの途中のデューティがちゃんと出るかをまとめて目で確かめられる。
+// フェーズが変わるたびにdefmtにフェーズの名前を出すので、検査の記録と突き合わせられる。
+//
+// 始め方
+//   ・mfg-testフィーチャーを有効にしてビルドすると、起動した直後から始まる
+//   ・UARTで test を送ると、その時点の最初のフェーズから始まる
+// 検査の間はburstや記録の再生、モードの表示よりこちらが優先する。モードを変えても止まらない。
+// 止めるにはリセットする（非常停止とコマ送りは検査中でもそのまま効く）。
+//
+// 表示はTIMER_IRQ_0が1ステップずつ進める。BreatheはBREATHE_STEP_MSごとにデューティを変えるので、
+// ALARM2のフェード（fade.rs）とは別に動き、全体の明るさ（brightness）にも触らない。
+//
+// Breatheの波形は三角形か正弦（rp2040_project_template::waveform_table）で、UARTの breathe で選ぶ。
+// どちらも波形の値をガンマ補正してからデューティにする。起動時はBREATHE_WAVEFORM。
+
+use crate::blink_count;
+use crate::cs_trace::free;
+use crate::led;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use defmt::Format;
+use rp2040_project_template::waveform_table::{self, Waveform};
+
+pub const SOLID_ON_MS: u32 = 1000;
+pub const SOLID_OFF_MS: u32 = 1000;
+pub const FAST_BLINK_MS: u32 = 2000;
+pub const FAST_BLINK_STEP_MS: u32 = 100;
+pub const BREATHE_MS: u32 = 2000;
+pub const BREATHE_STEP_MS: u32 = 50;
+pub const BREATHE_WAVEFORM: Waveform = Waveform::Sine;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+enum Phase {
+    SolidOn,
+    SolidOff,
+    FastBlink,
+    Breathe,
+}
+
+impl Phase {
+    fn next(self) -> Phase {
+        match self {
+            Phase::SolidOn => Phase::SolidOff,
+            Phase::SolidOff => Phase::FastBlink,
+            Phase::FastBlink => Phase::Breathe,
+            Phase::Breathe => Phase::SolidOn,
+        }
+    }
+
+    // 1ステップの時間。点灯/消灯したままのフェーズは1ステップで終わる。
+    fn step_ms(self) -> u32 {
+        match self {
+            Phase::SolidOn => SOLID_ON_MS,
+            Phase::SolidOff => SOLID_OFF_MS,
+            Phase::FastBlink => FAST_BLINK_STEP_MS,
+            Phase::Breathe => BREATHE_STEP_MS,
+        }
+    }
+
+    fn steps(self) -> u32 {
+        match self {
+            Phase::SolidOn | Phase::SolidOff => 1,
+            Phase::FastBlink => FAST_BLINK_MS / FAST_BLINK_STEP_MS,
+            Phase::Breathe => BREATHE_MS / BREATHE_STEP_MS,
+        }
+    }
+
+    // フェーズの中でindex番目のステップのデューティ
+    fn duty(self, cs: &CriticalSection, index: u32) -> u16 {
+        match self {
+            Phase::SolidOn => led::LED_BRIGHT_DUTY,
+            Phase::SolidOff => led::LED_OFF_DUTY,
+            Phase::FastBlink if index.is_multiple_of(2) => led::LED_BRIGHT_DUTY,
+            Phase::FastBlink => led::LED_OFF_DUTY,
+            Phase::Breathe => {
+                // 位相0（消灯）から1周期をsteps()等分する。最後のステップは消灯に近くなる。
+                let phase = (index * 0x10000 / self.steps()) as u16;
+                let level = waveform_table::level(breathe_waveform(cs), phase);
+                let duty = u32::from(led::LED_BRIGHT_DUTY)
+                    * u32::from(waveform_table::gamma_correct(level))
+                    / u32::from(u16::MAX);
+                duty as u16
+            }
+        }
+    }
+}
+
+// 1つのフェーズのステップ数がフェーズの時間にぴったり分かれるように
+// （Breatheは上げる半分と下げる半分に分けるので、ステップ数が偶数になるように）
+const _: () = assert!(
+    FAST_BLINK_MS.is_multiple_of(FAST_BLINK_STEP_MS) && FAST_BLINK_MS >= 2 * FAST_BLINK_STEP_MS
+);
+const _: () =
+    assert!(BREATHE_MS.is_multiple_of(2 * BREATHE_STEP_MS) && BREATHE_MS >= 2 * BREATHE_STEP_MS);
+
+// 検査中なら、今のフェーズと次に表示するステップ
+static MFG_TEST: Mutex<Cell<Option<(Phase, u32)>>> = Mutex::new(Cell::new(None));
+static WAVEFORM: Mutex<Cell<Waveform>> = Mutex::new(Cell::new(BREATHE_WAVEFORM));
+
+pub fn breathe_waveform(cs: &CriticalSection) -> Waveform {
+    WAVEFORM.borrow(cs).get()
+}
+
+// Breatheの波形を変える。次のステップから使う。
+pub fn set_breathe_waveform(waveform: Waveform) {
+    free(|cs| WAVEFORM.borrow(cs).set(waveform));
+}
+
+// 検査のパターンを最初のフェーズから始める。止めるにはリセットする。
+pub fn start() {
+    free(|cs| {
+        MFG_TEST.borrow(cs).set(Some((Phase::SolidOn, 0)));
+        // blink_times()で止まっていても表示する
+        blink_count::resume(cs);
+        crate::restart_blink(cs);
+    });
+}
+
+// TIMER_IRQ_0から呼ぶ。検査中なら次のステップのデューティと時間を返す。
+pub fn next_step(cs: &CriticalSection) -> Option<(u16, u32)> {
+    let test = MFG_TEST.borrow(cs);
+    let (phase, index) = test.get()?;
+    if index == 0 {
+        defmt::info!("mfg test: phase {}", phase);
+    }
+    let step = (phase.duty(cs, index), phase.step_ms());
+    if index + 1 < phase.steps() {
+        test.set(Some((phase, index + 1)));
+    } else {
+        test.set(Some((phase.next(), 0)));
+    }
+    Some(step)
+}
@@ -0,0 +1,59 @@
+// 割り込みカウンタが指定した値に達したときに処理を実行する「マイルストーン」
+//
+// at_count(target, action)で、割り込みカウンタ（counter.rs）がtargetになったときにactionを実行する。
+// TIMER_IRQ_0がカウンタを進めるたびにcheck()で照合し、一致したものは
+// 遅延実行キュー（deferred）に積んでから登録を消す。つまり1回実行すると終わり。
+// 例えばUARTから at 1000 flash 5 と送ると、カウンタが1000になったときに5回素早く点滅する。
+//
+// 同時に登録できるのはMAX_MILESTONES個まで。いっぱいのときのat_count()はfalseを返す。
+// 登録した時点でカウンタがすでにtarget以上なら、その場でキューに積む（すぐに実行する）。
+// カウンタが一周するのを待つと約136年（1秒周期の場合）かかり、実際には二度と来ないため。
+
+use crate::counter;
+use crate::cs_trace::free;
+use crate::deferred;
+use crate::work::Work;
+use core::cell::RefCell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+
+pub const MAX_MILESTONES: usize = 4;
+
+// 目標のカウンタの値と、そのときに実行する処理
+type Milestone = Option<(u32, Work)>;
+
+static MILESTONES: Mutex<RefCell<[Milestone; MAX_MILESTONES]>> =
+    Mutex::new(RefCell::new([None; MAX_MILESTONES]));
+
+// 登録できなかった（空きがない）ときはfalse
+pub fn at_count(target: u32, action: Work) -> bool {
+    free(|cs| {
+        if counter::count(cs) >= target {
+            return deferred::push(cs, action);
+        }
+        let mut milestones = MILESTONES.borrow(cs).borrow_mut();
+        match milestones.iter_mut().find(|m| m.is_none()) {
+            Some(slot) => {
+                *slot = Some((target, action));
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+// 登録をすべて消す
+pub fn clear() {
+    free(|cs| *MILESTONES.borrow(cs).borrow_mut() = [None; MAX_MILESTONES]);
+}
+
+// TIMER_IRQ_0がカウンタをcountに進めたあとに呼ぶ
+pub fn check(cs: &CriticalSection, count: u32) {
+    for slot in MILESTONES.borrow(cs).borrow_mut().iter_mut() {
+        if let Some((target, action)) = *slot {
+            if target == count {
+                deferred::push(cs, action);
+                *slot = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+// LEDの動作モードを扱うモジュール
+//
+// TIMER_IRQ_0はこのモードを見て、LEDをどう駆動するかを決める。
+// モードの切り替え自体はメインループなど割り込み以外の場所から行う。
+// 切り替わったときはset_mode()がEvent::ModeChangedをログに出すので、呼び出し側で出す必要はない。
+
+use crate::ambient;
+use crate::bar_graph;
+use crate::blink_count;
+use crate::double_blink;
+use crate::egg_timer;
+use crate::logging::{self, Event};
+use crate::recorder;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use defmt::Format;
+
+// Copyトレイトを実装しているのでCellに入れてget/setで扱える。
+// Formatはdefmtのログに"Blink"のように名前で出すため。
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum LedMode {
+    // 暗めの明るさで点灯し続ける（明るい部屋向け）
+    Solid,
+    // ALARM0の周期で明るく点滅する（暗い部屋向け）
+    Blink,
+    // 数値を10進数の桁ごとの点滅回数で表示する（number::blink_number()で開始する）
+    Number,
+    // 消灯したままにする
+    Off,
+    // 2回続けて短く点滅してから長く休む、心拍のような点滅（double_blink.rs）
+    Heartbeat,
+    // 0〜100%の値を、一定の窓のうち点灯している時間の割合で表す（bar_graph.rs）
+    Bar,
+    // 決まった秒数を1秒ごとの点滅で数え、最後は速く点滅し、終わったら知らせる（egg_timer.rs）
+    EggTimer,
+}
+
+impl LedMode {
+    // UARTなどに出力するときの名前
+    pub fn name(self) -> &'static str {
+        match self {
+            LedMode::Solid => "solid",
+            LedMode::Blink => "blink",
+            LedMode::Number => "number",
+            LedMode::Off => "off",
+            LedMode::Heartbeat => "heartbeat",
+            LedMode::Bar => "bar",
+            LedMode::EggTimer => "egg",
+        }
+    }
+
+    // name()の逆。UARTのコマンドでモードを指定するときに使う。
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "solid" => Some(LedMode::Solid),
+            "blink" => Some(LedMode::Blink),
+            "number" => Some(LedMode::Number),
+            "off" => Some(LedMode::Off),
+            "heartbeat" => Some(LedMode::Heartbeat),
+            "bar" => Some(LedMode::Bar),
+            "egg" => Some(LedMode::EggTimer),
+            _ => None,
+        }
+    }
+}
+
+// 起動時のモード
+pub const DEFAULT_MODE: LedMode = LedMode::Blink;
+
+static LED_MODE: Mutex<Cell<LedMode>> = Mutex::new(Cell::new(DEFAULT_MODE));
+
+pub fn mode(cs: &CriticalSection) -> LedMode {
+    LED_MODE.borrow(cs).get()
+}
+
+// モードを変える。周囲の明るさによる自動の切り替え（ambient.rs）は止める。
+pub fn set_mode(cs: &CriticalSection, mode: LedMode) {
+    ambient::suspend_auto_mode(cs);
+    set_mode_keep_auto(cs, mode);
+}
+
+// 自動の切り替えを止めずにモードを変える。自動の切り替えそのものと、起動時の設定やセレクタの最初の読み取りで使う。
+pub fn set_mode_keep_auto(cs: &CriticalSection, mode: LedMode) {
+    if LED_MODE.borrow(cs).replace(mode) != mode {
+        logging::log_event(Event::ModeChanged(mode));
+        blink_count::on_mode_changed(cs);
+        recorder::on_mode_changed(cs);
+        match mode {
+            LedMode::Heartbeat => double_blink::reset(cs),
+            LedMode::Bar => bar_graph::reset(cs),
+            LedMode::EggTimer => egg_timer::reset(cs),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,115 @@
+// 優先度の違う割り込みが多重に入る（ネストする）ことを確かめるデモ
+//
+// ほかの割り込みは同じ優先度にそろえていて互いに割り込まない（estop.rs参照）が、優先度が違えば
+// 優先度の低い割り込みの処理の途中に、優先度の高い割り込みが入る。それを2つのソフトウェア割り込みで見せる。
+//   SW0_IRQ : NESTED_LOW_PRIORITY（0xC0、いちばん低い）
+//   SW1_IRQ : NESTED_HIGH_PRIORITY（0x80）
+// どちらもRP2040の周辺機器につながっていない割り込みで、NVIC::pend()でソフトウェアから入れる。
+//
+// 流れ（UARTの nested で1回ずつ）
+//   1. trigger()がSW0_IRQを保留にする。メインループからは割り込んでいないので、すぐにon_low()に入る。
+//   2. on_low()はIN_LOWを立て、SW1_IRQを保留にする。
+//   3. SW1_IRQの方が優先度が高いので、on_low()の途中でon_high()が割り込む。
+//      on_high()はIN_LOWが立っているのを見て、PREEMPTIONSを1つ増やす。
+//   4. on_high()から戻ると、on_low()は続きから動き、IN_LOWを下ろす。
+//      on_high()がその間に動いていなければ（割り込まなかった）MISSEDを1つ増やす。
+//   つまりPREEMPTIONSが増えれば、優先度の高い処理が低い処理の途中で動いたことになる。
+//   SW1_IRQを保留にしてから割り込みが入るまでは数サイクルかかるので、DSBとISBで保留が反映されるのを待つ。
+//
+// Cortex-M0+の制約
+//   ・優先度は上位2bitだけが有効なので4段階（0x00、0x40、0x80、0xC0）。下位のビットは書いても無視される。
+//   ・M3以降のような優先度のグループ（プリエンプション優先度とサブ優先度の分け方、AIRCRのPRIGROUP）はない。
+//     同じ優先度どうしは割り込まず、両方が保留なら割り込み番号の小さい方から入る。
+//   ・BASEPRIがないので、「ある優先度以下だけ止める」ことはできない。止めるならPRIMASK（free()）ですべて止まる。
+//     free()の中（メインループのクリティカルセクションなど）でtrigger()を呼ぶと、出たあとに入るので割り込みは起きる。
+//   ・段階が4つしかないので、このデモの2つで残りの段階を使い切る
+//     （0x00は非常停止、0x40はほかの割り込み。estop.rsの表を参照）。
+//   SW0_IRQはほかの割り込み（0x40）よりも低いので、TIMER_IRQなどもon_low()に割り込みうる。
+//
+// on_low()とon_high()は、CriticalSection::new()で借りるグローバル変数（Mutex）には触らないこと。
+// 同じ優先度で多重に入らないという前提が、この2つには成り立たないため。カウンタはアトミックにしている。
+// Cortex-M0+にはfetch_addがないので、それぞれのカウンタは1つのハンドラだけが読み書きする。
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use cortex_m::peripheral::NVIC;
+use rp_pico::hal::pac;
+
+pub const NESTED_LOW_PRIORITY: u8 = 0xC0;
+pub const NESTED_HIGH_PRIORITY: u8 = 0x80;
+
+// on_low()の中にいる間true
+static IN_LOW: AtomicBool = AtomicBool::new(false);
+// on_low()だけが書く
+static LOW_RUNS: AtomicU32 = AtomicU32::new(0);
+static MISSED: AtomicU32 = AtomicU32::new(0);
+// on_high()だけが書く
+static HIGH_RUNS: AtomicU32 = AtomicU32::new(0);
+static PREEMPTIONS: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Copy)]
+pub struct NestedStats {
+    pub low_runs: u32,
+    pub high_runs: u32,
+    // on_high()がon_low()の途中で動いた回数
+    pub preemptions: u32,
+    // on_low()の途中でon_high()が動かなかった回数
+    pub missed: u32,
+}
+
+// 割り込みの優先度を設定する。NVICのunmaskより前に呼ぶ（estop::set_priorities()と同じ）。
+pub fn set_priorities(nvic: &mut NVIC) {
+    // 優先度を変えると多重に入るようになるのでunsafe。2つのハンドラはアトミックにしか触らない。
+    unsafe {
+        nvic.set_priority(pac::Interrupt::SW0_IRQ, NESTED_LOW_PRIORITY);
+        nvic.set_priority(pac::Interrupt::SW1_IRQ, NESTED_HIGH_PRIORITY);
+    }
+}
+
+fn increment(counter: &AtomicU32) {
+    counter.store(
+        counter.load(Ordering::Relaxed).wrapping_add(1),
+        Ordering::Relaxed,
+    );
+}
+
+pub fn stats() -> NestedStats {
+    NestedStats {
+        low_runs: LOW_RUNS.load(Ordering::Relaxed),
+        high_runs: HIGH_RUNS.load(Ordering::Relaxed),
+        preemptions: PREEMPTIONS.load(Ordering::Relaxed),
+        missed: MISSED.load(Ordering::Relaxed),
+    }
+}
+
+// 優先度の低い割り込みを1回入れ、終わったあとのカウンタを返す
+pub fn trigger() -> NestedStats {
+    NVIC::pend(pac::Interrupt::SW0_IRQ);
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+    stats()
+}
+
+// SW0_IRQから呼ぶ（優先度が低い方）
+pub fn on_low() {
+    increment(&LOW_RUNS);
+    IN_LOW.store(true, Ordering::Relaxed);
+    let before = HIGH_RUNS.load(Ordering::Relaxed);
+    NVIC::pend(pac::Interrupt::SW1_IRQ);
+    // ここでon_high()が割り込む
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+    let preempted = HIGH_RUNS.load(Ordering::Relaxed) != before;
+    IN_LOW.store(false, Ordering::Relaxed);
+    if !preempted {
+        increment(&MISSED);
+        defmt::warn!("nested irq: SW1_IRQ did not preempt SW0_IRQ");
+    }
+}
+
+// SW1_IRQから呼ぶ（優先度が高い方）
+pub fn on_high() {
+    increment(&HIGH_RUNS);
+    if IN_LOW.load(Ordering::Relaxed) {
+        increment(&PREEMPTIONS);
+    }
+}
@@ -0,0 +1,76 @@
+// ボタンを押して確認するまで、目立つ点滅をくり返して知らせる
+//
+// notify(pattern)で今のモードと点滅間隔を覚えておき、patternのモードとNOTIFY_INTERVAL_MSの速い間隔に切り替える。
+// タクトスイッチ（GPIO15）の短押しで確認する（acknowledge()）と、覚えておいたモードと間隔に戻す。
+// 知らせている間の短押しは確認だけに使い、いつもの点滅とSolidの切り替えはしない（main.rsのボタンの処理）。
+// blink_times()の回数は取り消すので、知らせている間は止まらずに点滅する（確認しても回数は戻らない）。
+//
+// 知らせている最中にもう一度notify()を呼んだとき
+//   新しい知らせは無視してfalseを返す（キューには積まない）。
+//   覚えておくのは最初の知らせの前のモードと間隔だけなので、何回重なっても確認すれば元の状態に戻る。
+//   重ねて新しいパターンに切り替えると、確認がどの知らせに対するものかわからなくなるため。
+// 知らせている間にUARTなどでモードや間隔を変えても、確認すると知らせる前の状態に戻る。
+// 長押しの設定リセットでは、知らせていたことも取り消す（clear()）。
+
+use crate::blink_count;
+use crate::cs_trace::free;
+use crate::interval;
+use crate::mode::{self, LedMode};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+
+// 知らせている間の点滅間隔
+pub const NOTIFY_INTERVAL_MS: u32 = 100;
+assert_alarm_interval_ms!(NOTIFY_INTERVAL_MS);
+
+#[derive(Clone, Copy)]
+struct Saved {
+    mode: LedMode,
+    interval_ms: u32,
+}
+
+// 知らせている間はSome（知らせる前のモードと間隔）
+static NOTIFYING: Mutex<Cell<Option<Saved>>> = Mutex::new(Cell::new(None));
+
+pub fn is_notifying(cs: &CriticalSection) -> bool {
+    NOTIFYING.borrow(cs).get().is_some()
+}
+
+// patternのモードで知らせ始める。すでに知らせている最中なら何もせずにfalseを返す。
+pub fn notify(pattern: LedMode) -> bool {
+    free(|cs| {
+        let notifying = NOTIFYING.borrow(cs);
+        if notifying.get().is_some() {
+            defmt::debug!("notify: already notifying, {} ignored", pattern);
+            return false;
+        }
+        notifying.set(Some(Saved {
+            mode: mode::mode(cs),
+            interval_ms: interval::interval_ms(cs),
+        }));
+        mode::set_mode(cs, pattern);
+        interval::set_interval_now(cs, NOTIFY_INTERVAL_MS);
+        // 同じモードのままでも、blink_times()の残りの回数は取り消して止まらないようにする
+        blink_count::on_mode_changed(cs);
+        crate::restart_blink(cs);
+        true
+    })
+}
+
+// 知らせていれば確認したことにして、知らせる前のモードと間隔に戻す。知らせていなければfalse。
+pub fn acknowledge() -> bool {
+    free(|cs| {
+        let Some(saved) = NOTIFYING.borrow(cs).take() else {
+            return false;
+        };
+        mode::set_mode(cs, saved.mode);
+        interval::set_interval_now(cs, saved.interval_ms);
+        crate::restart_blink(cs);
+        true
+    })
+}
+
+// 知らせていたことを取り消す。モードと間隔は戻さない（設定のリセットで戻すため）。
+pub fn clear() {
+    free(|cs| NOTIFYING.borrow(cs).set(None));
+}
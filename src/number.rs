@@ -0,0 +1,30 @@
+// 数値をLEDの点滅回数で表示するモード
+//
+// 点滅の並びの計算はライブラリのdecimal_blinkにあり、ここではその状態をグローバル変数に持つ。
+// TIMER_IRQ_0はLedMode::Numberのときにnext_step()を呼び、返ってきた時間で次のALARMをスケジュールする。
+
+use crate::cs_trace::free;
+use crate::mode::{self, LedMode};
+use core::cell::RefCell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::decimal_blink::{DecimalBlinker, Step};
+
+static NUMBER_BLINKER: Mutex<RefCell<DecimalBlinker>> =
+    Mutex::new(RefCell::new(DecimalBlinker::new()));
+
+// nの表示を先頭の桁から始める
+pub fn blink_number(n: u32) {
+    free(|cs| {
+        NUMBER_BLINKER.borrow(cs).borrow_mut().set(n);
+        mode::set_mode(cs, LedMode::Number);
+    });
+}
+
+// 表示を1周する間の点灯時間の合計と、1周の時間（ms）
+pub fn cycle_ms(cs: &CriticalSection) -> (u32, u32) {
+    NUMBER_BLINKER.borrow(cs).borrow().cycle_ms()
+}
+
+pub fn next_step(cs: &CriticalSection) -> Step {
+    NUMBER_BLINKER.borrow(cs).borrow_mut().next_step()
+}
@@ -0,0 +1,25 @@
+// ワンショットタイマー
+//
+// after(delay_ms, action)で、delay_ms後にTIMER_IRQ_1の中でactionを1回だけ実行する。
+// ALARM1はソフトウェアタイマー（soft_timer.rs）と分け合っていて、ここではONESHOT_IDのタイマーを1つだけ使う。
+//
+// すでに予約があるときにafter()を呼ぶと、前の予約は取り消して新しい予約で置き換える。
+// 「5秒後に消灯」を何度も予約し直すと、最後の予約から5秒後に消灯する、という使い方ができる。
+
+use crate::soft_timer::{self, ONESHOT_ID};
+use crate::work::Work;
+
+// delay_ms後にactionを1回だけ実行する。予約中のものがあれば置き換える。
+// delay_msはALARMでスケジュールできる範囲（MAX_ALARM_INTERVAL_MS）に切り詰める。
+pub fn after(delay_ms: u32, action: Work) {
+    let delay_ms = delay_ms.min(crate::MAX_ALARM_INTERVAL_MS);
+    // 同じidは置き換えになるので、タイマーがいっぱいでも失敗しない
+    if soft_timer::set_timer_with(delay_ms, ONESHOT_ID, action).is_err() {
+        defmt::warn!("oneshot: timer is not initialized");
+    }
+}
+
+// 予約中の処理を取り消す。取り消すものがあればtrue。
+pub fn cancel() -> bool {
+    soft_timer::cancel_timer(ONESHOT_ID)
+}
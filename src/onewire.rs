@@ -0,0 +1,98 @@
+// ビットバンギングによる1-Wireバス
+//
+// 1本の信号線で双方向に通信する。線は外付けの4.7kΩでプルアップし、
+// マスターもスレーブも「Lowに引く」か「離す（プルアップでHighになる）」かしかしない。
+// RP2040のGPIOはオープンドレインを持たないので、出力値をLowにしたままにしておき、
+// 機能をSIOの出力（Lowに引く）と入力（離す）で切り替えて再現する。
+// そのためピンは機能を実行時に変えられるDynFunctionの型で持つ。
+//
+// タイミング（µs）。すべてマスターが線をLowに引いた時点から数える。
+//   リセット     : 480以上Low → 離して70後にスレーブのプレゼンス（Low）を読む → 残り410待つ
+//   1を書く      : 1〜15 Low → 離して残りを待つ（1スロット60〜120）
+//   0を書く      : 60〜120 Low → 離して回復時間を待つ
+//   読む         : 1以上Low → 離して15以内に読む（スレーブが0ならLowのまま）
+// 特に「読む」の15µs以内は厳しく、途中でTIMER_IRQ_0などの割り込みが入って
+// 数µs遅れただけで読み間違える。そのため各スロットは割り込みを禁止して行う。
+// 禁止する時間は1ビットで約70µs、リセットでも約1msなので、割り込みの遅れは最大でもその程度で済む。
+// 1バイトごとではなく1ビットごとに許可し直して、ALARMの割り込みを長く待たせないようにしている。
+
+use crate::cs_trace::free;
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::InputPin;
+use rp_pico::hal::gpio::{self, DynFunction, DynSioConfig, PullUp};
+use rp_pico::hal::timer::Timer;
+
+pub type OneWirePin = gpio::Pin<gpio::bank0::Gpio22, DynFunction, PullUp>;
+
+pub struct OneWire {
+    pin: OneWirePin,
+    timer: Timer,
+}
+
+impl OneWire {
+    // ピンは出力値をLowにした状態で渡すこと（into_push_pull_output_in_state(PinState::Low)）
+    pub fn new(mut pin: OneWirePin, timer: Timer) -> Self {
+        release(&mut pin);
+        Self { pin, timer }
+    }
+
+    // リセットパルスを送り、デバイスが応答（プレゼンスパルス）したらtrue
+    pub fn reset(&mut self) -> bool {
+        let present = free(|_| {
+            drive_low(&mut self.pin);
+            self.timer.delay_us(480);
+            release(&mut self.pin);
+            self.timer.delay_us(70);
+            self.pin.as_input().is_low().unwrap()
+        });
+        self.timer.delay_us(410);
+        present
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        for i in 0..8 {
+            self.write_bit(byte & (1 << i) != 0);
+        }
+    }
+
+    pub fn read_byte(&mut self) -> u8 {
+        (0..8).fold(0, |byte, i| byte | (u8::from(self.read_bit()) << i))
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        free(|_| {
+            drive_low(&mut self.pin);
+            if bit {
+                self.timer.delay_us(6);
+                release(&mut self.pin);
+                self.timer.delay_us(64);
+            } else {
+                self.timer.delay_us(60);
+                release(&mut self.pin);
+                self.timer.delay_us(10);
+            }
+        });
+    }
+
+    fn read_bit(&mut self) -> bool {
+        let bit = free(|_| {
+            drive_low(&mut self.pin);
+            self.timer.delay_us(6);
+            release(&mut self.pin);
+            // Lowに引いてから13µsで読む。15µsの期限に対してTimerの1µsの分解能の分だけ余裕を取る。
+            self.timer.delay_us(7);
+            self.pin.as_input().is_high().unwrap()
+        });
+        self.timer.delay_us(57);
+        bit
+    }
+}
+
+// GPIO22はSIOを選べるピンなので、try_set_function()は失敗しない
+fn drive_low(pin: &mut OneWirePin) {
+    let _ = pin.try_set_function(DynFunction::Sio(DynSioConfig::Output));
+}
+
+fn release(pin: &mut OneWirePin) {
+    let _ = pin.try_set_function(DynFunction::Sio(DynSioConfig::Input));
+}
@@ -0,0 +1,143 @@
+// 出力ピンの極性（Highで点く/Lowで点く）を1か所で決めるモジュール
+//
+// どの出力も「オン/オフ」で考え、ピンをHighにするかLowにするかはOUTPUT_POLARITYの表だけで決める。
+// 各モジュールでアクティブLowの判定をばらばらに書くと、どこかで反転し忘れる（非常停止で止めたつもりが
+// 点灯したままになる、電源を入れた直後に一瞬点くなど）ので、極性はここ以外で判断しないこと。
+//
+// 出力（OutputId）と駆動のしかた
+//   Led          : PWM4（led.rsのLED_GPIOSのどれか）。led-active-lowフィーチャーでアクティブLow
+//   Tone         : PWM0A（GPIO16、ブザー）
+//   PulseTrain   : PIO1（GPIO12、pulse_train.rs）
+//   Heartbeat    : SIO（GPIO2、外付けのWDのWDI）
+//   CsTrace      : SIO（GPIO3、cs-traceフィーチャー）
+//   BicolorRed   : PWM3A（GPIO6）。アノードコモンならアクティブLow（bicolor.rsのBICOLOR_COMMON_ANODE）
+//   BicolorGreen : PWM3B（GPIO7）。同上
+//
+// 極性のかけ方
+//   ・SIOの出力（Heartbeat、CsTrace）はwrite_output()でオン/オフを書く。表を見てHighかLowかを決め、
+//     SIOのGPIO_OUT_SET/GPIO_OUT_CLRに1回書くだけなので、割り込みの中からも呼べて、ほかのピンとぶつからない。
+//   ・PWMの出力（Led、Tone、Bicolor）はデューティで明るさや音を決めるので、オン/オフでは書かない。
+//     代わりに初期化のときにis_active_low()を見てチャンネルの出力を反転しておく。
+//     以後はどのモジュールもデューティを「オンの割合」として書けばよい。
+//   ・PIOの出力（PulseTrain）はプログラムがset pinsで直接書くので、アクティブHighに限る（const assertで確かめる）。
+//   ・非常停止（estop.rs）はどの出力もオフの電圧（off_level()）にオーバーライドで固定する。
+//
+// 電源を入れた直後
+//   RP2040のGPIOはリセット後は入力（ハイインピーダンス）。SIOの出力はinitial_state()の状態で出力にし、
+//   PWMの出力は反転してからピンをPWMの機能にする（led.rs、bicolor.rsのinit()）ので、
+//   初期化の途中でオンの電圧が出ることはない。アクティブLowの出力は、入力の間に点かないように
+//   外付けのプルアップを付けておくこと。
+
+use crate::bicolor;
+use crate::pin_table;
+use rp_pico::hal::gpio::PinState;
+use rp_pico::hal::pac;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutputId {
+    Led,
+    Tone,
+    PulseTrain,
+    Heartbeat,
+    CsTrace,
+    BicolorRed,
+    BicolorGreen,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    // Highでオン
+    ActiveHigh,
+    // Lowでオン（3.3 V側につないだLED、アノードコモンの2色LED）
+    ActiveLow,
+}
+
+const fn polarity_if(active_low: bool) -> Polarity {
+    if active_low {
+        Polarity::ActiveLow
+    } else {
+        Polarity::ActiveHigh
+    }
+}
+
+// 出力ごとの極性。配線やフィーチャーで決まるので、動いている間は変わらない。
+pub const OUTPUT_POLARITY: [(OutputId, Polarity); 7] = [
+    (OutputId::Led, polarity_if(cfg!(feature = "led-active-low"))),
+    (OutputId::Tone, Polarity::ActiveHigh),
+    (OutputId::PulseTrain, Polarity::ActiveHigh),
+    (OutputId::Heartbeat, Polarity::ActiveHigh),
+    (OutputId::CsTrace, Polarity::ActiveHigh),
+    (
+        OutputId::BicolorRed,
+        polarity_if(bicolor::BICOLOR_COMMON_ANODE),
+    ),
+    (
+        OutputId::BicolorGreen,
+        polarity_if(bicolor::BICOLOR_COMMON_ANODE),
+    ),
+];
+
+// PIOのプログラムは反転しないので、アクティブHighでなければならない
+const _: () = ::core::assert!(!is_active_low(OutputId::PulseTrain));
+
+pub const fn polarity(id: OutputId) -> Polarity {
+    let mut i = 0;
+    while i < OUTPUT_POLARITY.len() {
+        let (entry, polarity) = OUTPUT_POLARITY[i];
+        if entry as u8 == id as u8 {
+            return polarity;
+        }
+        i += 1;
+    }
+    panic!("output missing from OUTPUT_POLARITY");
+}
+
+pub const fn is_active_low(id: OutputId) -> bool {
+    matches!(polarity(id), Polarity::ActiveLow)
+}
+
+// オン/オフをピンの電圧（trueでHigh）にする
+pub const fn level(id: OutputId, on: bool) -> bool {
+    on != is_active_low(id)
+}
+
+// オフのときの電圧（非常停止で固定する電圧）
+pub const fn off_level(id: OutputId) -> bool {
+    level(id, false)
+}
+
+// SIOの出力を出力にするときの最初の状態（オフ）
+pub const fn initial_state(id: OutputId) -> PinState {
+    if off_level(id) {
+        PinState::High
+    } else {
+        PinState::Low
+    }
+}
+
+// SIOで書く出力のGPIO。PWMやPIOの出力はNone。
+const fn sio_gpio(id: OutputId) -> Option<u8> {
+    match id {
+        OutputId::Heartbeat => Some(pin_table::HEARTBEAT),
+        OutputId::CsTrace => Some(pin_table::CS_TRACE),
+        _ => None,
+    }
+}
+
+// 出力をオン/オフにする唯一の入り口（SIOの出力）。極性はOUTPUT_POLARITYで決まる。
+// PWMとPIOの出力は上の説明のとおりここでは書かないので、何もしない（debugビルドでは止まる）。
+#[inline(always)]
+pub fn write_output(pin: OutputId, on: bool) {
+    let Some(gpio) = sio_gpio(pin) else {
+        debug_assert!(false, "write_output() is only for SIO outputs");
+        return;
+    };
+    let mask = 1 << gpio;
+    // Safety: GPIO_OUT_SET/CLRは書いたビットだけを変えるので、ほかのピンの出力とぶつからない
+    let sio = unsafe { &*pac::SIO::ptr() };
+    if level(pin, on) {
+        sio.gpio_out_set().write(|w| unsafe { w.bits(mask) });
+    } else {
+        sio.gpio_out_clr().write(|w| unsafe { w.bits(mask) });
+    }
+}
@@ -0,0 +1,100 @@
+// 外からの「出力の許可」の入力（GPIO3）
+//
+// 装置全体のマスタースイッチのように、この入力が許可になっている間だけLEDなどの出力を出す。
+// 許可でなくなると、モードに関係なく出力をすべて消す。許可に戻れば、そのまま元の動きを続ける。
+// 非常停止（estop.rs）と違ってラッチしないので、UARTで解除しなくても許可に戻った時点で元に戻る。
+//
+// 配線と極性
+//   GPIO3に、許可のときOUTPUT_ENABLE_ACTIVE_HIGH（trueならHigh）になる信号をつなぐ。
+//   プルは許可の側にかける（アクティブHighならプルアップ）ので、何もつながなければ常に許可になり、
+//   この入力を使わない基板でも今までどおりに動く。外付けのスイッチなら、開いていて許可、GNDに落として禁止。
+//   GPIO3はcs-traceフィーチャーの出力（cs_trace.rs）と共用なので、cs-traceフィーチャーのときはこの入力を使わず、
+//   常に許可になる。
+//
+// 止め方と戻し方
+//   TIMER_IRQ_3（sampler.rs）でSAMPLE_PERIOD_MSごとに読み、チャタリングを除く。
+//     ・禁止になったとき : 最初のサンプルですぐに採用し、非常停止と同じオーバーライド（estop::force_outputs_off()）で
+//                          LED、ブザー、PIO1のパルスをオフの電圧に固定する。遅れは最大でSAMPLE_PERIOD_MS。
+//     ・許可に戻ったとき : OUTPUT_ENABLE_DEBOUNCE（Integrator）で確かめてから、オーバーライドを外す。
+//                          非常停止で止まっている間は外さない（estop::release_outputs()）。
+//   禁止の間もALARM0の点滅やフェードは裏で進み、PWMのデューティも書き続けている（ピンに出ないだけ）。
+//   そのためオーバーライドを外せば、その時点のモードの正しい出力がすぐに出る。
+//   SIOで書く出力（write_output()の外付けのWD向けハートビートと、cs-traceの出力）は止めない。
+//   ハートビートを止めると外付けのWDが基板をリセットしてしまうため。
+
+use crate::cs_trace::free;
+use crate::estop;
+use crate::pull;
+use crate::sampler;
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::{Cell, RefCell};
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use embedded_hal::digital::InputPin;
+use rp2040_project_template::debounce::{Debounce, Edge, PressRelease};
+use rp_pico::hal::gpio;
+
+pub const OUTPUT_ENABLE_ACTIVE_HIGH: bool = true;
+// つながっていないときに許可になるように、許可の側にプルする
+#[cfg(not(feature = "cs-trace"))]
+pub const OUTPUT_ENABLE_PULL: pull::Pull = if OUTPUT_ENABLE_ACTIVE_HIGH {
+    pull::Pull::Up
+} else {
+    pull::Pull::Down
+};
+// 許可に戻ったことを確かめるサンプルの数（SAMPLE_PERIOD_MSごと）
+pub const OUTPUT_ENABLE_DEBOUNCE: Debounce = Debounce::Integrator(4);
+// 禁止はすぐに採用する
+const DISABLE_DEBOUNCE: Debounce = Debounce::Lockout(0);
+
+pub type OutputEnablePin = pull::InputPin<gpio::bank0::Gpio3>;
+
+static ENABLE_PIN: GlobalPeripheral<OutputEnablePin> = initial_global_peripheral();
+// 「押している」を禁止として扱う
+static DEBOUNCER: Mutex<RefCell<PressRelease<()>>> = Mutex::new(RefCell::new(PressRelease::new(
+    DISABLE_DEBOUNCE,
+    OUTPUT_ENABLE_DEBOUNCE,
+    sampler::SAMPLE_PERIOD_MS,
+)));
+static ENABLED: Mutex<Cell<bool>> = Mutex::new(Cell::new(true));
+
+#[cfg(not(feature = "cs-trace"))]
+pub fn init(cs: &CriticalSection, pin: OutputEnablePin) {
+    ENABLE_PIN.borrow(cs).replace(Some(pin));
+}
+
+// チャタリングを除いた状態で、出力が許可されているか。cs-traceフィーチャーでは常にtrue。
+pub fn is_enabled(cs: &CriticalSection) -> bool {
+    ENABLED.borrow(cs).get()
+}
+
+// TIMER_IRQ_3からSAMPLE_PERIOD_MSごとに呼ぶ（sampler.rs）
+pub fn sample(cs: &CriticalSection) {
+    let Some(asserted) = ENABLE_PIN.borrow(cs).borrow_mut().as_mut().map(|pin| {
+        // RP2040のGPIOの読み取りはエラーを返さない（Infallible）
+        pin.is_high().unwrap() == OUTPUT_ENABLE_ACTIVE_HIGH
+    }) else {
+        return;
+    };
+    let edge = DEBOUNCER.borrow(cs).borrow_mut().sample(!asserted);
+    match edge {
+        Some((Edge::Press, _)) => {
+            ENABLED.borrow(cs).set(false);
+            estop::force_outputs_off();
+            defmt::warn!("output enable: deasserted, outputs forced off");
+        }
+        Some((Edge::Release, _)) => {
+            ENABLED.borrow(cs).set(true);
+            // TIMER_IRQ_3のCriticalSectionは割り込みを止めていないので、非常停止と混ざらないようにfree()の中で外す
+            let released = free(estop::release_outputs);
+            defmt::info!(
+                "output enable: asserted, outputs {=str}",
+                if released {
+                    "restored"
+                } else {
+                    "held by estop"
+                }
+            );
+        }
+        None => {}
+    }
+}
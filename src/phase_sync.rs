@@ -0,0 +1,64 @@
+// 点滅している処理の位相を、共通の基準の時刻にそろえる
+//
+// LEDの点滅（ALARM0）、2色LEDのクロスフェード、PIO1のくり返しのパルス（どちらもALARM3のsampler）は
+// それぞれ自分の間隔で回っているので、始めた時刻がずれていれば、同じ間隔でも点灯がそろわない。
+// sync_phase()はタイマーカウンタから基準の時刻（今からSYNC_LEAD_USだけ後）を1つ決め、
+// すべてをその時刻に「最初から」始め直す。複数のLEDで見せるときに、点灯の瞬間をそろえるためのもの。
+//
+// 基準の時刻にそろうもの
+//   ・LEDの点滅（ALARM0）: ALARM0を基準の時刻にscheduleし直し、モードの最初の段階から出す。
+//       Blinkは点灯から、Heartbeatは1回目の点灯から、Barは窓の始まりから始める。
+//       Number（桁の途中）とEggTimer（残り時間）は、やり直すと表示の意味が変わるので、今の段階の続きを基準の時刻から出す。
+//   ・2色LEDのクロスフェード : 赤だけが点灯したところから始める（bicolor::restart_phase()）。
+//   ・くり返しのパルス       : 基準の時刻に最初のパルスを出す（pulse_train::restart_phase()）。
+//   ALARM0とALARM3は同じタイマーカウンタの同じ値で発火するので、割り込みの遅れ（数µs）を除いてそろう。
+//
+// 周期が違うとき
+//   そろうのは基準の時刻だけで、その後はそれぞれの間隔で進む。例えば点滅の間隔が500 msでクロスフェードの往復が4 sなら、
+//   4 sごと（最小公倍数）に点灯の瞬間がまたそろう。間隔が割り切れなければ、だんだんずれていく。
+//   ALARM0は基準の時刻から期限を数えてscheduleし直す（time.rs参照）ので、割り込みの遅れではずれていかない。
+//   ALARM3（sampler）は今の時刻から数えるので、長く回すと遅れの分だけずれる。そのときはもう一度sync_phase()を呼ぶ。
+//
+// そろえないもの
+//   ・外付けのウォッチドッグ向けのハートビート（heartbeat.rs）: 間隔を変えるとWDのタイムアウトに近づくだけで意味がない。
+//     samplerの回が基準の時刻に動く分（最大でSAMPLE_PERIOD_MS + SYNC_LEAD_US）だけ1回の間隔がずれるが、十分に短い。
+//   ・コマ送り（step_mode.rs）と、商用電源のゼロクロスで進めている間（mains_sync.rs）のLED : 時刻でなく外の信号で進むため。
+//   ・フェード（fade.rs）やソフトウェアタイマー（soft_timer.rs）: くり返す点滅ではないため。
+
+use crate::cs_trace::free;
+use crate::mode::{self, LedMode};
+use crate::sampler;
+use crate::{bar_graph, double_blink};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::time;
+use rp_pico::hal::timer::Timer;
+
+// 基準の時刻を今からどれだけ後にするか。ALARM0とALARM3をscheduleし直す間に過ぎてしまわない長さにする。
+// sampler::sync_at()の条件から、SAMPLE_PERIOD_MS以下にする。
+pub const SYNC_LEAD_US: u32 = 2_000;
+const _: () = assert!(SYNC_LEAD_US <= sampler::SAMPLE_PERIOD_MS * 1000);
+
+static SYNC_TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+
+pub fn init(cs: &CriticalSection, timer: Timer) {
+    SYNC_TIMER.borrow(cs).set(Some(timer));
+}
+
+// 点滅している処理をすべて、今からSYNC_LEAD_USだけ後の基準の時刻にそろえて始め直す。
+// 基準の時刻（タイマーカウンタの値）を返す。init()の前ならNone。
+pub fn sync_phase() -> Option<u64> {
+    free(|cs| {
+        let timer = SYNC_TIMER.borrow(cs).get()?;
+        let reference = time::add_interval(timer.get_counter().ticks(), SYNC_LEAD_US);
+        match mode::mode(cs) {
+            LedMode::Heartbeat => double_blink::reset(cs),
+            LedMode::Bar => bar_graph::reset(cs),
+            _ => {}
+        }
+        crate::restart_blink_at(cs, Some(reference));
+        sampler::sync_at(cs, reference);
+        defmt::info!("phase sync: reference at {=u64} us", reference);
+        Some(reference)
+    })
+}
@@ -0,0 +1,112 @@
+// どのGPIOを何に使っているかの一覧
+//
+// ピンを新しく使うときは、ここに番号の定数を足し、PIN_TABLEにも登録すること。
+// PIN_TABLEの中で同じGPIOが2回出てくると、下のconstの評価でassertが失敗してビルドが通らない。
+// （const fnの中ではcompile_error!は使えないが、constの初期化でのpanicはコンパイルエラーになる）
+//
+// main()でピンを取り出すときはregistered()を通してPinMap（banner.rs）に入れる。
+// registered()は実際に取り出したピンの番号と、ここに登録した番号が同じかを起動時に確かめる。
+// PinMapへの代入はregistered()を通す決まりなので、登録し忘れたピンや番号の違うピンは
+// ビルドか起動直後のどちらかで必ず見つかる。
+//
+// Picoのボードの中でつながっていて外には出ていないGPIO（23, 24, 29）も、使わないように登録しておく。
+// GPIO2/16/8/9は起動時だけDIPスイッチの読み取りにも使う（dip_switch.rs）。PIN_TABLEには本来の用途で登録している。
+// LEDをつないだピンはDIPスイッチに使えない（2色LEDのGPIO6/7など。理由はdip_switch.rs）。
+
+pub const UART_TX: u8 = 0;
+pub const UART_RX: u8 = 1;
+pub const HEARTBEAT: u8 = 2;
+// cs-traceフィーチャーのときだけ使う（cs_trace.rs）。それ以外では出力の許可の入力（output_enable.rs）。
+pub const CS_TRACE: u8 = 3;
+#[cfg(not(feature = "cs-trace"))]
+pub const OUTPUT_ENABLE: u8 = CS_TRACE;
+pub const I2C_SDA: u8 = 4;
+pub const I2C_SCL: u8 = 5;
+pub const BICOLOR_RED: u8 = 6;
+pub const BICOLOR_GREEN: u8 = 7;
+pub const LED_ALT_A: u8 = 8;
+pub const LED_ALT_B: u8 = 9;
+pub const ZERO_CROSS: u8 = 10;
+pub const EDGE_COUNTER: u8 = 11;
+pub const PULSE_TRAIN: u8 = 12;
+pub const IR_RECEIVER: u8 = 13;
+pub const TOGGLE_BUTTON: u8 = 14;
+pub const BUTTON: u8 = 15;
+pub const TONE: u8 = 16;
+pub const PULSE: u8 = 17;
+pub const SELECTOR_BIT0: u8 = 18;
+pub const SELECTOR_BIT1: u8 = 19;
+pub const MANCHESTER: u8 = 20;
+pub const ESTOP: u8 = 21;
+pub const ONEWIRE: u8 = 22;
+pub const LED: u8 = 25;
+pub const AMBIENT: u8 = 26;
+pub const ENCODER_A: u8 = 27;
+pub const ENCODER_B: u8 = 28;
+
+// RP2040のGPIOの数（bank0）
+const GPIO_COUNT: u8 = 30;
+
+pub const PIN_TABLE: &[(&str, u8)] = &[
+    ("uart tx", UART_TX),
+    ("uart rx", UART_RX),
+    ("heartbeat", HEARTBEAT),
+    ("cs trace / output enable", CS_TRACE),
+    ("i2c sda", I2C_SDA),
+    ("i2c scl", I2C_SCL),
+    ("bicolor red (PWM3A)", BICOLOR_RED),
+    ("bicolor green (PWM3B)", BICOLOR_GREEN),
+    ("led (alt, PWM4A)", LED_ALT_A),
+    ("led (alt, PWM4B)", LED_ALT_B),
+    ("mains zero cross", ZERO_CROSS),
+    ("edge counter", EDGE_COUNTER),
+    ("pulse train (PIO1)", PULSE_TRAIN),
+    ("ir receiver", IR_RECEIVER),
+    ("toggle button", TOGGLE_BUTTON),
+    ("button", BUTTON),
+    ("tone", TONE),
+    ("pulse width", PULSE),
+    ("selector bit0", SELECTOR_BIT0),
+    ("selector bit1", SELECTOR_BIT1),
+    ("manchester", MANCHESTER),
+    ("emergency stop", ESTOP),
+    ("1-wire", ONEWIRE),
+    ("led", LED),
+    ("ambient", AMBIENT),
+    ("encoder a", ENCODER_A),
+    ("encoder b", ENCODER_B),
+    // Picoのボードの中で使っているGPIO
+    ("smps power save", 23),
+    ("vbus sense", 24),
+    ("vsys adc", 29),
+];
+
+// すべてのGPIOが範囲内で、2回出てこないか
+const fn pins_unique(table: &[(&str, u8)]) -> bool {
+    let mut i = 0;
+    while i < table.len() {
+        if table[i].1 >= GPIO_COUNT {
+            return false;
+        }
+        let mut j = i + 1;
+        while j < table.len() {
+            if table[i].1 == table[j].1 {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+const _: () = assert!(
+    pins_unique(PIN_TABLE),
+    "a GPIO is assigned twice (or is out of range) in PIN_TABLE"
+);
+
+// main()でピンを取り出したときに呼ぶ。取り出したピンの番号numが登録した番号expectedと違えば止める。
+pub fn registered(num: u8, expected: u8) -> u8 {
+    assert_eq!(num, expected, "GPIO does not match PIN_TABLE");
+    num
+}
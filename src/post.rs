@@ -0,0 +1,179 @@
+// 起動時の自己診断（POST）の結果
+//
+// 起動の途中でいくつかの項目を確かめ、それぞれの合否を1ビットずつ結果のワードにまとめる。
+// 結果はRAMとWATCHDOGのSCRATCH2/3に入れておき、UARTのpostコマンドで読める。
+// 製造ラインで「どの項目で落ちたか」をLEDの様子からではなく、項目ごとに確かめるためのもの。
+//
+// 結果のワード（u32）
+//   ビット 0〜7  : 落ちた項目（1で失敗）
+//   ビット 8〜15 : 確かめた項目（POST_CHECKSで選んだもの。1で確かめた）
+//   ビット16〜31 : 0
+//   確かめていない項目は、失敗のビットも0になる。「失敗のビットが0」だけで合格とせず、確かめたかも見ること。
+//
+// 項目のビット（失敗のビットの位置。確かめたビットはこれを8ビット上にずらしたもの）
+//   ビット0 CHECK_CLOCK      : clk_sysがEXPECTED_SYS_HZになっている（PLLが設定どおりに動いている）
+//   ビット1 CHECK_CONFIG     : フラッシュの設定が読めた。一度も保存していない（not found）は合格とする
+//   ビット2 CHECK_RTC        : DS3231がI2Cで応答し、時刻のデータが正しい
+//   ビット3 CHECK_RTC_TIME   : DS3231の発振が止まっていない（電池が切れて時刻を失っていない）。
+//                              CHECK_RTCで応答がなければ確かめられないので、確かめなかったことにする
+//   ビット4 CHECK_LED        : 覚えているLEDの状態（led::is_on()）と、GPIOの実際の出力（led::led_is_on()）が一致する
+//   ビット5〜7               : 予約（0）
+//
+// どの項目を確かめるかはPOST_CHECKSで選ぶ。DS3231を載せない基板では、CHECK_RTCとCHECK_RTC_TIMEを外す。
+//
+// 保存のしかた
+//   SCRATCH2に印（POST_MAGIC）、SCRATCH3に結果のワードを書く。SCRATCHはウォッチドッグやsys_reset()の
+//   リセットでは消えないので、run()は書き換える前に前回の結果を読んで覚えておく（previous()）。
+//   電源を入れ直したときは印がないので、前回の結果はない。
+//   SCRATCH0/1はreset_log.rs、SCRATCH4〜7はブートROMが使う。
+//
+// 結果を使う順番
+//   このファームウェアには失敗を知らせるLEDの点滅（フォールトの点滅）はまだない。
+//   失敗したときに止めて点滅させるような処理を足すときは、かならずrun()の後に置き、
+//   run()が結果をSCRATCHに書き終えてから入ること（点滅のループから戻らなくても、結果は読める）。
+
+use crate::clock_info;
+use crate::config;
+use crate::cs_trace::free;
+use crate::ds3231::{self, RtcError};
+use crate::led;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::config_blob::BlobError;
+use rp_pico::hal::pac;
+
+pub const CHECK_CLOCK: u32 = 1 << 0;
+pub const CHECK_CONFIG: u32 = 1 << 1;
+pub const CHECK_RTC: u32 = 1 << 2;
+pub const CHECK_RTC_TIME: u32 = 1 << 3;
+pub const CHECK_LED: u32 = 1 << 4;
+
+// (ビット, UARTに出す名前)
+pub const CHECKS: [(u32, &str); 5] = [
+    (CHECK_CLOCK, "clock"),
+    (CHECK_CONFIG, "config"),
+    (CHECK_RTC, "rtc"),
+    (CHECK_RTC_TIME, "rtc-time"),
+    (CHECK_LED, "led"),
+];
+
+// 確かめる項目
+pub const POST_CHECKS: u32 = CHECK_CLOCK | CHECK_CONFIG | CHECK_RTC | CHECK_RTC_TIME | CHECK_LED;
+
+// init_clocks_and_plls()で設定するclk_sysの周波数
+pub const EXPECTED_SYS_HZ: u32 = 125_000_000;
+
+const FAILED_MASK: u32 = 0xFF;
+const RAN_SHIFT: u32 = 8;
+
+// SCRATCH2に書いておく印（"POST"）。SCRATCH3の値が今回のファームウェアが書いたものかを見分ける
+const POST_MAGIC: u32 = 0x504F_5354;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PostResult(pub u32);
+
+impl PostResult {
+    pub fn failed(&self) -> u32 {
+        self.0 & FAILED_MASK
+    }
+
+    pub fn ran(&self) -> u32 {
+        (self.0 >> RAN_SHIFT) & FAILED_MASK
+    }
+
+    // 確かめた項目がすべて合格
+    pub fn passed(&self) -> bool {
+        self.failed() == 0
+    }
+
+    // checkの結果。確かめていなければNone
+    pub fn check(&self, check: u32) -> Option<bool> {
+        (self.ran() & check != 0).then_some(self.failed() & check == 0)
+    }
+}
+
+static RESULT: Mutex<Cell<Option<PostResult>>> = Mutex::new(Cell::new(None));
+static PREVIOUS: Mutex<Cell<Option<PostResult>>> = Mutex::new(Cell::new(None));
+
+// 今回の起動の結果。run()の前ならNone
+pub fn result(cs: &CriticalSection) -> Option<PostResult> {
+    RESULT.borrow(cs).get()
+}
+
+// 前回の起動でSCRATCHに残っていた結果。電源を入れ直したときはNone
+pub fn previous(cs: &CriticalSection) -> Option<PostResult> {
+    PREVIOUS.borrow(cs).get()
+}
+
+// 起動時に1回だけ、クロック、設定、I2C、LEDの初期化が終わってから呼ぶ。
+// I2Cの転送をするので、割り込みを有効にする前でも後でもよいが、free()の中では呼ばないこと。
+pub fn run() -> PostResult {
+    let mut ran = 0;
+    let mut failed = 0;
+    let mut record = |check: u32, ok: bool| {
+        ran |= check;
+        if !ok {
+            failed |= check;
+        }
+    };
+
+    if POST_CHECKS & CHECK_CLOCK != 0 {
+        let sys_hz = clock_info::clock_freqs().map(|freqs| freqs.sys_hz);
+        record(CHECK_CLOCK, sys_hz == Some(EXPECTED_SYS_HZ));
+    }
+    if POST_CHECKS & CHECK_CONFIG != 0 {
+        let loaded = free(config::load_result);
+        record(
+            CHECK_CONFIG,
+            matches!(loaded, Ok(_) | Err(BlobError::NotFound)),
+        );
+    }
+    if POST_CHECKS & (CHECK_RTC | CHECK_RTC_TIME) != 0 {
+        match ds3231::read_datetime() {
+            Ok(_) => {
+                record(CHECK_RTC, true);
+                record(CHECK_RTC_TIME, true);
+            }
+            // 応答はあったが時刻を失っている
+            Err(RtcError::OscillatorStopped) => {
+                record(CHECK_RTC, true);
+                record(CHECK_RTC_TIME, false);
+            }
+            Err(_) => record(CHECK_RTC, false),
+        }
+    }
+    if POST_CHECKS & CHECK_LED != 0 {
+        record(CHECK_LED, free(led::is_on) == led::led_is_on());
+    }
+
+    let ran = ran & POST_CHECKS;
+    let result = PostResult((ran << RAN_SHIFT) | (failed & ran));
+    let previous = read_scratch();
+    write_scratch(result);
+    free(|cs| {
+        RESULT.borrow(cs).set(Some(result));
+        PREVIOUS.borrow(cs).set(previous);
+    });
+    if result.passed() {
+        defmt::info!("post: pass (0x{=u32:04x})", result.0);
+    } else {
+        defmt::error!(
+            "post: failed 0x{=u32:02x} (0x{=u32:04x})",
+            result.failed(),
+            result.0
+        );
+    }
+    result
+}
+
+fn read_scratch() -> Option<PostResult> {
+    let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+    (watchdog.scratch2().read().bits() == POST_MAGIC)
+        .then(|| PostResult(watchdog.scratch3().read().bits()))
+}
+
+fn write_scratch(result: PostResult) {
+    let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+    watchdog.scratch3().write(|w| unsafe { w.bits(result.0) });
+    watchdog.scratch2().write(|w| unsafe { w.bits(POST_MAGIC) });
+}
@@ -0,0 +1,80 @@
+// 平均消費電流の見積もり
+//
+// 電池でどのくらい動くかの目安にするための、ごく大まかな見積もり。測定値ではない。
+// 次の前提で計算している。
+//   ・RP2040とボード（レギュレータなど）の消費はPOWER_BASELINE_UAで一定とする。
+//     実際にはクロックやCPUの負荷でも変わるが、このファームウェアは常に125MHzで
+//     メインループを回し続けているので、ほぼ一定とみなせる。
+//   ・LEDの電流はデューティに比例するとみなし、デューティ100%のときをPOWER_LED_UAとする。
+//     PicoのLEDは電流制限抵抗を通してGPIO25から直接駆動している。
+//   ・ブザー、UART、I2Cのデバイスなど外付けのものは含めない。
+//   ・モードごとのLEDの平均デューティ
+//       Solid  : LED_DIM_DUTY
+//       Blink  : ON_BRIGHTNESSとOFF_BRIGHTNESSが半分ずつ
+//       Number : 表示1周の点灯時間の割合でON_BRIGHTNESS、残りでOFF_BRIGHTNESS
+//       Heartbeat : 1周（HEARTBEAT_PERIOD_MS）のうち2 × HEARTBEAT_ON_MSがON_BRIGHTNESS、残りがOFF_BRIGHTNESS
+//       Off    : 0
+//     全体の明るさと周囲の明るさによる調光はそのまま掛け、上限で頭打ちにする（led::output_duty()）。
+//     （平均に上限をかけているので、点滅の点灯中だけ頭打ちになる場合はやや多めに出る）
+//     サーマルスロットリング中（thermal.rs）はデューティが1/THERMAL_DUTY_DIVISORになる。
+// モードや明るさから毎回計算するので、切り替えるとすぐに見積もりに反映される。
+
+use crate::bar_graph;
+use crate::cs_trace::free;
+use crate::double_blink;
+use crate::egg_timer;
+use crate::led;
+use crate::mode::{self, LedMode};
+use crate::number;
+use crate::thermal;
+
+// RP2040（125MHz動作）とボードの消費電流
+pub const POWER_BASELINE_UA: u32 = 20_000;
+// LEDをデューティ100%で点灯したときの電流
+pub const POWER_LED_UA: u32 = 2_000;
+
+// total_msのうちon_msだけON_BRIGHTNESS、残りはOFF_BRIGHTNESSで光るときの平均のデューティ
+fn on_off_average(on_ms: u32, total_ms: u32) -> u64 {
+    let total_ms = total_ms.max(1);
+    let on_ms = on_ms.min(total_ms);
+    (u64::from(led::ON_BRIGHTNESS) * u64::from(on_ms)
+        + u64::from(led::OFF_BRIGHTNESS) * u64::from(total_ms - on_ms))
+        / u64::from(total_ms)
+}
+
+// 今のモードでの平均消費電流の見積もり（µA）
+pub fn estimate_current_ua() -> u32 {
+    let full = u64::from(u16::MAX);
+    // LEDの平均デューティを0〜u16::MAXの範囲で求める
+    let average_duty = free(|cs| {
+        let duty = match mode::mode(cs) {
+            LedMode::Solid => u64::from(led::LED_DIM_DUTY),
+            LedMode::Blink => (u64::from(led::ON_BRIGHTNESS) + u64::from(led::OFF_BRIGHTNESS)) / 2,
+            LedMode::Number => {
+                let (on_ms, total_ms) = number::cycle_ms(cs);
+                on_off_average(on_ms, total_ms)
+            }
+            LedMode::Off => 0,
+            LedMode::Heartbeat => on_off_average(
+                2 * double_blink::HEARTBEAT_ON_MS,
+                double_blink::HEARTBEAT_PERIOD_MS,
+            ),
+            LedMode::Bar => on_off_average(
+                bar_graph::on_ms(bar_graph::bar_value(cs)),
+                bar_graph::BAR_WINDOW_MS,
+            ),
+            LedMode::EggTimer => {
+                let (on_ms, total_ms) = egg_timer::cycle_ms(cs);
+                on_off_average(on_ms, total_ms)
+            }
+        };
+        // 平均のデューティは0〜u16::MAXに収まる
+        let duty = u64::from(led::output_duty(cs, duty as u16));
+        if thermal::is_throttled(cs) {
+            duty / u64::from(thermal::THERMAL_DUTY_DIVISOR)
+        } else {
+            duty
+        }
+    });
+    POWER_BASELINE_UA + (u64::from(POWER_LED_UA) * average_duty / full) as u32
+}
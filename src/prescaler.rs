@@ -0,0 +1,66 @@
+// ALARM0の割り込みを間引くソフトウェアプリスケーラ
+//
+// ALARMは32bitのマイクロ秒でしかスケジュールできないので、約71分より長い周期は直接作れない。
+// そこでALARM0は基本周期で回し続け、N回に1回だけLEDを更新することで周期を伸ばす。
+//
+//   実効周期 = 1段の長さ × プリスケール値
+//
+// 1段の長さは、LEDを更新した回にモードが決めた次の段の長さ（Blink、Solid、Offなら点滅間隔）。
+// 間引かれた回も同じ長さでscheduleする（record_step()で覚えておき、step_ms()で返す）ので、
+// Number、Heartbeat、Bar、EggTimerのように段ごとに長さが違うモードでも、どの段もN倍の長さになる。
+// 例えば点滅間隔が60_000（1分）でプリスケール値が1440なら、LEDは1日に1回切り替わる。
+// 間引かれた割り込みではカウンタを進めるだけなので、割り込み処理の負荷はほとんど変わらない。
+// 間引いている途中で点滅間隔を変えたときは、次にLEDを更新した回から新しい間隔になる。
+
+use crate::cs_trace::free;
+use crate::interval;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+
+// 起動時のプリスケール値。1なら間引かない。
+pub const PRESCALE: u32 = 1;
+
+static PRESCALE_N: Mutex<Cell<u32>> = Mutex::new(Cell::new(PRESCALE));
+// 前回LEDを更新してから何回割り込みが発生したか
+static PRESCALE_COUNT: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+// 最後にLEDを更新した回の、次の段の長さ（ms）。まだ更新していなければNone
+static STEP_MS: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+
+// プリスケール値を変更する。0は1として扱う。
+// 途中まで数えていたカウンタは捨てるので、変更後の最初の更新はちょうどN回目の割り込みになる。
+pub fn set_prescale(n: u32) {
+    free(|cs| {
+        PRESCALE_N.borrow(cs).set(n.max(1));
+        PRESCALE_COUNT.borrow(cs).set(0);
+    });
+}
+
+pub fn prescale(cs: &CriticalSection) -> u32 {
+    PRESCALE_N.borrow(cs).get()
+}
+
+// ALARM0の割り込みごとに呼ぶ。LEDを更新すべき回ならtrueを返す。
+pub fn tick(cs: &CriticalSection) -> bool {
+    let count = PRESCALE_COUNT.borrow(cs);
+    let next = count.get() + 1;
+    if next >= PRESCALE_N.borrow(cs).get() {
+        count.set(0);
+        true
+    } else {
+        count.set(next);
+        false
+    }
+}
+
+// LEDを更新した回に、モードが決めた次の段の長さを覚えておく
+pub fn record_step(cs: &CriticalSection, ms: u32) {
+    STEP_MS.borrow(cs).set(Some(ms));
+}
+
+// 間引いた回にscheduleする長さ。まだLEDを更新していなければ点滅間隔
+pub fn step_ms(cs: &CriticalSection) -> u32 {
+    STEP_MS
+        .borrow(cs)
+        .get()
+        .unwrap_or_else(|| interval::current_ms(cs))
+}
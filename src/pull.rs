@@ -0,0 +1,48 @@
+// 入力ピンのプル抵抗の設定をまとめるモジュール
+//
+// rp2040-halではプル抵抗の種類がピンの型（PullUp, PullDownなど）に含まれているので、
+// into_pull_up_input()のように書くと、型を変えない限りプル抵抗を変えられない。
+// ここではプル抵抗の型をDynPullTypeにして、実行時の値Pullで設定する。
+// ピンの型は Pin<Id, FunctionSioInput, DynPullType> に揃うので、
+// 各モジュールはピンの型を変えずにプル抵抗だけを差し替えられる。
+//
+// 注意：プル抵抗の向きは回路と対になっている。
+// 例えばボタンはGNDとの間にスイッチをつなぐ前提なのでUpでなければ押下を検出できない。
+// 各モジュールのプル抵抗の定数は、回路を変えたときだけ変えること。
+
+use rp_pico::hal::gpio::{
+    self, DynPullType, FunctionSioInput, Pin, PinId, PullType, ValidFunction,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Pull {
+    Up,
+    Down,
+    None,
+}
+
+impl From<Pull> for DynPullType {
+    fn from(pull: Pull) -> Self {
+        match pull {
+            Pull::Up => DynPullType::Up,
+            Pull::Down => DynPullType::Down,
+            Pull::None => DynPullType::None,
+        }
+    }
+}
+
+pub type InputPin<I> = Pin<I, FunctionSioInput, DynPullType>;
+
+// ピンを入力にして、指定したプル抵抗を設定する
+pub fn into_input<I, F, P>(pin: Pin<I, F, P>, pull: Pull) -> InputPin<I>
+where
+    I: PinId + ValidFunction<FunctionSioInput>,
+    F: gpio::Function,
+    P: PullType,
+{
+    let mut pin = pin
+        .into_function::<FunctionSioInput>()
+        .into_pull_type::<DynPullType>();
+    pin.set_pull_type(pull.into());
+    pin
+}
@@ -0,0 +1,223 @@
+// PIO1で決まった数のパルスを決まった周波数で出す（GPIO12）
+//
+// ステッピングモーターのステップ信号や赤外線の搬送波のように、周期が正確でなければならないパルスは、
+// ALARMの割り込みで1つずつ出すと割り込みの遅れで周期が揺れるし、速いとCPUが追いつかない。
+// そこでPIO1のステートマシン0にパルスを出させ、CPUは回数を渡して終わるのを待つだけにする。
+//
+// PIOのプログラム（1パルス = CYCLES_PER_PULSE = 32サイクル、デューティ50%）
+//   .wrap_target
+//       pull block           ; TX FIFOから「回数 - 1」を受け取るまで待つ
+//       out x, 32
+//   pulse:
+//       set pins, 1 [15]     ; 16サイクルHigh
+//       set pins, 0 [14]     ; 15サイクルLow
+//       jmp x-- pulse        ; +1サイクルでLowが16サイクル。xが0でなければ1つ減らして次のパルスへ
+//       irq 0                ; 出し終わったことをPIO1_IRQ_0で知らせる
+//   .wrap
+//   最後はLowのままpull blockで止まるので、出し終わった後のピンはLowになる。
+//
+// 周波数と分解能
+//   ステートマシンのクロックはシステムクロックを分周したもので、分周比は整数16bit + 小数8bit（1/256刻み）。
+//     周波数 = システムクロック ÷ (分周比 × 32)
+//   125 MHzなら、分周比1で最高の約3.9 MHz、分周比65535.996で最低の約60 Hzになる。
+//   分周比は1/256刻みなので、出せる周波数の刻みは周波数の (1/256) ÷ 分周比 倍になる。
+//     ・3.9 MHz付近（分周比1）では約0.4%刻み（約15 kHz）
+//     ・10 kHz付近（分周比約390）では約0.001%刻み（約0.1 Hz）
+//   実際に出す周波数はemit_pulses()の戻り値で確かめられる。
+//   分周比に小数があると、PIOのクロックの間隔が1サイクルずつ揺れる（平均の周波数は合っている）ので、
+//   1周期ごとの正確さが必要なら整数の分周比になる周波数を選ぶこと。
+//
+// 出し終わったことの検出
+//   プログラムの最後のirq 0でPIO1_IRQ_0に入り、on_interrupt()で出している最中の印（BUSY）を消す。
+//   出している間に次を頼まれたらBusyで断る（重ねて頼むと回数が混ざるため）。
+//
+// タイマーからの開始
+//   set_repeat()で間隔を決めると、sampler（SAMPLE_PERIOD_MSごと）が間隔ごとに同じパルスを出す。
+//   間隔はSAMPLE_PERIOD_MS単位に丸める。前のパルスがまだ終わっていなければ、その回は飛ばす。
+//
+// 非常停止（estop.rs）ではこのピンもLowに固定する。
+
+use crate::cs_trace::free;
+use crate::sampler::SAMPLE_PERIOD_MS;
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp_pico::hal::gpio;
+use rp_pico::hal::pac;
+use rp_pico::hal::pio::{PIOBuilder, PIOExt, PinDir, Running, StateMachine, Tx, PIO, SM0};
+
+pub const CYCLES_PER_PULSE: u32 = 32;
+
+pub type PulseTrainPin = gpio::Pin<gpio::bank0::Gpio12, gpio::FunctionPio1, gpio::PullNone>;
+
+type PulsePio = (
+    PIO<pac::PIO1>,
+    StateMachine<(pac::PIO1, SM0), Running>,
+    Tx<(pac::PIO1, SM0)>,
+    PulseTrainPin,
+);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PulseError {
+    // 前のパルスをまだ出している
+    Busy,
+    // 出せる周波数の範囲の外
+    OutOfRange,
+}
+
+impl PulseError {
+    // UARTなどに出力するときの説明
+    pub fn name(&self) -> &'static str {
+        match self {
+            PulseError::Busy => "busy",
+            PulseError::OutOfRange => "frequency out of range",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Repeat {
+    freq_hz: u32,
+    count: u32,
+    every_ticks: u32,
+}
+
+static PULSE_PIO: GlobalPeripheral<PulsePio> = initial_global_peripheral();
+static SYSTEM_CLOCK_HZ: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+static BUSY: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+static REPEAT: Mutex<Cell<Option<Repeat>>> = Mutex::new(Cell::new(None));
+static REPEAT_TICKS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+pub fn init(
+    cs: &CriticalSection,
+    pio1: pac::PIO1,
+    resets: &mut pac::RESETS,
+    pin: PulseTrainPin,
+    system_clock_hz: u32,
+) {
+    let mut assembler = pio::Assembler::<{ pio::RP2040_MAX_PROGRAM_SIZE }>::new();
+    let mut wrap_target = assembler.label();
+    let mut wrap_source = assembler.label();
+    let mut pulse = assembler.label();
+    assembler.bind(&mut wrap_target);
+    assembler.pull(false, true);
+    assembler.out(pio::OutDestination::X, 32);
+    assembler.bind(&mut pulse);
+    assembler.set_with_delay(pio::SetDestination::PINS, 1, 15);
+    assembler.set_with_delay(pio::SetDestination::PINS, 0, 14);
+    assembler.jmp(pio::JmpCondition::XDecNonZero, &mut pulse);
+    assembler.irq(false, false, 0, false);
+    assembler.bind(&mut wrap_source);
+    let program = assembler.assemble_with_wrap(wrap_source, wrap_target);
+
+    let pin_id = pin.id().num;
+    let (mut pio, sm0, _, _, _) = pio1.split(resets);
+    let installed = pio.install(&program).unwrap();
+    let (mut sm, _, tx) = PIOBuilder::from_installed_program(installed)
+        .set_pins(pin_id, 1)
+        .build(sm0);
+    sm.set_pindirs([(pin_id, PinDir::Output)]);
+    pio.irq0().enable_sm_interrupt(0);
+    let sm = sm.start();
+    PULSE_PIO.borrow(cs).replace(Some((pio, sm, tx, pin)));
+    SYSTEM_CLOCK_HZ.borrow(cs).set(system_clock_hz);
+}
+
+pub fn is_busy(cs: &CriticalSection) -> bool {
+    BUSY.borrow(cs).get()
+}
+
+// freq_hzの分周比（1/256単位）
+fn divisor_256(system_clock_hz: u32, freq_hz: u32) -> Option<u32> {
+    let pulse_hz = u64::from(freq_hz) * u64::from(CYCLES_PER_PULSE);
+    if pulse_hz == 0 {
+        return None;
+    }
+    let div = u64::from(system_clock_hz) * 256 / pulse_hz;
+    // 整数部が0だと65536扱いになるので、1以上65535以下に限る
+    (256..=(u64::from(u16::MAX) << 8 | 0xFF))
+        .contains(&div)
+        .then_some(div as u32)
+}
+
+fn start(cs: &CriticalSection, freq_hz: u32, count: u32) -> Result<u32, PulseError> {
+    if is_busy(cs) {
+        return Err(PulseError::Busy);
+    }
+    let system_clock_hz = SYSTEM_CLOCK_HZ.borrow(cs).get();
+    let div = divisor_256(system_clock_hz, freq_hz).ok_or(PulseError::OutOfRange)?;
+    let actual_hz =
+        (u64::from(system_clock_hz) * 256 / (u64::from(div) * u64::from(CYCLES_PER_PULSE))) as u32;
+    if count == 0 {
+        return Ok(actual_hz);
+    }
+    let mut pulse_pio = PULSE_PIO.borrow(cs).borrow_mut();
+    let Some((_, sm, tx, _)) = pulse_pio.as_mut() else {
+        return Err(PulseError::Busy);
+    };
+    // pull blockで止まっている間に分周比を変える
+    sm.clock_divisor_fixed_point((div >> 8) as u16, div as u8);
+    tx.write(count - 1);
+    BUSY.borrow(cs).set(true);
+    Ok(actual_hz)
+}
+
+// freq_hzでcount個のパルスを出し始める。実際に出す周波数（分周比の丸めの後）を返す。
+// 出し終わるのを待たずに戻る。countが0なら何もしない。
+pub fn emit_pulses(freq_hz: u32, count: u32) -> Result<u32, PulseError> {
+    free(|cs| start(cs, freq_hz, count))
+}
+
+// every_msごとにemit_pulses(freq_hz, count)を出す。every_msが0なら止める。
+// SAMPLE_PERIOD_MS単位に丸めた間隔を返す（止めたときは0）。
+pub fn set_repeat(freq_hz: u32, count: u32, every_ms: u32) -> Result<u32, PulseError> {
+    free(|cs| {
+        REPEAT_TICKS.borrow(cs).set(0);
+        if every_ms == 0 {
+            REPEAT.borrow(cs).set(None);
+            return Ok(0);
+        }
+        let system_clock_hz = SYSTEM_CLOCK_HZ.borrow(cs).get();
+        divisor_256(system_clock_hz, freq_hz).ok_or(PulseError::OutOfRange)?;
+        REPEAT.borrow(cs).set(Some(Repeat {
+            freq_hz,
+            count,
+            every_ticks: (every_ms / SAMPLE_PERIOD_MS).max(1),
+        }));
+        Ok(every_ms.max(SAMPLE_PERIOD_MS) / SAMPLE_PERIOD_MS * SAMPLE_PERIOD_MS)
+    })
+}
+
+// samplerから呼ぶ。set_repeat()の間隔ごとにパルスを出す。
+pub fn tick(cs: &CriticalSection) {
+    let Some(repeat) = REPEAT.borrow(cs).get() else {
+        return;
+    };
+    let ticks = REPEAT_TICKS.borrow(cs);
+    let next = ticks.get() + 1;
+    if next < repeat.every_ticks {
+        ticks.set(next);
+        return;
+    }
+    ticks.set(0);
+    if start(cs, repeat.freq_hz, repeat.count) == Err(PulseError::Busy) {
+        defmt::debug!("pulse train: previous burst still running, skipped");
+    }
+}
+
+// 次のtick()で、間隔の最初のパルスを出すようにする（phase_sync.rs）
+pub fn restart_phase(cs: &CriticalSection) {
+    if let Some(repeat) = REPEAT.borrow(cs).get() {
+        REPEAT_TICKS
+            .borrow(cs)
+            .set(repeat.every_ticks.saturating_sub(1));
+    }
+}
+
+// PIO1_IRQ_0から呼ぶ。出し終わった印を消す。
+pub fn on_interrupt(cs: &CriticalSection) {
+    if let Some((pio, _, _, _)) = PULSE_PIO.borrow(cs).borrow().as_ref() {
+        pio.clear_irq(1 << 0);
+    }
+    BUSY.borrow(cs).set(false);
+}
@@ -0,0 +1,80 @@
+// GPIO17に入ってくるパルスの幅を測る
+//
+// 立ち上がりと立ち下がりの両方のエッジで割り込み（IO_IRQ_BANK0）を入れ、
+// 立ち上がりの時刻を覚えておいて、立ち下がりで差を取ったものをHighのパルス幅とする。
+// 超音波センサーのエコー出力のような、幅で値を返す簡単なセンサーの読み取りに使える。
+//
+// 時刻はタイマーの下位32bit（get_counter_low()、µs）を使う。約71分で一周するが、
+// 差をwrapping_sub()で取れば一周をまたいでも正しい幅になる（パルスが71分より短い限り）。
+//
+// 測れないパルス
+//   ・立ち上がりを見ていない立ち下がり（起動直後にすでにHighだった、など）は幅がわからないので捨てる
+//   ・割り込みに入るまでの遅れより短いパルスは、2つのエッジが同時に来ているように見える。
+//     どちらが先か区別できないので、その回も捨てる
+
+use crate::pull::{self, Pull};
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp_pico::hal::gpio;
+use rp_pico::hal::timer::Timer;
+
+// センサーのプッシュプル出力をつなぐ前提。つないでいないときはLowに落ち着かせる。
+pub const PULSE_PULL: Pull = Pull::Down;
+
+pub type PulsePin = pull::InputPin<gpio::bank0::Gpio17>;
+
+static PULSE_PIN: GlobalPeripheral<PulsePin> = initial_global_peripheral();
+static PULSE_TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+// 最後の立ち上がりの時刻。立ち下がりで使ったらNoneに戻す。
+static RISE_AT: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+// 最後に測ったパルス幅（µs）
+static LAST_WIDTH_US: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+
+pub fn init(cs: &CriticalSection, pin: PulsePin, timer: Timer) {
+    pin.set_interrupt_enabled(gpio::Interrupt::EdgeHigh, true);
+    pin.set_interrupt_enabled(gpio::Interrupt::EdgeLow, true);
+    PULSE_PIN.borrow(cs).replace(Some(pin));
+    PULSE_TIMER.borrow(cs).set(Some(timer));
+}
+
+pub fn last_width_us(cs: &CriticalSection) -> Option<u32> {
+    LAST_WIDTH_US.borrow(cs).get()
+}
+
+// IO_IRQ_BANK0から呼ぶ
+pub fn on_interrupt(cs: &CriticalSection) {
+    let mut pin = PULSE_PIN.borrow(cs).borrow_mut();
+    let Some(pin) = pin.as_mut() else {
+        return;
+    };
+    let rose = pin.interrupt_status(gpio::Interrupt::EdgeHigh);
+    let fell = pin.interrupt_status(gpio::Interrupt::EdgeLow);
+    if !rose && !fell {
+        return;
+    }
+    // toggle_buttonと同じく、要因は最初に消す
+    pin.clear_interrupt(gpio::Interrupt::EdgeHigh);
+    pin.clear_interrupt(gpio::Interrupt::EdgeLow);
+
+    let Some(timer) = PULSE_TIMER.borrow(cs).get() else {
+        return;
+    };
+    let now = timer.get_counter_low();
+    let rise_at = RISE_AT.borrow(cs);
+    match (rose, fell) {
+        (true, false) => rise_at.set(Some(now)),
+        (false, true) => match rise_at.take() {
+            Some(rise) => {
+                let width_us = now.wrapping_sub(rise);
+                LAST_WIDTH_US.borrow(cs).set(Some(width_us));
+                defmt::info!("pulse width: {} us", width_us);
+            }
+            None => defmt::debug!("pulse: falling edge without rising edge, ignored"),
+        },
+        _ => {
+            rise_at.set(None);
+            defmt::debug!("pulse: both edges pending, too short to measure");
+        }
+    }
+}
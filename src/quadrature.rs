@@ -0,0 +1,198 @@
+// ロータリーエンコーダーの2相（A相とB相）の信号を、回した方向とステップ数に直す
+//
+// AとBは90°ずれた矩形波で、どちらが先に変わるかで回した方向がわかる。
+// 状態を s = (A << 1) | B とすると、時計回り（Aが先に変わる）では
+//   11 → 01 → 00 → 10 → 11 （s: 3 → 1 → 0 → 2 → 3）
+// と1bitずつ変わる（グレイコード）。反時計回りはその逆順になる。
+//
+// 状態遷移表
+//   前の状態と今の状態の組（16通り）から、+1（時計回り）、-1（反時計回り）、0を引く。
+//   ・同じ状態のまま           : 0
+//   ・AとBが同時に変わった     : 0（途中の変化を取りこぼしたので、どちら向きか決められない）
+//   接点のチャタリングでは片方の相だけが何度も行ったり来たりするので、+1と-1が交互に来て打ち消し合い、
+//   ステップ数が増えない。エッジのたびに両方の相を読み直すので、割り込みの取りこぼしにも強い。
+//
+// 分解能（Resolution）
+//   1周期（4回の遷移）を何ステップとして数えるか。クリック（デテント）の数に合わせて選ぶ。
+//     Quarter : 遷移1回で1ステップ（1周期で4ステップ）
+//     Half    : 遷移2回で1ステップ。1周期に2回クリックするエンコーダー向け
+//     Full    : 遷移4回で1ステップ。1周期に1回クリックするエンコーダー向け（いちばん多い）
+//   HalfとFullでは、クリックの位置で止まる状態（Fullは11、Halfは00と11）に来たら、
+//   1ステップに満たない端数を捨てる。チャタリングなどで1回分ずれても、次のクリックで揃い直す。
+//   11をクリックの位置とするのは、プルアップした入力で接点が両方とも開いている状態だから。
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Resolution {
+    Quarter,
+    Half,
+    Full,
+}
+
+impl Resolution {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Resolution::Quarter => "quarter",
+            Resolution::Half => "half",
+            Resolution::Full => "full",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Resolution> {
+        [Resolution::Quarter, Resolution::Half, Resolution::Full]
+            .into_iter()
+            .find(|r| r.name() == name)
+    }
+
+    // 1ステップの遷移の数
+    fn transitions(self) -> i8 {
+        match self {
+            Resolution::Quarter => 1,
+            Resolution::Half => 2,
+            Resolution::Full => 4,
+        }
+    }
+
+    // クリックの位置で止まる状態か
+    fn is_detent(self, state: u8) -> bool {
+        match self {
+            Resolution::Quarter => false,
+            Resolution::Half => state == 0b00 || state == 0b11,
+            Resolution::Full => state == 0b11,
+        }
+    }
+}
+
+// [前の状態 << 2 | 今の状態]。行が前の状態（00, 01, 10, 11）、列が今の状態。
+#[rustfmt::skip]
+const TRANSITIONS: [i8; 16] = [
+     0, -1,  1,  0,
+     1,  0,  0, -1,
+    -1,  0,  0,  1,
+     0,  1, -1,  0,
+];
+
+fn state(a: bool, b: bool) -> u8 {
+    (u8::from(a) << 1) | u8::from(b)
+}
+
+pub struct QuadratureDecoder {
+    resolution: Resolution,
+    state: u8,
+    // 1ステップに満たない遷移の数
+    pending: i8,
+}
+
+impl QuadratureDecoder {
+    // グローバル変数の初期値に使えるようにconst fnにしている。a, bは今のレベル。
+    pub const fn new(resolution: Resolution, a: bool, b: bool) -> Self {
+        Self {
+            resolution,
+            state: ((a as u8) << 1) | b as u8,
+            pending: 0,
+        }
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    // 分解能を変える。端数は捨てる。
+    pub fn set_resolution(&mut self, resolution: Resolution) {
+        self.resolution = resolution;
+        self.pending = 0;
+    }
+
+    // A相かB相のエッジのたびに今のレベルを渡す。進んだステップ数（時計回りが正）を返す。
+    pub fn update(&mut self, a: bool, b: bool) -> i8 {
+        let next = state(a, b);
+        let delta = TRANSITIONS[usize::from(self.state << 2 | next)];
+        self.state = next;
+        self.pending += delta;
+        let per_step = self.resolution.transitions();
+        let steps = self.pending / per_step;
+        self.pending -= steps * per_step;
+        if self.resolution.is_detent(next) {
+            self.pending = 0;
+        }
+        steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 時計回りの1周期（11から始めて11に戻る）
+    const CW: [(bool, bool); 4] = [(false, true), (false, false), (true, false), (true, true)];
+
+    fn feed(decoder: &mut QuadratureDecoder, seq: &[(bool, bool)]) -> i32 {
+        seq.iter()
+            .map(|&(a, b)| i32::from(decoder.update(a, b)))
+            .sum()
+    }
+
+    fn ccw() -> Vec<(bool, bool)> {
+        // 反時計回りは時計回りを逆にたどる（11の前の状態から11まで）
+        let mut seq: Vec<_> = CW.iter().rev().skip(1).copied().collect();
+        seq.push((true, true));
+        seq
+    }
+
+    #[test]
+    fn counts_each_resolution_in_both_directions() {
+        for (resolution, per_cycle) in [
+            (Resolution::Quarter, 4),
+            (Resolution::Half, 2),
+            (Resolution::Full, 1),
+        ] {
+            let mut decoder = QuadratureDecoder::new(resolution, true, true);
+            let cw: Vec<_> = CW.iter().cycle().take(CW.len() * 3).copied().collect();
+            assert_eq!(feed(&mut decoder, &cw), 3 * per_cycle, "{:?}", resolution);
+            let ccw: Vec<_> = ccw().iter().cycle().take(CW.len() * 2).copied().collect();
+            assert_eq!(feed(&mut decoder, &ccw), -2 * per_cycle, "{:?}", resolution);
+        }
+    }
+
+    #[test]
+    fn contact_bounce_cancels_out() {
+        let mut decoder = QuadratureDecoder::new(Resolution::Full, true, true);
+        // A相が01と00の間で何度も跳ねてから、最後まで回る
+        let seq = [
+            (false, true),
+            (false, false),
+            (false, true),
+            (false, false),
+            (false, true),
+            (false, false),
+            (true, false),
+            (false, false),
+            (true, false),
+            (true, true),
+        ];
+        assert_eq!(feed(&mut decoder, &seq), 1);
+    }
+
+    #[test]
+    fn skipped_transition_is_ignored_and_detent_resyncs() {
+        let mut decoder = QuadratureDecoder::new(Resolution::Full, true, true);
+        // 11 → 00 はAとBが同時に変わっているので数えない
+        assert_eq!(decoder.update(false, false), 0);
+        // 残りの遷移は2回分しかないので、クリック（11）で端数が捨てられて0
+        assert_eq!(feed(&mut decoder, &[(true, false), (true, true)]), 0);
+        // 揃い直したので、次の1周期は1ステップ
+        assert_eq!(feed(&mut decoder, &CW), 1);
+    }
+
+    #[test]
+    fn half_turn_is_not_a_full_step() {
+        let mut decoder = QuadratureDecoder::new(Resolution::Full, true, true);
+        // 半分回して戻すと、どちらにも進まない
+        assert_eq!(
+            feed(
+                &mut decoder,
+                &[(false, true), (false, false), (false, true), (true, true)]
+            ),
+            0
+        );
+    }
+}
@@ -0,0 +1,156 @@
+// 実際に測った頻度で点滅間隔を補正し、ALARM0を決めた頻度ちょうどで発火させる
+//
+// ALARM0は前の期限から数えてscheduleし直す（main.rsのreschedule_alarm0()）ので、割り込みの遅れはたまらないが、
+// 点滅間隔はms単位なので、2.000 Hzのような頻度の周期は表せても3 Hz（333.33 ms）のような周期は丸められる。set_target_rate()で頻度を決めると、
+// TIMER_IRQ_0のたびにタイマーのカウンタで実際の時刻を測り、次の間隔をµs単位で補正する。
+// 頻度はALARM0が発火する頻度（Blinkなら点灯と消灯の切り替えの頻度）で、mHz（1/1000 Hz）で指定する。
+// 補正するのはBlinkモードの間だけで、ほかのモードは今までどおりそれぞれの時間で進める。
+//
+// 補正のしかた（比例制御）
+//   始めた時刻からk回目の理想の時刻は 始めた時刻 + k × 周期。実際の時刻との差（遅れ）をeとして、
+//     次の間隔 = 周期 - e × RATE_GAIN_PERCENT / 100
+//   にする。遅れているほど次を短くして追いつく。差の積み重ね（位相）を見ているので、遅れはたまらず、
+//   長い目で見た頻度は目標どおりになる。
+//   期限は前の期限から数えるので、補正した分はそのまま位相に積み重なり、遅れは0に近づいていく。
+//   割り込みに入るまでの遅れが毎回違っても、その回の位相がふらつくだけで頻度には影響しない。
+//   1回の補正は周期のRATE_MAX_CORRECTION_PERCENTまでに抑える。ゲインを大きくしすぎたり、
+//   ほかの割り込みで1回だけ大きく遅れたりしても、間隔が0や2倍に振れて発振しないようにするため。
+//   遅れが1周期を超えた（点滅を止めていた、モードを変えたなど）ら、補正せずにそこから数え直す。
+//
+// 収束したこと（RATE_SETTLE_TICKS回続けて遅れの変化が1 µs以下）を確かめたら、1回だけ
+// 残った位相のずれと、始めてからの実際の頻度をログに出す。
+
+use crate::cs_trace::free;
+use crate::mode::{self, LedMode};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::time;
+use rp_pico::hal::timer::Timer;
+
+pub const RATE_GAIN_PERCENT: i64 = 50;
+pub const RATE_MAX_CORRECTION_PERCENT: i64 = 10;
+pub const RATE_SETTLE_TICKS: u32 = 10;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RateError {
+    // 周期がscheduleできる範囲（time::MIN_INTERVAL_US〜MAX_INTERVAL_US）の外
+    OutOfRange,
+}
+
+impl RateError {
+    // UARTなどに出力するときの説明
+    pub fn name(&self) -> &'static str {
+        match self {
+            RateError::OutOfRange => "rate out of range",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Control {
+    target_millihz: u32,
+    period_us: u32,
+    // 数え始めた時刻と、そこから発火した回数
+    started_at: Option<u64>,
+    ticks: u64,
+    // 前回の遅れと、遅れの変化が小さい回が続いた数
+    last_error_us: i64,
+    settled_ticks: u32,
+    reported: bool,
+}
+
+static RATE_TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+// 補正している間はSome
+static CONTROL: Mutex<Cell<Option<Control>>> = Mutex::new(Cell::new(None));
+
+pub fn init(cs: &CriticalSection, timer: Timer) {
+    RATE_TIMER.borrow(cs).set(Some(timer));
+}
+
+// 目標の頻度（mHz）。補正していなければNone。
+pub fn target_rate(cs: &CriticalSection) -> Option<u32> {
+    CONTROL.borrow(cs).get().map(|c| c.target_millihz)
+}
+
+// ALARM0をmillihz（mHz）の頻度で発火させる。0なら補正をやめて点滅間隔で進める。
+// 補正なしの周期（µs）を返す（やめたときは0）。
+pub fn set_target_rate(millihz: u32) -> Result<u32, RateError> {
+    if millihz == 0 {
+        free(|cs| CONTROL.borrow(cs).set(None));
+        return Ok(0);
+    }
+    let period_us = 1_000_000_000 / u64::from(millihz);
+    if !(u64::from(time::MIN_INTERVAL_US)..=u64::from(time::MAX_INTERVAL_US)).contains(&period_us) {
+        return Err(RateError::OutOfRange);
+    }
+    let period_us = period_us as u32;
+    free(|cs| {
+        CONTROL.borrow(cs).set(Some(Control {
+            target_millihz: millihz,
+            period_us,
+            started_at: None,
+            ticks: 0,
+            last_error_us: 0,
+            settled_ticks: 0,
+            reported: false,
+        }));
+        crate::restart_blink(cs);
+    });
+    Ok(period_us)
+}
+
+// do_tick()から呼ぶ。補正しているなら次のALARM0までの間隔（µs）を返す。
+pub fn next_interval_us(cs: &CriticalSection) -> Option<u32> {
+    let cell = CONTROL.borrow(cs);
+    let mut control = cell.get()?;
+    if mode::mode(cs) != LedMode::Blink {
+        // ほかのモードの間は数え直すだけにする
+        control.started_at = None;
+        cell.set(Some(control));
+        return None;
+    }
+    let now = RATE_TIMER.borrow(cs).get()?.get_counter().ticks();
+    let period = i64::from(control.period_us);
+
+    let Some(started_at) = control.started_at else {
+        control.started_at = Some(now);
+        control.ticks = 0;
+        control.settled_ticks = 0;
+        cell.set(Some(control));
+        return Some(control.period_us);
+    };
+    control.ticks += 1;
+    let ideal = started_at.saturating_add(control.ticks * u64::from(control.period_us));
+    let error_us = now as i64 - ideal as i64;
+    if error_us.abs() > period {
+        // 止まっていたなどで1周期以上ずれたら、ここから数え直す
+        control.started_at = Some(now);
+        control.ticks = 0;
+        control.settled_ticks = 0;
+        cell.set(Some(control));
+        return Some(control.period_us);
+    }
+
+    let limit = period * RATE_MAX_CORRECTION_PERCENT / 100;
+    let correction = (-error_us * RATE_GAIN_PERCENT / 100).clamp(-limit, limit);
+
+    if (error_us - control.last_error_us).abs() <= 1 {
+        control.settled_ticks = control.settled_ticks.saturating_add(1);
+    } else {
+        control.settled_ticks = 0;
+    }
+    control.last_error_us = error_us;
+    if !control.reported && control.settled_ticks >= RATE_SETTLE_TICKS {
+        control.reported = true;
+        let elapsed_us = time::elapsed_us(now, started_at).max(1);
+        let achieved_millihz = control.ticks * 1_000_000_000 / elapsed_us;
+        defmt::info!(
+            "rate control: settled, phase error {=i64} us, achieved {=u64} mHz (target {=u32} mHz)",
+            error_us,
+            achieved_millihz,
+            control.target_millihz
+        );
+    }
+    cell.set(Some(control));
+    Some((period + correction) as u32)
+}
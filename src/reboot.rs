@@ -0,0 +1,49 @@
+// 一定時間動いたら自分でリセットし直す（periodic-rebootフィーチャー）
+//
+// 何か月も動かし続ける設置では、まれにしか起きない不具合（カウンタのずれ、周辺機器の取りこぼしなど）が
+// たまっていく前に、定期的に起動し直して初期状態に戻したいことがある。
+// そこでREBOOT_AFTER_HOURS時間動いたら、メインループからSCBのsys_reset()でリセットする。
+// 経過時間はタイマーの64bitのカウンタ（µs）と比べるので、一周することはない。
+//
+// リセットの前に
+//   ・defmtとUARTに意図したリセットであることを出す（ウォッチドッグなどによるリセットと区別するため）
+//   ・UARTの送信中の行をすべて送り終えるまで待つ（StatusTx::flush()）
+//   ・defmt（RTT）はホストが読むまでバッファに残るので、REBOOT_FLUSH_MSだけ待ってからリセットする
+// 大事な処理の途中ではリセットしない（can_reboot()）。途中なら終わるまでメインループを回して待つ。
+//   ・パルス列を出している最中（pulse_train.rs）
+//   ・DS18B20の温度の変換中（変換を始めたあとは読み出すまで1-Wireのやり取りが続く）
+// 設定はフラッシュに保存していないので、リセットするとすべて起動時の設定（config.rs）に戻る。
+
+use crate::cs_trace::free;
+use crate::pulse_train;
+use crate::status_tx::StatusTx;
+
+pub const REBOOT_AFTER_HOURS: u64 = 24;
+const REBOOT_AFTER_US: u64 = REBOOT_AFTER_HOURS * 60 * 60 * 1_000_000;
+// defmtのログをホストが読み出すのを待つ時間
+const REBOOT_FLUSH_MS: u32 = 100;
+
+// 起動してからREBOOT_AFTER_HOURS時間が経ったか
+pub fn is_due(now_us: u64) -> bool {
+    now_us >= REBOOT_AFTER_US
+}
+
+// 大事な処理の途中でなければtrue。ds18b20_convertingはメインループが持っている変換中かどうか。
+pub fn can_reboot(ds18b20_converting: bool) -> bool {
+    !ds18b20_converting && !free(pulse_train::is_busy)
+}
+
+// ログを出して送信中のものを送り終えてから、リセットする
+pub fn reboot(tx: &mut StatusTx, system_clock_hz: u32) -> ! {
+    defmt::warn!(
+        "periodic reboot: {=u64} hours of uptime reached, resetting",
+        REBOOT_AFTER_HOURS
+    );
+    tx.write_line_blocking(format_args!(
+        "periodic reboot after {} hours",
+        REBOOT_AFTER_HOURS
+    ));
+    tx.flush();
+    cortex_m::asm::delay(system_clock_hz / 1000 * REBOOT_FLUSH_MS);
+    cortex_m::peripheral::SCB::sys_reset()
+}
@@ -0,0 +1,141 @@
+// トグルボタンで手で点灯/消灯させたパターンを記録して、くり返し再生する
+//
+// start_record()からstop_record()までの間、トグルボタン（GPIO14）でLEDを点灯/消灯させるたびに、
+// その前の状態（点灯か消灯か）と続いた時間を1ステップとして記録する。
+// stop_record()で最後の状態の時間を記録して終わる。
+// play_recorded()で記録したステップをTIMER_IRQ_0で順に表示し、最後まで行ったら最初からくり返す。
+// 再生はburstと同じく、モードの表示より優先する（burstの点滅中はburstが先）。
+// 再生はモードが変わる（トグルボタンやUARTでモードを変える）か、start_record()で止まる。
+//
+// 記録できる量と時間
+//   ・RECORD_CAPACITYステップまで。いっぱいになったらその時点で記録を止める（それまでの分は残る）
+//   ・1ステップの最短はトグルボタンのチャタリング除去の時間（TOGGLE_DEBOUNCE_MS）。
+//     それより短い間隔の押下はtoggle_buttonが受け付けないので記録もされない
+//   ・時間はms単位で記録し、最長はALARMでスケジュールできるMAX_ALARM_INTERVAL_MSまで。
+//     それより長い間はMAX_ALARM_INTERVAL_MSに切り詰める
+//   ・再生の時間はALARM0の周期そのものなので、プリスケーラや温度による間引きは掛からない
+
+use crate::blink_count;
+use crate::cs_trace::free;
+use crate::mode::{self, LedMode};
+use core::cell::{Cell, RefCell};
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::decimal_blink::Step;
+use rp2040_project_template::time;
+use rp_pico::hal::timer::Timer;
+
+pub const RECORD_CAPACITY: usize = 32;
+
+struct Recording {
+    steps: [Step; RECORD_CAPACITY],
+    len: usize,
+    // 記録中なら、今の状態と、その状態になった時刻（µs）
+    current: Option<(bool, u64)>,
+    // 再生中なら、次に表示するステップ
+    playing: Option<usize>,
+}
+
+static RECORDING: Mutex<RefCell<Recording>> = Mutex::new(RefCell::new(Recording {
+    steps: [Step {
+        on: false,
+        duration_ms: 0,
+    }; RECORD_CAPACITY],
+    len: 0,
+    current: None,
+    playing: None,
+}));
+static RECORDER_TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+
+pub fn init(cs: &CriticalSection, timer: Timer) {
+    RECORDER_TIMER.borrow(cs).set(Some(timer));
+}
+
+fn now_us(cs: &CriticalSection) -> Option<u64> {
+    RECORDER_TIMER
+        .borrow(cs)
+        .get()
+        .map(|timer| timer.get_counter().ticks())
+}
+
+impl Recording {
+    // 今の状態の時間を1ステップとして記録し、on（新しい状態）の記録を始める。
+    // いっぱいなら記録を止めてfalseを返す。
+    fn push(&mut self, now: u64, on: bool) -> bool {
+        let Some((was_on, since)) = self.current else {
+            return false;
+        };
+        if self.len == RECORD_CAPACITY {
+            self.current = None;
+            defmt::warn!("recorder: buffer full, recording stopped");
+            return false;
+        }
+        let duration_ms = time::elapsed_us(now, since) / 1000;
+        self.steps[self.len] = Step {
+            on: was_on,
+            // 0 msではALARMをスケジュールできないので、最短でも1 msにする
+            duration_ms: duration_ms.clamp(1, u64::from(crate::MAX_ALARM_INTERVAL_MS)) as u32,
+        };
+        self.len += 1;
+        self.current = Some((on, now));
+        true
+    }
+}
+
+// 記録を始める。前の記録と再生は消す。
+pub fn start_record() {
+    free(|cs| {
+        let Some(now) = now_us(cs) else {
+            return;
+        };
+        let mut recording = RECORDING.borrow(cs).borrow_mut();
+        recording.len = 0;
+        recording.playing = None;
+        recording.current = Some((mode::mode(cs) != LedMode::Off, now));
+    });
+}
+
+// 記録を終える。記録したステップ数を返す。
+pub fn stop_record() -> usize {
+    free(|cs| {
+        let mut recording = RECORDING.borrow(cs).borrow_mut();
+        if let (Some(now), Some((on, _))) = (now_us(cs), recording.current) {
+            recording.push(now, on);
+        }
+        recording.current = None;
+        recording.len
+    })
+}
+
+// 記録したパターンの再生を始める。記録中なら記録を終えてから再生する。
+// 記録が空ならfalse。
+pub fn play_recorded() -> bool {
+    let len = stop_record();
+    free(|cs| {
+        if len == 0 {
+            return false;
+        }
+        RECORDING.borrow(cs).borrow_mut().playing = Some(0);
+        // blink_times()で止まっていても再生する
+        blink_count::resume(cs);
+        true
+    })
+}
+
+// トグルボタンでLEDを切り替えたときに呼ぶ。onは切り替えた後の状態。
+pub fn on_toggle(cs: &CriticalSection, now: u64, on: bool) {
+    RECORDING.borrow(cs).borrow_mut().push(now, on);
+}
+
+// モードが変わったときにmode::set_mode()から呼ぶ
+pub fn on_mode_changed(cs: &CriticalSection) {
+    RECORDING.borrow(cs).borrow_mut().playing = None;
+}
+
+// TIMER_IRQ_0から呼ぶ。再生中なら次のステップを返す。
+pub fn next_step(cs: &CriticalSection) -> Option<Step> {
+    let mut recording = RECORDING.borrow(cs).borrow_mut();
+    let index = recording.playing?;
+    let step = recording.steps[index];
+    recording.playing = Some((index + 1) % recording.len);
+    Some(step)
+}
@@ -0,0 +1,149 @@
+// I2Cのスレーブ（ペリフェラル）として外に見せるレジスタの表
+//
+// 外のマスターから「アドレスaddrのレジスタを読む/書く」と言われたときの処理を、ISRのmatchに
+// 直接書くのではなく、REGISTERSの表の1行として書く。見せる値を増やすときは表に1行足すだけでよい。
+// handle_read(addr)/handle_write(addr, value)は表を上から探して、その行の処理を呼ぶ。
+//
+// 表の書き方（Registerの1行）
+//   addr  : レジスタのアドレス（0x00〜0xFF）。表の中で同じアドレスが2回出てくるとビルドが通らない
+//   name  : UARTのregコマンドで出す名前
+//   read  : 読まれたときに返す値を作る関数。クリティカルセクションの中で呼ぶので、Cellを読む程度の短い処理にする
+//   write : 書かれたときの処理。Noneなら読み出し専用で、書いても何もしない
+//   表にないアドレスを読むと0xFFを返し、書いても無視する（読み出し専用に書いたときと同じ）。
+//   どちらもエラーにはしない。I2Cのスレーブは読まれたら何かを返さなければならず、
+//   書き込みもACKを返した後にしか中身がわからないため。
+//   多バイトの値（割り込みカウンタ）は1バイトずつ別のアドレスにしているので、続けて読む間に
+//   値が進むと、上位と下位が別の瞬間の値になることがある。
+//
+// I2Cのスレーブそのもの（I2C1のペリフェラルモード）はまだない。使えるGPIOがすべて埋まっていて
+// （pin_table.rs）、I2C1に割り当てられるピンの組が残っていないため。
+// それまではUARTのreg ADDR [VALUE]で、同じ表を通して読み書きできる。
+// ピンが空いてスレーブを足すときは、受け取ったアドレスと値をそのままhandle_read()/handle_write()に渡せばよい。
+
+use crate::cs_trace::free;
+use crate::{counter, fade, heartbeat, idle_off, interval, led, thermal};
+use cortex_m::interrupt::CriticalSection;
+
+// 0x00で返す値（"P"）。マスターが正しいデバイスにつないだかを確かめるのに使う
+pub const REGISTER_MAP_ID: u8 = 0x50;
+// 表にないアドレスを読んだときの値
+pub const UNKNOWN_REGISTER: u8 = 0xFF;
+// 0x01の点滅間隔の単位
+pub const INTERVAL_UNIT_MS: u32 = 10;
+
+pub struct Register {
+    pub addr: u8,
+    pub name: &'static str,
+    pub read: fn(&CriticalSection) -> u8,
+    pub write: Option<fn(&CriticalSection, u8)>,
+}
+
+impl Register {
+    pub fn is_read_only(&self) -> bool {
+        self.write.is_none()
+    }
+}
+
+pub const REGISTERS: &[Register] = &[
+    Register {
+        addr: 0x00,
+        name: "id",
+        read: |_| REGISTER_MAP_ID,
+        write: None,
+    },
+    // 点滅の間隔（INTERVAL_UNIT_MS単位）。0を書いても無視する
+    Register {
+        addr: 0x01,
+        name: "interval",
+        read: |cs| (interval::interval_ms(cs) / INTERVAL_UNIT_MS).min(255) as u8,
+        write: Some(|cs, value| {
+            if value > 0 {
+                interval::set_interval_ms(cs, u32::from(value) * INTERVAL_UNIT_MS);
+            }
+        }),
+    },
+    // 全体の明るさの上位8bit。書いた値は下位8bitにも同じ値を入れる（0xFFで最大）
+    // UARTのbrightness Nと同じく、フェードの途中なら取り消す（次のALARM2で書いた値が上書きされないように）
+    Register {
+        addr: 0x02,
+        name: "brightness",
+        read: |cs| (led::brightness(cs) >> 8) as u8,
+        write: Some(|cs, value| {
+            fade::cancel(cs);
+            led::set_brightness(cs, u16::from_le_bytes([value, value]));
+        }),
+    },
+    // 割り込みカウンタ（ALARM0）の下位から1バイトずつ
+    Register {
+        addr: 0x03,
+        name: "count0",
+        read: |cs| counter::count(cs).to_le_bytes()[0],
+        write: None,
+    },
+    Register {
+        addr: 0x04,
+        name: "count1",
+        read: |cs| counter::count(cs).to_le_bytes()[1],
+        write: None,
+    },
+    // bit0: LEDが点いている、bit1: 温度で明るさを下げている、bit2: 外付けのWDへのハートビートを出している、
+    // bit3: 操作がなくて消灯している
+    Register {
+        addr: 0x05,
+        name: "status",
+        read: |cs| {
+            u8::from(led::is_lit(led::duty(cs)))
+                | u8::from(thermal::is_throttled(cs)) << 1
+                | u8::from(heartbeat::is_healthy(cs)) << 2
+                | u8::from(idle_off::is_idle(cs)) << 3
+        },
+        write: None,
+    },
+];
+
+// 表の中で同じアドレスが2回出てこないか
+const fn addresses_unique(table: &[Register]) -> bool {
+    let mut i = 0;
+    while i < table.len() {
+        let mut j = i + 1;
+        while j < table.len() {
+            if table[i].addr == table[j].addr {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+const _: () = assert!(
+    addresses_unique(REGISTERS),
+    "a register address appears twice in REGISTERS"
+);
+
+pub fn find(addr: u8) -> Option<&'static Register> {
+    REGISTERS.iter().find(|r| r.addr == addr)
+}
+
+// addrのレジスタを読む。表になければUNKNOWN_REGISTER。
+pub fn handle_read(cs: &CriticalSection, addr: u8) -> u8 {
+    find(addr).map_or(UNKNOWN_REGISTER, |r| (r.read)(cs))
+}
+
+// addrのレジスタにvalueを書く。表にないか読み出し専用なら何もしない。
+pub fn handle_write(cs: &CriticalSection, addr: u8, value: u8) {
+    match find(addr).and_then(|r| r.write) {
+        Some(write) => write(cs, value),
+        None => defmt::debug!("register {=u8:#04x} is not writable, ignored", addr),
+    }
+}
+
+// UARTのregコマンド用
+pub fn read(addr: u8) -> u8 {
+    free(|cs| handle_read(cs, addr))
+}
+
+pub fn write(addr: u8, value: u8) {
+    free(|cs| handle_write(cs, addr, value));
+}
@@ -0,0 +1,87 @@
+// 起動したときに、なぜリセットされたのかをハードウェアのフラグから調べる
+//
+// 読むレジスタ（どちらも読むだけで、フラグは消さない）
+//   WATCHDOG.REASON（0x40058008）
+//     bit0 TIMER : ウォッチドッグのカウントが0になってリセットされた
+//     bit1 FORCE : ソフトウェアがWATCHDOG.CTRLのTRIGGERでリセットした
+//   VREG_AND_CHIP_RESET.CHIP_RESET（0x4006400C）
+//     bit8  HAD_POR         : 電源を入れた（パワーオンリセット）か、電圧が下がった（ブラウンアウト）
+//     bit16 HAD_RUN         : RUNピンをLowにしてリセットした（リセットボタンなど）
+//     bit20 HAD_PSM_RESTART : デバッガがSWDのRESCUE DPからリセットした
+//   ウォッチドッグのリセットはCHIP_RESETを書き換えないので、REASONを先に見る。
+//   どれもチップ全体のリセットで消えて、そのリセットの原因だけが立つ。
+//
+// 区別できない場合
+//   ・SCBのsys_reset()（AIRCRのSYSRESETREQ、periodic-rebootフィーチャーのリセット）やデバッガからのコアのリセットは
+//     どのフラグも立てないので、CHIP_RESETには前の電源投入やRUNピンのフラグが残っている。
+//     そのため「電源投入」と「そのあとのソフトウェアのリセット」は区別できず、PowerOnOrSoftとして扱う。
+//   ・パワーオンリセットとブラウンアウトは同じHAD_PORなので区別できない。
+//   ・フラグが1つも立っていない（通常はない）ときはUnknownにする。
+//   判断に使ったレジスタの値もログに出すので、必要ならそれで確かめること。
+
+use defmt::Format;
+use rp_pico::hal::pac;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum ResetCause {
+    // 電源投入かブラウンアウト、またはそのあとのソフトウェアのリセット（上の説明を参照）
+    PowerOnOrSoft,
+    // RUNピン
+    RunPin,
+    // デバッガ（RESCUE DP）
+    Debugger,
+    // ウォッチドッグのタイムアウト
+    WatchdogTimeout,
+    // ウォッチドッグを使ったソフトウェアのリセット
+    WatchdogForce,
+    Unknown,
+}
+
+impl ResetCause {
+    // UARTなどに出力するときの説明
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResetCause::PowerOnOrSoft => "power-on (or software reset since)",
+            ResetCause::RunPin => "run pin",
+            ResetCause::Debugger => "debugger",
+            ResetCause::WatchdogTimeout => "watchdog timeout",
+            ResetCause::WatchdogForce => "watchdog forced",
+            ResetCause::Unknown => "unknown",
+        }
+    }
+}
+
+// (原因, WATCHDOG.REASONの値, CHIP_RESETの値)
+pub fn read() -> (ResetCause, u32, u32) {
+    // 読み出すだけなので、ほかのペリフェラルの状態には影響しない
+    let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+    let chip = unsafe { &*pac::VREG_AND_CHIP_RESET::ptr() };
+    let reason = watchdog.reason().read();
+    let chip_reset = chip.chip_reset().read();
+    let cause = if reason.timer().bit_is_set() {
+        ResetCause::WatchdogTimeout
+    } else if reason.force().bit_is_set() {
+        ResetCause::WatchdogForce
+    } else if chip_reset.had_psm_restart().bit_is_set() {
+        ResetCause::Debugger
+    } else if chip_reset.had_run().bit_is_set() {
+        ResetCause::RunPin
+    } else if chip_reset.had_por().bit_is_set() {
+        ResetCause::PowerOnOrSoft
+    } else {
+        ResetCause::Unknown
+    };
+    (cause, reason.bits(), chip_reset.bits())
+}
+
+// 起動時に呼ぶ。原因とレジスタの値をログに出す。
+// フラグは次のリセットまで変わらないので、あとからread()で読んでも同じ値になる。
+pub fn log_reset_cause() {
+    let (cause, reason, chip_reset) = read();
+    defmt::info!(
+        "reset cause: {} (WATCHDOG.REASON={=u32:#x} CHIP_RESET={=u32:#x})",
+        cause,
+        reason,
+        chip_reset
+    );
+}
@@ -0,0 +1,305 @@
+// リセットの記録をフラッシュに残す（reset-logフィーチャー）
+//
+// 現場で何度もリセットしている個体が、いつ、なぜリセットしたのかを電源を切った後でも追えるように、
+// 起動するたびに「今回のリセットの原因」と「前回起動してからリセットまでの時間」をフラッシュに1件足す。
+// UARTのresetlogで古い順に出す。
+//
+// 置き場所
+//   設定のセクタ（config.rsのCONFIG_FLASH_OFFSET）のすぐ前の4 KB（RESET_LOG_FLASH_OFFSET）。
+//   memory.xでプログラムの領域から外しているので、フィーチャーを有効にしてもしなくても配置は変わらない。
+//
+// 1件の形式（RESET_LOG_ENTRY_LEN = 16バイト、リトルエンディアン）
+//   0..4   : 通し番号（起動するたびに1ずつ増える）
+//   4      : リセットの原因（cause_code()）
+//   5..8   : 0（予備）
+//   8..12  : 前回起動してからリセットまでの秒数。わからないときは0xFFFFFFFF
+//   12..16 : 0..12のCRC-32（crc.rs）
+//   消去したままの16バイト（すべて0xFF）は空き。CRCが合わないもの（書いている途中で電源が切れたもの）は
+//   使用済みとして飛ばし、出力にも出さない。
+//
+// 前回の時間
+//   メインループがWATCHDOGのSCRATCH0/1に起動してからの秒数を書き続ける（note_uptime()）。
+//   SCRATCHはウォッチドッグやsys_reset()のリセットでは消えないので、次に起動したときに読める。
+//   電源を切ったときとRUNピンのリセットでは消えるので、わからない（0xFFFFFFFF）になる。
+//   SCRATCH4〜7はブートROMが使うので使わない。
+//
+// 追記と一周
+//   件数はセクタにRESET_LOG_ENTRIES（256）件まで。空きの先頭に追記する。
+//   いっぱいになったら、新しい方からRESET_LOG_KEEP_ON_WRAP（128）件をRAMに写してセクタを消去し、
+//   それを書き戻してから追記する。消去と書き戻しの間に電源が切れると、記録はすべてなくなる。
+//
+// フラッシュの書き換え回数
+//   フラッシュのセクタは消去できる回数に限りがある（W25Q16JVで10万回ほど）。
+//   消去するのは一周したとき（128回の起動に1回）だけで、追記はページを書くだけなので消去は要らない。
+//   1日に1回リセットしても10万回に届くのは3万年以上先になる。
+//   起動してすぐのリセットをくり返す不具合でも、128回に1回しか消去しない。
+//
+// 書き込むときの制約
+//   フラッシュの消去と書き込みの間はXIP（フラッシュをメモリとして読むこと）ができないので、
+//   その間に動くコードはすべてRAMに置く（write_flash()は.data.ram_funcに置き、起動時にRAMに写される）。
+//   ブートROMの関数は先にアドレスを引いておき、RAMの関数からはそのポインタだけを呼ぶ。
+//   割り込みのハンドラやベクタテーブルはフラッシュにあるので、free()で割り込みを止めてから書く。
+//   消去には数十ms、1ページの書き込みには1 msほどかかるので、起動時（割り込みを有効にする前）にだけ書く。
+//   書き終えたら、フラッシュの先頭のboot2（256バイト）をRAMに写したものを呼んで速いXIPの設定に戻す。
+//   コア1は使っていないので、コア1を止める手順は入れていない。
+
+use crate::config::CONFIG_FLASH_OFFSET;
+use crate::cs_trace::free;
+use crate::reset_cause::{self, ResetCause};
+use crate::status_tx::StatusTx;
+use rp2040_project_template::crc::crc32;
+use rp_pico::hal::{pac, rom_data};
+
+pub const RESET_LOG_SECTOR_LEN: usize = 4096;
+pub const RESET_LOG_FLASH_OFFSET: u32 = CONFIG_FLASH_OFFSET - RESET_LOG_SECTOR_LEN as u32;
+pub const RESET_LOG_ENTRY_LEN: usize = 16;
+pub const RESET_LOG_ENTRIES: usize = RESET_LOG_SECTOR_LEN / RESET_LOG_ENTRY_LEN;
+pub const RESET_LOG_KEEP_ON_WRAP: usize = RESET_LOG_ENTRIES / 2;
+// 前回の時間がわからないとき
+pub const UPTIME_UNKNOWN: u32 = u32::MAX;
+
+// flash_range_program()で一度に書ける単位
+const PAGE_LEN: usize = 256;
+const ENTRIES_PER_PAGE: usize = PAGE_LEN / RESET_LOG_ENTRY_LEN;
+// pico-sdkと同じ、64 KBのブロック消去のコマンド（セクタの中だけならセクタ消去になる）
+const BLOCK_SIZE: u32 = 1 << 16;
+const BLOCK_ERASE_CMD: u8 = 0xD8;
+const XIP_BASE: u32 = 0x1000_0000;
+// SCRATCH0に書いておく印（"RLOG"）。SCRATCH1の秒数が今回のファームウェアが書いたものかを見分ける
+const UPTIME_MAGIC: u32 = 0x474F_4C52;
+
+const _: () = assert!(RESET_LOG_SECTOR_LEN.is_multiple_of(PAGE_LEN));
+const _: () = assert!((RESET_LOG_KEEP_ON_WRAP * RESET_LOG_ENTRY_LEN).is_multiple_of(PAGE_LEN));
+
+// 記録に残すリセットの原因の番号。記録に残るので、並べ替えずに後ろに足すだけにすること。
+fn cause_code(cause: ResetCause) -> u8 {
+    match cause {
+        ResetCause::PowerOnOrSoft => 0,
+        ResetCause::RunPin => 1,
+        ResetCause::Debugger => 2,
+        ResetCause::WatchdogTimeout => 3,
+        ResetCause::WatchdogForce => 4,
+        ResetCause::Unknown => 0xFF,
+    }
+}
+
+fn cause_from_code(code: u8) -> ResetCause {
+    match code {
+        0 => ResetCause::PowerOnOrSoft,
+        1 => ResetCause::RunPin,
+        2 => ResetCause::Debugger,
+        3 => ResetCause::WatchdogTimeout,
+        4 => ResetCause::WatchdogForce,
+        _ => ResetCause::Unknown,
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Entry {
+    pub seq: u32,
+    pub cause: ResetCause,
+    pub uptime_s: u32,
+}
+
+impl Entry {
+    fn encode(&self) -> [u8; RESET_LOG_ENTRY_LEN] {
+        let mut bytes = [0; RESET_LOG_ENTRY_LEN];
+        bytes[0..4].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[4] = cause_code(self.cause);
+        bytes[8..12].copy_from_slice(&self.uptime_s.to_le_bytes());
+        let crc = crc32(&bytes[..12]);
+        bytes[12..16].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let word =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        (crc32(&bytes[..12]) == word(12)).then(|| Entry {
+            seq: word(0),
+            cause: cause_from_code(bytes[4]),
+            uptime_s: word(8),
+        })
+    }
+}
+
+// セクタを読む（XIPのまま読める）
+fn sector() -> &'static [u8] {
+    unsafe {
+        core::slice::from_raw_parts(
+            (XIP_BASE + RESET_LOG_FLASH_OFFSET) as *const u8,
+            RESET_LOG_SECTOR_LEN,
+        )
+    }
+}
+
+fn slot(index: usize) -> &'static [u8] {
+    &sector()[index * RESET_LOG_ENTRY_LEN..][..RESET_LOG_ENTRY_LEN]
+}
+
+fn is_erased(index: usize) -> bool {
+    slot(index).iter().all(|&b| b == 0xFF)
+}
+
+// 空きの先頭（いっぱいならRESET_LOG_ENTRIES）。追記は先頭から順なので、空きの後ろに使用済みはない。
+fn used() -> usize {
+    (0..RESET_LOG_ENTRIES)
+        .find(|&i| is_erased(i))
+        .unwrap_or(RESET_LOG_ENTRIES)
+}
+
+// 記録を古い順に返す
+pub fn entries() -> impl Iterator<Item = Entry> {
+    (0..used()).filter_map(|i| Entry::decode(slot(i)))
+}
+
+// ブートROMのフラッシュの関数
+struct Rom {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+}
+
+// offsetから、erase_lenバイトを消去（0なら消去しない）してからdataのlenバイトを書く（0なら書かない）。
+// XIPを止めている間に呼ぶものはすべてRAMか、ブートROMにある。
+// ここではフラッシュにある関数（スライスのメソッドなども）を呼ばないこと。
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn write_flash(
+    rom: &Rom,
+    boot2: *const u32,
+    offset: u32,
+    erase_len: usize,
+    data: *const u8,
+    len: usize,
+) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    if erase_len > 0 {
+        (rom.flash_range_erase)(offset, erase_len, BLOCK_SIZE, BLOCK_ERASE_CMD);
+    }
+    if len > 0 {
+        (rom.flash_range_program)(offset, data, len);
+    }
+    (rom.flash_flush_cache)();
+    // ブートROMのflash_enter_cmd_xip()は遅い設定のXIPに戻すので、boot2で起動時と同じ速い設定に戻す。
+    // boot2はThumbのコードなので、アドレスの最下位bitを立てて呼ぶ
+    let boot2: unsafe extern "C" fn() = core::mem::transmute(boot2 as usize | 1);
+    boot2();
+}
+
+// セクタの中のoffsetから消去/書き込みを行う。割り込みを止めて呼ぶ。
+fn program(offset: usize, erase: bool, data: &[u8]) {
+    // boot2をRAMに写す。最後の4バイトはCRC
+    let mut boot2 = [0u32; 64];
+    let boot2_flash = unsafe { core::slice::from_raw_parts(XIP_BASE as *const u32, 64) };
+    boot2.copy_from_slice(boot2_flash);
+    let rom = Rom {
+        connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+        flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+        flash_range_erase: rom_data::flash_range_erase::ptr(),
+        flash_range_program: rom_data::flash_range_program::ptr(),
+        flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+    };
+    free(|_| unsafe {
+        write_flash(
+            &rom,
+            boot2.as_ptr(),
+            RESET_LOG_FLASH_OFFSET + offset as u32,
+            if erase { RESET_LOG_SECTOR_LEN } else { 0 },
+            data.as_ptr(),
+            data.len(),
+        );
+    });
+}
+
+// 前回起動してからリセットまでの秒数。SCRATCHに印がなければUPTIME_UNKNOWN。
+fn previous_uptime_s() -> u32 {
+    let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+    if watchdog.scratch0().read().bits() != UPTIME_MAGIC {
+        return UPTIME_UNKNOWN;
+    }
+    watchdog.scratch1().read().bits()
+}
+
+// メインループから呼ぶ。起動してからの秒数をSCRATCHに書いておく。
+pub fn note_uptime(now_us: u64) {
+    let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+    watchdog
+        .scratch1()
+        .write(|w| unsafe { w.bits((now_us / 1_000_000) as u32) });
+    watchdog
+        .scratch0()
+        .write(|w| unsafe { w.bits(UPTIME_MAGIC) });
+}
+
+// 起動時に1回だけ、割り込みを有効にする前に呼ぶ。今回のリセットの記録を1件足す。
+pub fn record_boot() {
+    let (cause, _, _) = reset_cause::read();
+    let uptime_s = previous_uptime_s();
+    let mut position = used();
+    let seq = entries().last().map_or(0, |e| e.seq.wrapping_add(1));
+
+    if position == RESET_LOG_ENTRIES {
+        // 新しい方の半分を写してから消去し、書き戻す
+        let mut keep = [0u8; RESET_LOG_KEEP_ON_WRAP * RESET_LOG_ENTRY_LEN];
+        let start = (RESET_LOG_ENTRIES - RESET_LOG_KEEP_ON_WRAP) * RESET_LOG_ENTRY_LEN;
+        keep.copy_from_slice(&sector()[start..]);
+        program(0, true, &keep);
+        position = RESET_LOG_KEEP_ON_WRAP;
+        defmt::info!(
+            "reset log: wrapped, kept {=usize} entries",
+            RESET_LOG_KEEP_ON_WRAP
+        );
+    }
+
+    // 追記する件の入っているページを写し、その件だけを書き換えてページごと書く。
+    // すでに書いてある件は同じ値を書くので変わらない（フラッシュの書き込みはbitを0にするだけ）。
+    let page_start = position / ENTRIES_PER_PAGE * PAGE_LEN;
+    let mut page = [0u8; PAGE_LEN];
+    page.copy_from_slice(&sector()[page_start..][..PAGE_LEN]);
+    let in_page = position * RESET_LOG_ENTRY_LEN - page_start;
+    page[in_page..][..RESET_LOG_ENTRY_LEN].copy_from_slice(
+        &Entry {
+            seq,
+            cause,
+            uptime_s,
+        }
+        .encode(),
+    );
+    program(page_start, false, &page);
+    defmt::info!(
+        "reset log: #{=u32} {}, previous uptime {=u32} s",
+        seq,
+        cause,
+        uptime_s
+    );
+}
+
+// UARTのresetlogコマンド用。古い順に1行ずつ出す。
+pub fn dump(tx: &mut StatusTx) {
+    let mut count = 0;
+    for entry in entries() {
+        count += 1;
+        if entry.uptime_s == UPTIME_UNKNOWN {
+            tx.write_line_blocking(format_args!(
+                "reset #{}: {}, previous uptime unknown",
+                entry.seq,
+                entry.cause.name()
+            ));
+        } else {
+            tx.write_line_blocking(format_args!(
+                "reset #{}: {}, previous uptime {} s",
+                entry.seq,
+                entry.cause.name(),
+                entry.uptime_s
+            ));
+        }
+    }
+    tx.write_line_blocking(format_args!(
+        "reset log: {} entries (sector @{:#x})",
+        count, RESET_LOG_FLASH_OFFSET
+    ));
+}
@@ -0,0 +1,37 @@
+// RESETSコントローラを起動後も使えるようにするモジュール
+//
+// RP2040のペリフェラルはRESETSレジスタのビットでブロック単位にリセットできる。
+// ビットを立てるとそのブロックはリセット状態で止まり、下ろして完了を待つと初期状態から動き出す。
+// チップ全体を再起動しなくても、おかしくなったブロックだけを初期化し直せる。
+//
+// 単独でリセットしてよいブロックと、してはいけないブロック
+//   I2C0/I2C1, SPI0/SPI1, PWM, ADC, PIO0/PIO1 : 単独でリセットしてよい。
+//       そのブロックを使っているモジュールで設定し直せば元に戻る。
+//   UART0 : リセットしてよいが、送受信中のデータとコマンドの途中の行は失われる。
+//   DMA   : リセットするとUARTに送っている途中の転送が消え、StatusTxがバッファを取り戻せなくなる。
+//   TIMER : リセットするとカウンタ（起動してからの時間）とALARMの設定が消える。
+//   IO_BANK0, PADS_BANK0 : すべてのピンの機能設定が消え、LEDやUARTも止まる。
+//   PLL_SYS, PLL_USB, SYSCFG, BUSCTRLなど : クロックやバスが止まるのでリセットしてはいけない。
+// 割り込みカウンタなどのRAM上の状態はどのブロックをリセットしても消えない。
+
+use crate::cs_trace::free;
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use rp_pico::hal::pac;
+
+static RESETS: GlobalPeripheral<pac::RESETS> = initial_global_peripheral();
+
+// 起動時の初期化が終わってから、使い終わったRESETSを渡す
+pub fn init(resets: pac::RESETS) {
+    free(|cs| {
+        RESETS.borrow(cs).replace(Some(resets));
+    });
+}
+
+// RESETSを借りて処理を行う。初期化されていない（または使用中の）ときはNoneを返す。
+// i2c_bus::with_bus()と同じく、割り込みを許可したまま処理できるように一旦取り出す。
+pub fn with_resets<R>(f: impl FnOnce(&mut pac::RESETS) -> R) -> Option<R> {
+    let mut resets = free(|cs| RESETS.borrow(cs).take())?;
+    let result = f(&mut resets);
+    free(|cs| RESETS.borrow(cs).replace(Some(resets)));
+    Some(result)
+}
@@ -0,0 +1,152 @@
+// 入力の割合から出力の割合を決める応答曲線（周囲の明るさによる調光、ambient.rs）
+//
+// 入力も出力も0〜u16::MAXの割合（u16::MAXで100%）。どの曲線も単調増加（入力が増えて出力が減ることはない）で、
+// 0は0に、u16::MAXはu16::MAXに写す。調光では入力が「暗い〜明るい」の間のどこにいるか、
+// 出力が「最も暗い倍率〜最も明るい倍率」の間のどこにするかになる。
+//
+// 曲線
+//   Linear     : 入力に比例する。センサーの読み値の変化がそのまま明るさの変化になる
+//   Quadratic  : 入力の2乗に比例する。暗いところでは変化がゆるやかで、明るくなるほど急に明るくなる
+//   Log        : log(1 + 255 × 入力) / log(256)。暗いところで大きく変わり、明るいところではほとんど変わらない。
+//                周囲の明るさは数桁にわたって変わり、人の目はその比（対数）で感じるので、
+//                広い範囲で「周りが明るくなった分だけ明るくなった」と感じやすい
+//   Stepped(n) : 入力をn段に分け、段の中では同じ出力にする。出力は0からu16::MAXまでのn段。
+//                少しの変化では明るさが変わらないので、明るさが揺れて見えるのを嫌うときに使う。
+//                nが2未満のときは2段（消灯か全開か）として扱う
+//
+// Logはf32のlogがno_stdにないので、Q16の固定小数点のlog2（log2_q16()）で計算する。
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResponseCurve {
+    Linear,
+    Quadratic,
+    Log,
+    Stepped(u8),
+}
+
+const FULL: u32 = u16::MAX as u32;
+// Logの曲がり方。入力が100%のとき、logの中が1 + LOG_SPAN = 256になる
+const LOG_SPAN: u32 = 255;
+const ONE_Q16: u32 = 1 << 16;
+const LOG_MAX_Q16: u32 = log2_q16(ONE_Q16 + FULL * LOG_SPAN);
+
+// log2(v / 2^16)を2^16倍した値。vは2^16（1.0）以上。
+pub const fn log2_q16(v: u32) -> u32 {
+    // 整数部。vを[1, 2)に正規化する
+    let int = 31 - (v >> 16).leading_zeros();
+    let mut y = (v >> int) as u64;
+    let mut frac = 0;
+    // 2乗して2以上になったら、その桁の小数部は1
+    let mut bit = 0;
+    while bit < 16 {
+        y = (y * y) >> 16;
+        if y >= 2 << 16 {
+            y >>= 1;
+            frac |= 1 << (15 - bit);
+        }
+        bit += 1;
+    }
+    (int << 16) | frac
+}
+
+impl ResponseCurve {
+    // UARTなどに出力するときの名前（Steppedは段の数を別に出す）
+    pub fn name(self) -> &'static str {
+        match self {
+            ResponseCurve::Linear => "linear",
+            ResponseCurve::Quadratic => "quadratic",
+            ResponseCurve::Log => "log",
+            ResponseCurve::Stepped(_) => "stepped",
+        }
+    }
+
+    // 入力の割合から出力の割合を求める
+    pub fn apply(self, input: u16) -> u16 {
+        let x = u32::from(input);
+        let y = match self {
+            ResponseCurve::Linear => x,
+            ResponseCurve::Quadratic => x * x / FULL,
+            ResponseCurve::Log => {
+                let log = log2_q16(ONE_Q16 + x * LOG_SPAN);
+                (u64::from(log) * u64::from(FULL) / u64::from(LOG_MAX_Q16)) as u32
+            }
+            ResponseCurve::Stepped(steps) => {
+                let steps = u32::from(steps.max(2));
+                // 0〜steps - 1の段。u16::MAXだけが最後の段にならないように、FULL + 1で割る
+                let step = x * steps / (FULL + 1);
+                step * FULL / (steps - 1)
+            }
+        };
+        y.min(FULL) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVES: [ResponseCurve; 7] = [
+        ResponseCurve::Linear,
+        ResponseCurve::Quadratic,
+        ResponseCurve::Log,
+        ResponseCurve::Stepped(0),
+        ResponseCurve::Stepped(2),
+        ResponseCurve::Stepped(5),
+        ResponseCurve::Stepped(255),
+    ];
+
+    #[test]
+    fn every_curve_maps_the_endpoints_to_themselves() {
+        for curve in CURVES {
+            assert_eq!(curve.apply(0), 0, "{curve:?}");
+            assert_eq!(curve.apply(u16::MAX), u16::MAX, "{curve:?}");
+        }
+    }
+
+    #[test]
+    fn every_curve_is_monotonic() {
+        for curve in CURVES {
+            let mut last = 0;
+            for input in 0..=u16::MAX {
+                let output = curve.apply(input);
+                assert!(output >= last, "{curve:?} decreases at {input}");
+                last = output;
+            }
+        }
+    }
+
+    #[test]
+    fn log_rises_fastest_in_the_dark_and_quadratic_slowest() {
+        let quarter = u16::MAX / 4;
+        let linear = ResponseCurve::Linear.apply(quarter);
+        assert!(ResponseCurve::Log.apply(quarter) > linear);
+        assert!(ResponseCurve::Quadratic.apply(quarter) < linear);
+        // log2(1 + 255 / 4) / 8 ≒ 0.753
+        let log = ResponseCurve::Log.apply(quarter) as f64 / u16::MAX as f64;
+        assert!((log - (1.0 + 255.0 / 4.0f64).log2() / 8.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn stepped_has_exactly_n_levels() {
+        for steps in [2u8, 3, 5, 16] {
+            let mut levels: Vec<u16> = (0..=u16::MAX)
+                .map(|x| ResponseCurve::Stepped(steps).apply(x))
+                .collect();
+            levels.dedup();
+            assert_eq!(levels.len(), usize::from(steps), "{steps} steps");
+        }
+        // 2未満は2段
+        assert_eq!(ResponseCurve::Stepped(1).apply(u16::MAX / 2), 0);
+        assert_eq!(ResponseCurve::Stepped(1).apply(u16::MAX / 2 + 1), u16::MAX);
+    }
+
+    #[test]
+    fn log2_matches_known_values() {
+        assert_eq!(log2_q16(ONE_Q16), 0);
+        assert_eq!(log2_q16(2 * ONE_Q16), 1 << 16);
+        assert_eq!(log2_q16(256 * ONE_Q16), 8 << 16);
+        // log2(3) = 1.58496...
+        let log3 = log2_q16(3 * ONE_Q16) as f64 / 65536.0;
+        assert!((log3 - 3f64.log2()).abs() < 1.0 / 65536.0 * 2.0);
+    }
+}
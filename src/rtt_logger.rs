@@ -0,0 +1,122 @@
+// defmtのログをRTTのアップチャンネル0に書くロガー
+//
+// rtt-targetにもdefmtのロガー（defmtフィーチャー）はあるが、書ききれなかったことを呼び出し側に返さないので、
+// 捨てたログの数を数えるためにここで書いている。中身はrtt-targetのロガーとほぼ同じで、
+// acquire()で割り込みを止め、defmtのエンコーダーが出すバイト列をチャンネルに書き、release()で元に戻す。
+//
+// バッファがいっぱいのときの動作（ChannelMode）
+//   BlockIfFull  : ホストが読むまで待つ。プローブをつないでいないと永久に待つので、
+//                  割り込みの中のログで点滅もメインループも止まってしまう。
+//   NoBlockTrim  : 入る分だけ書く。途中で切れたフレームは、ホストで別のログとしてデコードされることがある。
+//   NoBlockSkip  : 入らない書き込みは丸ごと捨てる。
+//   ログのせいで点滅のタイミングが変わってはいけないので、NoBlockSkipで開く（init()）。
+//   1つのログ（フレーム）はエンコーダーから何回かに分けて書かれるので、その途中で1回でも入らなければ、
+//   そのフレームの残りは書かずに捨てたものとして数える。すでに書いた前半は残るので、
+//   ホストのデコーダーはそのフレームと、区切りのない続きの次のフレームを読めずに飛ばすことがある。
+//
+// 捨てた数はdropped()で読める（diagコマンド）。report_dropped()をメインループから定期的に呼ぶと、
+// 前回知らせてから増えていれば警告を出す。その警告自体も入らなければ、入るようになる（ホストが読み出して
+// バッファが空く）まで次の呼び出しで出し直す。
+
+use crate::cs_trace::free;
+use core::cell::{Cell, RefCell};
+use cortex_m::interrupt::{self, CriticalSection, Mutex};
+use rtt_target::{ChannelMode, UpChannel};
+
+static CHANNEL: Mutex<RefCell<Option<UpChannel>>> = Mutex::new(RefCell::new(None));
+static ENCODER: Mutex<RefCell<defmt::Encoder>> = Mutex::new(RefCell::new(defmt::Encoder::new()));
+// acquire()からrelease()の間ならtrue（ログの中でログを出していないかの確認）
+static TAKEN: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+// acquire()の前に割り込みが許可されていたか
+static RESTORE_INTERRUPTS: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+// 書いている途中のフレームが入りきらなかった
+static FRAME_DROPPED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+// 入りきらずに捨てたログの数（起動してからの累計）と、最後に警告を出したときの数
+static DROPPED: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+static REPORTED: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+// binary_log::init_rtt()から、defmtに使うチャンネルを渡す
+pub fn init(mut channel: UpChannel) {
+    channel.set_mode(ChannelMode::NoBlockSkip);
+    free(|cs| CHANNEL.borrow(cs).replace(Some(channel)));
+}
+
+pub fn dropped(cs: &CriticalSection) -> u32 {
+    DROPPED.borrow(cs).get()
+}
+
+// メインループから定期的に呼ぶ。前回知らせてから捨てたログがあれば警告を出す。
+pub fn report_dropped() {
+    let dropped = free(dropped);
+    let reported = free(|cs| REPORTED.borrow(cs).get());
+    if dropped == reported {
+        return;
+    }
+    defmt::warn!(
+        "defmt: {=u32} log messages dropped (RTT buffer full)",
+        dropped.wrapping_sub(reported)
+    );
+    // 警告も捨てられていれば、次の呼び出しで出し直す
+    if free(self::dropped) == dropped {
+        free(|cs| REPORTED.borrow(cs).set(dropped));
+    }
+}
+
+fn do_write(cs: &CriticalSection, bytes: &[u8]) {
+    let frame_dropped = FRAME_DROPPED.borrow(cs);
+    if frame_dropped.get() {
+        return;
+    }
+    if let Some(channel) = CHANNEL.borrow(cs).borrow_mut().as_mut() {
+        if channel.write(bytes) < bytes.len() {
+            frame_dropped.set(true);
+        }
+    }
+}
+
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {
+        let enabled = cortex_m::register::primask::read().is_active();
+        interrupt::disable();
+        // 割り込みを止めたので、release()までクリティカルセクションの中にいる
+        let cs = unsafe { CriticalSection::new() };
+        if TAKEN.borrow(&cs).replace(true) {
+            panic!("defmt logger taken reentrantly");
+        }
+        RESTORE_INTERRUPTS.borrow(&cs).set(enabled);
+        FRAME_DROPPED.borrow(&cs).set(false);
+        ENCODER
+            .borrow(&cs)
+            .borrow_mut()
+            .start_frame(|bytes| do_write(&cs, bytes));
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        let cs = CriticalSection::new();
+        ENCODER
+            .borrow(&cs)
+            .borrow_mut()
+            .end_frame(|bytes| do_write(&cs, bytes));
+        if FRAME_DROPPED.borrow(&cs).get() {
+            let dropped = DROPPED.borrow(&cs);
+            dropped.set(dropped.get().wrapping_add(1));
+        }
+        TAKEN.borrow(&cs).set(false);
+        if RESTORE_INTERRUPTS.borrow(&cs).get() {
+            interrupt::enable();
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        let cs = CriticalSection::new();
+        ENCODER
+            .borrow(&cs)
+            .borrow_mut()
+            .write(bytes, |bytes| do_write(&cs, bytes));
+    }
+}
@@ -0,0 +1,89 @@
+// ALARM3で回す速い周期の処理
+//
+// ALARM3をSAMPLE_PERIOD_MSごとに発火させ、TIMER_IRQ_3で次のことを行う。
+//   ・タクトスイッチ（GPIO15）のサンプリングとチャタリング除去（button::sample()）
+//   ・出力の許可の入力（GPIO3）のサンプリングと、禁止になったときに出力を止める（output_enable::sample()）
+//   ・モードを選ぶ2bitのセレクタ（GPIO18, 19）の読み取り（selector::sample()）
+//   ・マンチェスター符号の受信で、フレームの終わり（無信号）の検出（manchester::check_idle()）
+//   ・商用電源のゼロクロスがなくなったことの検出（mains_sync::check_lost()）
+//   ・速いパルスの回数をまとめて周波数にする（edge_counter::latch()）
+//   ・決まった間隔でPIO1のパルスを出す（pulse_train::tick()）
+//   ・ソフトウェアの時計を進める（soft_rtc::tick()）
+//   ・2色LEDのクロスフェードを進める（bicolor::tick()）
+//   ・しばらく操作がないときに、LEDをゆっくり暗くする／戻す（idle_dim::tick()）
+//   ・HEARTBEAT_TOGGLE_MSごとに、外付けのウォッチドッグ向けのハートビート（heartbeat::tick()）
+// phase_sync::sync_phase()で位相をそろえるときは、ALARM3を基準の時刻に合わせてscheduleし直し（sync_at()）、
+// その回でクロスフェードとパルスの間隔を最初からやり直す。
+// ALARMは4つしかないので、周期の違う処理を1つのALARMでまとめて回している。
+// ここで行う処理は、どれも数µsで終わる短いものだけにすること。
+
+use crate::{
+    bicolor, button, edge_counter, heartbeat, idle_dim, mains_sync, manchester, output_enable,
+    pulse_train, selector, soft_rtc,
+};
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp_pico::hal::timer::{Alarm, Alarm3};
+
+pub const SAMPLE_PERIOD_MS: u32 = 5;
+assert_alarm_interval_ms!(SAMPLE_PERIOD_MS);
+
+// ハートビートは何回に1回か
+const HEARTBEAT_TICKS: u32 = heartbeat::HEARTBEAT_TOGGLE_MS / SAMPLE_PERIOD_MS;
+const _: () =
+    assert!(heartbeat::HEARTBEAT_TOGGLE_MS.is_multiple_of(SAMPLE_PERIOD_MS) && HEARTBEAT_TICKS > 0);
+
+static ALARM3: GlobalPeripheral<Alarm3> = initial_global_peripheral();
+static TICKS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+// 次の回が位相をそろえる基準の時刻ならtrue
+static SYNC_PENDING: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+pub fn init(cs: &CriticalSection, mut alarm: Alarm3) {
+    alarm.enable_interrupt();
+    alarm.clear_interrupt();
+    crate::schedule_alarm_ms(&mut alarm, SAMPLE_PERIOD_MS);
+    ALARM3.borrow(cs).replace(Some(alarm));
+}
+
+// 次の回をreference（タイマーカウンタの時刻）に発火させ、その回でクロスフェードとパルスの間隔を最初に戻す。
+// referenceはSAMPLE_PERIOD_MSより先にしないこと（その間ボタンなどのサンプリングが止まる）。
+pub fn sync_at(cs: &CriticalSection, reference: u64) {
+    if let Some(alarm) = ALARM3.borrow(cs).borrow_mut().as_mut() {
+        alarm.clear_interrupt();
+        crate::schedule_alarm_at(alarm, reference);
+        SYNC_PENDING.borrow(cs).set(true);
+    }
+}
+
+// TIMER_IRQ_3から呼ぶ
+pub fn tick(cs: &CriticalSection) {
+    if let Some(alarm) = ALARM3.borrow(cs).borrow_mut().as_mut() {
+        alarm.clear_interrupt();
+        crate::schedule_alarm_ms(alarm, SAMPLE_PERIOD_MS);
+    }
+
+    button::sample(cs);
+    output_enable::sample(cs);
+    selector::sample(cs);
+    manchester::check_idle(cs);
+    mains_sync::check_lost(cs);
+    edge_counter::latch(cs);
+    if SYNC_PENDING.borrow(cs).replace(false) {
+        bicolor::restart_phase(cs);
+        pulse_train::restart_phase(cs);
+    }
+    pulse_train::tick(cs);
+    soft_rtc::tick(cs);
+    bicolor::tick(cs);
+    idle_dim::tick(cs);
+
+    let ticks = TICKS.borrow(cs);
+    let next = ticks.get() + 1;
+    if next >= HEARTBEAT_TICKS {
+        ticks.set(0);
+        heartbeat::tick(cs);
+    } else {
+        ticks.set(next);
+    }
+}
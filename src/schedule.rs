@@ -0,0 +1,108 @@
+// DS3231の時刻に合わせてモードを切り替える1日のスケジュール
+//
+// SCHEDULEの窓（開始, 終了, モード）を上から順に見て、今の時刻が入っている最初の窓のモードにする。
+// どの窓にも入っていなければSCHEDULE_DEFAULT_MODEにする。
+//
+// 時刻の範囲
+//   ・開始の時刻は含み、終了の時刻は含まない。08:00〜20:00なら08:00:00から19:59:59まで。
+//     秒まで見て判定するので、20:00:00になった時点で窓から外れる
+//   ・開始より終了が早い窓は日付をまたぐ。22:00〜06:00なら22:00:00から翌朝05:59:59まで
+//   ・開始と終了が同じ窓は1日中
+//
+// メインループがSCHEDULE_CHECK_INTERVAL_MSごとに時刻を読み、選ばれるモードが変わったときだけ
+// そのモードに切り替える。ボタンやUARTで変えたモードは、次にスケジュールが切り替わるまでそのまま。
+// 起動時は無効で、UARTのschedule onで有効にする。RTCが読めないときはモードを変えない。
+
+use crate::cs_trace::free;
+use crate::ds3231::DateTime;
+use crate::mode::{self, LedMode};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+
+// 時刻を読んでモードを選び直す間隔
+pub const SCHEDULE_CHECK_INTERVAL_MS: u32 = 1000;
+assert_alarm_interval_ms!(SCHEDULE_CHECK_INTERVAL_MS);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct TimeOfDay {
+    pub hour: u8,
+    pub minute: u8,
+}
+
+impl TimeOfDay {
+    const fn seconds(self) -> u32 {
+        (self.hour as u32 * 60 + self.minute as u32) * 60
+    }
+}
+
+pub struct Window {
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+    pub mode: LedMode,
+}
+
+impl Window {
+    // seconds（0時からの秒数）が窓に入っているか
+    fn contains(&self, seconds: u32) -> bool {
+        let (start, end) = (self.start.seconds(), self.end.seconds());
+        if start < end {
+            (start..end).contains(&seconds)
+        } else {
+            // 日付をまたぐ窓と、1日中の窓（start == end）
+            seconds >= start || seconds < end
+        }
+    }
+}
+
+const fn at(hour: u8, minute: u8) -> TimeOfDay {
+    TimeOfDay { hour, minute }
+}
+
+pub const SCHEDULE: &[Window] = &[Window {
+    start: at(8, 0),
+    end: at(20, 0),
+    mode: LedMode::Blink,
+}];
+
+// どの窓にも入っていないときのモード
+pub const SCHEDULE_DEFAULT_MODE: LedMode = LedMode::Off;
+
+static ENABLED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+// 前回選んだモード。変わったときだけ切り替えるために覚えておく。
+static SCHEDULED: Mutex<Cell<Option<LedMode>>> = Mutex::new(Cell::new(None));
+
+pub fn set_enabled(enabled: bool) {
+    free(|cs| {
+        ENABLED.borrow(cs).set(enabled);
+        // 有効にした直後の確認で、今の時刻のモードに必ず切り替える
+        SCHEDULED.borrow(cs).set(None);
+    });
+}
+
+pub fn is_enabled(cs: &CriticalSection) -> bool {
+    ENABLED.borrow(cs).get()
+}
+
+// 時刻に対応するモード
+fn mode_at(dt: &DateTime) -> LedMode {
+    let seconds = (u32::from(dt.hour) * 60 + u32::from(dt.minute)) * 60 + u32::from(dt.second);
+    SCHEDULE
+        .iter()
+        .find(|w| w.contains(seconds))
+        .map_or(SCHEDULE_DEFAULT_MODE, |w| w.mode)
+}
+
+// メインループからSCHEDULE_CHECK_INTERVAL_MSごとに呼ぶ。モードを切り替えたらそのモードを返す。
+pub fn update(dt: &DateTime) -> Option<LedMode> {
+    free(|cs| {
+        if !is_enabled(cs) {
+            return None;
+        }
+        let next = mode_at(dt);
+        if SCHEDULED.borrow(cs).replace(Some(next)) == Some(next) {
+            return None;
+        }
+        mode::set_mode(cs, next);
+        Some(next)
+    })
+}
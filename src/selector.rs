@@ -0,0 +1,95 @@
+// 2本のGPIOを2bitのセレクタとして読み、LEDのモードを選ぶ
+//
+// ジャンパーでモードを決めておく使い方を想定している。
+//   GPIO18 : bit0
+//   GPIO19 : bit1
+// どちらも内部プルアップで、ジャンパーでGNDにつなぐと1になる（何もつながなければ00）。
+//
+//   bit1 bit0  モード
+//    0    0    Blink（ジャンパーなし。起動時のモードと同じ）
+//    0    1    Solid
+//    1    0    Number（number::blink_number()で最後に設定した数値）
+//    1    1    Off
+//
+// TIMER_IRQ_3がsampler::SAMPLE_PERIOD_MSごとに2本をまとめて読む（sample()）。
+// ジャンパーの抜き差しの途中は接点がばたついたり、2本が同時に変わらずに
+// 01や10の中間の値を一瞬通ったりする。そこで同じ値がSELECTOR_STABLE_MSの間続いてから
+// 初めて採用し、その値が前に採用した値と違うときだけモードを切り替える。
+// 起動後に最初に安定した値もモードに反映する。
+// ボタンやUARTで変えたモードは、次にセレクタを変えるまでそのまま。
+
+use crate::mode::{self, LedMode};
+use crate::pull::{self, Pull};
+use crate::sampler;
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use embedded_hal::digital::InputPin;
+use rp_pico::hal::gpio;
+
+pub const SELECTOR_PULL: Pull = Pull::Up;
+// この時間だけ同じ値が続いたら採用する
+pub const SELECTOR_STABLE_MS: u32 = 50;
+const STABLE_SAMPLES: u32 = SELECTOR_STABLE_MS / sampler::SAMPLE_PERIOD_MS;
+const _: () = assert!(STABLE_SAMPLES > 0);
+
+pub type SelectorPins = (
+    pull::InputPin<gpio::bank0::Gpio18>,
+    pull::InputPin<gpio::bank0::Gpio19>,
+);
+
+// 2bitの値（bit1 << 1 | bit0）をインデックスにしたモードの表
+pub const SELECTOR_MODES: [LedMode; 4] = [
+    LedMode::Blink,
+    LedMode::Solid,
+    LedMode::Number,
+    LedMode::Off,
+];
+
+#[derive(Clone, Copy)]
+struct Reading {
+    // 読んでいる値と、その値が続いたサンプル数
+    value: u8,
+    samples: u32,
+    // 最後に採用した値
+    applied: Option<u8>,
+}
+
+static SELECTOR_PINS: GlobalPeripheral<SelectorPins> = initial_global_peripheral();
+static READING: Mutex<Cell<Reading>> = Mutex::new(Cell::new(Reading {
+    value: 0,
+    samples: 0,
+    applied: None,
+}));
+
+pub fn init(cs: &CriticalSection, pins: SelectorPins) {
+    SELECTOR_PINS.borrow(cs).replace(Some(pins));
+}
+
+// TIMER_IRQ_3からSAMPLE_PERIOD_MSごとに呼ぶ（sampler.rs）
+pub fn sample(cs: &CriticalSection) {
+    let mut pins = SELECTOR_PINS.borrow(cs).borrow_mut();
+    let Some((bit0, bit1)) = pins.as_mut() else {
+        return;
+    };
+    // RP2040のGPIOの読み取りはエラーを返さない（Infallible）
+    let value = (u8::from(bit1.is_low().unwrap()) << 1) | u8::from(bit0.is_low().unwrap());
+
+    let reading = READING.borrow(cs);
+    let mut next = reading.get();
+    if value != next.value {
+        next.value = value;
+        next.samples = 0;
+    }
+    next.samples = next.samples.saturating_add(1);
+    if next.samples >= STABLE_SAMPLES && next.applied != Some(value) {
+        // 起動して最初の読み取りは選んだ操作ではないので、周囲の明るさによる自動の切り替え（ambient.rs）を止めない
+        let mode = SELECTOR_MODES[usize::from(value)];
+        if next.applied.replace(value).is_none() {
+            mode::set_mode_keep_auto(cs, mode);
+        } else {
+            mode::set_mode(cs, mode);
+        }
+    }
+    reading.set(next);
+}
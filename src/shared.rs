@@ -0,0 +1,55 @@
+// TIMER_IRQ_0が使うペリフェラルを1つにまとめたグローバル変数
+//
+// ALARM0、LEDのPWMスライスとピン、ブザーのPWMスライスは、どれもTIMER_IRQ_0の1回の処理で
+// 続けて触る。別々のGlobalPeripheralに置くと、1回の処理でRefCellを何度も借りることになり、
+// 「ALARM0を借りたままLEDを借りる」のような入れ子の借用がどこで起きているのかを追いにくい。
+// そこでこれらをShared構造体にまとめてSHAREDに置き、with()の1回の借用で全部を触れるようにする。
+// 1回のwith()の中で読み書きしたものは、割り込みに割り込まれず、同じ瞬間の状態として揃う。
+//
+// 借用の決まり
+//   ・with()のクロージャの中では、with()を使う関数（led::write_led()、tone::gate()、
+//     crate::restart_blink()など）を呼ばないこと。RefCellを2回借りることになり、panicする。
+//     クロージャの中ではフィールドを直接触るだけにし、ほかの処理はwith()から戻ってから行う。
+//   ・with()はCriticalSectionの中でしか呼べないので、割り込みとメインループが同時に借りることはない。
+//     割り込みの中の借用と、メインループのfree()の中の借用はいつも順番に起きる。
+//   ・with()のクロージャは短くすること（レジスタを数回書くだけ）。TIMER_IRQ_0の処理は、
+//     ALARM0の要因を消す → LEDの値を決める（ここは借りない）→ 次をscheduleする のように、
+//     借りている時間をレジスタの読み書きの間だけにしている（main.rsのdo_tick()）。
+//
+// ほかの割り込み（ALARM1〜3、GPIO、PIO）のペリフェラルは、それぞれのモジュールのGlobalPeripheralのままにしている。
+// TIMER_IRQ_0の処理からは触らないので、まとめても借用は減らず、モジュールの中に閉じていた方が追いやすい。
+//
+// RTICとの比較
+//   RTIC（cortex-m-rtic / rtic）を使えば、この借用の決まりをフレームワークが守らせてくれる。
+//   TIMER_IRQ_0をハードウェアタスク（#[task(binds = TIMER_IRQ_0, shared = [alarm0, led_pwm, counter])]）にし、
+//   Sharedのフィールドと割り込みカウンタを#[shared]のリソースにすると、lock()が優先度の天井まで
+//   割り込みを止める（Stack Resource Policy）ので、free()もRefCellも要らず、入れ子の借用はコンパイル時に断られる。
+//   rticフィーチャーでは、点滅（ALARM0）とカウントだけをこの形で書いた版（src/bin/rtic_blink.rs）もビルドする。
+//   ふだんのファームウェアはこのファイルのSHAREDとfree()のままで、rticフィーチャーを有効にしても変わらない。
+//   2つを比べるための版なので、点滅とカウントの動きを変えるときは、両方をそろえること。
+
+use crate::{initial_global_peripheral, led, tone, GlobalPeripheral};
+use cortex_m::interrupt::CriticalSection;
+use rp_pico::hal::timer::Alarm0;
+
+pub struct Shared {
+    pub alarm0: Alarm0,
+    // LEDのPWMスライスと、出力先に選べるピン（led::LED_GPIOSの順）
+    pub led_pwm: led::LedPwm,
+    pub led_pins: [led::LedPin; 3],
+    pub tone_pwm: tone::TonePwm,
+}
+
+// constfnでグローバル変数の初期値を設定。
+// 必ず同じ結果になるのでコンパイル時点で式の評価を行い、結果をグローバル変数の初期値としている。
+static SHARED: GlobalPeripheral<Shared> = initial_global_peripheral();
+
+// main()の初期化で1回だけ呼ぶ。led::init()とtone::init()より先に呼ぶこと。
+pub fn init(cs: &CriticalSection, shared: Shared) {
+    SHARED.borrow(cs).replace(Some(shared));
+}
+
+// SHAREDを1回だけ借りてfを呼ぶ。init()の前ならNone。
+pub fn with<R>(cs: &CriticalSection, f: impl FnOnce(&mut Shared) -> R) -> Option<R> {
+    SHARED.borrow(cs).borrow_mut().as_mut().map(f)
+}
@@ -0,0 +1,95 @@
+// 電源を切る前の片付け
+//
+// shutdown(fade)で、割り込みを止め、出力をすべてオフにしてから止まる（WFIで待ち続ける）。
+// 動き出すにはリセットするか、電源を入れ直す。UARTのshutdown [now]から呼ぶ。
+//
+// 手順
+//   1. 割り込みをすべてマスクする。ALARMの割り込みがLEDやブザーを書き換えなくなる
+//      （watchdogフィーチャーでは、止まっている間にリセットされないようにウォッチドッグも止める）
+//   2. fadeがtrueなら、LEDを今の明るさからSHUTDOWN_FADE_MSかけて0まで下げる（下に説明）
+//   3. ブザーを止め、LEDを消し、非常停止と同じピン（estop.rsのFORCED_GPIOS）をオフの電圧に固定する
+//   4. UARTとdefmtにログを出し、送り終えてから止まる
+//   出力を固定するのはフェードが終わってからなので、フェードの途中でLEDのピンが切り離されることはない。
+//
+// フェード
+//   SHUTDOWN_FADE_STEPS段に分けて、1段ごとにタイマーでSHUTDOWN_FADE_MS / SHUTDOWN_FADE_STEPSだけ待つ
+//   （割り込みを止めているので、ALARMではなくタイマーのカウンタを見て待つ）。
+//   待つ間はメインループも止まるので、片付けがSHUTDOWN_FADE_MS（500 ms）だけ遅くなる。
+//   すぐに止めたいとき（安全のためなど）はfadeをfalseにする（UARTのshutdown now）。
+//   LEDが消えているときはフェードしない。
+
+use crate::cs_trace::free;
+use crate::estop;
+use crate::led::{self, LED_OFF_DUTY};
+use crate::status_tx::StatusTx;
+use crate::tone;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use embedded_hal::delay::DelayNs;
+use rp_pico::hal::pac;
+use rp_pico::hal::timer::Timer;
+
+pub const SHUTDOWN_FADE_MS: u32 = 500;
+pub const SHUTDOWN_FADE_STEPS: u32 = 50;
+
+const _: () = assert!(SHUTDOWN_FADE_STEPS > 0 && SHUTDOWN_FADE_MS >= SHUTDOWN_FADE_STEPS);
+
+// main.rsで有効にしている割り込み
+const INTERRUPTS: [pac::Interrupt; 9] = [
+    pac::Interrupt::TIMER_IRQ_0,
+    pac::Interrupt::TIMER_IRQ_1,
+    pac::Interrupt::TIMER_IRQ_2,
+    pac::Interrupt::TIMER_IRQ_3,
+    pac::Interrupt::IO_IRQ_BANK0,
+    pac::Interrupt::PIO0_IRQ_0,
+    pac::Interrupt::PIO1_IRQ_0,
+    pac::Interrupt::SW0_IRQ,
+    pac::Interrupt::SW1_IRQ,
+];
+
+static SHUTDOWN_TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+
+pub fn init(cs: &CriticalSection, timer: Timer) {
+    SHUTDOWN_TIMER.borrow(cs).set(Some(timer));
+}
+
+// LEDを今の明るさから0まで、SHUTDOWN_FADE_MSかけて下げる
+fn fade_out(timer: &mut Timer) {
+    let start = free(led::duty);
+    if start == LED_OFF_DUTY {
+        return;
+    }
+    for step in 1..=SHUTDOWN_FADE_STEPS {
+        let duty = u32::from(start) * (SHUTDOWN_FADE_STEPS - step) / SHUTDOWN_FADE_STEPS;
+        free(|cs| led::write_led(cs, duty as u16));
+        timer.delay_ms(SHUTDOWN_FADE_MS / SHUTDOWN_FADE_STEPS);
+    }
+}
+
+// 片付けてから止まる。fadeがtrueならLEDをフェードアウトさせてから出力を切る。
+pub fn shutdown(tx: &mut StatusTx, fade: bool) -> ! {
+    for irq in INTERRUPTS {
+        pac::NVIC::mask(irq);
+    }
+    #[cfg(feature = "watchdog")]
+    crate::last_gasp::stop();
+
+    if fade {
+        if let Some(mut timer) = free(|cs| SHUTDOWN_TIMER.borrow(cs).get()) {
+            fade_out(&mut timer);
+        }
+    }
+    free(|cs| {
+        tone::gate(cs, false);
+        led::write_led(cs, LED_OFF_DUTY);
+    });
+    estop::force_outputs_off();
+
+    defmt::warn!("shutdown: outputs off, halted until reset");
+    tx.write_line_blocking(format_args!("shutdown: halted, reset to restart"));
+    tx.flush();
+    loop {
+        // 割り込みはすべてマスクしているので、ここから先には進まない
+        cortex_m::asm::wfi();
+    }
+}
@@ -0,0 +1,97 @@
+// DS3231（ds3231.rs）を使わずに、割り込みで進めるソフトウェアの時計
+//
+// RTCをつないでいないボードでも、おおよその時刻（その日の時:分:秒.ミリ秒）がわかるようにする。
+// 日付は持たず、24時間（MS_PER_DAY）で0:00:00.000に戻る。電源を切ると0:00:00.000からやり直しになる。
+//
+// 進め方
+//   sampler（ALARM3、SAMPLE_PERIOD_MSごと）の割り込みでtick()を呼び、前回のtick()からの時間だけ進める。
+//   LEDの点滅（ALARM0）は、blink_times()の回数を終えたときやコマ送りの間は止まり、間隔もモードで変わるので使わない。
+//   1回の割り込みの間隔をSAMPLE_PERIOD_MSちょうどとはみなさず、タイマーの64bitのカウンタで実際の経過時間（µs）を測って足す。
+//   ALARMは割り込みに入ってから次をscheduleするので、割り込みの遅れの分だけ毎回の間隔が少し長くなる。
+//   SAMPLE_PERIOD_MSを足していくと、その遅れが積もって時計が遅れていくが、カウンタで測ればたまらない。
+//   1 ms未満の端数も持ち越すので、ミリ秒への丸めで遅れることもない。
+//
+// 精度
+//   タイマーの1 µsは水晶発振子（12 MHz）から作っているので、時計の精度は水晶の精度で決まる。
+//   Picoの水晶は±30 ppm程度なので、1日に最大2.6秒ほどずれる。温度でも変わる。
+//   時刻をときどき合わせ直す（set_soft_time()）か、正確さが必要ならDS3231を使うこと。
+
+use crate::cs_trace::free;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::time;
+use rp_pico::hal::timer::Timer;
+
+const MS_PER_DAY: u32 = 24 * 60 * 60 * 1000;
+
+static RTC_TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+// 0:00:00.000からのミリ秒（MS_PER_DAY未満）
+static MS_OF_DAY: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+// まだミリ秒に足していない端数（µs、1000未満）
+static REMAINDER_US: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+// 前回tick()で進めた時刻（タイマーのカウンタ）
+static LAST_TICK_AT: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+
+pub fn init(cs: &CriticalSection, timer: Timer) {
+    RTC_TIMER.borrow(cs).set(Some(timer));
+    LAST_TICK_AT.borrow(cs).set(timer.get_counter().ticks());
+}
+
+// samplerから呼ぶ。前回からの実際の経過時間だけ時計を進める。
+pub fn tick(cs: &CriticalSection) {
+    let Some(timer) = RTC_TIMER.borrow(cs).get() else {
+        return;
+    };
+    let now = timer.get_counter().ticks();
+    let elapsed_us = time::elapsed_us(now, LAST_TICK_AT.borrow(cs).replace(now));
+    let total_us = u64::from(REMAINDER_US.borrow(cs).get()) + elapsed_us;
+    REMAINDER_US.borrow(cs).set((total_us % 1000) as u32);
+    let ms = MS_OF_DAY.borrow(cs);
+    // 24時間を過ぎたら0:00:00.000に戻る
+    ms.set(((u64::from(ms.get()) + total_us / 1000) % u64::from(MS_PER_DAY)) as u32);
+}
+
+// 今の時刻（時, 分, 秒, ミリ秒）
+pub fn soft_time() -> (u8, u8, u8, u16) {
+    let ms = free(|cs| MS_OF_DAY.borrow(cs).get());
+    let seconds = ms / 1000;
+    (
+        (seconds / 3600) as u8,
+        (seconds / 60 % 60) as u8,
+        (seconds % 60) as u8,
+        (ms % 1000) as u16,
+    )
+}
+
+// 時刻を合わせる。範囲の外（25時など）ならfalseを返して変えない。
+pub fn set_soft_time(hour: u8, minute: u8, second: u8, millis: u16) -> bool {
+    if hour >= 24 || minute >= 60 || second >= 60 || millis >= 1000 {
+        return false;
+    }
+    let ms = ((u32::from(hour) * 60 + u32::from(minute)) * 60 + u32::from(second)) * 1000
+        + u32::from(millis);
+    free(|cs| {
+        MS_OF_DAY.borrow(cs).set(ms);
+        REMAINDER_US.borrow(cs).set(0);
+    });
+    true
+}
+
+// "HH:MM:SS"か"HH:MM:SS.mmm"の形の文字列から読み取る（範囲は確かめない）
+// ミリ秒はちょうど3桁に限る（".5"を5 msと読み違えないように、"12:00:00.5"や".05"はNoneにする）。
+pub fn parse_soft_time(s: &str) -> Option<(u8, u8, u8, u16)> {
+    let (hms, millis) = match s.split_once('.') {
+        Some((hms, millis)) if millis.len() == 3 && millis.bytes().all(|b| b.is_ascii_digit()) => {
+            (hms, millis.parse().ok()?)
+        }
+        Some(_) => return None,
+        None => (s, 0),
+    };
+    let mut hms = hms.splitn(3, ':');
+    Some((
+        hms.next()?.parse().ok()?,
+        hms.next()?.parse().ok()?,
+        hms.next()?.parse().ok()?,
+        millis,
+    ))
+}
@@ -0,0 +1,155 @@
+// ALARM1だけで、たくさんのタイムアウトを同時に待つソフトウェアタイマー
+//
+// set_timer(delay_ms, id)で、delay_ms後にidのタイマーを発火させる。
+// 発火したタイマーはFIREDにidのビットを立てるので、take_fired()で確かめる（読むとビットは消える）。
+// 発火したときに処理（Work）も実行させたいときはset_timer_with()を使う（oneshot.rsのafter()もこれを使う）。
+//
+// 期限はrp2040_project_template::timer_list::TimerListに期限の近い順に入れておき、
+// ALARM1はいちばん近い期限に合わせてscheduleし直す。入るのはSOFT_TIMER_CAPACITY個まで。
+// 構造（追加がO(n)、いちばん近い期限を取り出すのがO(1)）と同じ期限のときの順番はtimer_list.rsを参照。
+// TIMER_IRQ_1で期限が来ているものをすべて取り出し、残ったもののいちばん近い期限でscheduleし直す。
+//
+// ALARMでscheduleできるのはMAX_ALARM_INTERVAL_MSまでなので、それより先の期限では途中で1回空振りの割り込みを入れ、
+// そこからscheduleし直す。期限そのものは64bitのタイマーカウンタで持つので、遅れはたまらない。
+//
+// idは0からSOFT_TIMER_IDS - 1まで。ONESHOT_IDはoneshot.rs、BLINK_BURST_IDはblink_burst.rsが使うので、ほかでは使わないこと。
+
+use crate::cs_trace::free;
+use crate::work::Work;
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::{Cell, RefCell};
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::timer_list::TimerList;
+use rp_pico::hal::timer::{Alarm, Alarm1, Timer};
+
+pub const SOFT_TIMER_CAPACITY: usize = 16;
+// FIREDのビットの数
+pub const SOFT_TIMER_IDS: u8 = 32;
+pub const ONESHOT_ID: u8 = SOFT_TIMER_IDS - 1;
+pub const BLINK_BURST_ID: u8 = SOFT_TIMER_IDS - 2;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    // SOFT_TIMER_CAPACITY個が予約済み
+    Full,
+    // idがSOFT_TIMER_IDS以上
+    BadId,
+}
+
+impl TimerError {
+    // UARTなどに出力するときの説明
+    pub fn name(&self) -> &'static str {
+        match self {
+            TimerError::Full => "too many timers",
+            TimerError::BadId => "bad timer id",
+        }
+    }
+}
+
+static ALARM1: GlobalPeripheral<Alarm1> = initial_global_peripheral();
+static SOFT_TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+static TIMERS: Mutex<RefCell<TimerList<Option<Work>, SOFT_TIMER_CAPACITY>>> =
+    Mutex::new(RefCell::new(TimerList::new()));
+// 発火したタイマーのidのビット
+static FIRED: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+pub fn init(cs: &CriticalSection, mut alarm: Alarm1, timer: Timer) {
+    alarm.enable_interrupt();
+    alarm.clear_interrupt();
+    ALARM1.borrow(cs).replace(Some(alarm));
+    SOFT_TIMER.borrow(cs).set(Some(timer));
+}
+
+// いちばん近い期限に合わせてALARM1をscheduleし直す。予約がなければ止める。
+fn rearm(cs: &CriticalSection) {
+    let mut alarm = ALARM1.borrow(cs).borrow_mut();
+    let Some(alarm) = alarm.as_mut() else {
+        return;
+    };
+    let Some(timer) = SOFT_TIMER.borrow(cs).get() else {
+        return;
+    };
+    // 前の期限の割り込みが保留されていると、新しい期限より先に発火してしまうので消しておく
+    alarm.clear_interrupt();
+    match TIMERS.borrow(cs).borrow().next_deadline() {
+        Some(deadline) => {
+            let now = timer.get_counter().ticks();
+            // 期限が過ぎていればMIN_INTERVAL_US後、遠ければMAX_INTERVAL_US後になる（time.rs）
+            let delay_us = deadline.saturating_sub(now).min(u64::from(u32::MAX)) as u32;
+            crate::schedule_alarm_us(alarm, delay_us);
+        }
+        None => {
+            alarm.cancel().unwrap();
+        }
+    }
+}
+
+fn set(cs: &CriticalSection, delay_ms: u32, id: u8, work: Option<Work>) -> Result<(), TimerError> {
+    if id >= SOFT_TIMER_IDS {
+        return Err(TimerError::BadId);
+    }
+    let Some(timer) = SOFT_TIMER.borrow(cs).get() else {
+        return Err(TimerError::Full);
+    };
+    let deadline = timer.get_counter().ticks() + u64::from(delay_ms) * 1000;
+    // 前に発火したままの印は、予約し直したら消す
+    let fired = FIRED.borrow(cs);
+    fired.set(fired.get() & !(1 << id));
+    if !TIMERS.borrow(cs).borrow_mut().insert(deadline, id, work) {
+        return Err(TimerError::Full);
+    }
+    rearm(cs);
+    Ok(())
+}
+
+// delay_ms後にidのタイマーを発火させる。同じidが予約中なら置き換える。
+pub fn set_timer(delay_ms: u32, id: u8) -> Result<(), TimerError> {
+    free(|cs| set(cs, delay_ms, id, None))
+}
+
+// set_timer()と同じで、発火したときにworkも実行する（TIMER_IRQ_1の中で）
+pub fn set_timer_with(delay_ms: u32, id: u8, work: Work) -> Result<(), TimerError> {
+    free(|cs| set(cs, delay_ms, id, Some(work)))
+}
+
+// idのタイマーを取り消す。取り消すものがあればtrue。
+pub fn cancel_timer(id: u8) -> bool {
+    free(|cs| {
+        let cancelled = TIMERS.borrow(cs).borrow_mut().cancel(id);
+        if cancelled {
+            rearm(cs);
+        }
+        cancelled
+    })
+}
+
+// 予約中のタイマーの数
+pub fn pending() -> usize {
+    free(|cs| TIMERS.borrow(cs).borrow().len())
+}
+
+// 前に呼んでから発火したタイマーのidのビットを返し、消す
+pub fn take_fired() -> u32 {
+    free(|cs| FIRED.borrow(cs).replace(0))
+}
+
+// TIMER_IRQ_1から呼ぶ。期限が来ているタイマーをすべて発火させ、次の期限でscheduleし直す。
+pub fn fire(cs: &CriticalSection) {
+    let Some(timer) = SOFT_TIMER.borrow(cs).get() else {
+        return;
+    };
+    let now = timer.get_counter().ticks();
+    loop {
+        // Workの中でタイマーを予約し直せるように、1つ取り出すごとに借用を返す
+        let Some(entry) = TIMERS.borrow(cs).borrow_mut().pop_due(now) else {
+            break;
+        };
+        let fired = FIRED.borrow(cs);
+        fired.set(fired.get() | 1 << entry.id);
+        defmt::debug!("soft timer {} fired", entry.id);
+        if let Some(work) = entry.payload {
+            work.run(cs);
+        }
+    }
+    rearm(cs);
+}
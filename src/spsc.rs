@@ -0,0 +1,227 @@
+// 割り込みからメインループへ値を渡す、ロックなしの1対1のキュー（SPSC: single producer, single consumer）
+//
+// deferred.rsのキューは、積むときも取り出すときも割り込みを止めて（free()で）中身を書き換える。
+// 積む側が1か所（割り込み）、取り出す側が1か所（メインループ）と決まっていれば、
+// 読み書きの位置をアトミック変数にするだけで、割り込みを止めずに受け渡せる。
+//
+// 使い方
+//   Queue::new()で作り、split()で積む側（Producer）と取り出す側（Consumer）に分ける。
+//   split()は&mut selfを取るので、それぞれ1つずつしか作れない。
+//   ファームウェアではcortex_m::singleton!で&'static mutのキューを作り、
+//   Producerを割り込みのモジュールに、Consumerをメインループに渡す（waveform.rs参照）。
+//
+// 構造
+//   Nは2のべき乗に限る。位置headとtailはそれぞれ取り出した数と積んだ数で、一周しても
+//   wrapping_addで増やし続け、配列の添字にはN - 1とのANDを使う（割り算を使わない）。
+//     ・中身の数   : tail - head（wrapping_sub）
+//     ・空         : tail == head
+//     ・いっぱい   : tail - head == N
+//   Cortex-M0+にはcompare_exchangeやfetch_addがないが、ここで使うのはloadとstoreだけで、
+//   それぞれの位置を書き換えるのは片方だけなので足りる。
+//
+// メモリの順序
+//   積む側   : 配列に値を書いてから、tailをReleaseで書く
+//   取り出す側 : tailをAcquireで読んでから、配列の値を読む
+//   これで、取り出す側がtailの増えたのを見たときには、値の書き込みも見えている。
+//   逆向き（取り出した後のheadをRelease、積む側がAcquireで読む）も同じで、
+//   読み終わる前の場所に次の値を上書きしない。
+//   自分が書く方の位置は自分しか書かないので、Relaxedで読めばよい。
+//
+// 値はCopyに限る（取り出さずにキューを捨てたときにdropしなくてよいように）。
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct Queue<T, const N: usize> {
+    buf: [UnsafeCell<MaybeUninit<T>>; N],
+    // 取り出した数（Consumerだけが書く）
+    head: AtomicUsize,
+    // 積んだ数（Producerだけが書く）
+    tail: AtomicUsize,
+}
+
+// bufの同じ場所をProducerとConsumerが同時に触らないことは、headとtailで保証している
+unsafe impl<T: Copy + Send, const N: usize> Sync for Queue<T, N> {}
+
+pub struct Producer<'a, T, const N: usize> {
+    queue: &'a Queue<T, N>,
+}
+
+pub struct Consumer<'a, T, const N: usize> {
+    queue: &'a Queue<T, N>,
+}
+
+impl<T: Copy, const N: usize> Queue<T, N> {
+    const MASK: usize = {
+        assert!(N.is_power_of_two(), "capacity must be a power of two");
+        N - 1
+    };
+
+    pub const fn new() -> Self {
+        // Nが2のべき乗でなければここでコンパイルエラーになる
+        let _ = Self::MASK;
+        Self {
+            buf: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    // 積む側と取り出す側に分ける
+    pub fn split(&mut self) -> (Producer<'_, T, N>, Consumer<'_, T, N>) {
+        (Producer { queue: self }, Consumer { queue: self })
+    }
+
+    fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+}
+
+impl<T: Copy, const N: usize> Default for Queue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy, const N: usize> Producer<'_, T, N> {
+    // 値を積む。いっぱいなら積まずにその値を返す。
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let queue = self.queue;
+        let tail = queue.tail.load(Ordering::Relaxed);
+        let head = queue.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == N {
+            return Err(value);
+        }
+        // Safety: tail & MASKの場所は、Consumerがすでに読み終えている（headがそこを過ぎている）か、
+        // まだ書いていない場所なので、今はProducerしか触らない。
+        unsafe { (*queue.buf[tail & Queue::<T, N>::MASK].get()).write(value) };
+        queue.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.queue.len() == N
+    }
+}
+
+impl<T: Copy, const N: usize> Consumer<'_, T, N> {
+    // いちばん古い値を取り出す。空ならNone。
+    pub fn pop(&mut self) -> Option<T> {
+        let queue = self.queue;
+        let head = queue.head.load(Ordering::Relaxed);
+        let tail = queue.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        // Safety: head != tailなので、head & MASKの場所はProducerが書き終えている（tailのReleaseで見える）。
+        let value = unsafe { (*queue.buf[head & Queue::<T, N>::MASK].get()).assume_init() };
+        queue.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_and_full() {
+        let mut queue = Queue::<u32, 4>::new();
+        let (mut tx, mut rx) = queue.split();
+        assert!(rx.is_empty());
+        assert_eq!(rx.pop(), None);
+        for i in 0..4 {
+            assert_eq!(tx.push(i), Ok(()));
+        }
+        assert!(tx.is_full());
+        // いっぱいのときは積めず、値が返ってくる
+        assert_eq!(tx.push(99), Err(99));
+        assert_eq!(rx.len(), 4);
+        assert_eq!(rx.pop(), Some(0));
+        // 1つ空けば、また積める
+        assert_eq!(tx.push(4), Ok(()));
+        assert_eq!(tx.push(5), Err(5));
+        assert_eq!(
+            [rx.pop(), rx.pop(), rx.pop(), rx.pop()],
+            [1, 2, 3, 4].map(Some)
+        );
+        assert_eq!(rx.pop(), None);
+    }
+
+    #[test]
+    fn keeps_order_across_wraparound() {
+        let mut queue = Queue::<u16, 8>::new();
+        let (mut tx, mut rx) = queue.split();
+        // 添字が何周もするように、少しずつ積んでは取り出す
+        let mut next_in = 0u16;
+        let mut next_out = 0u16;
+        for round in 0..100 {
+            for _ in 0..(round % 8 + 1) {
+                tx.push(next_in).unwrap();
+                next_in += 1;
+            }
+            while let Some(value) = rx.pop() {
+                assert_eq!(value, next_out);
+                next_out += 1;
+            }
+        }
+        assert_eq!(next_in, next_out);
+    }
+
+    #[test]
+    fn positions_wrap_at_usize_max() {
+        let mut queue = Queue::<u8, 2>::new();
+        // headとtailがusizeの最大値をまたいでも、数と空/いっぱいの判定が合うこと
+        queue.head = AtomicUsize::new(usize::MAX);
+        queue.tail = AtomicUsize::new(usize::MAX);
+        let (mut tx, mut rx) = queue.split();
+        assert!(rx.is_empty());
+        tx.push(1).unwrap();
+        tx.push(2).unwrap();
+        assert!(tx.is_full());
+        assert_eq!(tx.push(3), Err(3));
+        assert_eq!(rx.pop(), Some(1));
+        assert_eq!(rx.pop(), Some(2));
+        assert_eq!(rx.pop(), None);
+    }
+
+    #[test]
+    fn producer_and_consumer_on_separate_threads() {
+        let mut queue = Queue::<u32, 16>::new();
+        let (mut tx, mut rx) = queue.split();
+        const COUNT: u32 = 10_000;
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..COUNT {
+                    while tx.push(i).is_err() {
+                        std::thread::yield_now();
+                    }
+                }
+            });
+            let mut expected = 0;
+            while expected < COUNT {
+                if let Some(value) = rx.pop() {
+                    assert_eq!(value, expected);
+                    expected += 1;
+                } else {
+                    std::thread::yield_now();
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,421 @@
+// I2C接続の128x64のOLED（SSD1306）に状態を表示する
+//
+// DS3231と同じI2C0バス（i2c_bus.rs）につなぐ。アドレスはSSD1306_ADDR（SA0をGNDにしたとき。VCCなら0x3D）。
+// 表示するのは4行で、それぞれ2ページ（16ドット）ごとに置く。
+//     count  割り込みの回数
+//     mode   LEDのモード
+//     blink  実際に使っている点滅間隔（interval::current_ms()）
+//     up     起動してからの時間（HH:MM:SS）
+//
+// 更新のしかた
+//   メインループからpoll()を呼ぶ。SSD1306_UPDATE_INTERVAL_MSごとに表示したい文字を作り直し、
+//   画面に出ている文字と違う文字だけを書き込む。
+//   1文字は6列（5x7のフォント＋1列の空き）で、コマンド5バイトとデータ7バイトの2回の転送になる。
+//   100kHzでは1文字に約1.3 msかかるので、1回のpoll()で書くのはSSD1306_CHARS_PER_POLL文字まで。
+//   書ききれなかった文字は次のpoll()で書く。
+//   転送はwith_bus()で割り込みを許可したまま行うのでTIMER_IRQ_0は待たされないが、
+//   その間メインループ（UARTのコマンドなど）は止まる。そのため画面全体をまとめて書き直すことはしない。
+//
+// OLEDがつながっていないとき
+//   起動時にinit()で初期化のコマンドを送り、NAKが返ったら（または何かの転送に失敗したら）つながっていないとみなす。
+//   つながっていない間は何も書かず、SSD1306_PROBE_INTERVAL_MSごとにもう一度初期化を試す（後からつないでもよい）。
+//   つながっていないのは普通のことなので、このときの失敗はI2Cの復旧（i2c_bus::record_transfer()）には数えない。
+//   表示している途中で転送に失敗したときは数えてから、つながっていないとみなす。
+//
+// 初期化の後の画面（GDDRAM）の中身は不定なので、表示をONにする前に1回のpoll()で1ページずつ消していく
+// （1ページ128バイトで約12 ms）。8ページ消し終えたら表示をONにして、文字を書き始める。
+
+use crate::cs_trace::free;
+use crate::mode::LedMode;
+use crate::{i2c_bus, time};
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use cortex_m::interrupt::Mutex;
+use embedded_hal::i2c::I2c;
+use rp_pico::hal::i2c;
+
+const SSD1306_ADDR: u8 = 0x3C;
+pub const SSD1306_UPDATE_INTERVAL_MS: u32 = 250;
+pub const SSD1306_PROBE_INTERVAL_MS: u32 = 10_000;
+pub const SSD1306_CHARS_PER_POLL: usize = 4;
+
+const WIDTH: usize = 128;
+const PAGES: u8 = 8;
+const GLYPH_WIDTH: usize = 5;
+// 文字と文字の間の1列を含めた幅
+const CELL_WIDTH: usize = GLYPH_WIDTH + 1;
+const COLUMNS: usize = WIDTH / CELL_WIDTH;
+const LINES: usize = 4;
+// 左右の余りを半分ずつにして、文字を真ん中に寄せる
+const X_OFFSET: usize = (WIDTH - COLUMNS * CELL_WIDTH) / 2;
+
+// 続くバイトがコマンドかデータか（コントロールバイト）
+const CONTROL_COMMAND: u8 = 0x00;
+const CONTROL_DATA: u8 = 0x40;
+const DISPLAY_ON: u8 = 0xAF;
+// 画面に出ている文字で、まだ何も書いていないことを示す（フォントにない値）
+const UNKNOWN: u8 = 0;
+
+// 初期化のコマンド（128x64、内部のチャージポンプを使うモジュールの一般的な値）
+// 表示をONにするのは、画面を消し終えてから
+const INIT_SEQUENCE: [u8; 25] = [
+    0xAE, // 表示OFF
+    0xD5, 0x80, // 表示クロックの分周比と発振周波数
+    0xA8, 0x3F, // マルチプレックス比（64行）
+    0xD3, 0x00, // 表示のオフセット
+    0x40, // 表示の開始行 = 0
+    0x8D, 0x14, // チャージポンプON
+    0x20, 0x02, // ページアドレッシングモード
+    0xA1, // 列0をSEG127に（左右反転）
+    0xC8, // COMの走査方向を逆に（上下反転）
+    0xDA, 0x12, // COMピンの配置
+    0x81, 0xCF, // コントラスト
+    0xD9, 0xF1, // プリチャージ期間
+    0xDB, 0x40, // VCOMHの電圧
+    0xA4, // GDDRAMの中身を表示する
+    0xA6, // 反転しない
+    0x2E, // スクロールしない
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OledError {
+    // I2Cバスが初期化されていないか、他で使われている
+    BusUnavailable,
+    // NAKなどI2Cの転送に失敗した（つながっていない）
+    Bus,
+}
+
+impl OledError {
+    // UARTなどに出力するときの説明
+    pub fn name(&self) -> &'static str {
+        match self {
+            OledError::BusUnavailable => "bus unavailable",
+            OledError::Bus => "not found",
+        }
+    }
+}
+
+impl From<i2c::Error> for OledError {
+    fn from(_: i2c::Error) -> Self {
+        OledError::Bus
+    }
+}
+
+// 表示する値（メインループが集める）
+pub struct Status {
+    pub count: u32,
+    pub mode: LedMode,
+    pub interval_ms: u32,
+    pub uptime_us: u64,
+}
+
+struct Display {
+    present: bool,
+    // 次に消すページ。PAGESなら消し終えて表示している。
+    clear_page: u8,
+    // 画面に出ている文字と、出したい文字
+    shown: [[u8; COLUMNS]; LINES],
+    wanted: [[u8; COLUMNS]; LINES],
+    next_update: u64,
+    next_probe: u64,
+}
+
+static DISPLAY: Mutex<RefCell<Option<Display>>> = Mutex::new(RefCell::new(None));
+
+// 5x7のフォント（0x20〜0x7E）。1バイトが1列で、bit0が上。
+const FONT_FIRST: u8 = 0x20;
+const FONT_LAST: u8 = 0x7E;
+const FONT: [[u8; GLYPH_WIDTH]; (FONT_LAST - FONT_FIRST + 1) as usize] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5F, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7F, 0x14, 0x7F, 0x14], // #
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x55, 0x22, 0x50], // &
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '
+    [0x00, 0x1C, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1C, 0x00], // )
+    [0x08, 0x2A, 0x1C, 0x2A, 0x08], // *
+    [0x08, 0x08, 0x3E, 0x08, 0x08], // +
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x60, 0x60, 0x00, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3E, 0x51, 0x49, 0x45, 0x3E], // 0
+    [0x00, 0x42, 0x7F, 0x40, 0x00], // 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x45, 0x4B, 0x31], // 3
+    [0x18, 0x14, 0x12, 0x7F, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3C, 0x4A, 0x49, 0x49, 0x30], // 6
+    [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x06, 0x49, 0x49, 0x29, 0x1E], // 9
+    [0x00, 0x36, 0x36, 0x00, 0x00], // :
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+    [0x08, 0x14, 0x22, 0x41, 0x00], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+    [0x32, 0x49, 0x79, 0x41, 0x3E], // @
+    [0x7E, 0x11, 0x11, 0x11, 0x7E], // A
+    [0x7F, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3E, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7F, 0x41, 0x41, 0x22, 0x1C], // D
+    [0x7F, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7F, 0x09, 0x09, 0x09, 0x01], // F
+    [0x3E, 0x41, 0x49, 0x49, 0x7A], // G
+    [0x7F, 0x08, 0x08, 0x08, 0x7F], // H
+    [0x00, 0x41, 0x7F, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3F, 0x01], // J
+    [0x7F, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7F, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7F, 0x02, 0x0C, 0x02, 0x7F], // M
+    [0x7F, 0x04, 0x08, 0x10, 0x7F], // N
+    [0x3E, 0x41, 0x41, 0x41, 0x3E], // O
+    [0x7F, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3E, 0x41, 0x51, 0x21, 0x5E], // Q
+    [0x7F, 0x09, 0x19, 0x29, 0x46], // R
+    [0x46, 0x49, 0x49, 0x49, 0x31], // S
+    [0x01, 0x01, 0x7F, 0x01, 0x01], // T
+    [0x3F, 0x40, 0x40, 0x40, 0x3F], // U
+    [0x1F, 0x20, 0x40, 0x20, 0x1F], // V
+    [0x3F, 0x40, 0x38, 0x40, 0x3F], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x07, 0x08, 0x70, 0x08, 0x07], // Y
+    [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+    [0x00, 0x7F, 0x41, 0x41, 0x00], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // \
+    [0x00, 0x41, 0x41, 0x7F, 0x00], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x01, 0x02, 0x04, 0x00], // `
+    [0x20, 0x54, 0x54, 0x54, 0x78], // a
+    [0x7F, 0x48, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x20], // c
+    [0x38, 0x44, 0x44, 0x48, 0x7F], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x08, 0x7E, 0x09, 0x01, 0x02], // f
+    [0x0C, 0x52, 0x52, 0x52, 0x3E], // g
+    [0x7F, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7D, 0x40, 0x00], // i
+    [0x20, 0x40, 0x44, 0x3D, 0x00], // j
+    [0x7F, 0x10, 0x28, 0x44, 0x00], // k
+    [0x00, 0x41, 0x7F, 0x40, 0x00], // l
+    [0x7C, 0x04, 0x18, 0x04, 0x78], // m
+    [0x7C, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0x7C, 0x14, 0x14, 0x14, 0x08], // p
+    [0x08, 0x14, 0x14, 0x18, 0x7C], // q
+    [0x7C, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x20], // s
+    [0x04, 0x3F, 0x44, 0x40, 0x20], // t
+    [0x3C, 0x40, 0x40, 0x20, 0x7C], // u
+    [0x1C, 0x20, 0x40, 0x20, 0x1C], // v
+    [0x3C, 0x40, 0x30, 0x40, 0x3C], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x0C, 0x50, 0x50, 0x50, 0x3C], // y
+    [0x44, 0x64, 0x54, 0x4C, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x7F, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x10, 0x08, 0x08, 0x10, 0x08], // ~
+];
+
+// フォントにない文字は'?'で表示する
+fn glyph(c: u8) -> &'static [u8; GLYPH_WIDTH] {
+    let c = if (FONT_FIRST..=FONT_LAST).contains(&c) {
+        c
+    } else {
+        b'?'
+    };
+    &FONT[(c - FONT_FIRST) as usize]
+}
+
+// 1行分の文字を作る。入りきらない分は捨て、残りは空白で埋める。
+struct TextLine {
+    text: [u8; COLUMNS],
+    len: usize,
+}
+
+impl TextLine {
+    fn new() -> Self {
+        TextLine {
+            text: [b' '; COLUMNS],
+            len: 0,
+        }
+    }
+}
+
+impl Write for TextLine {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &c in s.as_bytes() {
+            if self.len < COLUMNS {
+                self.text[self.len] = c;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn render(status: &Status) -> [[u8; COLUMNS]; LINES] {
+    let uptime_s = status.uptime_us / 1_000_000;
+    let mut lines = [
+        TextLine::new(),
+        TextLine::new(),
+        TextLine::new(),
+        TextLine::new(),
+    ];
+    // TextLineへの書き込みは失敗しない
+    let _ = write!(lines[0], "count  {}", status.count);
+    let _ = write!(lines[1], "mode   {}", status.mode.name());
+    let _ = write!(lines[2], "blink  {} ms", status.interval_ms);
+    let _ = write!(
+        lines[3],
+        "up     {:02}:{:02}:{:02}",
+        uptime_s / 3600,
+        uptime_s / 60 % 60,
+        uptime_s % 60
+    );
+    lines.map(|line| line.text)
+}
+
+fn command(bus: &mut i2c_bus::I2cBus, commands: &[u8]) -> Result<(), OledError> {
+    let mut frame = [0u8; INIT_SEQUENCE.len() + 1];
+    frame[0] = CONTROL_COMMAND;
+    frame[1..=commands.len()].copy_from_slice(commands);
+    bus.write(SSD1306_ADDR, &frame[..=commands.len()])?;
+    Ok(())
+}
+
+// 書き込む位置（ページと列）を決める
+fn set_position(bus: &mut i2c_bus::I2cBus, page: u8, x: usize) -> Result<(), OledError> {
+    command(bus, &[0xB0 | page, (x & 0x0F) as u8, 0x10 | (x >> 4) as u8])
+}
+
+fn clear_page(bus: &mut i2c_bus::I2cBus, page: u8) -> Result<(), OledError> {
+    set_position(bus, page, 0)?;
+    let mut frame = [0u8; WIDTH + 1];
+    frame[0] = CONTROL_DATA;
+    bus.write(SSD1306_ADDR, &frame)?;
+    Ok(())
+}
+
+fn write_char(
+    bus: &mut i2c_bus::I2cBus,
+    line: usize,
+    column: usize,
+    c: u8,
+) -> Result<(), OledError> {
+    set_position(bus, (line * 2) as u8, X_OFFSET + column * CELL_WIDTH)?;
+    let mut frame = [0u8; CELL_WIDTH + 1];
+    frame[0] = CONTROL_DATA;
+    frame[1..=GLYPH_WIDTH].copy_from_slice(glyph(c));
+    bus.write(SSD1306_ADDR, &frame)?;
+    Ok(())
+}
+
+// 初期化のコマンドを送る。NAKならつながっていない。
+fn probe(display: &mut Display) -> Result<(), OledError> {
+    i2c_bus::with_bus(|bus| command(bus, &INIT_SEQUENCE)).ok_or(OledError::BusUnavailable)??;
+    display.present = true;
+    display.clear_page = 0;
+    display.shown = [[UNKNOWN; COLUMNS]; LINES];
+    defmt::info!("ssd1306: found at {=u8:#x}", SSD1306_ADDR);
+    Ok(())
+}
+
+// 画面を1ページ消すか、違っている文字をSSD1306_CHARS_PER_POLL文字まで書く。何か転送したらtrue。
+fn refresh(display: &mut Display) -> Result<bool, OledError> {
+    i2c_bus::with_bus(|bus| {
+        if display.clear_page < PAGES {
+            clear_page(bus, display.clear_page)?;
+            display.clear_page += 1;
+            if display.clear_page == PAGES {
+                command(bus, &[DISPLAY_ON])?;
+            }
+            return Ok(true);
+        }
+        let mut written = 0;
+        for line in 0..LINES {
+            for column in 0..COLUMNS {
+                let c = display.wanted[line][column];
+                if written == SSD1306_CHARS_PER_POLL {
+                    return Ok(true);
+                }
+                if display.shown[line][column] != c {
+                    write_char(bus, line, column, c)?;
+                    display.shown[line][column] = c;
+                    written += 1;
+                }
+            }
+        }
+        Ok(written > 0)
+    })
+    .ok_or(OledError::BusUnavailable)?
+}
+
+// 起動時に1回呼ぶ。i2c_bus::init()の後で。
+pub fn init(now_us: u64) {
+    let mut display = Display {
+        present: false,
+        clear_page: 0,
+        shown: [[UNKNOWN; COLUMNS]; LINES],
+        wanted: [[b' '; COLUMNS]; LINES],
+        next_update: now_us,
+        next_probe: time::add_interval(now_us, SSD1306_PROBE_INTERVAL_MS * 1000),
+    };
+    if let Err(e) = probe(&mut display) {
+        defmt::info!("ssd1306: {=str}, display disabled", e.name());
+    }
+    free(|cs| DISPLAY.borrow(cs).replace(Some(display)));
+}
+
+// メインループから呼ぶ。statusは表示を作り直すときだけ呼ぶ。
+pub fn poll(now_us: u64, status: impl FnOnce() -> Status) {
+    // 転送の間は割り込みを許可したいので、with_bus()と同じように取り出してから戻す
+    let Some(mut display) = free(|cs| DISPLAY.borrow(cs).take()) else {
+        return;
+    };
+
+    if !display.present && time::deadline_passed(now_us, display.next_probe) {
+        display.next_probe = time::add_interval(now_us, SSD1306_PROBE_INTERVAL_MS * 1000);
+        let _ = probe(&mut display);
+    }
+
+    if display.present {
+        if time::deadline_passed(now_us, display.next_update) {
+            display.wanted = render(&status());
+            display.next_update = time::add_interval(now_us, SSD1306_UPDATE_INTERVAL_MS * 1000);
+        }
+        match refresh(&mut display) {
+            Ok(true) => i2c_bus::record_transfer(true),
+            Ok(false) => {}
+            // 次のpoll()でもう一度書く
+            Err(OledError::BusUnavailable) => {}
+            Err(OledError::Bus) => {
+                i2c_bus::record_transfer(false);
+                display.present = false;
+                defmt::warn!("ssd1306: transfer failed, display disabled");
+            }
+        }
+    }
+
+    free(|cs| DISPLAY.borrow(cs).replace(Some(display)));
+}
+
+// UARTの oled コマンド。つながっていなければ、すぐにもう一度初期化を試す。
+pub fn check() -> Result<(), OledError> {
+    let Some(mut display) = free(|cs| DISPLAY.borrow(cs).take()) else {
+        return Err(OledError::BusUnavailable);
+    };
+    let result = if display.present {
+        Ok(())
+    } else {
+        probe(&mut display)
+    };
+    free(|cs| DISPLAY.borrow(cs).replace(Some(display)));
+    result
+}
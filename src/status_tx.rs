@@ -0,0 +1,169 @@
+// UART0へステータス行をDMAで送信するモジュール
+//
+// UARTへのブロッキング書き込みは1文字ごとにFIFOの空きを待つので、
+// 115200bpsだと1行（数十バイト）の送信に数msかかり、その間メインループが止まってしまう。
+// そこで送信はDMAに任せ、CPUはバッファに文字列を詰めるだけにする。
+//
+// バッファは2面（ダブルバッファ）で、以下のように所有権を受け渡す。
+//
+//   1. メインループはwrite_line()で「書き込み側」のバッファ（pending）に行を追記する。
+//   2. poll()でDMAが空いていれば、pendingとDMA用の空バッファを入れ替え、
+//      中身の入ったバッファの所有権をTransferに渡して転送を開始する。
+//      転送中のバッファはTransferが所有しているので、CPU側からは触れない（触れないことを型で保証している）。
+//   3. 転送が終わったらpoll()がwait()でバッファの所有権を取り戻し、空にして次の入れ替えに備える。
+//
+// 完了の検出は割り込みではなくpoll()でのポーリングで行う。
+// メインループは常に回っているので、割り込みを使うほどの即応性は必要ない。
+
+use core::fmt;
+
+use embedded_dma::ReadBuffer;
+use rp_pico::hal::{dma, gpio, pac, uart};
+
+pub type UartTxPin = gpio::Pin<gpio::bank0::Gpio0, gpio::FunctionUart, gpio::PullDown>;
+pub type UartRxPin = gpio::Pin<gpio::bank0::Gpio1, gpio::FunctionUart, gpio::PullDown>;
+pub type UartPins = (UartTxPin, UartRxPin);
+pub type UartWriter = uart::Writer<pac::UART0, UartPins>;
+pub type StatusDmaChannel = dma::Channel<dma::CH0>;
+
+// 1回の転送で送れる最大バイト数。これを超えた分は切り捨てる。
+pub const LINE_BUF_LEN: usize = 128;
+
+// DMAに渡すためのバッファ。
+// &'static mutで確保した領域を指しているので、この構造体自体がムーブされても
+// DMAが読んでいるメモリの位置は変わらない。
+pub struct LineBuf {
+    buf: &'static mut [u8; LINE_BUF_LEN],
+    len: usize,
+}
+
+impl LineBuf {
+    fn new(buf: &'static mut [u8; LINE_BUF_LEN]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+// Safety: bufは'staticな領域なのでLineBufをドロップしない限り解放されない。
+// また、転送中はTransferがLineBufを所有するので&mut selfのメソッドは呼ばれない。
+unsafe impl ReadBuffer for LineBuf {
+    type Word = u8;
+
+    unsafe fn read_buffer(&self) -> (*const u8, usize) {
+        (self.buf.as_ptr(), self.len)
+    }
+}
+
+// write!マクロで書式付きの文字列を詰められるようにする。
+// 入り切らない場合は入る分だけ書いてErrを返す。
+impl fmt::Write for LineBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let space = LINE_BUF_LEN - self.len;
+        let n = s.len().min(space);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        if n < s.len() {
+            Err(fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+enum TxState {
+    // DMAは空いている。channelとwriterと空のバッファを持っている。
+    Idle(StatusDmaChannel, LineBuf, UartWriter),
+    // 転送中。バッファの所有権はTransferにある。
+    Busy(dma::single_buffer::Transfer<StatusDmaChannel, LineBuf, UartWriter>),
+}
+
+pub struct StatusTx {
+    // 所有権を取り出して状態を進めるためにOptionで包んでいる。
+    // poll()の途中以外は常にSome。
+    state: Option<TxState>,
+    pending: LineBuf,
+}
+
+impl StatusTx {
+    // 2面分のバッファをsingleton!で静的に確保するので、この関数は1回しか呼べない。
+    pub fn new(channel: StatusDmaChannel, writer: UartWriter) -> Self {
+        let front = cortex_m::singleton!(: [u8; LINE_BUF_LEN] = [0; LINE_BUF_LEN]).unwrap();
+        let back = cortex_m::singleton!(: [u8; LINE_BUF_LEN] = [0; LINE_BUF_LEN]).unwrap();
+        Self {
+            state: Some(TxState::Idle(channel, LineBuf::new(back), writer)),
+            pending: LineBuf::new(front),
+        }
+    }
+
+    // 書き込み側のバッファに1行追記する。
+    // バッファが満杯で行の全体が入らなかった場合は、途中まで書いた分を取り消してfalseを返す。
+    pub fn write_line(&mut self, args: fmt::Arguments) -> bool {
+        use fmt::Write;
+        let len = self.pending.len;
+        let written =
+            self.pending.write_fmt(args).is_ok() && self.pending.write_str("\r\n").is_ok();
+        if !written {
+            self.pending.len = len;
+        }
+        written
+    }
+
+    // write_line()と同じだが、バッファに空きができるまで転送の完了を待つ。
+    // 起動時のバナーやdiagコマンドのように、まとめて何行も送るときだけに使う。
+    // 空のバッファにも入りきらない長い行はfalseを返す。
+    pub fn write_line_blocking(&mut self, args: fmt::Arguments) -> bool {
+        loop {
+            if self.write_line(args) {
+                return true;
+            }
+            if self.pending.is_empty() {
+                return false;
+            }
+            self.poll();
+        }
+    }
+
+    // 書き込み側のバッファと転送中の行をすべて送り終えるまで待つ。
+    // DMAが送り終えても、UARTの送信FIFOに残った分（最大32バイト）はまだ送っている途中なので、それも待つ。
+    pub fn flush(&mut self) {
+        loop {
+            self.poll();
+            if let Some(TxState::Idle(..)) = self.state {
+                if self.pending.is_empty() {
+                    break;
+                }
+            }
+        }
+        // UART0のFRのBUSYは、送信FIFOが空になって最後のビットを送り終えるまで立っている
+        let uart = unsafe { &*pac::UART0::ptr() };
+        while uart.uartfr().read().busy().bit_is_set() {}
+    }
+
+    // メインループから毎周呼ぶ。
+    // 転送が終わっていればバッファを回収し、送るものがあれば次の転送を開始する。
+    pub fn poll(&mut self) {
+        let state = match self.state.take().unwrap() {
+            TxState::Busy(transfer) if transfer.is_done() => {
+                let (channel, mut buf, writer) = transfer.wait();
+                buf.clear();
+                TxState::Idle(channel, buf, writer)
+            }
+            state => state,
+        };
+
+        self.state = Some(match state {
+            TxState::Idle(channel, mut buf, writer) if !self.pending.is_empty() => {
+                core::mem::swap(&mut buf, &mut self.pending);
+                TxState::Busy(dma::single_buffer::Config::new(channel, buf, writer).start())
+            }
+            state => state,
+        });
+    }
+}
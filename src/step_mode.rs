@@ -0,0 +1,46 @@
+// ボタンを押すたびにTIMER_IRQ_0の処理を1回だけ進める、コマ送りのデバッグモード
+//
+// 有効にするとALARM0の割り込みを止め、タイマーでは点滅が進まないようにする。
+// かわりにタクトスイッチ（GPIO15）を短押しするたびに、TIMER_IRQ_0と同じ処理（do_tick()）を
+// メインループから1回だけ呼ぶ。LEDの切り替え1回と割り込みカウンタの1回分がボタン1回に対応するので、
+// 点滅やプリスケーラ、blink_times()の回数などの動きを人の速さで1つずつ追ってログで確かめられる。
+//
+// コマ送りの間の決まり
+//   ・短押しはモードの切り替えに使わない。押した瞬間のタップテンポも測らない
+//   ・長押しの設定リセットはそのまま使える
+//   ・blink_times()やトグルボタンで点滅をやり直しても、ALARM0の割り込みは止めたまま
+//   ・無効に戻すと、次の点滅間隔でALARM0を動かし直す（blink_times()で止まっていれば止めたまま）
+// コマ送りの間は割り込みカウンタがボタンを押したときしか進まないが、heartbeatはこれを異常とはみなさない。
+
+use crate::blink_count;
+use crate::cs_trace::free;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp_pico::hal::timer::Alarm;
+
+static STEPPING: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+pub fn is_enabled(cs: &CriticalSection) -> bool {
+    STEPPING.borrow(cs).get()
+}
+
+pub fn set_enabled(enabled: bool) {
+    free(|cs| {
+        if STEPPING.borrow(cs).replace(enabled) == enabled {
+            return;
+        }
+        if enabled {
+            crate::shared::with(cs, |shared| {
+                shared.alarm0.disable_interrupt();
+                shared.alarm0.clear_interrupt();
+            });
+        } else if !blink_count::is_stopped(cs) {
+            crate::restart_blink(cs);
+        }
+    });
+}
+
+// ボタンの短押しで呼ぶ。1回分進めたあとの割り込みカウンタの値を返す。
+pub fn step() -> u32 {
+    free(crate::do_tick)
+}
@@ -0,0 +1,57 @@
+// ボタンを2回タップした間隔を点滅間隔にする「タップテンポ」
+//
+// 1回目のタップで時刻を覚え、2回目のタップでその差を点滅間隔にする。
+// 3回目のタップは新しい計測の1回目として扱う。
+// 1回目からTAP_TIMEOUT_MS以内に2回目が来なければ計測を取り消す。
+
+use rp2040_project_template::time;
+use rp_pico::hal::timer::Instant;
+
+pub const TAP_TIMEOUT_MS: u32 = 3000;
+// 計測した間隔はこの範囲に丸める
+pub const TAP_MIN_INTERVAL_MS: u32 = 50;
+pub const TAP_MAX_INTERVAL_MS: u32 = 5000;
+
+assert_alarm_interval_ms!(TAP_TIMEOUT_MS);
+assert_alarm_interval_ms!(TAP_MAX_INTERVAL_MS);
+
+#[derive(Default)]
+pub struct TapTempo {
+    first_tap: Option<Instant>,
+}
+
+impl TapTempo {
+    // タップされたときに呼ぶ。2回目のタップなら新しい点滅間隔を返す。
+    pub fn tap(&mut self, now: Instant) -> Option<u32> {
+        match self.first_tap.take() {
+            Some(first) if !Self::timed_out(first, now) => {
+                let elapsed_ms = time::elapsed_us(now.ticks(), first.ticks()) / 1000;
+                let interval = elapsed_ms.clamp(
+                    u64::from(TAP_MIN_INTERVAL_MS),
+                    u64::from(TAP_MAX_INTERVAL_MS),
+                );
+                Some(interval as u32)
+            }
+            // 最初のタップ、またはタイムアウト済みの計測の後のタップ
+            _ => {
+                self.first_tap = Some(now);
+                None
+            }
+        }
+    }
+
+    // メインループから呼ぶ。タイムアウトで計測を取り消したらtrueを返す。
+    pub fn poll(&mut self, now: Instant) -> bool {
+        match self.first_tap {
+            Some(first) if Self::timed_out(first, now) => {
+                self.first_tap = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn timed_out(first: Instant, now: Instant) -> bool {
+        time::elapsed_us(now.ticks(), first.ticks()) > u64::from(TAP_TIMEOUT_MS) * 1000
+    }
+}
@@ -0,0 +1,86 @@
+// チップ内蔵の温度センサーによる熱制御（サーマルスロットリング）
+//
+// RP2040のADCの入力4には温度センサーがつながっている。
+// データシートの式で電圧から温度に変換する。
+//
+//   温度[°C] = 27 - (電圧[V] - 0.706) / 0.001721
+//
+// センサーの個体差は数°Cあるので、閾値は余裕を持たせている。
+// 温度がTHERMAL_ENTER_MCを超えたら「スロットリング中」にし、
+// THERMAL_EXIT_MCを下回るまで解除しない（ヒステリシス）。
+// スロットリング中、TIMER_IRQ_0は点滅間隔をTHERMAL_INTERVAL_FACTOR倍にし、
+// LEDのデューティを1/THERMAL_DUTY_DIVISORにする。
+//
+// LEDの点滅くらいでRP2040が熱くなることはまずないが、
+// 「測る → フラグを立てる → 割り込み側がフラグを見て動作を落とす」という構造は
+// モーターやヒーターを駆動する場合にもそのまま使える。
+
+use crate::cs_trace::free;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp_pico::hal::adc::{Adc, TempSense};
+
+// ADCのread()はembedded-hal 0.2のOneShotトレイトで宣言されている。
+use embedded_hal_0_2::adc::OneShot;
+
+// 温度の単位はm°C（1/1000 °C）
+pub const THERMAL_ENTER_MC: i32 = 60_000;
+pub const THERMAL_EXIT_MC: i32 = 50_000;
+pub const THERMAL_SAMPLE_INTERVAL_MS: u32 = 1000;
+assert_alarm_interval_ms!(THERMAL_SAMPLE_INTERVAL_MS);
+pub const THERMAL_INTERVAL_FACTOR: u32 = 2;
+pub const THERMAL_DUTY_DIVISOR: u16 = 4;
+
+const _: () = assert!(THERMAL_EXIT_MC < THERMAL_ENTER_MC);
+
+static THROTTLED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+pub struct DieTemp {
+    sensor: TempSense,
+}
+
+impl DieTemp {
+    // 温度センサーを有効にする。1回しか呼べない。
+    pub fn new(adc: &mut Adc) -> Self {
+        Self {
+            sensor: adc.take_temp_sensor().unwrap(),
+        }
+    }
+
+    pub fn read_millicelsius(&mut self, adc: &mut Adc) -> i32 {
+        // 12bitで、3.3Vが4096に相当する
+        let raw: u16 = adc.read(&mut self.sensor).unwrap();
+        let microvolts = i32::from(raw) * 3_300_000 / 4096;
+        27_000 - (microvolts - 706_000) * 1000 / 1721
+    }
+}
+
+// 新しい温度でスロットリングの状態を更新する。状態が変わったときは新しい状態を返す。
+pub fn update(temp_mc: i32) -> Option<bool> {
+    free(|cs| {
+        let throttled = THROTTLED.borrow(cs);
+        let next = if throttled.get() {
+            temp_mc >= THERMAL_EXIT_MC
+        } else {
+            temp_mc > THERMAL_ENTER_MC
+        };
+        (throttled.replace(next) != next).then_some(next)
+    })
+}
+
+pub fn is_throttled(cs: &CriticalSection) -> bool {
+    THROTTLED.borrow(cs).get()
+}
+
+// TIMER_IRQ_0から呼ぶ。スロットリング中ならデューティと次の更新までの時間を落とす。
+pub fn throttle(cs: &CriticalSection, duty: u16, next_ms: u32) -> (u16, u32) {
+    if !is_throttled(cs) {
+        return (duty, next_ms);
+    }
+    (
+        duty / THERMAL_DUTY_DIVISOR,
+        next_ms
+            .saturating_mul(THERMAL_INTERVAL_FACTOR)
+            .min(crate::MAX_ALARM_INTERVAL_MS),
+    )
+}
@@ -0,0 +1,76 @@
+// タイマーの1µsの基準（clk_tick）を変える実験用の機能（tick-sourceフィーチャー）
+//
+// RP2040のTIMERはWATCHDOGブロックのTICKレジスタが作るclk_tickを1µsとして数えている。
+// TICKはclk_ref（Picoでは12 MHzの水晶）をCYCLESで割ってclk_tickを作り、
+// 通常はinit_clocks_and_plls()がCYCLESをclk_refのMHz（12）にして、ちょうど1 MHzにしている。
+//
+// set_tick_divisor()でCYCLESを変えると、タイマーが数える「1µs」の実際の長さが変わる。
+//   実際の1µs = CYCLES / clk_refの周波数
+// 例えばCYCLESを6にするとタイマーは2倍速で進み、1000 msの点滅は実際には500 msになる。
+//
+// 注意：ALARM0〜3、タップテンポ、ボタンのデバウンス、DS18B20の待ち時間、1-Wireのタイミングなど、
+// タイマーを使う時間はすべて同じ割合でずれる。1-WireやI2Cの復旧のようにデバイス側の時間が
+// 決まっているものは動かなくなることがあるので、精度の実験以外では有効にしないこと。
+// WATCHDOG自体のカウントダウンもclk_tickで数えるので、WDを使う場合はその時間もずれる。
+//
+// 確かめ方：タイマーとは別に、clk_sysで動くSysTickで1 msごとに数える（reference_tick()）。
+// メインループはタイマーで測った経過時間とSysTickで測った経過時間を並べてログに出すので、
+// 点滅の周期が分周比の分だけ変わったことをデバッガだけで確かめられる（tick_check()）。
+
+use crate::cs_trace::free;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use cortex_m::peripheral::{syst::SystClkSource, SYST};
+use rp_pico::hal::watchdog::Watchdog;
+
+// 実験で使う分周比。clk_refが12 MHzなのでタイマーは2倍速になる。
+pub const TEST_TICK_CYCLES: u8 = 6;
+
+// SysTickで数えた経過時間（ms）。タイマーのずれを確かめる基準。
+static REFERENCE_MS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+// clk_tickの分周比を変え、タイマーが実際に数える速さ（Hz）を返す。0は1として扱う。
+// タイマーを使うすべての時間に影響する（上の注意を参照）。
+pub fn set_tick_divisor(watchdog: &mut Watchdog, ref_hz: u32, cycles: u8) -> u32 {
+    let cycles = cycles.max(1);
+    watchdog.enable_tick_generation(cycles);
+    let tick_hz = ref_hz / u32::from(cycles);
+    defmt::warn!(
+        "timer tick source: clk_ref {} Hz / {} = {} Hz (nominal 1000000 Hz)",
+        ref_hz,
+        cycles,
+        tick_hz
+    );
+    tick_hz
+}
+
+// SysTickをclk_sysで1 msごとに割り込ませる
+pub fn start_reference(mut syst: SYST, system_clock_hz: u32) {
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(system_clock_hz / 1000 - 1);
+    syst.clear_current();
+    syst.enable_interrupt();
+    syst.enable_counter();
+}
+
+// SysTickの例外から呼ぶ
+pub fn reference_tick(cs: &CriticalSection) {
+    let ms = REFERENCE_MS.borrow(cs);
+    ms.set(ms.get().wrapping_add(1));
+}
+
+pub fn reference_ms() -> u32 {
+    free(|cs| REFERENCE_MS.borrow(cs).get())
+}
+
+// タイマーで測った経過時間と、前回からSysTickで測った経過時間を並べて出す。
+// 今のSysTickの値を返すので、次の呼び出しに渡す。
+pub fn tick_check(timer_elapsed_us: u64, reference_old_ms: u32) -> u32 {
+    let reference = reference_ms();
+    defmt::info!(
+        "tick check: timer {} ms = {} ms by SysTick",
+        timer_elapsed_us / 1000,
+        reference.wrapping_sub(reference_old_ms)
+    );
+    reference
+}
@@ -0,0 +1,231 @@
+// 64bitのタイマーカウンタ（1µs単位）の時刻計算
+//
+// 「今の時刻 + 間隔」や「期限 - 今の時刻」を各所でそのまま書くと、
+// オーバーフローでパニックしたり、期限の比較が逆転したりする。
+// 時刻の計算はこのモジュールの関数を通して行う。
+//
+// カウンタは1MHzで進むので、64bitが一周するのは約58万年後で、実際には一周しない。
+// そのため時刻は一周しないものとして扱い、足し算は飽和させる。
+// u64::MAX付近では期限がu64::MAXに張り付くが、「期限を過ぎたか」の判定は正しく行える。
+//
+// ALARMにscheduleする間隔はすべてclamp_interval()を通し、MIN_INTERVAL_US〜MAX_INTERVAL_USに収める。
+//   MIN_INTERVAL_US（100 µs）
+//     UARTやADCから0や1 µsのような間隔が来ても、割り込みだけでCPUが埋まらないようにする下限。
+//     1回の割り込みの処理は数µs〜数十µsなので、100 µsごとでもメインループは回り続ける。
+//     すでに過ぎた時刻を指定すると次の一周（約71分後）まで発火しないので、「すぐに」の意味でもこの間隔を使う。
+//   MAX_INTERVAL_US（4294967000 µs、約71分）
+//     ALARMは32bitのµsで比べるので、u32::MAX µsまでしかscheduleできない。
+//     ミリ秒の間隔の上限（main.rsのMAX_ALARM_INTERVAL_MS）とそろえて、1000の倍数にしている。
+//
+// 周期的な割り込みを、前の期限からの絶対時刻でscheduleし直す（next_deadline()）
+//   schedule()（今の時刻 + 間隔）でscheduleし直すと、割り込みの入り口からscheduleするまでの時間の分だけ
+//   毎回遅れがたまる。main.rsのALARM0はreschedule()（中でnext_deadline()を使う）でscheduleし直している。
+//   next_deadline()は「前の期限 + 間隔」を次の期限にするので、何回くり返しても遅れがたまらない。
+//   短い間隔では、次の期限が今の時刻に近いか、すでに過ぎていることがある。
+//     ・ALARMは下位32bitが一致したときに発火するので、書いた時点で過ぎている値だと一周（約71分）発火しない。
+//       HALのschedule_at()は書いたあとで時刻を比べ、過ぎていれば割り込みを強制的に立てるが、
+//       比べたときにちょうど期限の時刻だと「過ぎていない」と判定するので、一致を逃すと取りこぼす。
+//       そこで期限がSCHEDULE_MARGIN_US以内に迫っていたら、ALARMには今の時刻 + SCHEDULE_MARGIN_USを書く
+//       （発火が少し遅れるだけで、期限そのものはずらさないので次の期限も遅れない）。
+//     ・期限を1周期以上過ぎていたら（割り込みが長く待たされた）、過ぎた周期は飛ばして、その数を返す。
+//       飛ばさずに続けて発火させると、遅れを取り戻すまで割り込みが続けて入り、1回の期限で何回も発火したように見える。
+//   nowはscheduleする直前に読んだ時刻にすること（読んでから書くまでに割り込まれると、そのぶん余裕が減る）。
+//   この方法で取りこぼしも二重の発火もなく回せるのは、割り込みの処理が間隔より十分に短い間で、
+//   実機のテスト（tests/alarm_reschedule.rs）ではMIN_INTERVAL_US（100 µs）まで確かめている。
+
+use fugit::ExtU32;
+use rp_pico::hal::timer::{Alarm, Instant};
+
+// baseからdelta_usだけ後の時刻。u64::MAXを超える場合はu64::MAXになる。
+pub fn add_interval(base: u64, delta_us: u32) -> u64 {
+    base.saturating_add(u64::from(delta_us))
+}
+
+// nowがdeadlineに達していればtrue
+pub fn deadline_passed(now: u64, deadline: u64) -> bool {
+    now >= deadline
+}
+
+// sinceからnowまでの経過時間。nowがsinceより前（呼び出し側の取り違えなど）なら0。
+pub fn elapsed_us(now: u64, since: u64) -> u64 {
+    now.saturating_sub(since)
+}
+
+pub const MIN_INTERVAL_US: u32 = 100;
+pub const MAX_INTERVAL_US: u32 = u32::MAX / 1000 * 1000;
+
+// ALARMにscheduleする間隔を、MIN_INTERVAL_US〜MAX_INTERVAL_USに収める
+pub fn clamp_interval(us: u32) -> u32 {
+    us.clamp(MIN_INTERVAL_US, MAX_INTERVAL_US)
+}
+
+// 期限がこれより近ければ、ALARMに書く時刻をこれだけ先にする
+pub const SCHEDULE_MARGIN_US: u64 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct NextDeadline {
+    // 次の期限。次にnext_deadline()を呼ぶときのpreviousにする。
+    pub deadline: u64,
+    // ALARMに書く時刻（deadlineか、迫っていれば今の時刻 + SCHEDULE_MARGIN_US）
+    pub alarm_at: u64,
+    // 間に合わずに飛ばした周期の数
+    pub skipped: u32,
+}
+
+// 前の期限previousからinterval_usごとの、次の期限（interval_usはclamp_interval()で収める）
+pub fn next_deadline(previous: u64, interval_us: u32, now: u64) -> NextDeadline {
+    let interval_us = clamp_interval(interval_us);
+    let interval = u64::from(interval_us);
+    let mut deadline = add_interval(previous, interval_us);
+    let mut skipped = 0;
+    // 1周期以上過ぎていれば、今の時刻から1周期以内の期限まで進める
+    let late = elapsed_us(now, deadline);
+    if late >= interval {
+        let periods = late / interval;
+        deadline = deadline.saturating_add(periods * interval);
+        skipped = u32::try_from(periods).unwrap_or(u32::MAX);
+    }
+    NextDeadline {
+        deadline,
+        alarm_at: deadline.max(now.saturating_add(SCHEDULE_MARGIN_US)),
+        skipped,
+    }
+}
+
+// 周期的な割り込みのALARMを、前の期限previousからinterval_usだけ後の期限（next_deadline()）にscheduleし直す。
+// 割り込みの処理の中で、alarm.clear_interrupt()の後に呼ぶこと。HALのclear_interrupt()は強制した割り込み（INTF）も
+// 消すので、後で呼ぶと、schedule_at()が過ぎた期限のために立てた割り込みまで消して取りこぼす。
+// nowはscheduleする直前に読んだ時刻。返したdeadlineを次のpreviousにする。
+// ファームウェアのALARM0（main.rsのreschedule_alarm0()）と実機のテスト（tests/alarm_reschedule.rs）は、どちらもこれを通す。
+// ALARMを書くだけでペリフェラルを直接は触らないが、ホストのテストでは呼ばない（実機のテストで確かめる）。
+pub fn reschedule(
+    alarm: &mut impl Alarm,
+    previous: u64,
+    interval_us: u32,
+    now: u64,
+) -> NextDeadline {
+    let next = next_deadline(previous, interval_us, now);
+    // 期限は今から1周期以内なのでschedule_at()は失敗しない。
+    // 失敗したとしてもpanicせず、今の時刻から1周期後を期限にして数え直す。
+    if alarm
+        .schedule_at(Instant::from_ticks(next.alarm_at))
+        .is_err()
+    {
+        let interval_us = clamp_interval(interval_us);
+        // 範囲に収めているので失敗しない
+        alarm.schedule(interval_us.micros()).ok();
+        let deadline = add_interval(now, interval_us);
+        return NextDeadline {
+            deadline,
+            alarm_at: deadline,
+            skipped: next.skipped,
+        };
+    }
+    next
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_interval_adds_normally() {
+        assert_eq!(add_interval(1_000, 500), 1_500);
+        assert_eq!(add_interval(0, u32::MAX), u64::from(u32::MAX));
+    }
+
+    #[test]
+    fn add_interval_saturates_near_max() {
+        assert_eq!(add_interval(u64::MAX - 10, 5), u64::MAX - 5);
+        assert_eq!(add_interval(u64::MAX - 10, 10), u64::MAX);
+        assert_eq!(add_interval(u64::MAX - 10, 11), u64::MAX);
+        assert_eq!(add_interval(u64::MAX, u32::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn deadline_passed_at_and_after_deadline() {
+        assert!(!deadline_passed(999, 1_000));
+        assert!(deadline_passed(1_000, 1_000));
+        assert!(deadline_passed(1_001, 1_000));
+    }
+
+    #[test]
+    fn deadline_near_max_is_not_considered_passed_early() {
+        // 飽和した期限がu64::MAX付近で「過去」に見えてしまわないこと
+        let deadline = add_interval(u64::MAX - 100, 1_000);
+        assert!(!deadline_passed(u64::MAX - 100, deadline));
+        assert!(!deadline_passed(u64::MAX - 1, deadline));
+        assert!(deadline_passed(u64::MAX, deadline));
+    }
+
+    #[test]
+    fn elapsed_never_underflows() {
+        assert_eq!(elapsed_us(1_500, 1_000), 500);
+        assert_eq!(elapsed_us(1_000, 1_500), 0);
+        assert_eq!(elapsed_us(u64::MAX, 0), u64::MAX);
+    }
+
+    #[test]
+    fn clamp_interval_keeps_values_within_bounds() {
+        assert_eq!(clamp_interval(0), MIN_INTERVAL_US);
+        assert_eq!(clamp_interval(MIN_INTERVAL_US - 1), MIN_INTERVAL_US);
+        assert_eq!(clamp_interval(MIN_INTERVAL_US), MIN_INTERVAL_US);
+        assert_eq!(clamp_interval(500_000), 500_000);
+        assert_eq!(clamp_interval(MAX_INTERVAL_US), MAX_INTERVAL_US);
+        assert_eq!(clamp_interval(MAX_INTERVAL_US + 1), MAX_INTERVAL_US);
+        assert_eq!(clamp_interval(u32::MAX), MAX_INTERVAL_US);
+    }
+
+    #[test]
+    fn next_deadline_does_not_drift() {
+        // 割り込みが遅れて入っても、次の期限は前の期限から数える
+        let next = next_deadline(1_000, 100, 1_030);
+        assert_eq!(next.deadline, 1_100);
+        assert_eq!(next.alarm_at, 1_100);
+        assert_eq!(next.skipped, 0);
+        let next = next_deadline(next.deadline, 100, 1_180);
+        assert_eq!(next.deadline, 1_200);
+    }
+
+    #[test]
+    fn next_deadline_close_to_now_is_written_with_margin() {
+        let next = next_deadline(1_000, 100, 1_099);
+        assert_eq!(next.deadline, 1_100);
+        assert_eq!(next.alarm_at, 1_099 + SCHEDULE_MARGIN_US);
+        let next = next_deadline(1_000, 100, 1_100);
+        assert_eq!(next.alarm_at, 1_100 + SCHEDULE_MARGIN_US);
+    }
+
+    #[test]
+    fn next_deadline_less_than_a_period_late_fires_at_once() {
+        let next = next_deadline(1_000, 100, 1_150);
+        assert_eq!(next.deadline, 1_100);
+        assert_eq!(next.alarm_at, 1_150 + SCHEDULE_MARGIN_US);
+        assert_eq!(next.skipped, 0);
+    }
+
+    #[test]
+    fn next_deadline_skips_whole_missed_periods() {
+        let next = next_deadline(1_000, 100, 1_350);
+        assert_eq!(next.deadline, 1_300);
+        assert_eq!(next.alarm_at, 1_350 + SCHEDULE_MARGIN_US);
+        assert_eq!(next.skipped, 2);
+        // ちょうど1周期遅れたら、その周期を飛ばして今の期限で発火させる
+        let next = next_deadline(1_000, 100, 1_200);
+        assert_eq!(next.deadline, 1_200);
+        assert_eq!(next.skipped, 1);
+    }
+
+    #[test]
+    fn next_deadline_clamps_interval() {
+        let next = next_deadline(1_000, 0, 1_000);
+        assert_eq!(next.deadline, 1_000 + u64::from(MIN_INTERVAL_US));
+    }
+
+    #[test]
+    fn next_deadline_saturates_near_max() {
+        let next = next_deadline(u64::MAX - 10, 100, u64::MAX - 10);
+        assert_eq!(next.deadline, u64::MAX);
+        assert_eq!(next.skipped, 0);
+    }
+}
@@ -0,0 +1,185 @@
+// 期限（deadline）の近い順に並べたソフトウェアタイマーの一覧
+//
+// ハードウェアのALARMは4つしかないので、それより多くのタイムアウトを同時に待つときは
+// この一覧に期限を入れておき、1つのALARMをいちばん近い期限に合わせて発火させる（soft_timer.rs）。
+// 時刻は64bitのタイマーカウンタ（µs）で、一周しないものとして扱う（time.rsと同じ）。
+//
+// 構造
+//   固定長の配列に、期限の遠い順に並べて入れる。いちばん近い期限は配列の最後にある。
+//     ・いちばん近い期限を見る、取り出す : O(1)（最後の要素を見る/外すだけ）
+//     ・追加                             : O(n)（入れる場所より後ろを1つずつずらす）
+//     ・取り消し、同じidの置き換え       : O(n)（idを探してから詰める）
+//   タイマーホイール（時間を区切ったバケツの輪）なら追加もO(1)にできるが、
+//   バケツを進めるために一定の周期で割り込みを入れ続ける必要があり、分解能もその周期で決まる。
+//   この一覧は期限が来たときにだけ割り込めばよく、µs単位の期限をそのまま扱える。
+//   入る数はN個（数十個まで）なので、O(n)でも数µsで終わる。
+//
+// 同じ期限のタイマー
+//   同じ期限のものは、先に追加したものから取り出す（追加した順を守る）。
+//   追加するときは、同じ期限のものより配列の前（取り出すのが後）に入れる。
+//
+// idは呼び出し側が決める番号で、同じidを追加すると前のものを置き換える（取り消してから追加する）。
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Entry<T> {
+    pub deadline: u64,
+    pub id: u8,
+    pub payload: T,
+}
+
+pub struct TimerList<T, const N: usize> {
+    // 0..lenに、期限の遠い順に並んでいる
+    entries: [Option<Entry<T>>; N],
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> TimerList<T, N> {
+    // グローバル変数の初期値に使えるようにconst fnにしている
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; N],
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn find(&self, id: u8) -> Option<usize> {
+        self.entries[..self.len]
+            .iter()
+            .position(|e| e.is_some_and(|e| e.id == id))
+    }
+
+    // idのタイマーを取り消す。取り消すものがあればtrue。
+    pub fn cancel(&mut self, id: u8) -> bool {
+        let Some(index) = self.find(id) else {
+            return false;
+        };
+        self.entries.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+        self.entries[self.len] = None;
+        true
+    }
+
+    // deadlineにidのタイマーを追加する。同じidがあれば置き換える。いっぱいならfalse。
+    pub fn insert(&mut self, deadline: u64, id: u8, payload: T) -> bool {
+        self.cancel(id);
+        if self.len == N {
+            return false;
+        }
+        // 期限がdeadlineより遠いものの後ろ、同じ期限のものの前に入れる
+        let index = self.entries[..self.len]
+            .iter()
+            .take_while(|e| e.is_some_and(|e| e.deadline > deadline))
+            .count();
+        self.entries.copy_within(index..self.len, index + 1);
+        self.entries[index] = Some(Entry {
+            deadline,
+            id,
+            payload,
+        });
+        self.len += 1;
+        true
+    }
+
+    // いちばん近い期限
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.len
+            .checked_sub(1)
+            .and_then(|last| self.entries[last])
+            .map(|e| e.deadline)
+    }
+
+    // 期限がnowまでに来ているものを、近い順に1つ取り出す
+    pub fn pop_due(&mut self, now: u64) -> Option<Entry<T>> {
+        if self.next_deadline()? > now {
+            return None;
+        }
+        self.len -= 1;
+        self.entries[self.len].take()
+    }
+}
+
+impl<T: Copy, const N: usize> Default for TimerList<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // nowまでに期限が来たもののidを、取り出した順に返す
+    fn drain(list: &mut TimerList<(), 8>, now: u64) -> Vec<u8> {
+        core::iter::from_fn(|| list.pop_due(now))
+            .map(|e| e.id)
+            .collect()
+    }
+
+    #[test]
+    fn pops_in_deadline_order_regardless_of_insert_order() {
+        let mut list = TimerList::<(), 8>::new();
+        for (deadline, id) in [(300, 3), (100, 1), (400, 4), (200, 2)] {
+            assert!(list.insert(deadline, id, ()));
+        }
+        assert_eq!(list.next_deadline(), Some(100));
+        assert_eq!(drain(&mut list, 250), [1, 2]);
+        assert_eq!(list.next_deadline(), Some(300));
+        assert_eq!(drain(&mut list, 1000), [3, 4]);
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn same_deadline_pops_in_insert_order() {
+        let mut list = TimerList::<(), 8>::new();
+        list.insert(500, 7, ());
+        list.insert(100, 1, ());
+        list.insert(500, 5, ());
+        list.insert(500, 6, ());
+        assert_eq!(drain(&mut list, 500), [1, 7, 5, 6]);
+    }
+
+    #[test]
+    fn nothing_pops_before_its_deadline() {
+        let mut list = TimerList::<(), 8>::new();
+        list.insert(100, 1, ());
+        assert_eq!(list.pop_due(99), None);
+        assert_eq!(list.len(), 1);
+        assert_eq!(drain(&mut list, 100), [1]);
+    }
+
+    #[test]
+    fn same_id_replaces_and_cancel_removes() {
+        let mut list = TimerList::<u32, 8>::new();
+        list.insert(100, 1, 10);
+        list.insert(200, 2, 20);
+        // id 1を200より後に置き換えると、2が先になる
+        list.insert(300, 1, 11);
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.pop_due(1000).map(|e| (e.id, e.payload)), Some((2, 20)));
+        assert_eq!(list.pop_due(1000).map(|e| (e.id, e.payload)), Some((1, 11)));
+
+        list.insert(100, 3, 30);
+        assert!(list.cancel(3));
+        assert!(!list.cancel(3));
+        assert_eq!(list.next_deadline(), None);
+    }
+
+    #[test]
+    fn insert_fails_when_full_but_replacing_still_works() {
+        let mut list = TimerList::<(), 2>::new();
+        assert!(list.insert(100, 1, ()));
+        assert!(list.insert(200, 2, ()));
+        assert!(!list.insert(150, 3, ()));
+        // 同じidなら空きがなくても置き換えられる
+        assert!(list.insert(50, 2, ()));
+        assert_eq!(list.next_deadline(), Some(50));
+    }
+}
@@ -0,0 +1,92 @@
+// GPIOの割り込み（IO_IRQ_BANK0）でLEDを消灯/点灯させるボタン
+//
+// タップテンポ用のボタン（GPIO15）はメインループからポーリングしているが、
+// こちらはタイマーとは関係なく、ボタンの立ち下がりエッジの割り込みで直接LEDを切り替える。
+// GPIO14とGNDの間にスイッチをつなぐ（内部プルアップ、押すとLow）。
+//
+// 押すたびにLedMode::Offと、Offにする前のモードを行き来する。
+//   ・消灯は割り込みの中ですぐにLEDに書き込む
+//   ・点灯に戻すときはALARM0をすぐに発火させ、TIMER_IRQ_0にそのモードの表示をさせる
+//     （その分、割り込みカウンタも1つ余計に進む）
+//
+// 注意：割り込みの要因（INTRレジスタのエッジのビット）はclear_interrupt()で消すまで残る。
+// 消さずに割り込みから戻ると、すぐにまたIO_IRQ_BANK0に入り続けてメインループが動かなくなる
+// （割り込みストーム）。そのため要因を確かめたら最初に消している。
+//
+// チャタリングでは1回押しただけで何度もエッジが来るので、
+// 前回受け付けてからTOGGLE_DEBOUNCE_MS以内のエッジは無視する。
+// その前に、Lowがフィルタの時間だけ続かなかったエッジはノイズとして捨てる（glitch_filter.rs）。
+
+use crate::glitch_filter;
+use crate::idle_off;
+use crate::led;
+use crate::mode::{self, LedMode};
+use crate::pull::{self, Pull};
+use crate::recorder;
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use embedded_hal::digital::InputPin;
+use rp2040_project_template::time;
+use rp_pico::hal::gpio;
+use rp_pico::hal::timer::Timer;
+
+pub const TOGGLE_BUTTON_PULL: Pull = Pull::Up;
+pub const TOGGLE_DEBOUNCE_MS: u32 = 50;
+
+pub type ToggleButtonPin = pull::InputPin<gpio::bank0::Gpio14>;
+
+static TOGGLE_PIN: GlobalPeripheral<ToggleButtonPin> = initial_global_peripheral();
+static TOGGLE_TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+// 最後にエッジを受け付けた時刻
+static LAST_EDGE: Mutex<Cell<Option<u64>>> = Mutex::new(Cell::new(None));
+// Offにする前のモード
+static SAVED_MODE: Mutex<Cell<LedMode>> = Mutex::new(Cell::new(mode::DEFAULT_MODE));
+
+pub fn init(cs: &CriticalSection, pin: ToggleButtonPin, timer: Timer) {
+    // 押したときの立ち下がりだけで割り込む
+    pin.set_interrupt_enabled(gpio::Interrupt::EdgeLow, true);
+    TOGGLE_PIN.borrow(cs).replace(Some(pin));
+    TOGGLE_TIMER.borrow(cs).set(Some(timer));
+}
+
+// IO_IRQ_BANK0から呼ぶ。LEDを点灯に戻したときはtrueを返すので、呼び出し側でALARM0を発火させる。
+pub fn on_interrupt(cs: &CriticalSection) -> bool {
+    let mut pin = TOGGLE_PIN.borrow(cs).borrow_mut();
+    let Some(pin) = pin.as_mut() else {
+        return false;
+    };
+    if !pin.interrupt_status(gpio::Interrupt::EdgeLow) {
+        return false;
+    }
+    pin.clear_interrupt(gpio::Interrupt::EdgeLow);
+
+    let Some(timer) = TOGGLE_TIMER.borrow(cs).get() else {
+        return false;
+    };
+    if !glitch_filter::is_stable(cs, &timer, || pin.is_low().unwrap()) {
+        return false;
+    }
+    let now = timer.get_counter().ticks();
+    let last = LAST_EDGE.borrow(cs);
+    if let Some(last_edge) = last.get() {
+        if time::elapsed_us(now, last_edge) < u64::from(TOGGLE_DEBOUNCE_MS) * 1000 {
+            return false;
+        }
+    }
+    last.set(Some(now));
+    idle_off::note_activity(cs);
+
+    let current = mode::mode(cs);
+    if current == LedMode::Off {
+        mode::set_mode(cs, SAVED_MODE.borrow(cs).get());
+        recorder::on_toggle(cs, now, true);
+        true
+    } else {
+        SAVED_MODE.borrow(cs).set(current);
+        mode::set_mode(cs, LedMode::Off);
+        led::write_led(cs, led::LED_OFF_DUTY);
+        recorder::on_toggle(cs, now, false);
+        false
+    }
+}
@@ -0,0 +1,115 @@
+// 圧電ブザー（またはスピーカー）から点滅に合わせて音を出すモジュール
+//
+// GPIO16とGNDの間に圧電ブザーをつなぐ。GPIO16はPWMスライス0のチャンネルA。
+// PWMを可聴域の周波数・デューティ50%で動かし、LEDが点灯している間だけ鳴らす。
+//
+// PWMの周波数は次の式で決まる。
+//
+//   周波数 = sys_clk / (分周比 × (TOP + 1))
+//
+// 分周比は1〜255（整数部のみ使う）、TOPは最大65535なので、
+// sys_clk = 125MHzでは約7.5Hz（255 × 65536）から数MHzまで作れる。
+// 実際に設定できるのは耳に聞こえるTONE_MIN_HZ〜TONE_MAX_HZに限っている。
+// 分周比をできるだけ小さくしてTOPを大きく取るので、誤差はどの周波数でも0.01%程度に収まる。
+//
+// 止めるときはスライスを無効にするのではなく、デューティを0にして出力をLowにする。
+// 無効にすると出力がHighのまま止まることがあり、次に鳴らしたときにプチッと音が出るため。
+
+use crate::cs_trace::free;
+use crate::output::{self, OutputId};
+use crate::shared;
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use embedded_hal::pwm::SetDutyCycle;
+use rp_pico::hal::pwm;
+
+pub type TonePwm = pwm::Slice<pwm::Pwm0, pwm::FreeRunning>;
+
+// 起動時の周波数
+pub const TONE_FREQ_HZ: u32 = 2000;
+pub const TONE_MIN_HZ: u32 = 20;
+pub const TONE_MAX_HZ: u32 = 20_000;
+
+// PWMスライスはALARM0と一緒にshared.rsのSHAREDに置いている
+static SYSTEM_CLOCK_HZ: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+// falseなら点灯していても鳴らさない
+static TONE_ENABLED: Mutex<Cell<bool>> = Mutex::new(Cell::new(true));
+// 設定した周波数
+static TONE_HZ: Mutex<Cell<u32>> = Mutex::new(Cell::new(TONE_FREQ_HZ));
+// 今鳴らしているか
+static TONE_ON: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+// スライスはenable()済みで、チャンネルAにGPIO16を割り当ててから、shared::init()でSHAREDに置いておく
+pub fn init(cs: &CriticalSection, system_clock_hz: u32) {
+    shared::with(cs, |shared| {
+        // 極性はoutput.rsの表で決める
+        if output::is_active_low(OutputId::Tone) {
+            shared.tone_pwm.channel_a.set_inverted();
+        }
+        shared.tone_pwm.channel_a.set_duty_cycle(0).unwrap()
+    });
+    SYSTEM_CLOCK_HZ.borrow(cs).set(system_clock_hz);
+    configure(cs, TONE_FREQ_HZ);
+}
+
+// 音の周波数を変える。TONE_MIN_HZ〜TONE_MAX_HZの範囲に丸め、実際に設定した周波数を返す。
+pub fn set_tone_freq(hz: u32) -> u32 {
+    free(|cs| configure(cs, hz))
+}
+
+pub fn tone_freq(cs: &CriticalSection) -> u32 {
+    TONE_HZ.borrow(cs).get()
+}
+
+pub fn set_enabled(enabled: bool) {
+    free(|cs| {
+        TONE_ENABLED.borrow(cs).set(enabled);
+        if !enabled {
+            gate(cs, false);
+        }
+    });
+}
+
+// LEDの点灯に合わせてTIMER_IRQ_0から呼ぶ。onならデューティ50%で鳴らし、そうでなければLowにする。
+pub fn gate(cs: &CriticalSection, on: bool) {
+    let on = on && TONE_ENABLED.borrow(cs).get();
+    TONE_ON.borrow(cs).set(on);
+    shared::with(cs, |shared| {
+        let slice = &mut shared.tone_pwm;
+        let duty = if on { duty_half(slice.get_top()) } else { 0 };
+        slice.channel_a.set_duty_cycle(duty).unwrap();
+    });
+}
+
+// TOP + 1カウントのうち半分をHighにする（TOP = 65535でもあふれないように書いている）
+fn duty_half(top: u16) -> u16 {
+    top / 2 + 1
+}
+
+// 周波数に合わせて分周比とTOPを設定する
+fn configure(cs: &CriticalSection, hz: u32) -> u32 {
+    let hz = hz.clamp(TONE_MIN_HZ, TONE_MAX_HZ);
+    TONE_HZ.borrow(cs).set(hz);
+    let (div, top) = divider_for(SYSTEM_CLOCK_HZ.borrow(cs).get(), hz);
+    let tone_on = TONE_ON.borrow(cs).get();
+    shared::with(cs, |shared| {
+        let slice = &mut shared.tone_pwm;
+        slice.set_div_int(div);
+        slice.set_div_frac(0);
+        slice.set_top(top);
+        // TOPが変わるとデューティ50%の値も変わるので、鳴らしている最中なら設定し直す
+        if tone_on {
+            slice.channel_a.set_duty_cycle(duty_half(top)).unwrap();
+        }
+    });
+    hz
+}
+
+// sys_clkからhzを作る分周比とTOPを求める。
+// TOPを65535以下に収められる最小の分周比を選ぶと、TOPが大きくなり分解能が最もよくなる。
+fn divider_for(system_clock_hz: u32, hz: u32) -> (u8, u16) {
+    let counts = system_clock_hz / hz;
+    let div = counts.div_ceil(1 << 16).clamp(1, 255);
+    let top = (counts / div).clamp(2, 1 << 16) - 1;
+    (div as u8, top as u16)
+}
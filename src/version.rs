@@ -0,0 +1,19 @@
+// ファームウェアのバージョン情報
+//
+// 現場に出した個体がどのビルドで動いているかを確認できるように、
+// 起動時のログとUARTのversionコマンドで出力する。
+// GIT_HASHとBUILD_TIMESTAMPはbuild.rsが環境変数として埋め込む。
+// gitが使えない環境でビルドした場合は"unknown"になる。
+
+use defmt::info;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_HASH: &str = env!("PICO_TIMER_GIT_HASH");
+pub const BUILD_TIMESTAMP: &str = env!("PICO_TIMER_BUILD_TIMESTAMP");
+
+pub fn log_version() {
+    info!(
+        "firmware version {} (git {}), built {}",
+        VERSION, GIT_HASH, BUILD_TIMESTAMP
+    );
+}
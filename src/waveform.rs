@@ -0,0 +1,102 @@
+// LEDの点灯/消灯をdefmtのログに0/1の値として出すモジュール
+//
+// ロジックアナライザの波形とLEDの動きを見比べるためのもの。
+// LEDの状態が変わるたびに、次の形の行を1行だけ出す。
+//
+//   wave led=1 t=12345678
+//
+// ledは点灯なら1、消灯なら0。tはタイマーのカウンタ（起動してからのµs）。
+// 値が変わった時刻しか出さないので、前の値を次の行の時刻まで保持すれば矩形波になる。
+//
+// 見方
+//   ・このモジュールのログだけを残すには、ビルド時にDEFMT_LOGでモジュールを指定する。
+//       DEFMT_LOG=off,rp2040_project_template::waveform=info cargo run
+//     逆に波形の行を消すには DEFMT_LOG=debug,rp2040_project_template::waveform=off とする。
+//   ・probe-rsの出力から "wave " で始まる行を取り出し、tとledを列にすれば
+//     表計算ソフトやgnuplotのステップ表示でそのまま描ける。
+//
+// ログの量が増えるので起動時は無効。UARTの wave on / wave off で切り替える。
+// LEDを書き換えるのはTIMER_IRQ_0などの割り込みの中なので、そこでは時刻と値を
+// ロックなしのキュー（rp2040_project_template::spsc）に積むだけにし、defmtへの出力は
+// メインループのdrain()で行う。キューがあふれた分は捨てて数え、次にdrain()したときに警告を出す。
+
+use crate::cs_trace::free;
+use crate::{initial_global_peripheral, GlobalPeripheral};
+use core::cell::Cell;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use rp2040_project_template::spsc::{Consumer, Producer, Queue};
+use rp_pico::hal::timer::Timer;
+
+// drain()はメインループの1周ごとに呼ぶので、1周の間に変わる回数より多くしておく
+pub const WAVEFORM_QUEUE_LEN: usize = 16;
+
+#[derive(Clone, Copy)]
+pub struct Sample {
+    at_us: u64,
+    on: bool,
+}
+
+pub type WaveformRx = Consumer<'static, Sample, WAVEFORM_QUEUE_LEN>;
+
+static WAVEFORM_ENABLED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+// 最後に出した値。状態が変わったときだけ出すために覚えておく。
+static WAVEFORM_LAST: Mutex<Cell<Option<bool>>> = Mutex::new(Cell::new(None));
+// タイムスタンプ用のタイマー。Timerはカウンタを読むだけの型なのでCopyでき、Cellに置ける。
+static WAVEFORM_TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+// キューの積む側。record()はいつもCriticalSectionの中で呼ばれるので、積む側は1つにまとまる。
+static WAVEFORM_TX: GlobalPeripheral<Producer<'static, Sample, WAVEFORM_QUEUE_LEN>> =
+    initial_global_peripheral();
+// キューがいっぱいで捨てた数。drain()で警告を出したら0に戻す。
+static WAVEFORM_DROPPED: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+// 1回しか呼べない（キューをsingleton!で確保するため）。取り出す側はメインループで持つ。
+pub fn init(cs: &CriticalSection, timer: Timer) -> WaveformRx {
+    WAVEFORM_TIMER.borrow(cs).set(Some(timer));
+    let queue = cortex_m::singleton!(: Queue<Sample, WAVEFORM_QUEUE_LEN> = Queue::new()).unwrap();
+    let (tx, rx) = queue.split();
+    WAVEFORM_TX.borrow(cs).replace(Some(tx));
+    rx
+}
+
+pub fn set_enabled(enabled: bool) {
+    free(|cs| {
+        WAVEFORM_ENABLED.borrow(cs).set(enabled);
+        // 有効にした直後は、変化がなくても今の値を1行出して波形の始まりにする
+        WAVEFORM_LAST.borrow(cs).set(None);
+    });
+}
+
+// LEDを書き換えるたびにled::write_led()から呼ぶ
+pub fn record(cs: &CriticalSection, on: bool) {
+    if !WAVEFORM_ENABLED.borrow(cs).get() {
+        return;
+    }
+    if WAVEFORM_LAST.borrow(cs).replace(Some(on)) == Some(on) {
+        return;
+    }
+    let Some(timer) = WAVEFORM_TIMER.borrow(cs).get() else {
+        return;
+    };
+    let sample = Sample {
+        at_us: timer.get_counter().ticks(),
+        on,
+    };
+    let mut tx = WAVEFORM_TX.borrow(cs).borrow_mut();
+    if let Some(tx) = tx.as_mut() {
+        if tx.push(sample).is_err() {
+            let dropped = WAVEFORM_DROPPED.borrow(cs);
+            dropped.set(dropped.get().wrapping_add(1));
+        }
+    }
+}
+
+// メインループから呼ぶ。積まれた変化を順にdefmtへ出す。取り出すのに割り込みは止めない。
+pub fn drain(rx: &mut WaveformRx) {
+    while let Some(sample) = rx.pop() {
+        defmt::info!("wave led={=u8} t={=u64}", u8::from(sample.on), sample.at_us);
+    }
+    let dropped = free(|cs| WAVEFORM_DROPPED.borrow(cs).replace(0));
+    if dropped > 0 {
+        defmt::warn!("wave: {=u32} edges dropped (queue full)", dropped);
+    }
+}
@@ -0,0 +1,171 @@
+// 「呼吸」のように明るさをなめらかに上げ下げするための波形
+//
+// 1周期の中の位置（位相）を0〜65535のu16で表し、その位置の明るさを0〜65535で返す。
+// 位相は65535の次が0に戻るので、位相を一定の速さで進めればそのまま周期的な波形になる。
+//   Triangle : 真ん中で最大になる三角形。変わる速さが一定なので、消灯と最大の付近で急に折り返して見える。
+//   Sine     : 1 - cosの形（二乗正弦）。消灯と最大の付近でゆっくりになり、自然に息をしているように見える。
+//
+// 正弦の表
+//   SINE_QUARTERはsin(0)〜sin(π/2)を SINE_QUARTER_STEPS（64）等分した65個の値（0〜65535）で、
+//   コンパイル時に計算する（const fnのテイラー展開。誤差は表の1目盛りより十分小さい）。
+//   残りの3/4周期は、1/4周期を左右（π/2を中心に）と上下（πを中心に）に折り返して求める。
+//   位相（u16）の上位2bitで4分の1周期のどこか、次の6bitで表の添字、下位8bitで隣の値との間を
+//   直線補間するので、1周期を65536分割した位相のどこでも使える。
+//
+// ガンマ補正
+//   目は明るさを対数に近い感じ方で見るので、デューティをそのまま波形にすると明るい側が間延びして見える。
+//   gamma_correct()で波形の値を2乗（ガンマ2.0。よく使われる2.2に近く、掛け算1回で済む）してから
+//   デューティにする。順番は「波形で形を決める → ガンマ補正 → デューティ」で、逆にするとガンマの
+//   曲がりが波形の形を崩してしまう。
+
+pub const SINE_QUARTER_STEPS: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Waveform {
+    Triangle,
+    Sine,
+}
+
+impl Waveform {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Waveform::Triangle => "triangle",
+            Waveform::Sine => "sine",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Waveform> {
+        [Waveform::Triangle, Waveform::Sine]
+            .into_iter()
+            .find(|w| w.name() == name)
+    }
+}
+
+// 0〜π/2のsin（テイラー展開を13次まで）
+const fn sin_quarter(x: f64) -> f64 {
+    let x2 = x * x;
+    let mut term = x;
+    let mut sum = x;
+    let mut n = 1;
+    while n < 7 {
+        term = -term * x2 / ((2 * n) as f64 * (2 * n + 1) as f64);
+        sum += term;
+        n += 1;
+    }
+    sum
+}
+
+const fn sine_quarter_table() -> [u16; SINE_QUARTER_STEPS + 1] {
+    let mut table = [0u16; SINE_QUARTER_STEPS + 1];
+    let mut i = 0;
+    while i <= SINE_QUARTER_STEPS {
+        let x = core::f64::consts::FRAC_PI_2 * i as f64 / SINE_QUARTER_STEPS as f64;
+        let v = sin_quarter(x) * u16::MAX as f64 + 0.5;
+        table[i] = if v >= u16::MAX as f64 {
+            u16::MAX
+        } else {
+            v as u16
+        };
+        i += 1;
+    }
+    table
+}
+
+pub const SINE_QUARTER: [u16; SINE_QUARTER_STEPS + 1] = sine_quarter_table();
+
+// 1/4周期の中の位置x（0〜16384）のsin。表の間は直線補間する。
+fn quarter(x: u32) -> u32 {
+    let index = (x >> 8) as usize;
+    let frac = x & 0xFF;
+    let a = u32::from(SINE_QUARTER[index]);
+    let Some(&b) = SINE_QUARTER.get(index + 1) else {
+        return a;
+    };
+    let b = u32::from(b);
+    // sinは0〜π/2で増えるだけなので、b >= a
+    a + (b - a) * frac / 256
+}
+
+// 位相phaseのsin（-65535〜65535）
+pub fn sine(phase: u16) -> i32 {
+    let within = u32::from(phase & 0x3FFF);
+    let value = match phase >> 14 {
+        0 => quarter(within),
+        1 => quarter(0x4000 - within),
+        2 => return -(quarter(within) as i32),
+        _ => return -(quarter(0x4000 - within) as i32),
+    };
+    value as i32
+}
+
+// 位相phaseでの明るさ（0〜65535）。位相0で消灯、32768で最大。
+pub fn level(waveform: Waveform, phase: u16) -> u16 {
+    match waveform {
+        Waveform::Triangle => {
+            let up = if phase < 0x8000 {
+                phase
+            } else {
+                0u16.wrapping_sub(phase)
+            };
+            (u32::from(up) * 2).min(u32::from(u16::MAX)) as u16
+        }
+        // (1 - cos) / 2。cosはsinの位相を1/4周期進めたもの。
+        Waveform::Sine => ((65535 - sine(phase.wrapping_add(0x4000))) / 2) as u16,
+    }
+}
+
+// 波形の値（見た目の明るさ）をデューティの割合に直す（ガンマ2.0）
+pub fn gamma_correct(level: u16) -> u16 {
+    (u32::from(level) * u32::from(level) / u32::from(u16::MAX)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quarter_table_rises_from_zero_to_full_scale() {
+        assert_eq!(SINE_QUARTER[0], 0);
+        assert_eq!(SINE_QUARTER[SINE_QUARTER_STEPS], u16::MAX);
+        assert!(SINE_QUARTER.windows(2).all(|w| w[0] < w[1]));
+        // sin(π/6) = 0.5は表の目盛りにちょうど来ないので、π/4 = 1/√2で確かめる
+        let mid = SINE_QUARTER[SINE_QUARTER_STEPS / 2];
+        assert!(mid.abs_diff((f64::from(u16::MAX) / 2f64.sqrt()).round() as u16) <= 1);
+    }
+
+    #[test]
+    fn mirrored_sine_and_breath_are_symmetric() {
+        for phase in 0..=u16::MAX {
+            // 前半と後半は上下に、π/2とπ*3/2を中心に左右に対称
+            assert_eq!(sine(phase), -sine(phase.wrapping_add(0x8000)));
+            assert_eq!(
+                sine(0x4000u16.wrapping_sub(phase)),
+                sine(0x4000u16.wrapping_add(phase))
+            );
+            // 呼吸は最大（32768）を中心に左右対称
+            for waveform in [Waveform::Triangle, Waveform::Sine] {
+                assert_eq!(
+                    level(waveform, phase),
+                    level(waveform, 0u16.wrapping_sub(phase))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn breath_starts_dark_and_peaks_in_the_middle() {
+        for waveform in [Waveform::Triangle, Waveform::Sine] {
+            assert_eq!(level(waveform, 0), 0);
+            assert_eq!(level(waveform, 0x8000), u16::MAX);
+        }
+        // 正弦は消灯の近くでゆっくり変わる
+        assert!(level(Waveform::Sine, 0x0800) < level(Waveform::Triangle, 0x0800));
+    }
+
+    #[test]
+    fn gamma_keeps_the_ends() {
+        assert_eq!(gamma_correct(0), 0);
+        assert_eq!(gamma_correct(u16::MAX), u16::MAX);
+        assert!(gamma_correct(0x8000) < 0x4100);
+    }
+}
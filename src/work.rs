@@ -0,0 +1,40 @@
+// 割り込みやワンショットタイマーから後で実行させる処理
+//
+// クロージャ（関数ポインタとキャプチャした値）はグローバル変数に入れにくく、
+// 何を実行するのかもログに出しにくいので、実行できる処理を列挙型で表す。
+// Copyにしてあるので、Cellに入れて割り込みとの間で受け渡せる。
+
+use crate::blink_burst;
+use crate::burst;
+use crate::mode::{self, LedMode};
+use cortex_m::interrupt::CriticalSection;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Work {
+    // LEDのモードを切り替える（消灯はLedMode::Off）
+    SetMode(LedMode),
+    // 今のモードに割り込んで、LEDを素早くN回点滅させる
+    Flash(u32),
+    // blink_burst_for()で変えた点滅の間隔を元に戻す（UARTからは指定できない）
+    EndBlinkBurst,
+}
+
+impl Work {
+    // 割り込みの中からも呼ぶので、短い処理だけにすること
+    pub fn run(self, cs: &CriticalSection) {
+        match self {
+            Work::SetMode(m) => mode::set_mode(cs, m),
+            Work::Flash(n) => burst::start(cs, n),
+            Work::EndBlinkBurst => blink_burst::end(cs),
+        }
+    }
+
+    // UARTのコマンドの引数から読み取る。モード名（solid, blink, number, off, heartbeat, bar, egg）か"flash N"。
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.split_once(' ') {
+            Some(("flash", n)) => n.trim().parse().ok().map(Work::Flash),
+            Some(_) => None,
+            None => LedMode::from_name(s).map(Work::SetMode),
+        }
+    }
+}
@@ -0,0 +1,222 @@
+// 短い間隔でALARMをscheduleし直しても、取りこぼしも二重の発火もないことを実機で確かめるテスト
+//
+// 間隔が短いと、次の期限が今の時刻に近いか、すでに過ぎていることがある（time.rsのnext_deadline()参照）。
+// 扱いを間違えると、次のどちらかになる。
+//   ・取りこぼし : 過ぎた時刻をALARMに書くと一周（約71分）発火しない。割り込みが止まったように見える。
+//   ・二重の発火 : 強制的に立てた割り込みが消えずに残ったり、遅れを一度に取り戻したりして、
+//                  1回の期限で何回も発火する。
+// どちらも数µsの隙間で起きるので、LEDの点滅を見ても気づかない。
+//
+// 各テストはTIMER_IRQ_0でALARM0をtime::reschedule()でscheduleし直しながらRUN_MSだけ待ち、
+//   ・発火した回数が、経過時間 / 間隔 とRUN_TOLERANCE回以内で一致する
+//   ・期限より前に発火した回がない（二重の発火なら、次の期限より前に割り込みが入る）
+//   ・周期を飛ばしていない（割り込みの処理が間隔に間に合っている）
+// ことを確かめる。next_deadline()の計算そのものは、ホストのテスト（cargo test-host）で確かめている。
+// scheduleし直すのは、ファームウェアのALARM0（main.rsのreschedule_alarm0()）と同じtime::reschedule()なので、
+// このテストはファームウェアが実際に使う手順を確かめている。
+//
+// ISRの中では、scheduleし直す前にclear_interrupt()を呼ぶこと。HALのclear_interrupt()は強制した割り込み（INTF）も
+// 消すので、後で呼ぶと、schedule_at()が過ぎた期限のために立てた割り込みまで消して取りこぼす。
+//
+// 確かめられる最短の間隔はtime::MIN_INTERVAL_US（100 µs）。これより短い間隔はclamp_interval()で100 µsになる。
+//
+// 実行のしかた（デバッグプローブとprobe-rsが必要）
+//   CARGO_TARGET_THUMBV6M_NONE_EABI_RUNNER="probe-rs run --chip RP2040" \
+//       cargo test --test alarm_reschedule --features on-target-test
+// テストごとにリセットし直して、1つずつ実行される。
+
+#![no_std]
+#![no_main]
+
+use defmt_rtt as _;
+
+use core::cell::{Cell, RefCell};
+use cortex_m::interrupt::{free, Mutex};
+use rp2040_project_template::time;
+use rp_pico::hal::pac::{self, interrupt};
+use rp_pico::hal::timer::{Alarm, Alarm0, Timer};
+
+// 1つの間隔を回す時間
+const RUN_MS: u32 = 200;
+// 止めたときにちょうど期限が来ていた1回は、数えられていないことがある
+const RUN_TOLERANCE: u64 = 1;
+
+#[derive(Clone, Copy)]
+struct Run {
+    interval_us: u32,
+    // 次に発火するはずの期限
+    deadline: u64,
+    fired: u32,
+    // 期限より前に発火した回数
+    early: u32,
+    skipped: u32,
+    // 期限から割り込みに入るまでの最大（µs）
+    max_late_us: u64,
+}
+
+static ALARM: Mutex<RefCell<Option<Alarm0>>> = Mutex::new(RefCell::new(None));
+static TIMER: Mutex<Cell<Option<Timer>>> = Mutex::new(Cell::new(None));
+static RUN: Mutex<Cell<Option<Run>>> = Mutex::new(Cell::new(None));
+
+#[interrupt]
+fn TIMER_IRQ_0() {
+    free(|cs| {
+        let mut alarm = ALARM.borrow(cs).borrow_mut();
+        let (Some(alarm), Some(timer), Some(mut run)) =
+            (alarm.as_mut(), TIMER.borrow(cs).get(), RUN.borrow(cs).get())
+        else {
+            return;
+        };
+        // scheduleし直す前に消す（先頭のコメント参照）
+        alarm.clear_interrupt();
+
+        let entered = timer.get_counter().ticks();
+        run.fired += 1;
+        if entered < run.deadline {
+            run.early += 1;
+        }
+        run.max_late_us = run.max_late_us.max(time::elapsed_us(entered, run.deadline));
+
+        let next = time::reschedule(
+            alarm,
+            run.deadline,
+            run.interval_us,
+            timer.get_counter().ticks(),
+        );
+        run.deadline = next.deadline;
+        run.skipped = run.skipped.saturating_add(next.skipped);
+        RUN.borrow(cs).set(Some(run));
+    });
+}
+
+struct State {
+    timer: Timer,
+}
+
+// 今の時刻よりlate_start_usだけ前を最初の「前の期限」にしてALARM0を回し始め、RUN_MSたったら止める。
+// (結果, 最初の「前の期限」から止めるまでの時間µs)を返す。
+fn run(timer: Timer, interval_us: u32, late_start_us: u64) -> (Run, u64) {
+    let start = free(|cs| {
+        let now = timer.get_counter().ticks();
+        let previous = now.saturating_sub(late_start_us);
+        let mut alarm = ALARM.borrow(cs).borrow_mut();
+        let alarm = alarm.as_mut().unwrap();
+        alarm.clear_interrupt();
+        let next = time::reschedule(alarm, previous, interval_us, now);
+        RUN.borrow(cs).set(Some(Run {
+            interval_us,
+            deadline: next.deadline,
+            fired: 0,
+            early: 0,
+            skipped: 0,
+            max_late_us: 0,
+        }));
+        previous
+    });
+    // SAFETY: TIMER_IRQ_0はこのテストのISRだけが使う
+    unsafe { pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_0) };
+
+    let end = time::add_interval(start, RUN_MS * 1000);
+    while !time::deadline_passed(timer.get_counter().ticks(), end) {}
+
+    pac::NVIC::mask(pac::Interrupt::TIMER_IRQ_0);
+    let stopped = timer.get_counter().ticks();
+    let run = free(|cs| {
+        let mut alarm = ALARM.borrow(cs).borrow_mut();
+        alarm.as_mut().unwrap().cancel().unwrap();
+        RUN.borrow(cs).take().unwrap()
+    });
+    defmt::info!(
+        "interval {=u32} us: fired {=u32} early {=u32} skipped {=u32} max late {=u64} us",
+        run.interval_us,
+        run.fired,
+        run.early,
+        run.skipped,
+        run.max_late_us
+    );
+    (run, time::elapsed_us(stopped, start))
+}
+
+fn check_interval(state: State, interval_us: u32, late_start_us: u64) {
+    let (run, elapsed_us) = run(state.timer, interval_us, late_start_us);
+    let expected = elapsed_us / u64::from(time::clamp_interval(interval_us));
+    let fired = u64::from(run.fired);
+    assert!(
+        fired.abs_diff(expected) <= RUN_TOLERANCE,
+        "fired {} times, expected {}",
+        fired,
+        expected
+    );
+    assert_eq!(run.early, 0, "fired before the deadline (double fire)");
+    assert_eq!(run.skipped, 0, "missed whole periods");
+    assert!(run.max_late_us < u64::from(interval_us));
+}
+
+#[cfg(test)]
+#[embedded_test::tests]
+mod tests {
+    use super::*;
+    use rp_pico::hal::{clocks::init_clocks_and_plls, watchdog::Watchdog};
+
+    #[init]
+    fn init() -> State {
+        let mut pac = pac::Peripherals::take().unwrap();
+        let mut watchdog = Watchdog::new(pac.WATCHDOG);
+        let clocks = init_clocks_and_plls(
+            rp_pico::XOSC_CRYSTAL_FREQ,
+            pac.XOSC,
+            pac.CLOCKS,
+            pac.PLL_SYS,
+            pac.PLL_USB,
+            &mut pac.RESETS,
+            &mut watchdog,
+        )
+        .ok()
+        .unwrap();
+        let mut timer = Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
+        let mut alarm = timer.alarm_0().unwrap();
+        alarm.enable_interrupt();
+        free(|cs| {
+            ALARM.borrow(cs).replace(Some(alarm));
+            TIMER.borrow(cs).set(Some(timer));
+        });
+        State { timer }
+    }
+
+    #[test]
+    fn reschedule_at_min_interval(state: State) {
+        check_interval(state, time::MIN_INTERVAL_US, 0);
+    }
+
+    #[test]
+    fn reschedule_at_150_us(state: State) {
+        check_interval(state, 150, 0);
+    }
+
+    #[test]
+    fn reschedule_at_250_us(state: State) {
+        check_interval(state, 250, 0);
+    }
+
+    #[test]
+    fn reschedule_at_1_ms(state: State) {
+        check_interval(state, 1000, 0);
+    }
+
+    // 最初の期限がちょうど今の時刻になる（書いた時点で一致を逃しうる）場合でも、取りこぼさずにすぐ発火する
+    #[test]
+    fn deadline_at_now_is_not_lost(state: State) {
+        check_interval(
+            state,
+            time::MIN_INTERVAL_US,
+            u64::from(time::MIN_INTERVAL_US),
+        );
+    }
+
+    // 最初の期限が1周期近く過ぎている場合でも、1回だけ発火して遅れを取り戻す
+    #[test]
+    fn deadline_in_the_past_fires_once(state: State) {
+        let interval_us = 1000;
+        check_interval(state, interval_us, u64::from(interval_us) * 2 - 100);
+    }
+}